@@ -0,0 +1,239 @@
+use crate::cpu::{decode_mnemonic, undocumented, AddressingMode, CpuOptions, CpuVariant};
+use crate::disasm::full_mnemonic;
+use std::fmt;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum AssembleError {
+    UnknownMnemonic(String),
+    InvalidOperand(String),
+    UnsupportedMode(String), // the instruction exists, but not with this operand
+    BranchOutOfRange(u16),   // the target is too far from the branch
+}
+
+impl fmt::Display for AssembleError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AssembleError::UnknownMnemonic(mnemonic) => write!(f, "unknown mnemonic {}", mnemonic),
+            AssembleError::InvalidOperand(operand) => write!(f, "invalid operand {}", operand),
+            AssembleError::UnsupportedMode(line) => write!(f, "no such addressing mode: {}", line),
+            AssembleError::BranchOutOfRange(target) => {
+                write!(f, "branch target ${:04X} out of range", target)
+            }
+        }
+    }
+}
+
+// assembles a single line such as "LDA ($10),Y" to be placed at addr, using the same opcode
+// table as the executor. Numbers are hex with a $, or decimal, and operands of one or two hex
+// digits select the zero page modes where there are any
+pub fn assemble_line(
+    line: &str,
+    addr: u16,
+    options: &CpuOptions,
+) -> Result<Vec<u8>, AssembleError> {
+    let line = line.trim();
+    let (mnemonic, operand) = match line.split_once(char::is_whitespace) {
+        Some((mnemonic, operand)) => (mnemonic, operand.trim()),
+        None => (line, ""),
+    };
+    let mnemonic = mnemonic.to_uppercase();
+
+    let opcodes: Vec<(u8, AddressingMode)> = (0..=0xff)
+        .filter_map(|opcode| {
+            let (name, mode) = decode_mnemonic(opcode, options);
+            (full_mnemonic(opcode, name) == mnemonic).then_some((opcode, mode))
+        })
+        .collect();
+    if opcodes.is_empty() {
+        return Err(AssembleError::UnknownMnemonic(mnemonic));
+    }
+
+    let (modes, values) = parse_operand(operand)?;
+
+    // documented opcodes are preferred where an undocumented one does the same
+    let cmos = options.variant == CpuVariant::Cmos65c02;
+    let (opcode, mode) = modes
+        .iter()
+        .find_map(|&mode| {
+            let mut matching = opcodes.iter().filter(|&&(_, m)| m == mode);
+            let first = matching.clone().next().copied();
+            matching
+                .find(|&&(opcode, _)| cmos || !undocumented(opcode))
+                .copied()
+                .or(first)
+        })
+        .ok_or_else(|| AssembleError::UnsupportedMode(line.to_string()))?;
+
+    let branch = |target: u16, len: u16| {
+        let displacement = target.wrapping_sub(addr.wrapping_add(len)) as i16;
+        if (-128..=127).contains(&displacement) {
+            Ok(displacement as u8)
+        } else {
+            Err(AssembleError::BranchOutOfRange(target))
+        }
+    };
+
+    let mut bytes = vec![opcode];
+    match mode {
+        AddressingMode::Acc | AddressingMode::Imp => {}
+        AddressingMode::I
+        | AddressingMode::Zp
+        | AddressingMode::Zpix
+        | AddressingMode::Zpiy
+        | AddressingMode::Zpiix
+        | AddressingMode::Zpiiy
+        | AddressingMode::Zpi => bytes.push(values[0] as u8),
+        AddressingMode::A
+        | AddressingMode::Aix
+        | AddressingMode::Aiy
+        | AddressingMode::Ind
+        | AddressingMode::Aiix => bytes.extend(values[0].to_le_bytes()),
+        AddressingMode::Rel => bytes.push(branch(values[0], 2)?),
+        AddressingMode::Zpr => {
+            bytes.push(values[0] as u8);
+            bytes.push(branch(values[1], 3)?);
+        }
+    }
+
+    Ok(bytes)
+}
+
+fn parse_number(text: &str) -> Option<(u16, bool)> {
+    // returns the value, and whether it was written as a zero page address
+    let text = text.trim();
+    match text.strip_prefix('$') {
+        Some(hex) => {
+            let value = u16::from_str_radix(hex, 16).ok()?;
+            Some((value, hex.len() <= 2))
+        }
+        None => {
+            let value: u16 = text.parse().ok()?;
+            Some((value, value <= 0xff))
+        }
+    }
+}
+
+fn parse_operand(operand: &str) -> Result<(Vec<AddressingMode>, Vec<u16>), AssembleError> {
+    use AddressingMode::*;
+
+    let invalid = || AssembleError::InvalidOperand(operand.to_string());
+    let number = |text: &str| parse_number(text).ok_or_else(invalid);
+    let upper = operand.to_uppercase().replace(' ', "");
+
+    if upper.is_empty() {
+        return Ok((vec![Imp, Acc], vec![]));
+    }
+    if upper == "A" {
+        return Ok((vec![Acc], vec![]));
+    }
+    if let Some(value) = upper.strip_prefix('#') {
+        let (value, _) = number(value)?;
+        if value > 0xff {
+            return Err(invalid());
+        }
+        return Ok((vec![I], vec![value]));
+    }
+
+    let (modes, value) = if let Some(inner) = upper.strip_prefix('(') {
+        if let Some(ptr) = inner.strip_suffix(",X)") {
+            let (value, zp) = number(ptr)?;
+            (if zp { vec![Zpiix, Aiix] } else { vec![Aiix] }, value)
+        } else if let Some(ptr) = inner.strip_suffix("),Y") {
+            let (value, zp) = number(ptr)?;
+            if !zp {
+                return Err(invalid());
+            }
+            (vec![Zpiiy], value)
+        } else if let Some(ptr) = inner.strip_suffix(')') {
+            let (value, zp) = number(ptr)?;
+            (if zp { vec![Zpi, Ind] } else { vec![Ind] }, value)
+        } else {
+            return Err(invalid());
+        }
+    } else if let Some(base) = upper.strip_suffix(",X") {
+        let (value, zp) = number(base)?;
+        (if zp { vec![Zpix, Aix] } else { vec![Aix] }, value)
+    } else if let Some(base) = upper.strip_suffix(",Y") {
+        let (value, zp) = number(base)?;
+        (if zp { vec![Zpiy, Aiy] } else { vec![Aiy] }, value)
+    } else if let Some((zp, target)) = upper.split_once(',') {
+        // BBR and BBS take a zero page address and a branch target
+        let (zp, _) = number(zp)?;
+        let (target, _) = number(target)?;
+        return Ok((vec![Zpr], vec![zp, target]));
+    } else {
+        let (value, zp) = number(&upper)?;
+        (if zp { vec![Zp, A, Rel] } else { vec![A, Rel] }, value)
+    };
+
+    Ok((modes, vec![value]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::FlatRam;
+    use crate::disasm::disassemble;
+
+    #[test]
+    fn test_assemble_line() {
+        let options = CpuOptions::default();
+        let assemble = |line| assemble_line(line, 0x0200, &options);
+
+        assert_eq!(Ok(vec![0xa9, 0x42]), assemble("LDA #$42"));
+        assert_eq!(Ok(vec![0xa5, 0x10]), assemble("lda $10"));
+        assert_eq!(Ok(vec![0xad, 0x10, 0x00]), assemble("LDA $0010"));
+        assert_eq!(Ok(vec![0xb1, 0x10]), assemble("LDA ($10),Y"));
+        assert_eq!(Ok(vec![0x0a]), assemble("ASL"));
+        assert_eq!(Ok(vec![0xea]), assemble("NOP"));
+        assert_eq!(Ok(vec![0xe9, 0x01]), assemble("SBC #1"));
+        assert_eq!(Ok(vec![0x4c, 0x10, 0x00]), assemble("JMP $10"));
+        assert_eq!(Ok(vec![0xd0, 0xfe]), assemble("BNE $0200"));
+        assert_eq!(Ok(vec![0xa7, 0x10]), assemble("LAX $10"));
+
+        assert_eq!(
+            Err(AssembleError::UnknownMnemonic("FOO".to_string())),
+            assemble("FOO")
+        );
+        assert_eq!(
+            Err(AssembleError::UnsupportedMode("STA #$10".to_string())),
+            assemble("STA #$10")
+        );
+        assert_eq!(
+            Err(AssembleError::BranchOutOfRange(0x0300)),
+            assemble("BEQ $0300")
+        );
+
+        let options = CpuOptions::for_variant(CpuVariant::Cmos65c02);
+        assert_eq!(
+            Ok(vec![0x9f, 0x20, 0x10]),
+            assemble_line("BBS1 $20,$0213", 0x0200, &options)
+        );
+        assert_eq!(
+            Ok(vec![0x7c, 0x00, 0x30]),
+            assemble_line("JMP ($3000,X)", 0x0200, &options)
+        );
+    }
+
+    #[test]
+    fn test_assemble_disassemble() {
+        // everything the disassembler prints assembles back to the same bytes
+        let options = CpuOptions::default();
+        let mut ram = FlatRam::default();
+        for opcode in 0..=0xff {
+            ram[0x0200..0x0203].copy_from_slice(&[opcode, 0x34, 0x12]);
+            let instruction = disassemble(&ram, 0x0200, &options);
+            let bytes = assemble_line(&instruction.to_string(), 0x0200, &options).unwrap();
+            let reassembled = disassemble(
+                &{
+                    let mut ram = FlatRam::default();
+                    ram[0x0200..0x0200 + bytes.len()].copy_from_slice(&bytes);
+                    ram
+                },
+                0x0200,
+                &options,
+            );
+            assert_eq!(instruction.to_string(), reassembled.to_string());
+        }
+    }
+}
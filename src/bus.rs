@@ -0,0 +1,726 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::ops::{Index, IndexMut, RangeInclusive};
+use std::rc::Rc;
+use std::slice::SliceIndex;
+
+// everything the CPU can address. Reads take &mut self since reading a device register
+// often has side effects
+pub trait Bus {
+    fn read(&mut self, addr: u16) -> u8;
+    fn write(&mut self, addr: u16, val: u8);
+    // reads without side effects, for debuggers and snapshots
+    fn peek(&self, addr: u16) -> u8;
+    // writes to RAM without side effects, for filling memory at power on. What a write
+    // couldn't change, like ROM, is left alone
+    fn poke(&mut self, addr: u16, val: u8);
+}
+
+// 64K of plain RAM filling the whole address space. It lives on the heap, so systems are
+// cheap to move and keep many of
+#[derive(Clone)]
+pub struct FlatRam {
+    bytes: Box<[u8; 0x10000]>,
+}
+
+impl Default for FlatRam {
+    fn default() -> Self {
+        // going through a Vec avoids building the array on the stack first
+        FlatRam {
+            bytes: vec![0; 0x10000].into_boxed_slice().try_into().unwrap(),
+        }
+    }
+}
+
+impl Bus for FlatRam {
+    fn read(&mut self, addr: u16) -> u8 {
+        self.bytes[addr as usize]
+    }
+
+    fn write(&mut self, addr: u16, val: u8) {
+        self.bytes[addr as usize] = val;
+    }
+
+    fn peek(&self, addr: u16) -> u8 {
+        self.bytes[addr as usize]
+    }
+
+    fn poke(&mut self, addr: u16, val: u8) {
+        self.bytes[addr as usize] = val;
+    }
+}
+
+// serde only handles arrays up to 32 long, so the RAM goes as a sequence of bytes
+#[cfg(feature = "serde")]
+impl serde::Serialize for FlatRam {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.bytes[..].serialize(serializer)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for FlatRam {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let bytes = Vec::<u8>::deserialize(deserializer)?;
+        let len = bytes.len();
+        let bytes = bytes
+            .into_boxed_slice()
+            .try_into()
+            .map_err(|_| serde::de::Error::invalid_length(len, &"65536 bytes"))?;
+        Ok(FlatRam { bytes })
+    }
+}
+
+impl<I: SliceIndex<[u8]>> Index<I> for FlatRam {
+    type Output = I::Output;
+
+    fn index(&self, index: I) -> &I::Output {
+        &self.bytes[index]
+    }
+}
+
+impl<I: SliceIndex<[u8]>> IndexMut<I> for FlatRam {
+    fn index_mut(&mut self, index: I) -> &mut I::Output {
+        &mut self.bytes[index]
+    }
+}
+
+type ReadHandler = Box<dyn FnMut(u16) -> u8>;
+type WriteHandler = Box<dyn FnMut(u16, u8)>;
+type PeekHandler = Box<dyn Fn(u16) -> u8>;
+
+struct Region {
+    start: u16,
+    end: u16, // inclusive, so a region can reach $FFFF
+    read: Option<ReadHandler>,
+    write: Option<WriteHandler>,
+    peek: Option<PeekHandler>,
+}
+
+// RAM with devices mapped over ranges of it. Handlers are called with the offset of the
+// address into their range, and accesses nothing handles go to the RAM underneath
+#[derive(Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct MappedBus {
+    ram: FlatRam,
+    // devices are mapped again after a savestate is loaded
+    #[cfg_attr(feature = "serde", serde(skip))]
+    regions: Vec<Region>, // later regions take priority where they overlap
+    read_only: Vec<RangeInclusive<u16>>,
+    mirrors: Vec<(RangeInclusive<u16>, u16)>, // ranges that repeat every so many bytes
+    // called with the address and value of writes to read-only RAM, which are otherwise
+    // ignored
+    #[cfg_attr(feature = "serde", serde(skip))]
+    bus_fault: Option<WriteHandler>,
+
+    unmapped: Vec<RangeInclusive<u16>>, // ranges with no RAM behind them
+    // reads of unmapped addresses see the last value driven on the data bus, which the
+    // bus capacitance holds, rather than 0
+    open_bus: bool,
+    last_value: u8,
+}
+
+impl MappedBus {
+    pub fn new() -> Self {
+        MappedBus::default()
+    }
+
+    pub fn map(
+        &mut self,
+        range: RangeInclusive<u16>,
+        read: impl FnMut(u16) -> u8 + 'static,
+        write: impl FnMut(u16, u8) + 'static,
+    ) {
+        self.add_region(range, Some(Box::new(read)), Some(Box::new(write)), None);
+    }
+
+    pub fn map_read(&mut self, range: RangeInclusive<u16>, read: impl FnMut(u16) -> u8 + 'static) {
+        self.add_region(range, Some(Box::new(read)), None, None);
+    }
+
+    pub fn map_write(&mut self, range: RangeInclusive<u16>, write: impl FnMut(u16, u8) + 'static) {
+        self.add_region(range, None, Some(Box::new(write)), None);
+    }
+
+    pub fn map_peek(&mut self, range: RangeInclusive<u16>, peek: impl Fn(u16) -> u8 + 'static) {
+        // what peeking sees in a range, for devices that can show their contents without
+        // side effects
+        self.add_region(range, None, None, Some(Box::new(peek)));
+    }
+
+    pub fn map_rom(&mut self, start: u16, rom: &[u8]) {
+        // copies the ROM image into place and protects it from writes
+        let end = start as usize + rom.len();
+        assert!(
+            !rom.is_empty() && end <= 0x10000,
+            "ROM of {} bytes doesn't fit at ${:04x}",
+            rom.len(),
+            start
+        );
+
+        self.ram[start as usize..end].copy_from_slice(rom);
+        self.protect(start..=(end - 1) as u16);
+    }
+
+    pub fn mirror(&mut self, range: RangeInclusive<u16>, size: u16) {
+        // partially decoded addresses repeat the first `size` bytes of the range through
+        // the rest of it, e.g. the NES's 2K of RAM through $0000-$1FFF
+        assert!(
+            size != 0,
+            "mirror of ${:04x}-${:04x} has no size",
+            range.start(),
+            range.end()
+        );
+        self.mirrors.push((range, size));
+    }
+
+    pub fn protect(&mut self, range: RangeInclusive<u16>) {
+        self.read_only.push(range);
+    }
+
+    pub fn set_bus_fault_handler(&mut self, handler: impl FnMut(u16, u8) + 'static) {
+        self.bus_fault = Some(Box::new(handler));
+    }
+
+    pub fn map_banked(&mut self, start: u16, banks: Rc<RefCell<BankedMemory>>) {
+        // the window is one bank long, and shows whichever bank is selected
+        let end = start as usize + banks.borrow().bank_size - 1;
+        assert!(
+            end <= 0xffff,
+            "bank window at ${:04x} runs off the end of memory",
+            start
+        );
+
+        let read_banks = banks.clone();
+        let peek_banks = banks.clone();
+        self.map(
+            start..=end as u16,
+            move |offset| read_banks.borrow().read(offset),
+            move |offset, val| banks.borrow_mut().write(offset, val),
+        );
+        self.map_peek(start..=end as u16, move |offset| {
+            peek_banks.borrow().read(offset)
+        });
+    }
+
+    pub fn map_bank_select(
+        &mut self,
+        range: RangeInclusive<u16>,
+        banks: Rc<RefCell<BankedMemory>>,
+    ) {
+        // writes anywhere in the range select the bank numbered by the value written
+        self.map_write(range, move |_, val| banks.borrow_mut().select(val as usize));
+    }
+
+    pub fn unmap(&mut self, range: RangeInclusive<u16>) {
+        // removes the RAM from a range, leaving it to any devices mapped there
+        self.unmapped.push(range);
+    }
+
+    pub fn set_open_bus(&mut self, enabled: bool) {
+        self.open_bus = enabled;
+    }
+
+    pub fn open_bus_value(&self) -> u8 {
+        self.last_value
+    }
+
+    pub fn ram(&self) -> &FlatRam {
+        &self.ram
+    }
+
+    pub fn ram_mut(&mut self) -> &mut FlatRam {
+        &mut self.ram
+    }
+
+    fn decode(&self, addr: u16) -> u16 {
+        match self
+            .mirrors
+            .iter()
+            .rev()
+            .find(|(range, _)| range.contains(&addr))
+        {
+            Some((range, size)) => range.start() + (addr - range.start()) % size,
+            None => addr,
+        }
+    }
+
+    fn is_unmapped(&self, addr: u16) -> bool {
+        self.unmapped.iter().any(|range| range.contains(&addr))
+    }
+
+    fn add_region(
+        &mut self,
+        range: RangeInclusive<u16>,
+        read: Option<ReadHandler>,
+        write: Option<WriteHandler>,
+        peek: Option<PeekHandler>,
+    ) {
+        self.regions.push(Region {
+            start: *range.start(),
+            end: *range.end(),
+            read,
+            write,
+            peek,
+        });
+    }
+}
+
+impl Bus for MappedBus {
+    fn read(&mut self, addr: u16) -> u8 {
+        let addr = self.decode(addr);
+        let unmapped = self.is_unmapped(addr);
+        let region =
+            self.regions.iter_mut().rev().find(|region| {
+                (region.start..=region.end).contains(&addr) && region.read.is_some()
+            });
+
+        let val = match region {
+            Some(region) => (region.read.as_mut().unwrap())(addr - region.start),
+            None if unmapped => {
+                if self.open_bus {
+                    self.last_value
+                } else {
+                    0x00
+                }
+            }
+            None => self.ram.read(addr),
+        };
+        self.last_value = val;
+
+        val
+    }
+
+    fn write(&mut self, addr: u16, val: u8) {
+        let addr = self.decode(addr);
+        let unmapped = self.is_unmapped(addr);
+        self.last_value = val;
+        let region =
+            self.regions.iter_mut().rev().find(|region| {
+                (region.start..=region.end).contains(&addr) && region.write.is_some()
+            });
+
+        match region {
+            Some(region) => (region.write.as_mut().unwrap())(addr - region.start, val),
+            None if self.read_only.iter().any(|range| range.contains(&addr)) => {
+                if let Some(bus_fault) = self.bus_fault.as_mut() {
+                    bus_fault(addr, val);
+                }
+            }
+            None if unmapped => {}
+            None => self.ram.write(addr, val),
+        }
+    }
+
+    fn peek(&self, addr: u16) -> u8 {
+        // read handlers can't be called without their side effects, so peeking at a device
+        // sees the RAM underneath it unless the device has a peek handler
+        let addr = self.decode(addr);
+        let region =
+            self.regions.iter().rev().find(|region| {
+                (region.start..=region.end).contains(&addr) && region.peek.is_some()
+            });
+        if let Some(region) = region {
+            return (region.peek.as_ref().unwrap())(addr - region.start);
+        }
+        match (self.is_unmapped(addr), self.open_bus) {
+            (true, true) => self.last_value,
+            (true, false) => 0x00,
+            (false, _) => self.ram.peek(addr),
+        }
+    }
+
+    fn poke(&mut self, addr: u16, val: u8) {
+        // devices and the bus fault handler don't see it, and it reaches the RAM
+        // underneath a device
+        let addr = self.decode(addr);
+        if !self.is_unmapped(addr) && !self.read_only.iter().any(|range| range.contains(&addr)) {
+            self.ram.poke(addr, val);
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum BankSize {
+    Kb4,
+    Kb8,
+    Kb16,
+}
+
+impl BankSize {
+    fn bytes(self) -> usize {
+        match self {
+            BankSize::Kb4 => 0x1000,
+            BankSize::Kb8 => 0x2000,
+            BankSize::Kb16 => 0x4000,
+        }
+    }
+}
+
+// ROM or RAM larger than the window it's seen through, a bank at a time, as cartridge
+// mappers and the Apple II language card do. Shared with the bus through an Rc so that
+// registers elsewhere in the memory map can switch banks
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct BankedMemory {
+    data: Vec<u8>,
+    bank_size: usize,
+    selected: usize,
+    writable: bool,
+}
+
+impl BankedMemory {
+    pub fn rom(data: Vec<u8>, bank_size: BankSize) -> Self {
+        let bank_size = bank_size.bytes();
+        assert!(
+            !data.is_empty() && data.len().is_multiple_of(bank_size),
+            "{} bytes isn't a whole number of {} byte banks",
+            data.len(),
+            bank_size
+        );
+
+        BankedMemory {
+            data,
+            bank_size,
+            selected: 0,
+            writable: false,
+        }
+    }
+
+    pub fn ram(banks: usize, bank_size: BankSize) -> Self {
+        BankedMemory {
+            writable: true,
+            ..BankedMemory::rom(vec![0; banks * bank_size.bytes()], bank_size)
+        }
+    }
+
+    pub fn banks(&self) -> usize {
+        self.data.len() / self.bank_size
+    }
+
+    pub fn selected(&self) -> usize {
+        self.selected
+    }
+
+    pub fn select(&mut self, bank: usize) {
+        // mappers ignore bank number bits beyond the banks they have
+        self.selected = bank % self.banks();
+    }
+
+    pub fn read(&self, offset: u16) -> u8 {
+        self.data[self.selected * self.bank_size + offset as usize]
+    }
+
+    pub fn write(&mut self, offset: u16, val: u8) {
+        if self.writable {
+            self.data[self.selected * self.bank_size + offset as usize] = val;
+        }
+    }
+}
+
+// a block copy for a DMA controller to make, a byte every two cycles
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DmaTransfer {
+    pub source: u16,
+    pub dest: u16,
+    pub len: u16,
+    pub increment_dest: bool, // false to write every byte to a single register
+    // only read on even cycles, as the NES's DMA unit can only read on its get cycles
+    pub align_reads: bool,
+}
+
+impl DmaTransfer {
+    pub fn nes_oam(page: u8) -> Self {
+        // a write of the page number to $4014 copies it into the PPU's OAM through $2004
+        DmaTransfer {
+            source: (page as u16) << 8,
+            dest: 0x2004,
+            len: 0x100,
+            increment_dest: false,
+            align_reads: true,
+        }
+    }
+}
+
+// a DMA controller that takes over the bus by holding RDY low, shared through an Rc with
+// the bus so that a register write can start a transfer. The CPU finishes any writes it is
+// making, spends a cycle halting, and then the transfer runs until it is done
+#[derive(Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Dma {
+    transfer: Option<DmaTransfer>,
+    halted: bool,     // the CPU has spent its cycle halting
+    data: Option<u8>, // the byte read and not yet written
+    done: u16,
+}
+
+impl Dma {
+    pub fn new() -> Self {
+        Dma::default()
+    }
+
+    pub fn start(&mut self, transfer: DmaTransfer) {
+        // a transfer of nothing is over as soon as it starts, without halting the CPU
+        *self = Dma {
+            transfer: (transfer.len != 0).then_some(transfer),
+            ..Dma::default()
+        };
+    }
+
+    pub fn active(&self) -> bool {
+        self.transfer.is_some()
+    }
+
+    // runs a cycle of the transfer on a bus the CPU has given up, returning the access made
+    pub(crate) fn cycle(&mut self, bus: &mut impl Bus, cycle: u64) -> Option<(u16, u8, bool)> {
+        let transfer = self.transfer?;
+
+        if !self.halted {
+            self.halted = true;
+            return None;
+        }
+
+        let access = match self.data.take() {
+            None if transfer.align_reads && cycle % 2 == 1 => return None,
+            None => {
+                let addr = transfer.source.wrapping_add(self.done);
+                let val = bus.read(addr);
+                self.data = Some(val);
+                (addr, val, false)
+            }
+            Some(val) => {
+                let addr = if transfer.increment_dest {
+                    transfer.dest.wrapping_add(self.done)
+                } else {
+                    transfer.dest
+                };
+                bus.write(addr, val);
+                self.done += 1;
+                (addr, val, true)
+            }
+        };
+
+        if self.done == transfer.len {
+            self.transfer = None;
+        }
+
+        Some(access)
+    }
+}
+
+// the IRQ line shared by several devices, any of which can pull it low, so the CPU sees it
+// asserted while any device asserts it. Devices are told apart by IDs of their choosing
+#[derive(Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct InterruptLines {
+    asserted: Vec<bool>,
+}
+
+impl InterruptLines {
+    pub fn new() -> Self {
+        InterruptLines::default()
+    }
+
+    pub fn assert(&mut self, id: usize) {
+        if id >= self.asserted.len() {
+            self.asserted.resize(id + 1, false);
+        }
+        self.asserted[id] = true;
+    }
+
+    pub fn deassert(&mut self, id: usize) {
+        if let Some(asserted) = self.asserted.get_mut(id) {
+            *asserted = false;
+        }
+    }
+
+    pub fn asserted_by(&self, id: usize) -> bool {
+        self.asserted.get(id).copied().unwrap_or(false)
+    }
+
+    pub fn is_asserted(&self) -> bool {
+        self.asserted.contains(&true)
+    }
+}
+
+// identifies a scheduled event, and orders events by when they run, in the order they
+// were scheduled for the same cycle
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct EventId {
+    cycle: u64,
+    seq: u64,
+}
+
+type Event = Box<dyn FnOnce(u64)>;
+
+// callbacks for devices to run at given cycle counts, so that timers and the like needn't
+// be polled on every cycle. Shared through an Rc with the devices, and run by the CPU at
+// the start of the cycle they're due. Events are passed the cycle count, and may schedule
+// further events
+#[derive(Default)]
+pub struct Scheduler {
+    events: BTreeMap<EventId, Event>,
+    next_seq: u64,
+}
+
+impl Scheduler {
+    pub fn new() -> Self {
+        Scheduler::default()
+    }
+
+    pub fn schedule(&mut self, cycle: u64, event: impl FnOnce(u64) + 'static) -> EventId {
+        let id = EventId {
+            cycle,
+            seq: self.next_seq,
+        };
+        self.next_seq += 1;
+        self.events.insert(id, Box::new(event));
+
+        id
+    }
+
+    pub fn cancel(&mut self, id: EventId) -> bool {
+        self.events.remove(&id).is_some()
+    }
+
+    pub fn next_event(&self) -> Option<u64> {
+        self.events.keys().next().map(|id| id.cycle)
+    }
+
+    pub(crate) fn pop_due(&mut self, cycle: u64) -> Option<Event> {
+        match self.events.first_key_value() {
+            Some((id, _)) if id.cycle <= cycle => self.events.pop_first().map(|(_, event)| event),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mapped_bus() {
+        let mut bus = MappedBus::new();
+
+        // a device with 16 registers at $6000, like a VIA
+        let registers = Rc::new(RefCell::new([0u8; 0x10]));
+        let read_registers = registers.clone();
+        let write_registers = registers.clone();
+        bus.map(
+            0x6000..=0x600f,
+            move |offset| read_registers.borrow()[offset as usize],
+            move |offset, val| write_registers.borrow_mut()[offset as usize] = val,
+        );
+
+        bus.write(0x6003, 0x42);
+        assert_eq!(0x42, registers.borrow()[0x03]);
+        assert_eq!(0x42, bus.read(0x6003));
+        assert_eq!(0x00, bus.ram()[0x6003]);
+
+        // unmapped addresses are RAM
+        bus.write(0x6010, 0x99);
+        assert_eq!(0x99, bus.read(0x6010));
+
+        // a later read-only mapping takes over reads, while writes still reach the device
+        bus.map_read(0x6000..=0x6000, |_| 0xff);
+        assert_eq!(0xff, bus.read(0x6000));
+        bus.write(0x6000, 0x12);
+        assert_eq!(0x12, registers.borrow()[0x00]);
+    }
+
+    #[test]
+    fn test_rom() {
+        let mut bus = MappedBus::new();
+        bus.map_rom(0xfffc, &[0x00, 0x80, 0x00, 0x90]);
+        assert_eq!(0x80, bus.read(0xfffd));
+
+        // writes to ROM are ignored, unless something is listening for bus faults
+        bus.write(0xfffd, 0x12);
+        assert_eq!(0x80, bus.read(0xfffd));
+
+        let faults = Rc::new(RefCell::new(Vec::new()));
+        let handler_faults = faults.clone();
+        bus.set_bus_fault_handler(move |addr, val| handler_faults.borrow_mut().push((addr, val)));
+        bus.write(0xffff, 0x34);
+        bus.write(0xfffb, 0x56);
+        assert_eq!(vec![(0xffff, 0x34)], *faults.borrow());
+        assert_eq!(0x90, bus.read(0xffff));
+        assert_eq!(0x56, bus.read(0xfffb));
+    }
+
+    #[test]
+    fn test_mirror() {
+        // the NES mirrors its 2K of RAM four times, and the PPU's 8 registers every 8 bytes
+        let mut bus = MappedBus::new();
+        bus.mirror(0x0000..=0x1fff, 0x0800);
+        bus.mirror(0x2000..=0x3fff, 0x0008);
+
+        let ppu_writes = Rc::new(RefCell::new(Vec::new()));
+        let handler_writes = ppu_writes.clone();
+        bus.map_write(0x2000..=0x2007, move |offset, val| {
+            handler_writes.borrow_mut().push((offset, val))
+        });
+
+        bus.write(0x0801, 0x42);
+        assert_eq!(0x42, bus.read(0x0001));
+        assert_eq!(0x42, bus.read(0x1801));
+        assert_eq!(0x00, bus.ram()[0x0801]);
+
+        bus.write(0x3ff9, 0x99);
+        assert_eq!(vec![(0x01, 0x99)], *ppu_writes.borrow());
+    }
+
+    #[test]
+    fn test_banked_memory() {
+        // UxROM switches a 16K bank in at $8000, selected by writing anywhere in ROM, while
+        // the last bank stays fixed at $C000
+        let prg: Vec<u8> = (0..4).flat_map(|bank| vec![bank; 0x4000]).collect();
+        let switched = Rc::new(RefCell::new(BankedMemory::rom(prg.clone(), BankSize::Kb16)));
+        let fixed = Rc::new(RefCell::new(BankedMemory::rom(prg, BankSize::Kb16)));
+        fixed.borrow_mut().select(3);
+
+        let mut bus = MappedBus::new();
+        bus.map_banked(0x8000, switched.clone());
+        bus.map_banked(0xc000, fixed);
+        bus.map_bank_select(0x8000..=0xffff, switched.clone());
+
+        assert_eq!(0x00, bus.read(0x8000));
+        assert_eq!(0x03, bus.read(0xffff));
+        bus.write(0xc123, 0x06);
+        assert_eq!(2, switched.borrow().selected());
+        assert_eq!(0x02, bus.read(0xbfff));
+        assert_eq!(0x02, bus.peek(0xbfff));
+        assert_eq!(0x03, bus.read(0xc000));
+
+        // banks of RAM keep what's written to them
+        let banks = Rc::new(RefCell::new(BankedMemory::ram(2, BankSize::Kb4)));
+        bus.map_banked(0xd000, banks.clone());
+        bus.write(0xd000, 0x42);
+        banks.borrow_mut().select(1);
+        assert_eq!(0x00, bus.read(0xd000));
+        banks.borrow_mut().select(0);
+        assert_eq!(0x42, bus.read(0xd000));
+    }
+
+    #[test]
+    fn test_open_bus() {
+        let mut bus = MappedBus::new();
+        bus.unmap(0x4000..=0x5fff);
+        bus.write(0x0010, 0x42);
+
+        // unmapped reads are 0 unless open bus is enabled
+        bus.read(0x0010);
+        assert_eq!(0x00, bus.read(0x4000));
+
+        bus.set_open_bus(true);
+        bus.read(0x0010);
+        assert_eq!(0x42, bus.read(0x4000));
+
+        // writes to unmapped addresses drive the bus but go nowhere
+        bus.write(0x5000, 0x99);
+        assert_eq!(0x99, bus.read(0x4000));
+        assert_eq!(0x00, bus.ram()[0x5000]);
+    }
+}
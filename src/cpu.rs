@@ -1,478 +1,5378 @@
-#[derive(Default)]
+use crate::bus::{Bus, Dma, FlatRam, InterruptLines, Scheduler};
+use crate::expr::Condition;
+use crate::heatmap::Heatmap;
+use crate::symbols::Symbols;
+use crate::trace;
+use std::cell::RefCell;
+use std::collections::BTreeSet;
+use std::ops::RangeInclusive;
+use std::rc::Rc;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum RunState {
+    #[default]
+    Running,
+    Halted,  // a JAM opcode locked up the CPU
+    Waiting, // WAI is waiting for an interrupt
+    Stopped, // STP stopped the clock until reset
+}
+
+#[derive(Debug, PartialEq)]
+pub enum StepResult {
+    Executed(u16), // cycles taken
+    Halted,
+    Waiting,
+    Stopped,
+    Stalled(u16), // RDY is low, cycles taken before the instruction stalled
+    Trapped(u16), // address of an instruction that has jumped to itself too many times
+}
+
+#[derive(Debug, PartialEq)]
+pub enum RunUntil {
+    Satisfied,   // the predicate returned true
+    OutOfCycles, // the budget ran out first
+    Halted,
+    Stopped,
+    Trapped(u16),
+}
+
+#[derive(Debug, PartialEq)]
+pub enum RunEvent {
+    BreakpointHit(u16),  // the PC reached a breakpoint, which hasn't been executed yet
+    ConditionMet(usize), // index of the condition that became true after an instruction
+    WatchpointHit(WatchpointHit), // the first watched access of the instruction just run
+    Halted,
+    Waiting,
+    Stopped,
+    Stalled,
+    Trapped(u16),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+enum Interrupt {
+    Irq,
+    Nmi,
+    Reset,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct CpuState {
     // registers
     a: u8,
     x: u8,
     y: u8,
-    pch: u8,
-    pcl: u8,
+    pc: u16,
     s: u8,
 
     // flags
     negative: bool,
     signed_overflow: bool,
-    brk_interrupt: bool,
     decimal_mode: bool,
     irq_interrupt_disable: bool,
     zero: bool,
     carry: bool,
-}
 
-pub struct SystemState {
-    cpu_state: CpuState,
-    memory: [u8; 0x10000],
+    run_state: RunState,
+    // CLI, SEI and PLP change I after the CPU has polled for interrupts, so the next poll
+    // still sees the previous value
+    delayed_irq_interrupt_disable: Option<bool>,
+
+    // internal state carried between the cycles of an instruction
+    instruction_addr: u16,
+    sync: bool, // high while the opcode is fetched, as on the SYNC pin
+    opcode: u8,
+    cycle: u8,   // the next cycle of the current instruction, 0 between instructions
+    addr: u16,   // effective address, built up over the addressing cycles
+    pointer: u8, // zero page pointer of the indirect modes
+    data: u8,    // operand latched by an earlier cycle
+    page_cross: bool,
+    interrupt: Option<Interrupt>, // a hardware interrupt running through the BRK sequence
+    cycle_count: u64,
 }
 
-impl Default for SystemState {
-    fn default() -> Self {
-        SystemState {
-            cpu_state: CpuState::default(), // TODO: init stack pointer to 0xff
-            memory: [0; 0x10000],
+// the B flag only exists in pushed copies of the status, where it marks a BRK or PHP rather
+// than a hardware interrupt
+const BREAK_FLAG: u8 = 0x10;
+
+impl CpuState {
+    pub fn cycle_count(&self) -> u64 {
+        self.cycle_count
+    }
+
+    pub fn a(&self) -> u8 {
+        self.a
+    }
+
+    pub fn set_a(&mut self, value: u8) {
+        self.a = value;
+    }
+
+    pub fn x(&self) -> u8 {
+        self.x
+    }
+
+    pub fn set_x(&mut self, value: u8) {
+        self.x = value;
+    }
+
+    pub fn y(&self) -> u8 {
+        self.y
+    }
+
+    pub fn set_y(&mut self, value: u8) {
+        self.y = value;
+    }
+
+    pub fn s(&self) -> u8 {
+        self.s
+    }
+
+    pub fn set_s(&mut self, value: u8) {
+        self.s = value;
+    }
+
+    pub fn pc(&self) -> u16 {
+        self.pc
+    }
+
+    pub fn set_pc(&mut self, addr: u16) {
+        self.pc = addr;
+    }
+
+    pub fn register(&self, register: Register) -> u16 {
+        match register {
+            Register::A => self.a as u16,
+            Register::X => self.x as u16,
+            Register::Y => self.y as u16,
+            Register::S => self.s as u16,
+            Register::Pc => self.pc,
+            Register::Status => self.status_byte() as u16,
         }
     }
+
+    pub fn status_byte(&self) -> u8 {
+        // bit 5 isn't a real flag either, but always reads as set
+        self.carry as u8
+            | (self.zero as u8) << 1
+            | (self.irq_interrupt_disable as u8) << 2
+            | (self.decimal_mode as u8) << 3
+            | 1 << 5
+            | (self.signed_overflow as u8) << 6
+            | (self.negative as u8) << 7
+    }
+
+    pub fn set_status_byte(&mut self, byte: u8) {
+        // bits 4 and 5 don't exist in the register, so they are ignored
+        self.carry = (byte & 0x01) != 0;
+        self.zero = (byte & 0x02) != 0;
+        self.irq_interrupt_disable = (byte & 0x04) != 0;
+        self.decimal_mode = (byte & 0x08) != 0;
+        self.signed_overflow = (byte & 0x40) != 0;
+        self.negative = (byte & 0x80) != 0;
+    }
 }
 
-#[derive(Debug)]
-enum AddressingMode {
-    I,     // Immediate
-    A,     // Absolute
-    Zp,    // Zero Page
-    Aix,   // Absolute Indexed X
-    Aiy,   // Absolute Indexed Y
-    Zpix,  // Zero Page Indexed X
-    Zpiix, // Zero Page Indexed Indirect X
-    Zpiiy, // Zero Page Indirect Indexed Y
-    Acc,   //Accumulator
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum CpuVariant {
+    #[default]
+    Nmos6502,
+    Cmos65c02,
+    Ricoh2a03, // the NES CPU, an NMOS 6502 without decimal mode
+    Mos6510,   // the C64 CPU, an NMOS 6502 with an I/O port at $0000 and $0001
+    Mos6507,   // the Atari 2600 CPU, an NMOS 6502 in a smaller package
 }
 
-// -- Helper functions --
+// how decimal mode SBC treats digits above 9, where the NMOS and CMOS parts differ
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum InvalidBcd {
+    Nmos,
+    Cmos,
+}
 
-fn get_byte_at_addr(sys: &mut SystemState, addr: u16) -> u8 {
-    sys.memory[addr as usize]
+// what the NMOS parts do with the opcodes that were never documented
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum IllegalOpcodes {
+    Execute, // as the silicon does
+    Halt,    // halt as if by JAM, leaving the PC on the opcode
 }
 
-fn set_byte_at_addr(sys: &mut SystemState, addr: u16, byte: u8) {
-    sys.memory[addr as usize] = byte;
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CpuOptions {
+    pub variant: CpuVariant,
+    // address lines that are brought out of the package, the rest read as 0
+    pub address_mask: u16,
+    // whether the package has IRQ and NMI pins at all
+    pub interrupt_pins: bool,
+    // the NMOS 6502 doesn't carry into the high byte of the pointer, so a vector at
+    // $xxff fetches its high byte from $xx00
+    pub jmp_indirect_bug: bool,
+    // whether ADC and SBC honour the D flag
+    pub decimal_mode: bool,
+    pub invalid_bcd: InvalidBcd,
+    pub illegal_opcodes: IllegalOpcodes,
+    // the unstable undocumented opcodes ANE and LXA OR the accumulator with a constant
+    // that varies between chips, usually $EE, $FF or $00
+    pub ane_magic_constant: u8,
+    pub lxa_magic_constant: u8,
 }
 
-fn cat_bytes(b1: u8, b2: u8) -> u16 {
-    (u16::from(b1) << 8) | u16::from(b2)
+impl Default for CpuOptions {
+    fn default() -> Self {
+        CpuOptions::for_variant(CpuVariant::default())
+    }
+}
+
+impl CpuOptions {
+    pub fn for_variant(variant: CpuVariant) -> Self {
+        // the 6507 only brings out A0-A12, and has no interrupt pins
+        let reduced_package = variant == CpuVariant::Mos6507;
+        let cmos = variant == CpuVariant::Cmos65c02;
+
+        CpuOptions {
+            variant,
+            address_mask: if reduced_package { 0x1fff } else { 0xffff },
+            interrupt_pins: !reduced_package,
+            jmp_indirect_bug: !cmos,
+            decimal_mode: variant != CpuVariant::Ricoh2a03,
+            invalid_bcd: if cmos {
+                InvalidBcd::Cmos
+            } else {
+                InvalidBcd::Nmos
+            },
+            illegal_opcodes: IllegalOpcodes::Execute,
+            ane_magic_constant: 0xee,
+            lxa_magic_constant: 0xee,
+        }
+    }
 }
 
-fn get_immediate_byte(sys: &mut SystemState, offset: u16) -> u8 {
-    let addr = cat_bytes(sys.cpu_state.pch, sys.cpu_state.pcl) + offset;
-    get_byte_at_addr(sys, addr)
+// what RAM holds at power-on, which real hardware leaves to chance
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum MemoryFill {
+    #[default]
+    Zero,
+    Ones,             // every byte $FF
+    Stripes(usize),   // runs of $00 then $FF of the given length, as DRAM often powers up
+    Pattern(Vec<u8>), // repeated to fill memory
 }
 
-fn get_absolute_addr(sys: &mut SystemState) -> u16 {
-    let addr_lo = get_immediate_byte(sys, 1);
-    let addr_hi = get_immediate_byte(sys, 2);
-    cat_bytes(addr_hi, addr_lo)
+impl MemoryFill {
+    fn byte_at(&self, addr: usize) -> u8 {
+        match self {
+            MemoryFill::Zero => 0x00,
+            MemoryFill::Ones => 0xff,
+            MemoryFill::Stripes(length) => [0x00, 0xff][(addr / (*length).max(1)) & 1],
+            MemoryFill::Pattern(bytes) if bytes.is_empty() => 0x00,
+            MemoryFill::Pattern(bytes) => bytes[addr % bytes.len()],
+        }
+    }
 }
 
-fn get_absolute_byte(sys: &mut SystemState) -> u8 {
-    let addr = get_absolute_addr(sys);
-    get_byte_at_addr(sys, addr)
+// the registers and memory a system starts with, before the reset sequence runs. The PC
+// isn't included since reset loads it from the vector
+#[derive(Clone, Debug, PartialEq)]
+pub struct PowerOnState {
+    pub a: u8,
+    pub x: u8,
+    pub y: u8,
+    pub s: u8,
+    pub status: u8,
+    pub memory_fill: MemoryFill,
 }
 
-fn set_absolute_byte(sys: &mut SystemState, byte: u8) {
-    let addr = get_absolute_addr(sys);
-    set_byte_at_addr(sys, addr, byte)
+impl Default for PowerOnState {
+    fn default() -> Self {
+        PowerOnState {
+            a: 0x00,
+            x: 0x00,
+            y: 0x00,
+            s: 0xff,
+            status: 0x00,
+            memory_fill: MemoryFill::Zero,
+        }
+    }
 }
 
-fn get_absolute_addr_indexed(sys: &mut SystemState, index: u8) -> (u16, bool) {
-    let mut addr_lo = get_immediate_byte(sys, 1);
-    let mut addr_hi = get_immediate_byte(sys, 2);
+// the 6510's on-chip I/O port, which replaces memory at $0000 and $0001
+#[derive(Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+struct IoPort {
+    direction: u8, // set bits are outputs
+    data: u8,
 
-    let carry: bool;
-    (addr_lo, carry) = addr_lo.overflowing_add(index);
+    // the external lines, driving the input pins and driven by the output pins
+    #[cfg_attr(feature = "serde", serde(skip))]
+    input: Option<Box<dyn FnMut() -> u8>>,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    output: Option<Box<dyn FnMut(u8)>>,
+}
 
-    if carry {
-        addr_hi = addr_hi
-            .checked_add(1)
-            .expect("Overflow indexing memory address")
+impl IoPort {
+    fn pins(&self) -> u8 {
+        // pins not driven as outputs are pulled high
+        self.data | !self.direction
     }
-
-    (cat_bytes(addr_hi, addr_lo), carry)
 }
 
-fn get_absolute_byte_indexed(sys: &mut SystemState, index: u8) -> (u8, bool) {
-    let (addr, boundary_cross) = get_absolute_addr_indexed(sys, index);
-    (get_byte_at_addr(sys, addr), boundary_cross)
+// rules for how a program may use a region of memory, to catch wild pointers. Breaking
+// them is reported without stopping emulation
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Permission {
+    NoExecute,
+    ReadOnly,
+    DeviceOnly, // for memory only other devices should touch, so any CPU access breaks it
 }
 
-fn set_absolute_byte_indexed(sys: &mut SystemState, index: u8, byte: u8) {
-    let (addr, _) = get_absolute_addr_indexed(sys, index);
-    set_byte_at_addr(sys, addr, byte)
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Access {
+    Read,
+    Write,
+    Execute, // an opcode fetch, which is also a read
 }
 
-fn get_zero_page_byte(sys: &mut SystemState) -> u8 {
-    let addr = get_immediate_byte(sys, 1) as u16;
-    get_byte_at_addr(sys, addr)
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AccessViolation {
+    pub addr: u16,
+    pub access: Access,
+    pub permission: Permission,
+    pub instruction_addr: u16, // the opcode address of the offending instruction
 }
 
-fn set_zero_page_byte(sys: &mut SystemState, byte: u8) {
-    let addr = get_immediate_byte(sys, 1) as u16;
-    set_byte_at_addr(sys, addr, byte)
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StackProblem {
+    Overflow,        // a push wrapped S from $00 to $FF
+    Underflow,       // a pull wrapped S from $FF to $00
+    UnmatchedReturn, // an RTS with no JSR to return from, seen by the debugger
 }
 
-fn get_zero_page_byte_indexed(sys: &mut SystemState, index: u8) -> u8 {
-    let addr = get_immediate_byte(sys, 1).wrapping_add(index) as u16;
-    get_byte_at_addr(sys, addr)
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct StackWarning {
+    pub problem: StackProblem,
+    pub instruction_addr: u16, // the opcode address of the offending instruction
 }
 
-fn set_zero_page_byte_indexed(sys: &mut SystemState, index: u8, byte: u8) {
-    let addr = get_immediate_byte(sys, 1).wrapping_add(index) as u16;
-    set_byte_at_addr(sys, addr, byte)
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Watch {
+    Read,
+    Write,
+    ReadWrite,
 }
 
-fn get_zero_page_byte_indexed_indirect(sys: &mut SystemState, index: u8) -> u8 {
-    let addr1 = get_immediate_byte(sys, 1).wrapping_add(index) as u16;
+// reads that are only a side effect of the bus timing don't hit watchpoints
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct WatchpointHit {
+    pub addr: u16,
+    pub write: bool,
+    pub old: u8, // the same as new for reads
+    pub new: u8,
+    pub instruction_addr: u16,
+}
 
-    let addr2_lo = get_byte_at_addr(sys, addr1);
-    let addr2_hi = get_byte_at_addr(sys, (addr1 + 1) & 0xff); // is this and really needed?
-    let addr2 = cat_bytes(addr2_hi, addr2_lo);
+// a single cycle's access as seen on the pins, for tracing the bus like a logic analyzer
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BusAccess {
+    pub cycle: u64,
+    pub addr: u16,
+    pub value: u8,
+    pub write: bool,
+    pub sync: bool, // an opcode fetch
+    pub irq: bool,  // the interrupt lines, true when asserted
+    pub nmi: bool,
+}
 
-    get_byte_at_addr(sys, addr2)
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct UninitializedRead {
+    pub addr: u16,
+    pub instruction_addr: u16, // the opcode address of the offending instruction
 }
 
-fn get_zero_page_byte_indirect_indexed(sys: &mut SystemState, index: u8) -> (u8, bool) {
-    //
-    let addr1 = get_immediate_byte(sys, 1) as u16;
+type Tracer = Box<dyn FnMut(&str)>;
 
-    let mut addr2_lo = get_byte_at_addr(sys, addr1);
-    let mut addr2_hi = get_byte_at_addr(sys, (addr1 + 1) & 0xff); // is this and really needed?
+// savestates hold the machine itself, without the debugging on top of it or the
+// callbacks and devices shared with the host, which are set up again after loading
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SystemState<B: Bus = FlatRam> {
+    cpu_state: CpuState,
+    memory: B,
+    options: CpuOptions,
+    io_port: IoPort,
 
-    let carry: bool;
-    (addr2_lo, carry) = addr2_lo.overflowing_add(index);
+    // interrupt lines, true when asserted
+    irq_line: bool,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    irq_lines: Option<Rc<RefCell<InterruptLines>>>, // wired-OR with irq_line
+    nmi_line: bool,
+    nmi_pending: bool, // NMI is edge-triggered, so an assertion is latched until serviced
 
-    if carry {
-        addr2_hi = addr2_hi
-            .checked_add(1)
-            .expect("Overflow indexing memory address")
-    }
+    // the SO pin sets V on an edge too, at the end of the following cycle
+    so_line: bool,
+    so_pending: bool,
 
-    let addr2 = cat_bytes(addr2_hi, addr2_lo);
+    // RDY is high when the bus is ready, and pulling it low stalls the CPU
+    rdy_line: bool,
 
-    (get_byte_at_addr(sys, addr2), carry)
+    #[cfg_attr(feature = "serde", serde(skip))]
+    permissions: Vec<(RangeInclusive<u16>, Permission)>, // later ranges take priority
+    #[cfg_attr(feature = "serde", serde(skip))]
+    access_violation: Option<Box<dyn FnMut(AccessViolation)>>,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    bus_observer: Option<Box<dyn FnMut(BusAccess)>>,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    dma: Option<Rc<RefCell<Dma>>>,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    scheduler: Option<Rc<RefCell<Scheduler>>>,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    tracer: Option<Tracer>,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    symbols: Option<Symbols>, // shown in place of addresses in traces
+    #[cfg_attr(feature = "serde", serde(skip))]
+    breakpoints: BTreeSet<u16>,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    conditions: Vec<Condition>,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    watchpoints: Vec<(RangeInclusive<u16>, Watch)>,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    watchpoint_hit: Option<WatchpointHit>,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    write_log: Option<Vec<(u16, u8)>>, // addresses written and what they held before
+    #[cfg_attr(feature = "serde", serde(skip))]
+    heatmap: Option<Heatmap>,
+
+    // test ROMs signal success or failure with a branch or JMP to itself. After this many
+    // in a row, step reports a trap rather than running it forever
+    #[cfg_attr(feature = "serde", serde(skip))]
+    trap_window: Option<u32>,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    trap_repeats: u32,
+
+    // which addresses have been written, when looking for reads of uninitialized memory
+    #[cfg_attr(feature = "serde", serde(skip))]
+    initialized: Option<Vec<bool>>,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    uninitialized_read: Option<Box<dyn FnMut(UninitializedRead)>>,
+
+    #[cfg_attr(feature = "serde", serde(skip))]
+    stack_warning: Option<Box<dyn FnMut(StackWarning)>>,
 }
 
-fn increment_pc(sys: &mut SystemState, num: u8) -> bool {
-    let carry: bool;
-    (sys.cpu_state.pcl, carry) = sys.cpu_state.pcl.overflowing_add(num);
+impl Default for SystemState<FlatRam> {
+    fn default() -> Self {
+        SystemState::new(FlatRam::default())
+    }
+}
 
-    if carry {
-        sys.cpu_state.pch = sys
-            .cpu_state
-            .pch
-            .checked_add(1)
-            .expect("Overflow of program counter");
+impl<B: Bus> SystemState<B> {
+    pub fn new(memory: B) -> Self {
+        SystemState {
+            cpu_state: CpuState {
+                s: 0xff,
+                ..Default::default()
+            },
+            memory,
+            options: CpuOptions::default(),
+            io_port: IoPort::default(),
+            irq_line: false,
+            irq_lines: None,
+            nmi_line: false,
+            nmi_pending: false,
+            so_line: false,
+            so_pending: false,
+            rdy_line: true,
+            permissions: Vec::new(),
+            access_violation: None,
+            bus_observer: None,
+            dma: None,
+            scheduler: None,
+            tracer: None,
+            symbols: None,
+            breakpoints: BTreeSet::new(),
+            conditions: Vec::new(),
+            watchpoints: Vec::new(),
+            watchpoint_hit: None,
+            write_log: None,
+            heatmap: None,
+            trap_window: None,
+            trap_repeats: 0,
+            initialized: None,
+            uninitialized_read: None,
+            stack_warning: None,
+        }
     }
 
-    carry
-}
+    pub fn cpu_state(&self) -> &CpuState {
+        &self.cpu_state
+    }
 
-fn decrement_pc(sys: &mut SystemState, num: u8) -> bool {
-    let carry: bool;
-    (sys.cpu_state.pcl, carry) = sys.cpu_state.pcl.overflowing_sub(num);
+    pub fn cpu_state_mut(&mut self) -> &mut CpuState {
+        &mut self.cpu_state
+    }
 
-    if carry {
-        sys.cpu_state.pch = sys
-            .cpu_state
-            .pch
-            .checked_sub(1)
-            .expect("Overflow of program counter");
+    pub fn memory(&self) -> &B {
+        &self.memory
     }
 
-    carry
-}
+    pub fn memory_mut(&mut self) -> &mut B {
+        &mut self.memory
+    }
 
-fn negative_u8(num: u8) -> bool {
-    (num >> 7) != 0
-}
+    pub fn load_bytes(&mut self, addr: u16, bytes: &[u8]) {
+        // straight onto the bus, wrapping at $FFFF
+        for (i, &byte) in bytes.iter().enumerate() {
+            self.memory.write(addr.wrapping_add(i as u16), byte);
+        }
+        self.mark_initialized(addr, bytes.len());
+    }
 
-fn set_n_z(sys: &mut SystemState, result: u8) {
-    sys.cpu_state.negative = negative_u8(result);
-    sys.cpu_state.zero = result == 0;
-}
+    pub fn fill(&mut self, addr: u16, len: usize, pattern: &[u8]) {
+        // with the pattern repeated
+        let bytes: Vec<u8> = pattern.iter().copied().cycle().take(len).collect();
+        self.load_bytes(addr, &bytes);
+    }
 
-fn bcd_add_digit(a: u8, b: u8, carry: bool) -> (u8, bool) {
-    let sum = a + b + carry as u8;
-    if sum > 9 {
-        (sum - 10, true)
-    } else {
-        (sum, false)
+    pub fn copy(&mut self, src: u16, len: usize, dest: u16) {
+        // read without side effects, and as if through a buffer so overlapping ranges copy
+        // correctly
+        let bytes: Vec<u8> = (0..len)
+            .map(|i| self.memory.peek(src.wrapping_add(i as u16)))
+            .collect();
+        self.load_bytes(dest, &bytes);
     }
-}
 
-fn bcd_add(a: u8, b: u8) -> (u8, bool) {
-    let (res_lo, carry_lo) = bcd_add_digit(a & 0x0f, b & 0x0f, false);
-    let (res_hi, carry) = bcd_add_digit(a >> 4, b >> 4, carry_lo);
+    // these read without side effects on the bus, like copy
+    pub fn read_bytes(&self, addr: u16, len: usize) -> Vec<u8> {
+        (0..len)
+            .map(|i| self.memory.peek(addr.wrapping_add(i as u16)))
+            .collect()
+    }
 
-    ((res_hi << 4) | res_lo, carry)
-}
+    pub fn read_u16(&self, addr: u16) -> u16 {
+        // little-endian, wrapping at $FFFF
+        let lo = self.memory.peek(addr);
+        let hi = self.memory.peek(addr.wrapping_add(1));
+        cat_bytes(hi, lo)
+    }
 
-fn branch(sys: &mut SystemState, predicate: bool) -> (u8, u8) {
-    if predicate {
-        let displacement = get_immediate_byte(sys, 1);
-        let displacement_mag = displacement & 0x7f;
+    pub fn read_u16_zp_wrapped(&self, addr: u16) -> u16 {
+        // with the high byte from the start of the page when the low byte is at its end, as
+        // zero page pointers are read
+        let lo = self.memory.peek(addr);
+        let hi = self.memory.peek(page_wrapped_next(addr));
+        cat_bytes(hi, lo)
+    }
 
-        let page_cross = if (displacement & 0x80) != 0 {
-            decrement_pc(sys, displacement_mag)
-        } else {
-            increment_pc(sys, displacement_mag)
+    pub fn write_u16(&mut self, addr: u16, val: u16) {
+        self.load_bytes(addr, &val.to_le_bytes());
+    }
+
+    pub fn options(&self) -> &CpuOptions {
+        &self.options
+    }
+
+    pub fn options_mut(&mut self) -> &mut CpuOptions {
+        &mut self.options
+    }
+
+    pub fn power_on(&mut self, state: &PowerOnState) {
+        // puts the system back in the given state, without running the reset sequence
+        self.cpu_state = CpuState {
+            a: state.a,
+            x: state.x,
+            y: state.y,
+            s: state.s,
+            ..Default::default()
         };
-        (0, 3 + page_cross as u8) // TODO: verify this byte offset is correct
-    } else {
-        (2, 2)
+        self.cpu_state.set_status_byte(state.status);
+
+        for addr in 0..=0xffff {
+            self.memory
+                .poke(addr, state.memory_fill.byte_at(addr as usize));
+        }
+        if let Some(initialized) = self.initialized.as_mut() {
+            initialized.fill(false);
+        }
     }
-}
 
-fn push_to_stack(sys: &mut SystemState, byte: u8) {
-    set_byte_at_addr(sys, cat_bytes(0x01, sys.cpu_state.s), byte);
+    pub fn reset(&mut self) -> u16 {
+        // any instruction in progress is abandoned for the reset sequence
+        self.cpu_state.run_state = RunState::Running;
+        self.cpu_state.delayed_irq_interrupt_disable = None;
+        self.cpu_state.cycle = 0;
+        self.cpu_state.interrupt = Some(Interrupt::Reset);
+        self.trap_repeats = 0;
 
-    sys.cpu_state.s = sys.cpu_state.s.checked_sub(1).expect("Stack overflow");
-}
+        // reset turns all the 6510's port lines into inputs
+        if self.options.variant == CpuVariant::Mos6510 {
+            write_io_port(self, 0x0000, 0x00);
+        }
 
-fn make_status_byte(sys: &SystemState) -> u8 {
-    sys.cpu_state.carry as u8
-        | (sys.cpu_state.zero as u8) << 1
-        | (sys.cpu_state.irq_interrupt_disable as u8) << 2
-        | (sys.cpu_state.decimal_mode as u8) << 3
-        | (sys.cpu_state.brk_interrupt as u8) << 4
-        | (sys.cpu_state.signed_overflow as u8) << 6
-        | (sys.cpu_state.negative as u8) << 7
-}
+        run_instruction(self).0
+    }
 
-fn load_interrupt_vector(sys: &mut SystemState) {
-    sys.cpu_state.pcl = get_byte_at_addr(sys, cat_bytes(0xff, 0xfe));
-    sys.cpu_state.pch = get_byte_at_addr(sys, cat_bytes(0xff, 0xff));
-}
+    pub fn io_port_pins(&self) -> u8 {
+        self.io_port.pins()
+    }
 
-// -- Instructions --
+    pub fn set_io_port_input(&mut self, input: impl FnMut() -> u8 + 'static) {
+        self.io_port.input = Some(Box::new(input));
+    }
 
-fn adc(sys: &mut SystemState, mode: AddressingMode) -> (u8, u8) {
-    let (operand, length, cycles) = match mode {
-        AddressingMode::I => (get_immediate_byte(sys, 1), 2, 2),
-        AddressingMode::A => (get_absolute_byte(sys), 3, 4),
-        AddressingMode::Zp => (get_zero_page_byte(sys), 2, 3),
-        AddressingMode::Aix => {
-            let (byte, page_cross) = get_absolute_byte_indexed(sys, sys.cpu_state.x);
-            (byte, 3, 4 + page_cross as u8)
-        }
-        AddressingMode::Aiy => {
-            let (byte, page_cross) = get_absolute_byte_indexed(sys, sys.cpu_state.y);
-            (byte, 3, 4 + page_cross as u8)
-        }
-        AddressingMode::Zpix => (get_zero_page_byte_indexed(sys, sys.cpu_state.x), 2, 4),
-        AddressingMode::Zpiix => (
-            get_zero_page_byte_indexed_indirect(sys, sys.cpu_state.x),
-            2,
-            6,
-        ),
-        AddressingMode::Zpiiy => {
-            let (byte, page_cross) = get_zero_page_byte_indirect_indexed(sys, sys.cpu_state.y);
-            (byte, 2, 5 + page_cross as u8)
+    pub fn set_io_port_output(&mut self, output: impl FnMut(u8) + 'static) {
+        // called with the state of the pins whenever the port is written
+        self.io_port.output = Some(Box::new(output));
+    }
+
+    pub fn set_irq_line(&mut self, asserted: bool) {
+        if !self.options.interrupt_pins {
+            return;
         }
-        _ => panic!("unsupported mode {:?} on instruction ADC", mode),
-    };
-    let negative_before = negative_u8(sys.cpu_state.a);
-    let (carry1, carry2): (bool, bool);
-
-    if sys.cpu_state.decimal_mode {
-        // TODO: check that the inputs are valid decimal numbers?
-        // not sure how the 6502 handles invalid inputs here
-        (sys.cpu_state.a, carry1) = bcd_add(sys.cpu_state.a, operand);
-        (sys.cpu_state.a, carry2) = bcd_add(sys.cpu_state.a, sys.cpu_state.carry as u8);
-    } else {
-        (sys.cpu_state.a, carry1) = sys.cpu_state.a.overflowing_add(operand);
-        (sys.cpu_state.a, carry2) = sys.cpu_state.a.overflowing_add(sys.cpu_state.carry as u8);
+
+        self.irq_line = asserted;
     }
 
-    sys.cpu_state.carry = carry1 || carry2;
-    set_n_z(sys, sys.cpu_state.a);
-    sys.cpu_state.signed_overflow = !negative_before && negative_u8(sys.cpu_state.a);
+    pub fn attach_irq_lines(&mut self, lines: Rc<RefCell<InterruptLines>>) {
+        self.irq_lines = Some(lines);
+    }
 
-    (length, cycles)
-}
+    pub fn set_nmi_line(&mut self, asserted: bool) {
+        if !self.options.interrupt_pins {
+            return;
+        }
 
-fn and(sys: &mut SystemState, mode: AddressingMode) -> (u8, u8) {
-    let (operand, length, cycles) = match mode {
-        AddressingMode::I => (get_immediate_byte(sys, 1), 2, 2),
-        AddressingMode::A => (get_absolute_byte(sys), 3, 4),
-        AddressingMode::Zp => (get_zero_page_byte(sys), 2, 3),
-        AddressingMode::Aix => {
-            let (byte, page_cross) = get_absolute_byte_indexed(sys, sys.cpu_state.x);
-            (byte, 3, 4 + page_cross as u8)
+        if asserted && !self.nmi_line {
+            self.nmi_pending = true;
         }
-        AddressingMode::Aiy => {
-            let (byte, page_cross) = get_absolute_byte_indexed(sys, sys.cpu_state.y);
-            (byte, 3, 4 + page_cross as u8)
+        self.nmi_line = asserted;
+    }
+
+    pub fn set_overflow_pin(&mut self, asserted: bool) {
+        if asserted && !self.so_line {
+            self.so_pending = true;
         }
-        AddressingMode::Zpix => (get_zero_page_byte_indexed(sys, sys.cpu_state.x), 2, 4),
-        AddressingMode::Zpiix => (
-            get_zero_page_byte_indexed_indirect(sys, sys.cpu_state.x),
-            2,
-            6,
-        ),
-        AddressingMode::Zpiiy => {
-            let (byte, page_cross) = get_zero_page_byte_indirect_indexed(sys, sys.cpu_state.y);
-            (byte, 2, 5 + page_cross as u8)
+        self.so_line = asserted;
+    }
+
+    pub fn set_rdy_line(&mut self, ready: bool) {
+        self.rdy_line = ready;
+    }
+
+    pub fn set_permission(&mut self, range: RangeInclusive<u16>, permission: Permission) {
+        self.permissions.push((range, permission));
+    }
+
+    pub fn set_access_violation_handler(&mut self, handler: impl FnMut(AccessViolation) + 'static) {
+        self.access_violation = Some(Box::new(handler));
+    }
+
+    pub fn set_bus_observer(&mut self, observer: impl FnMut(BusAccess) + 'static) {
+        // called after every read and write the CPU makes, dummy accesses included
+        self.bus_observer = Some(Box::new(observer));
+    }
+
+    pub fn clear_bus_observer(&mut self) {
+        self.bus_observer = None;
+    }
+
+    pub fn set_uninitialized_read_handler(
+        &mut self,
+        handler: impl FnMut(UninitializedRead) + 'static,
+    ) {
+        // poisons all of memory, so that any read of an address before the CPU or
+        // load_bytes writes it is reported. Reads that only happen as a side effect of how
+        // the CPU works, like the extra reads of indexing, aren't reported
+        self.initialized = Some(vec![false; 0x10000]);
+        self.uninitialized_read = Some(Box::new(handler));
+    }
+
+    pub fn set_stack_warning_handler(&mut self, handler: impl FnMut(StackWarning) + 'static) {
+        self.stack_warning = Some(Box::new(handler));
+    }
+
+    pub(crate) fn warn_stack(&mut self, problem: StackProblem, instruction_addr: u16) {
+        if let Some(handler) = self.stack_warning.as_mut() {
+            handler(StackWarning {
+                problem,
+                instruction_addr,
+            });
         }
-        _ => panic!("unsupported mode {:?} on instruction AND", mode),
-    };
+    }
 
-    sys.cpu_state.a &= operand;
+    pub fn mark_initialized(&mut self, addr: u16, len: usize) {
+        // for memory filled some other way, like ROM mapped into the bus
+        if let Some(initialized) = self.initialized.as_mut() {
+            for i in 0..len {
+                let addr = addr.wrapping_add(i as u16) & self.options.address_mask;
+                initialized[addr as usize] = true;
+            }
+        }
+    }
 
-    set_n_z(sys, sys.cpu_state.a);
+    pub fn attach_dma(&mut self, dma: Rc<RefCell<Dma>>) {
+        // while a transfer is active the controller holds RDY low and has the bus
+        self.dma = Some(dma);
+    }
 
-    (length, cycles)
-}
+    pub fn attach_scheduler(&mut self, scheduler: Rc<RefCell<Scheduler>>) {
+        self.scheduler = Some(scheduler);
+    }
 
-fn asl(sys: &mut SystemState, mode: AddressingMode) -> (u8, u8) {
-    let (operand, length, cycles) = match mode {
-        AddressingMode::Acc => (sys.cpu_state.a, 1, 2),
-        AddressingMode::A => (get_absolute_byte(sys), 3, 6),
-        AddressingMode::Zp => (get_zero_page_byte(sys), 2, 5),
-        AddressingMode::Aix => (get_absolute_byte_indexed(sys, sys.cpu_state.x).0, 3, 7),
-        AddressingMode::Zpix => (get_zero_page_byte_indexed(sys, sys.cpu_state.x), 2, 6),
-        _ => panic!("unsupported mode {:?} on instruction ASL", mode),
-    };
+    pub fn set_tracer(&mut self, tracer: impl FnMut(&str) + 'static) {
+        // called with a line in the nestest log format before each instruction runs
+        self.tracer = Some(Box::new(tracer));
+    }
 
-    let result = operand << 1;
+    pub fn clear_tracer(&mut self) {
+        self.tracer = None;
+    }
 
-    match mode {
-        AddressingMode::Acc => sys.cpu_state.a = result,
-        AddressingMode::A => set_absolute_byte(sys, result),
-        AddressingMode::Zp => set_zero_page_byte(sys, result),
-        AddressingMode::Aix => set_absolute_byte_indexed(sys, sys.cpu_state.x, result),
-        AddressingMode::Zpix => set_zero_page_byte_indexed(sys, sys.cpu_state.x, result),
-        _ => panic!("unsupported mode {:?} on instruction ASL", mode),
+    pub fn set_symbols(&mut self, symbols: Symbols) {
+        self.symbols = Some(symbols);
     }
 
-    (length, cycles)
-}
+    pub fn symbols(&self) -> Option<&Symbols> {
+        self.symbols.as_ref()
+    }
 
-fn bcc(sys: &mut SystemState) -> (u8, u8) {
-    branch(sys, !sys.cpu_state.carry)
-}
+    pub fn add_breakpoint(&mut self, addr: u16) {
+        self.breakpoints.insert(addr);
+    }
 
-fn bcs(sys: &mut SystemState) -> (u8, u8) {
-    branch(sys, sys.cpu_state.carry)
-}
+    pub fn remove_breakpoint(&mut self, addr: u16) -> bool {
+        self.breakpoints.remove(&addr)
+    }
 
-fn beq(sys: &mut SystemState) -> (u8, u8) {
-    branch(sys, sys.cpu_state.zero)
-}
+    pub fn clear_breakpoints(&mut self) {
+        self.breakpoints.clear();
+    }
 
-fn bit(sys: &mut SystemState, mode: AddressingMode) -> (u8, u8) {
-    let (operand, length, cycles) = match mode {
-        AddressingMode::A => (get_absolute_byte(sys), 3, 4),
-        AddressingMode::Zp => (get_zero_page_byte(sys), 2, 3),
-        _ => panic!("unsupported mode {:?} on instruction BIT", mode),
-    };
+    pub fn breakpoints(&self) -> impl Iterator<Item = u16> + '_ {
+        self.breakpoints.iter().copied()
+    }
 
-    sys.cpu_state.negative = negative_u8(operand);
-    sys.cpu_state.signed_overflow = (operand & 0x40) != 0x00;
-    sys.cpu_state.zero = (operand & sys.cpu_state.a) == 0x00;
+    pub fn is_breakpoint(&self, addr: u16) -> bool {
+        self.breakpoints.contains(&addr)
+    }
 
-    (length, cycles)
-}
+    pub fn add_condition(&mut self, condition: Condition) -> usize {
+        self.conditions.push(condition);
+        self.conditions.len() - 1
+    }
 
-fn bmi(sys: &mut SystemState) -> (u8, u8) {
-    branch(sys, sys.cpu_state.negative)
-}
+    pub fn remove_condition(&mut self, index: usize) -> Condition {
+        // later conditions move down to fill the gap
+        self.conditions.remove(index)
+    }
 
-fn bne(sys: &mut SystemState) -> (u8, u8) {
-    branch(sys, !sys.cpu_state.zero)
-}
+    pub fn conditions(&self) -> &[Condition] {
+        &self.conditions
+    }
+
+    pub fn add_watchpoint(&mut self, range: RangeInclusive<u16>, watch: Watch) {
+        self.watchpoints.push((range, watch));
+    }
 
-fn bpl(sys: &mut SystemState) -> (u8, u8) {
-    branch(sys, !sys.cpu_state.negative)
+    pub fn clear_watchpoints(&mut self) {
+        self.watchpoints.clear();
+    }
+
+    pub fn set_trap_window(&mut self, window: Option<u32>) {
+        // None turns trap detection off, which is the default
+        self.trap_window = window;
+        self.trap_repeats = 0;
+    }
+
+    pub fn start_heatmap(&mut self) {
+        // counts accesses from now on, adding to any already counted
+        self.heatmap.get_or_insert_with(Heatmap::new);
+    }
+
+    pub fn stop_heatmap(&mut self) -> Option<Heatmap> {
+        self.heatmap.take()
+    }
+
+    pub fn heatmap(&self) -> Option<&Heatmap> {
+        self.heatmap.as_ref()
+    }
+
+    pub(crate) fn start_write_log(&mut self) {
+        self.write_log = Some(Vec::new());
+    }
+
+    pub(crate) fn take_write_log(&mut self) -> Vec<(u16, u8)> {
+        self.write_log.take().unwrap_or_default()
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            cpu_state: self.cpu_state.clone(),
+            memory: (0..=0xffff).map(|addr| self.memory.peek(addr)).collect(),
+        }
+    }
 }
 
-fn brk(sys: &mut SystemState) -> (u8, u8) {
-    increment_pc(sys, 2);
-    push_to_stack(sys, sys.cpu_state.pch);
-    push_to_stack(sys, sys.cpu_state.pcl);
+// the CPU state and everything on the bus at one moment, for comparing against another
+#[derive(Clone, Debug, PartialEq)]
+pub struct Snapshot {
+    cpu_state: CpuState,
+    memory: Vec<u8>,
+}
 
-    sys.cpu_state.brk_interrupt = true;
-    push_to_stack(sys, make_status_byte(sys));
-    sys.cpu_state.irq_interrupt_disable = true;
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Register {
+    A,
+    X,
+    Y,
+    S,
+    Pc,
+    Status,
+}
 
-    load_interrupt_vector(sys);
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RegisterChange {
+    pub register: Register,
+    pub old: u16,
+    pub new: u16,
+}
 
-    // the length is actually 2 bytes, but pc must be incemented negative_before
-    // it is pushed to the stack
-    (0, 7)
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MemoryChange {
+    pub addr: u16,
+    pub old: u8,
+    pub new: u8,
 }
 
-// -- Emulation zone --
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SnapshotDiff {
+    pub registers: Vec<RegisterChange>,
+    pub memory: Vec<MemoryChange>, // in address order
+}
 
-pub fn emulate_op(sys: &mut SystemState) -> u8 {
-    let opcode = get_immediate_byte(sys, 0);
+impl SnapshotDiff {
+    pub fn is_empty(&self) -> bool {
+        self.registers.is_empty() && self.memory.is_empty()
+    }
+}
 
-    let (length, cyc) = match opcode {
-        0x00 => brk(sys),
-        0x06 => asl(sys, AddressingMode::Zp),
+impl Snapshot {
+    pub fn cpu_state(&self) -> &CpuState {
+        &self.cpu_state
+    }
 
-        0x0a => asl(sys, AddressingMode::Acc),
-        0x0e => asl(sys, AddressingMode::A),
+    pub fn memory(&self) -> &[u8] {
+        &self.memory
+    }
 
-        0x10 => bpl(sys),
-        0x16 => asl(sys, AddressingMode::Zpix),
+    pub fn diff(&self, later: &Snapshot) -> SnapshotDiff {
+        let registers = |cpu_state: &CpuState| {
+            [
+                Register::A,
+                Register::X,
+                Register::Y,
+                Register::S,
+                Register::Pc,
+                Register::Status,
+            ]
+            .map(|register| (register, cpu_state.register(register)))
+        };
 
-        0x1e => asl(sys, AddressingMode::Aix),
+        let registers = registers(&self.cpu_state)
+            .into_iter()
+            .zip(registers(&later.cpu_state))
+            .filter(|((_, old), (_, new))| old != new)
+            .map(|((register, old), (_, new))| RegisterChange { register, old, new })
+            .collect();
 
-        0x21 => and(sys, AddressingMode::Zpiix),
-        0x24 => bit(sys, AddressingMode::Zp),
-        0x25 => and(sys, AddressingMode::Zp),
+        let memory = self
+            .memory
+            .iter()
+            .zip(&later.memory)
+            .enumerate()
+            .filter(|(_, (old, new))| old != new)
+            .map(|(addr, (&old, &new))| MemoryChange {
+                addr: addr as u16,
+                old,
+                new,
+            })
+            .collect();
 
-        0x29 => and(sys, AddressingMode::I),
-        0x2c => bit(sys, AddressingMode::A),
-        0x2d => and(sys, AddressingMode::A),
+        SnapshotDiff { registers, memory }
+    }
+}
 
-        0x30 => bmi(sys),
-        0x31 => and(sys, AddressingMode::Zpiiy),
-        0x35 => and(sys, AddressingMode::Zpix),
+const NMI_VECTOR: u16 = 0xfffa;
+const RESET_VECTOR: u16 = 0xfffc;
+const IRQ_VECTOR: u16 = 0xfffe;
 
-        0x39 => and(sys, AddressingMode::Aiy),
-        0x3d => and(sys, AddressingMode::Aix),
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AddressingMode {
+    I,     // Immediate
+    A,     // Absolute
+    Zp,    // Zero Page
+    Aix,   // Absolute Indexed X
+    Aiy,   // Absolute Indexed Y
+    Zpix,  // Zero Page Indexed X
+    Zpiy,  // Zero Page Indexed Y
+    Zpiix, // Zero Page Indexed Indirect X
+    Zpiiy, // Zero Page Indirect Indexed Y
+    Zpi,   // Zero Page Indirect (65C02)
+    Zpr,   // Zero Page Relative (65C02)
+    Acc,   //Accumulator
+    Ind,   // Absolute Indirect
+    Aiix,  // Absolute Indexed Indirect X (65C02)
+    Rel,   // Relative
+    Imp,   // Implied
+}
 
-        0x61 => adc(sys, AddressingMode::Zpiix),
-        0x65 => adc(sys, AddressingMode::Zp),
+// what an instruction does once its addressing mode has produced an operand
+enum Operation<B: Bus> {
+    Read(fn(&mut SystemState<B>, u8)),
+    DecimalRead(fn(&mut SystemState<B>, u8)), // ADC and SBC, slower in decimal mode on the 65C02
+    Write(fn(&mut SystemState<B>) -> u8),
+    ReadModifyWrite(fn(&mut SystemState<B>, u8) -> u8),
+    StoreAndHighByte(fn(&mut SystemState<B>) -> u8),
+    Implied(fn(&mut SystemState<B>)),
+    Branch(fn(&SystemState<B>) -> bool),
+    BranchOnBit(fn(&SystemState<B>, u8) -> bool),
+    Push(fn(&mut SystemState<B>) -> u8),
+    Pull(fn(&mut SystemState<B>, u8)),
+    OneCycleNop,
+    // instructions with their own cycle sequences
+    Brk,
+    Jam,
+    Jmp,
+    Jsr,
+    Rti,
+    Rts,
+    Nop5c, // a 65C02 NOP that takes 8 cycles
+    Stp,
+    Wai,
+}
 
-        0x69 => adc(sys, AddressingMode::I),
-        0x6d => adc(sys, AddressingMode::A),
+// -- Helper functions --
 
-        0x71 => adc(sys, AddressingMode::Zpiiy),
-        0x75 => adc(sys, AddressingMode::Zpix),
+fn get_byte_at_addr<B: Bus>(sys: &mut SystemState<B>, addr: u16) -> u8 {
+    check_initialized(sys, addr & sys.options.address_mask);
+    let byte = read_bus(sys, addr);
+    check_watchpoints(sys, addr & sys.options.address_mask, false, byte, byte);
 
-        0x79 => adc(sys, AddressingMode::Aiy),
-        0x7d => adc(sys, AddressingMode::Aix),
+    byte
+}
 
-        0x90 => bcc(sys),
+fn read_bus<B: Bus>(sys: &mut SystemState<B>, addr: u16) -> u8 {
+    let addr = addr & sys.options.address_mask;
+    check_access(sys, addr, Access::Read);
+    let byte = if addr < 0x0002 && sys.options.variant == CpuVariant::Mos6510 {
+        read_io_port(sys, addr)
+    } else {
+        sys.memory.read(addr)
+    };
+    if let Some(heatmap) = sys.heatmap.as_mut() {
+        heatmap.count(addr, Access::Read);
+        if sys.cpu_state.sync {
+            heatmap.count(addr, Access::Execute);
+        }
+    }
+    observe_bus(sys, addr, byte, false);
 
-        0xb0 => bcs(sys),
+    byte
+}
 
-        0xd0 => bne(sys),
+fn set_byte_at_addr<B: Bus>(sys: &mut SystemState<B>, addr: u16, byte: u8) {
+    let addr = addr & sys.options.address_mask;
+    if let Some(initialized) = sys.initialized.as_mut() {
+        initialized[addr as usize] = true;
+    }
+    check_access(sys, addr, Access::Write);
+    if let Some(log) = sys.write_log.as_mut() {
+        log.push((addr, sys.memory.peek(addr)));
+    }
+    if !sys.watchpoints.is_empty() {
+        let old = sys.memory.peek(addr);
+        check_watchpoints(sys, addr, true, old, byte);
+    }
+    if addr < 0x0002 && sys.options.variant == CpuVariant::Mos6510 {
+        write_io_port(sys, addr, byte);
+    } else {
+        sys.memory.write(addr, byte);
+    }
+    if let Some(heatmap) = sys.heatmap.as_mut() {
+        heatmap.count(addr, Access::Write);
+    }
+    observe_bus(sys, addr, byte, true);
+}
 
-        0xf0 => beq(sys),
+fn observe_bus<B: Bus>(sys: &mut SystemState<B>, addr: u16, value: u8, write: bool) {
+    if sys.bus_observer.is_none() {
+        return;
+    }
 
-        _ => panic!("unimplemented instruction {}", opcode),
+    let access = BusAccess {
+        cycle: sys.cpu_state.cycle_count,
+        addr,
+        value,
+        write,
+        sync: sys.cpu_state.sync,
+        irq: irq_asserted(sys),
+        nmi: sys.nmi_line,
     };
+    if let Some(observer) = sys.bus_observer.as_mut() {
+        observer(access);
+    }
+}
 
-    increment_pc(sys, length);
+fn check_initialized<B: Bus>(sys: &mut SystemState<B>, addr: u16) {
+    let initialized = match sys.initialized.as_ref() {
+        Some(initialized) => initialized[addr as usize],
+        None => return,
+    };
 
-    cyc
+    if let (false, Some(handler)) = (initialized, sys.uninitialized_read.as_mut()) {
+        handler(UninitializedRead {
+            addr,
+            instruction_addr: sys.cpu_state.instruction_addr,
+        });
+    }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+fn check_watchpoints<B: Bus>(sys: &mut SystemState<B>, addr: u16, write: bool, old: u8, new: u8) {
+    // only the first hit is kept until run() reports it
+    if sys.watchpoint_hit.is_some() {
+        return;
+    }
+
+    let watched = sys.watchpoints.iter().any(|(range, watch)| {
+        range.contains(&addr)
+            && matches!(
+                (watch, write),
+                (Watch::ReadWrite, _) | (Watch::Read, false) | (Watch::Write, true)
+            )
+    });
+    if watched {
+        sys.watchpoint_hit = Some(WatchpointHit {
+            addr,
+            write,
+            old,
+            new,
+            instruction_addr: sys.cpu_state.instruction_addr,
+        });
+    }
+}
+
+fn check_access<B: Bus>(sys: &mut SystemState<B>, addr: u16, access: Access) {
+    let permission = match sys
+        .permissions
+        .iter()
+        .rev()
+        .find(|(range, _)| range.contains(&addr))
+    {
+        Some(&(_, permission)) => permission,
+        None => return,
+    };
+
+    // an opcode fetch is also checked as a read, so device-only memory needn't be checked
+    // for execution too
+    let violated = matches!(
+        (permission, access),
+        (Permission::DeviceOnly, Access::Read | Access::Write)
+            | (Permission::ReadOnly, Access::Write)
+            | (Permission::NoExecute, Access::Execute)
+    );
+
+    if let (true, Some(handler)) = (violated, sys.access_violation.as_mut()) {
+        handler(AccessViolation {
+            addr,
+            access,
+            permission,
+            instruction_addr: sys.cpu_state.instruction_addr,
+        });
+    }
+}
+
+fn read_io_port<B: Bus>(sys: &mut SystemState<B>, addr: u16) -> u8 {
+    let port = &mut sys.io_port;
+    if addr == 0x0000 {
+        return port.direction;
+    }
+
+    // input pins with nothing attached are pulled high
+    let input = port.input.as_mut().map_or(0xff, |input| input());
+    (port.data & port.direction) | (input & !port.direction)
+}
+
+fn write_io_port<B: Bus>(sys: &mut SystemState<B>, addr: u16, byte: u8) {
+    let port = &mut sys.io_port;
+    if addr == 0x0000 {
+        port.direction = byte;
+    } else {
+        port.data = byte;
+    }
+
+    let pins = port.pins();
+    if let Some(output) = port.output.as_mut() {
+        output(pins);
+    }
+}
+
+fn cat_bytes(b1: u8, b2: u8) -> u16 {
+    (u16::from(b1) << 8) | u16::from(b2)
+}
+
+fn page_wrapped_next(addr: u16) -> u16 {
+    // the address after addr without carrying into the high byte, as pointers in the zero
+    // page and the NMOS JMP indirect bug fetch their high bytes from
+    cat_bytes((addr >> 8) as u8, (addr as u8).wrapping_add(1))
+}
+
+fn fetch_byte<B: Bus>(sys: &mut SystemState<B>) -> u8 {
+    let byte = get_byte_at_addr(sys, sys.cpu_state.pc);
+    increment_pc(sys, 1);
+
+    byte
+}
+
+fn dummy_read<B: Bus>(sys: &mut SystemState<B>, addr: u16) {
+    // the CPU reads on every cycle, even when it has no use for the result, so these reads
+    // can't be reading uninitialized memory by mistake
+    read_bus(sys, addr);
+}
+
+fn stack_addr<B: Bus>(sys: &SystemState<B>) -> u16 {
+    cat_bytes(0x01, sys.cpu_state.s)
+}
+
+fn address_cycles(mode: AddressingMode) -> u8 {
+    // cycles after the opcode fetch spent forming the effective address, not counting the
+    // fix-up of the high byte when indexing crosses a page
+    match mode {
+        AddressingMode::Zp => 1,
+        AddressingMode::A
+        | AddressingMode::Aix
+        | AddressingMode::Aiy
+        | AddressingMode::Zpix
+        | AddressingMode::Zpiy => 2,
+        AddressingMode::Zpiiy | AddressingMode::Zpi => 3,
+        AddressingMode::Zpiix => 4,
+        _ => panic!("mode {:?} has no effective address", mode),
+    }
+}
+
+fn may_cross_page(mode: AddressingMode) -> bool {
+    matches!(
+        mode,
+        AddressingMode::Aix | AddressingMode::Aiy | AddressingMode::Zpiiy
+    )
+}
+
+fn address_cycle<B: Bus>(sys: &mut SystemState<B>, mode: AddressingMode, cycle: u8) {
+    match (mode, cycle) {
+        (_, 1) => {
+            let byte = fetch_byte(sys);
+            sys.cpu_state.addr = byte as u16;
+            sys.cpu_state.pointer = byte;
+        }
+        (AddressingMode::A, 2) => fetch_addr_hi(sys),
+        (AddressingMode::Aix, 2) => {
+            fetch_addr_hi(sys);
+            index_addr(sys, sys.cpu_state.x);
+        }
+        (AddressingMode::Aiy, 2) => {
+            fetch_addr_hi(sys);
+            index_addr(sys, sys.cpu_state.y);
+        }
+        (AddressingMode::Zpix, 2) => index_zero_page_addr(sys, sys.cpu_state.x),
+        (AddressingMode::Zpiy, 2) => index_zero_page_addr(sys, sys.cpu_state.y),
+        (AddressingMode::Zpiix, 2) => {
+            dummy_read(sys, sys.cpu_state.pointer as u16);
+            sys.cpu_state.pointer = sys.cpu_state.pointer.wrapping_add(sys.cpu_state.x);
+        }
+        (AddressingMode::Zpiix, 3) | (AddressingMode::Zpiiy, 2) | (AddressingMode::Zpi, 2) => {
+            sys.cpu_state.addr = get_byte_at_addr(sys, sys.cpu_state.pointer as u16) as u16;
+        }
+        (AddressingMode::Zpiix, 4) | (AddressingMode::Zpiiy, 3) | (AddressingMode::Zpi, 3) => {
+            // the pointer wraps within the zero page
+            let addr_hi = get_byte_at_addr(sys, page_wrapped_next(sys.cpu_state.pointer as u16));
+            sys.cpu_state.addr = cat_bytes(addr_hi, sys.cpu_state.addr as u8);
+
+            if let AddressingMode::Zpiiy = mode {
+                index_addr(sys, sys.cpu_state.y);
+            }
+        }
+        _ => panic!("mode {:?} has no address cycle {}", mode, cycle),
+    }
+}
+
+fn fetch_addr_hi<B: Bus>(sys: &mut SystemState<B>) {
+    let addr_hi = fetch_byte(sys);
+    sys.cpu_state.addr = cat_bytes(addr_hi, sys.cpu_state.addr as u8);
+}
+
+fn index_addr<B: Bus>(sys: &mut SystemState<B>, index: u8) {
+    let base = sys.cpu_state.addr;
+    let addr = base.wrapping_add(index as u16);
+
+    // the index is added to the low byte first, and carrying into the high byte takes
+    // another cycle
+    sys.cpu_state.page_cross = (addr >> 8) != (base >> 8);
+    sys.cpu_state.addr = cat_bytes((base >> 8) as u8, addr as u8);
+}
+
+fn fix_page_cross<B: Bus>(sys: &mut SystemState<B>) {
+    // the NMOS 6502 reads from the un-carried address while it fixes up the high byte,
+    // which devices with read side effects can see. The 65C02 reads the last operand byte
+    // again instead
+    if sys.cpu_state.page_cross && sys.options.variant == CpuVariant::Cmos65c02 {
+        dummy_read(sys, sys.cpu_state.pc.wrapping_sub(1));
+    } else {
+        dummy_read(sys, sys.cpu_state.addr);
+    }
+
+    if sys.cpu_state.page_cross {
+        sys.cpu_state.addr = sys.cpu_state.addr.wrapping_add(0x100);
+    }
+}
+
+fn index_zero_page_addr<B: Bus>(sys: &mut SystemState<B>, index: u8) {
+    // the base address is read while the index is added, and the sum wraps within the zero
+    // page
+    dummy_read(sys, sys.cpu_state.addr);
+    sys.cpu_state.addr = (sys.cpu_state.addr as u8).wrapping_add(index) as u16;
+}
+
+fn increment_pc<B: Bus>(sys: &mut SystemState<B>, num: u8) {
+    sys.cpu_state.pc = sys.cpu_state.pc.wrapping_add(num as u16);
+}
+
+fn negative_u8(num: u8) -> bool {
+    (num >> 7) != 0
+}
+
+fn set_n_z<B: Bus>(sys: &mut SystemState<B>, result: u8) {
+    sys.cpu_state.negative = negative_u8(result);
+    sys.cpu_state.zero = result == 0;
+}
+
+// The decimal mode algorithms below are those of the NMOS silicon as described in Bruce
+// Clark's decimal mode tutorial, so they also give hardware-accurate results for invalid
+// BCD inputs
+
+fn bcd_add_low_nybble(a: u8, b: u8, carry: bool) -> u16 {
+    let mut lo = (a & 0x0f) as u16 + (b & 0x0f) as u16 + carry as u16;
+    if lo >= 0x0a {
+        lo = ((lo + 0x06) & 0x0f) + 0x10;
+    }
+    lo
+}
+
+fn bcd_add(a: u8, b: u8, carry: bool) -> (u8, bool) {
+    let lo = bcd_add_low_nybble(a, b, carry);
+
+    let mut sum = (a & 0xf0) as u16 + (b & 0xf0) as u16 + lo;
+    if sum >= 0xa0 {
+        sum += 0x60;
+    }
+
+    (sum as u8, sum > 0xff)
+}
+
+fn bcd_add_n_v(a: u8, b: u8, carry: bool) -> (bool, bool) {
+    // N and V are taken from the sum before the high nybble is adjusted
+    let lo = bcd_add_low_nybble(a, b, carry);
+    let sum = (a & 0xf0) as i8 as i16 + (b & 0xf0) as i8 as i16 + lo as i16;
+
+    ((sum & 0x80) != 0, !(-128..=127).contains(&sum))
+}
+
+fn bcd_sub(a: u8, b: u8, borrow: bool) -> u8 {
+    let mut lo = (a & 0x0f) as i16 - (b & 0x0f) as i16 - borrow as i16;
+    if lo < 0 {
+        lo = ((lo - 0x06) & 0x0f) - 0x10;
+    }
+
+    let mut diff = (a & 0xf0) as i16 - (b & 0xf0) as i16 + lo;
+    if diff < 0 {
+        diff -= 0x60;
+    }
+
+    diff as u8
+}
+
+fn decimal_arithmetic<B: Bus>(sys: &SystemState<B>) -> bool {
+    // the 2A03 still has the D flag, but its ALU has no decimal mode
+    sys.cpu_state.decimal_mode && sys.options.decimal_mode
+}
+
+fn bcd_sub_cmos(a: u8, b: u8, borrow: bool) -> u8 {
+    // the 65C02 corrects the whole result rather than each nybble, which only differs from
+    // the NMOS part for invalid digits
+    let lo = (a & 0x0f) as i16 - (b & 0x0f) as i16 - borrow as i16;
+
+    let mut diff = a as i16 - b as i16 - borrow as i16;
+    if diff < 0 {
+        diff -= 0x60;
+    }
+    if lo < 0 {
+        diff -= 0x06;
+    }
+
+    diff as u8
+}
+
+fn signed_overflow(a: u8, operand: u8, result: u8) -> bool {
+    // overflow happens when both inputs have the same sign and the result's sign differs
+    ((a ^ result) & (operand ^ result) & 0x80) != 0
+}
+
+fn add_with_carry<B: Bus>(sys: &mut SystemState<B>, operand: u8) {
+    let a_before = sys.cpu_state.a;
+    let carry_before = sys.cpu_state.carry;
+
+    let sum = a_before as u16 + operand as u16 + carry_before as u16;
+    let binary_result = sum as u8;
+
+    if decimal_arithmetic(sys) {
+        // on the NMOS 6502, Z still comes from the binary result
+        (sys.cpu_state.a, sys.cpu_state.carry) = bcd_add(a_before, operand, carry_before);
+        sys.cpu_state.zero = binary_result == 0;
+        (sys.cpu_state.negative, sys.cpu_state.signed_overflow) =
+            bcd_add_n_v(a_before, operand, carry_before);
+
+        // the 65C02 spends an extra cycle setting N and Z from the decimal result
+        if sys.options.variant == CpuVariant::Cmos65c02 {
+            set_n_z(sys, sys.cpu_state.a);
+        }
+    } else {
+        sys.cpu_state.a = binary_result;
+        sys.cpu_state.carry = sum > 0xff;
+        set_n_z(sys, binary_result);
+        sys.cpu_state.signed_overflow = signed_overflow(a_before, operand, binary_result);
+    }
+}
+
+fn subtract_with_carry<B: Bus>(sys: &mut SystemState<B>, operand: u8) {
+    let a_before = sys.cpu_state.a;
+
+    // the carry flag acts as an inverted borrow. Subtraction is addition of the one's
+    // complement, so carry and overflow come out of the same binary add as ADC
+    let sum = a_before as u16 + !operand as u16 + sys.cpu_state.carry as u16;
+    let binary_result = sum as u8;
+
+    // on the NMOS 6502, all flags in decimal mode are set from the binary result
+    set_n_z(sys, binary_result);
+    sys.cpu_state.signed_overflow = signed_overflow(a_before, !operand, binary_result);
+
+    if decimal_arithmetic(sys) {
+        let borrow = !sys.cpu_state.carry;
+        sys.cpu_state.a = match sys.options.invalid_bcd {
+            InvalidBcd::Nmos => bcd_sub(a_before, operand, borrow),
+            InvalidBcd::Cmos => bcd_sub_cmos(a_before, operand, borrow),
+        };
+
+        if sys.options.variant == CpuVariant::Cmos65c02 {
+            set_n_z(sys, sys.cpu_state.a);
+        }
+    } else {
+        sys.cpu_state.a = binary_result;
+    }
+    sys.cpu_state.carry = sum > 0xff;
+}
+
+fn shift_left<B: Bus>(sys: &mut SystemState<B>, operand: u8) -> u8 {
+    let result = operand << 1;
+
+    sys.cpu_state.carry = (operand & 0x80) != 0;
+    set_n_z(sys, result);
+
+    result
+}
+
+fn shift_right<B: Bus>(sys: &mut SystemState<B>, operand: u8) -> u8 {
+    let result = operand >> 1;
+
+    sys.cpu_state.carry = (operand & 0x01) != 0;
+    set_n_z(sys, result);
+
+    result
+}
+
+fn rotate_left<B: Bus>(sys: &mut SystemState<B>, operand: u8) -> u8 {
+    let result = (operand << 1) | sys.cpu_state.carry as u8;
+
+    sys.cpu_state.carry = (operand & 0x80) != 0;
+    set_n_z(sys, result);
+
+    result
+}
+
+fn rotate_right<B: Bus>(sys: &mut SystemState<B>, operand: u8) -> u8 {
+    let result = (operand >> 1) | (sys.cpu_state.carry as u8) << 7;
+
+    sys.cpu_state.carry = (operand & 0x01) != 0;
+    set_n_z(sys, result);
+
+    result
+}
+
+fn compare<B: Bus>(sys: &mut SystemState<B>, register: u8, operand: u8) {
+    let (result, borrow) = register.overflowing_sub(operand);
+
+    sys.cpu_state.carry = !borrow;
+    set_n_z(sys, result);
+}
+
+fn push_to_stack<B: Bus>(sys: &mut SystemState<B>, byte: u8) {
+    set_byte_at_addr(sys, cat_bytes(0x01, sys.cpu_state.s), byte);
+
+    // the stack pointer wraps within page one rather than spilling into other pages, which
+    // is almost always a bug
+    if sys.cpu_state.s == 0x00 {
+        sys.warn_stack(StackProblem::Overflow, sys.cpu_state.instruction_addr);
+    }
+    sys.cpu_state.s = sys.cpu_state.s.wrapping_sub(1);
+}
+
+fn pull_from_stack<B: Bus>(sys: &mut SystemState<B>) -> u8 {
+    if sys.cpu_state.s == 0xff {
+        sys.warn_stack(StackProblem::Underflow, sys.cpu_state.instruction_addr);
+    }
+    sys.cpu_state.s = sys.cpu_state.s.wrapping_add(1);
+
+    get_byte_at_addr(sys, cat_bytes(0x01, sys.cpu_state.s))
+}
+
+fn push_interrupt_byte<B: Bus>(sys: &mut SystemState<B>, byte: u8) {
+    // reset runs the interrupt sequence with the stack writes turned into reads, so S is
+    // still decremented by 3
+    if sys.cpu_state.interrupt == Some(Interrupt::Reset) {
+        dummy_read(sys, stack_addr(sys));
+        sys.cpu_state.s = sys.cpu_state.s.wrapping_sub(1);
+    } else {
+        push_to_stack(sys, byte);
+    }
+}
+
+// -- Instructions --
+
+fn adc<B: Bus>(sys: &mut SystemState<B>, operand: u8) {
+    add_with_carry(sys, operand);
+}
+
+fn and<B: Bus>(sys: &mut SystemState<B>, operand: u8) {
+    sys.cpu_state.a &= operand;
+
+    set_n_z(sys, sys.cpu_state.a);
+}
+
+fn asl<B: Bus>(sys: &mut SystemState<B>, operand: u8) -> u8 {
+    shift_left(sys, operand)
+}
+
+fn bcc<B: Bus>(sys: &SystemState<B>) -> bool {
+    !sys.cpu_state.carry
+}
+
+fn bcs<B: Bus>(sys: &SystemState<B>) -> bool {
+    sys.cpu_state.carry
+}
+
+fn beq<B: Bus>(sys: &SystemState<B>) -> bool {
+    sys.cpu_state.zero
+}
+
+fn bit<B: Bus>(sys: &mut SystemState<B>, operand: u8) {
+    sys.cpu_state.negative = negative_u8(operand);
+    sys.cpu_state.signed_overflow = (operand & 0x40) != 0x00;
+    sys.cpu_state.zero = (operand & sys.cpu_state.a) == 0x00;
+}
+
+fn bmi<B: Bus>(sys: &SystemState<B>) -> bool {
+    sys.cpu_state.negative
+}
+
+fn bne<B: Bus>(sys: &SystemState<B>) -> bool {
+    !sys.cpu_state.zero
+}
+
+fn bpl<B: Bus>(sys: &SystemState<B>) -> bool {
+    !sys.cpu_state.negative
+}
+
+fn brk<B: Bus>(sys: &mut SystemState<B>, cycle: u8) -> bool {
+    // hardware interrupts run through this same sequence
+    let interrupt = sys.cpu_state.interrupt;
+
+    match cycle {
+        1 => {
+            // BRK skips the byte after its opcode, but an interrupt must return to the
+            // instruction it displaced
+            if interrupt.is_none() {
+                fetch_byte(sys);
+            } else {
+                dummy_read(sys, sys.cpu_state.pc);
+            }
+        }
+        2 => push_interrupt_byte(sys, (sys.cpu_state.pc >> 8) as u8),
+        3 => push_interrupt_byte(sys, sys.cpu_state.pc as u8),
+        4 => {
+            let status =
+                sys.cpu_state.status_byte() | if interrupt.is_none() { BREAK_FLAG } else { 0 };
+            push_interrupt_byte(sys, status);
+            sys.cpu_state.irq_interrupt_disable = true;
+
+            // the 65C02 also leaves decimal mode, so handlers needn't CLD
+            if sys.options.variant == CpuVariant::Cmos65c02 {
+                sys.cpu_state.decimal_mode = false;
+            }
+
+            // an NMI arriving before the vector is fetched hijacks a BRK or IRQ sequence, which
+            // then jumps through the NMI vector, though the pushed B flag still reflects the BRK
+            sys.cpu_state.addr = match interrupt {
+                Some(Interrupt::Reset) => RESET_VECTOR,
+                Some(Interrupt::Nmi) => NMI_VECTOR,
+                _ if sys.nmi_pending => {
+                    sys.nmi_pending = false;
+                    NMI_VECTOR
+                }
+                _ => IRQ_VECTOR,
+            };
+        }
+        5 => sys.cpu_state.data = get_byte_at_addr(sys, sys.cpu_state.addr),
+        _ => {
+            let addr_hi = get_byte_at_addr(sys, sys.cpu_state.addr + 1);
+            sys.cpu_state.pc = cat_bytes(addr_hi, sys.cpu_state.data);
+            sys.cpu_state.interrupt = None;
+
+            return true;
+        }
+    }
+
+    false
+}
+
+fn bvc<B: Bus>(sys: &SystemState<B>) -> bool {
+    !sys.cpu_state.signed_overflow
+}
+
+fn bvs<B: Bus>(sys: &SystemState<B>) -> bool {
+    sys.cpu_state.signed_overflow
+}
+
+fn clc<B: Bus>(sys: &mut SystemState<B>) {
+    sys.cpu_state.carry = false;
+}
+
+fn cld<B: Bus>(sys: &mut SystemState<B>) {
+    sys.cpu_state.decimal_mode = false;
+}
+
+fn cli<B: Bus>(sys: &mut SystemState<B>) {
+    sys.cpu_state.delayed_irq_interrupt_disable = Some(sys.cpu_state.irq_interrupt_disable);
+    sys.cpu_state.irq_interrupt_disable = false;
+}
+
+fn clv<B: Bus>(sys: &mut SystemState<B>) {
+    sys.cpu_state.signed_overflow = false;
+}
+
+fn cmp<B: Bus>(sys: &mut SystemState<B>, operand: u8) {
+    compare(sys, sys.cpu_state.a, operand);
+}
+
+fn cpx<B: Bus>(sys: &mut SystemState<B>, operand: u8) {
+    compare(sys, sys.cpu_state.x, operand);
+}
+
+fn cpy<B: Bus>(sys: &mut SystemState<B>, operand: u8) {
+    compare(sys, sys.cpu_state.y, operand);
+}
+
+fn dec<B: Bus>(sys: &mut SystemState<B>, operand: u8) -> u8 {
+    let result = operand.wrapping_sub(1);
+
+    set_n_z(sys, result);
+
+    result
+}
+
+fn dex<B: Bus>(sys: &mut SystemState<B>) {
+    sys.cpu_state.x = sys.cpu_state.x.wrapping_sub(1);
+    set_n_z(sys, sys.cpu_state.x);
+}
+
+fn dey<B: Bus>(sys: &mut SystemState<B>) {
+    sys.cpu_state.y = sys.cpu_state.y.wrapping_sub(1);
+    set_n_z(sys, sys.cpu_state.y);
+}
+
+fn eor<B: Bus>(sys: &mut SystemState<B>, operand: u8) {
+    sys.cpu_state.a ^= operand;
+
+    set_n_z(sys, sys.cpu_state.a);
+}
+
+fn inc<B: Bus>(sys: &mut SystemState<B>, operand: u8) -> u8 {
+    let result = operand.wrapping_add(1);
+
+    set_n_z(sys, result);
+
+    result
+}
+
+fn inx<B: Bus>(sys: &mut SystemState<B>) {
+    sys.cpu_state.x = sys.cpu_state.x.wrapping_add(1);
+    set_n_z(sys, sys.cpu_state.x);
+}
+
+fn iny<B: Bus>(sys: &mut SystemState<B>) {
+    sys.cpu_state.y = sys.cpu_state.y.wrapping_add(1);
+    set_n_z(sys, sys.cpu_state.y);
+}
+
+fn jmp<B: Bus>(sys: &mut SystemState<B>, mode: AddressingMode, cycle: u8) -> bool {
+    let cmos = sys.options.variant == CpuVariant::Cmos65c02;
+
+    match (mode, cycle) {
+        (_, 1) => sys.cpu_state.data = fetch_byte(sys),
+        (AddressingMode::A, _) => {
+            let addr_hi = get_byte_at_addr(sys, sys.cpu_state.pc);
+            sys.cpu_state.pc = cat_bytes(addr_hi, sys.cpu_state.data);
+
+            return true;
+        }
+        (AddressingMode::Ind | AddressingMode::Aiix, 2) => {
+            let ptr_hi = fetch_byte(sys);
+            sys.cpu_state.addr = cat_bytes(ptr_hi, sys.cpu_state.data);
+        }
+        (AddressingMode::Ind, 3) if cmos => {
+            // the 65C02 spends an extra cycle carrying into the high byte of the pointer
+            dummy_read(sys, sys.cpu_state.pc.wrapping_sub(1));
+        }
+        (AddressingMode::Ind, 4) if cmos => {
+            sys.cpu_state.data = get_byte_at_addr(sys, sys.cpu_state.addr);
+        }
+        (AddressingMode::Ind, 3) | (AddressingMode::Aiix, 4) => {
+            sys.cpu_state.data = get_byte_at_addr(sys, sys.cpu_state.addr);
+        }
+        (AddressingMode::Aiix, 3) => {
+            // the high byte of the operand is read again while X is added
+            dummy_read(sys, sys.cpu_state.pc.wrapping_sub(1));
+            sys.cpu_state.addr = sys.cpu_state.addr.wrapping_add(sys.cpu_state.x as u16);
+        }
+        (AddressingMode::Aiix, _) => {
+            let addr_hi = get_byte_at_addr(sys, sys.cpu_state.addr.wrapping_add(1));
+            sys.cpu_state.pc = cat_bytes(addr_hi, sys.cpu_state.data);
+
+            return true;
+        }
+        (AddressingMode::Ind, _) => {
+            let ptr = sys.cpu_state.addr;
+
+            let next_ptr = if sys.options.jmp_indirect_bug {
+                page_wrapped_next(ptr)
+            } else {
+                ptr.wrapping_add(1)
+            };
+
+            let addr_hi = get_byte_at_addr(sys, next_ptr);
+            sys.cpu_state.pc = cat_bytes(addr_hi, sys.cpu_state.data);
+
+            return true;
+        }
+        _ => panic!("unsupported mode {:?} on instruction JMP", mode),
+    }
+
+    false
+}
+
+fn jsr<B: Bus>(sys: &mut SystemState<B>, cycle: u8) -> bool {
+    match cycle {
+        1 => sys.cpu_state.data = fetch_byte(sys),
+        2 => dummy_read(sys, stack_addr(sys)),
+        // the return address pushed is that of the last byte of the JSR instruction
+        3 => push_to_stack(sys, (sys.cpu_state.pc >> 8) as u8),
+        4 => push_to_stack(sys, sys.cpu_state.pc as u8),
+        _ => {
+            let addr_hi = get_byte_at_addr(sys, sys.cpu_state.pc);
+            sys.cpu_state.pc = cat_bytes(addr_hi, sys.cpu_state.data);
+
+            return true;
+        }
+    }
+
+    false
+}
+
+fn lda<B: Bus>(sys: &mut SystemState<B>, operand: u8) {
+    sys.cpu_state.a = operand;
+    set_n_z(sys, sys.cpu_state.a);
+}
+
+fn ldx<B: Bus>(sys: &mut SystemState<B>, operand: u8) {
+    sys.cpu_state.x = operand;
+    set_n_z(sys, sys.cpu_state.x);
+}
+
+fn ldy<B: Bus>(sys: &mut SystemState<B>, operand: u8) {
+    sys.cpu_state.y = operand;
+    set_n_z(sys, sys.cpu_state.y);
+}
+
+fn lsr<B: Bus>(sys: &mut SystemState<B>, operand: u8) -> u8 {
+    shift_right(sys, operand)
+}
+
+fn nop<B: Bus>(_sys: &mut SystemState<B>, _operand: u8) {
+    // only $EA is documented, the rest are undocumented NOPs that still read their operand
+}
+
+fn ora<B: Bus>(sys: &mut SystemState<B>, operand: u8) {
+    sys.cpu_state.a |= operand;
+
+    set_n_z(sys, sys.cpu_state.a);
+}
+
+fn pha<B: Bus>(sys: &mut SystemState<B>) -> u8 {
+    sys.cpu_state.a
+}
+
+fn php<B: Bus>(sys: &mut SystemState<B>) -> u8 {
+    sys.cpu_state.status_byte() | BREAK_FLAG
+}
+
+fn pla<B: Bus>(sys: &mut SystemState<B>, byte: u8) {
+    sys.cpu_state.a = byte;
+    set_n_z(sys, sys.cpu_state.a);
+}
+
+fn plp<B: Bus>(sys: &mut SystemState<B>, byte: u8) {
+    sys.cpu_state.delayed_irq_interrupt_disable = Some(sys.cpu_state.irq_interrupt_disable);
+    sys.cpu_state.set_status_byte(byte);
+}
+
+fn rol<B: Bus>(sys: &mut SystemState<B>, operand: u8) -> u8 {
+    rotate_left(sys, operand)
+}
+
+fn ror<B: Bus>(sys: &mut SystemState<B>, operand: u8) -> u8 {
+    rotate_right(sys, operand)
+}
+
+fn rti<B: Bus>(sys: &mut SystemState<B>, cycle: u8) -> bool {
+    match cycle {
+        1 => dummy_read(sys, sys.cpu_state.pc),
+        2 => dummy_read(sys, stack_addr(sys)),
+        3 => {
+            let status = pull_from_stack(sys);
+            sys.cpu_state.set_status_byte(status);
+        }
+        4 => sys.cpu_state.data = pull_from_stack(sys),
+        _ => {
+            // unlike RTS, the pulled address is the next instruction to execute
+            let pc_hi = pull_from_stack(sys);
+            sys.cpu_state.pc = cat_bytes(pc_hi, sys.cpu_state.data);
+
+            return true;
+        }
+    }
+
+    false
+}
+
+fn rts<B: Bus>(sys: &mut SystemState<B>, cycle: u8) -> bool {
+    match cycle {
+        1 => dummy_read(sys, sys.cpu_state.pc),
+        2 => dummy_read(sys, stack_addr(sys)),
+        3 => sys.cpu_state.data = pull_from_stack(sys),
+        4 => {
+            let pc_hi = pull_from_stack(sys);
+            sys.cpu_state.pc = cat_bytes(pc_hi, sys.cpu_state.data);
+        }
+        _ => {
+            // the pulled address points at the last byte of the JSR, so we still step over it
+            fetch_byte(sys);
+
+            return true;
+        }
+    }
+
+    false
+}
+
+fn sbc<B: Bus>(sys: &mut SystemState<B>, operand: u8) {
+    subtract_with_carry(sys, operand);
+}
+
+fn sec<B: Bus>(sys: &mut SystemState<B>) {
+    sys.cpu_state.carry = true;
+}
+
+fn sed<B: Bus>(sys: &mut SystemState<B>) {
+    sys.cpu_state.decimal_mode = true;
+}
+
+fn sei<B: Bus>(sys: &mut SystemState<B>) {
+    sys.cpu_state.delayed_irq_interrupt_disable = Some(sys.cpu_state.irq_interrupt_disable);
+    sys.cpu_state.irq_interrupt_disable = true;
+}
+
+fn sta<B: Bus>(sys: &mut SystemState<B>) -> u8 {
+    sys.cpu_state.a
+}
+
+fn stx<B: Bus>(sys: &mut SystemState<B>) -> u8 {
+    sys.cpu_state.x
+}
+
+fn sty<B: Bus>(sys: &mut SystemState<B>) -> u8 {
+    sys.cpu_state.y
+}
+
+fn tax<B: Bus>(sys: &mut SystemState<B>) {
+    sys.cpu_state.x = sys.cpu_state.a;
+    set_n_z(sys, sys.cpu_state.x);
+}
+
+fn tay<B: Bus>(sys: &mut SystemState<B>) {
+    sys.cpu_state.y = sys.cpu_state.a;
+    set_n_z(sys, sys.cpu_state.y);
+}
+
+fn tsx<B: Bus>(sys: &mut SystemState<B>) {
+    sys.cpu_state.x = sys.cpu_state.s;
+    set_n_z(sys, sys.cpu_state.x);
+}
+
+fn txa<B: Bus>(sys: &mut SystemState<B>) {
+    sys.cpu_state.a = sys.cpu_state.x;
+    set_n_z(sys, sys.cpu_state.a);
+}
+
+fn txs<B: Bus>(sys: &mut SystemState<B>) {
+    sys.cpu_state.s = sys.cpu_state.x;
+    // unlike the other transfers, TXS doesn't affect any flags
+}
+
+fn tya<B: Bus>(sys: &mut SystemState<B>) {
+    sys.cpu_state.a = sys.cpu_state.y;
+    set_n_z(sys, sys.cpu_state.a);
+}
+
+// -- Undocumented instructions --
+
+fn alr<B: Bus>(sys: &mut SystemState<B>, operand: u8) {
+    // AND followed by LSR A
+    sys.cpu_state.a = shift_right(sys, sys.cpu_state.a & operand);
+}
+
+fn anc<B: Bus>(sys: &mut SystemState<B>, operand: u8) {
+    // AND, with bit 7 of the result copied into carry
+    sys.cpu_state.a &= operand;
+    set_n_z(sys, sys.cpu_state.a);
+    sys.cpu_state.carry = sys.cpu_state.negative;
+}
+
+fn ane<B: Bus>(sys: &mut SystemState<B>, operand: u8) {
+    // also known as XAA
+    sys.cpu_state.a =
+        (sys.cpu_state.a | sys.options.ane_magic_constant) & sys.cpu_state.x & operand;
+    set_n_z(sys, sys.cpu_state.a);
+}
+
+fn arr<B: Bus>(sys: &mut SystemState<B>, operand: u8) {
+    // AND followed by ROR A, but with flags set as if by a mix of ADC and ROR
+    let and_result = sys.cpu_state.a & operand;
+    let result = (and_result >> 1) | (sys.cpu_state.carry as u8) << 7;
+
+    if decimal_arithmetic(sys) {
+        // N and Z come from the binary result, then each nybble is BCD-fixed up
+        // based on the value before rotation
+        sys.cpu_state.negative = sys.cpu_state.carry;
+        sys.cpu_state.zero = result == 0;
+        sys.cpu_state.signed_overflow = ((and_result ^ result) & 0x40) != 0;
+
+        let mut fixed = result;
+        if (and_result & 0x0f) + (and_result & 0x01) > 0x05 {
+            fixed = (fixed & 0xf0) | (fixed.wrapping_add(0x06) & 0x0f);
+        }
+
+        sys.cpu_state.carry = (and_result & 0xf0) as u16 + (and_result & 0x10) as u16 > 0x50;
+        if sys.cpu_state.carry {
+            fixed = fixed.wrapping_add(0x60);
+        }
+
+        sys.cpu_state.a = fixed;
+    } else {
+        sys.cpu_state.a = result;
+        set_n_z(sys, result);
+        sys.cpu_state.carry = (result & 0x40) != 0;
+        sys.cpu_state.signed_overflow = (((result >> 6) ^ (result >> 5)) & 0x01) != 0;
+    }
+}
+
+fn dcp<B: Bus>(sys: &mut SystemState<B>, operand: u8) -> u8 {
+    // DEC followed by CMP
+    let result = operand.wrapping_sub(1);
+
+    compare(sys, sys.cpu_state.a, result);
+
+    result
+}
+
+fn isc<B: Bus>(sys: &mut SystemState<B>, operand: u8) -> u8 {
+    // INC followed by SBC
+    let result = operand.wrapping_add(1);
+
+    subtract_with_carry(sys, result);
+
+    result
+}
+
+fn las<B: Bus>(sys: &mut SystemState<B>, operand: u8) {
+    let result = operand & sys.cpu_state.s;
+    sys.cpu_state.a = result;
+    sys.cpu_state.x = result;
+    sys.cpu_state.s = result;
+    set_n_z(sys, result);
+}
+
+fn jam<B: Bus>(sys: &mut SystemState<B>, _cycle: u8) -> bool {
+    // also known as KIL. The real CPU locks up until reset, so we leave the PC on the opcode
+    dummy_read(sys, sys.cpu_state.pc);
+    sys.cpu_state.pc = sys.cpu_state.pc.wrapping_sub(1);
+    sys.cpu_state.run_state = RunState::Halted;
+
+    true
+}
+
+fn lax<B: Bus>(sys: &mut SystemState<B>, operand: u8) {
+    // LDA and LDX with the same operand
+    sys.cpu_state.a = operand;
+    sys.cpu_state.x = operand;
+    set_n_z(sys, operand);
+}
+
+fn lxa<B: Bus>(sys: &mut SystemState<B>, operand: u8) {
+    let result = (sys.cpu_state.a | sys.options.lxa_magic_constant) & operand;
+    sys.cpu_state.a = result;
+    sys.cpu_state.x = result;
+    set_n_z(sys, result);
+}
+
+fn rla<B: Bus>(sys: &mut SystemState<B>, operand: u8) -> u8 {
+    // ROL followed by AND
+    let result = rotate_left(sys, operand);
+
+    sys.cpu_state.a &= result;
+    set_n_z(sys, sys.cpu_state.a);
+
+    result
+}
+
+fn rra<B: Bus>(sys: &mut SystemState<B>, operand: u8) -> u8 {
+    // ROR followed by ADC
+    let result = rotate_right(sys, operand);
+
+    add_with_carry(sys, result);
+
+    result
+}
+
+fn sax<B: Bus>(sys: &mut SystemState<B>) -> u8 {
+    // stores A & X without affecting any flags
+    sys.cpu_state.a & sys.cpu_state.x
+}
+
+fn sbx<B: Bus>(sys: &mut SystemState<B>, operand: u8) {
+    // also known as AXS. Subtracts like CMP, so ignores carry-in and decimal mode
+    let (result, borrow) = (sys.cpu_state.a & sys.cpu_state.x).overflowing_sub(operand);
+    sys.cpu_state.x = result;
+    sys.cpu_state.carry = !borrow;
+    set_n_z(sys, result);
+}
+
+fn sha<B: Bus>(sys: &mut SystemState<B>) -> u8 {
+    sys.cpu_state.a & sys.cpu_state.x
+}
+
+fn shx<B: Bus>(sys: &mut SystemState<B>) -> u8 {
+    sys.cpu_state.x
+}
+
+fn shy<B: Bus>(sys: &mut SystemState<B>) -> u8 {
+    sys.cpu_state.y
+}
+
+fn slo<B: Bus>(sys: &mut SystemState<B>, operand: u8) -> u8 {
+    // ASL followed by ORA
+    let result = shift_left(sys, operand);
+
+    sys.cpu_state.a |= result;
+    set_n_z(sys, sys.cpu_state.a);
+
+    result
+}
+
+fn sre<B: Bus>(sys: &mut SystemState<B>, operand: u8) -> u8 {
+    // LSR followed by EOR
+    let result = shift_right(sys, operand);
+
+    sys.cpu_state.a ^= result;
+    set_n_z(sys, sys.cpu_state.a);
+
+    result
+}
+
+fn tas<B: Bus>(sys: &mut SystemState<B>) -> u8 {
+    sys.cpu_state.s = sys.cpu_state.a & sys.cpu_state.x;
+    sys.cpu_state.s
+}
+
+// -- 65C02 instructions --
+
+fn bbr<B: Bus>(sys: &SystemState<B>, operand: u8) -> bool {
+    // the bit tested is given by bits 4-6 of the opcode
+    operand & (1 << ((sys.cpu_state.opcode >> 4) & 0x07)) == 0
+}
+
+fn bbs<B: Bus>(sys: &SystemState<B>, operand: u8) -> bool {
+    !bbr(sys, operand)
+}
+
+fn bit_immediate<B: Bus>(sys: &mut SystemState<B>, operand: u8) {
+    // there is no memory operand to copy N and V from, so only Z is affected
+    sys.cpu_state.zero = (operand & sys.cpu_state.a) == 0x00;
+}
+
+fn bra<B: Bus>(_sys: &SystemState<B>) -> bool {
+    true
+}
+
+fn phx<B: Bus>(sys: &mut SystemState<B>) -> u8 {
+    sys.cpu_state.x
+}
+
+fn phy<B: Bus>(sys: &mut SystemState<B>) -> u8 {
+    sys.cpu_state.y
+}
+
+fn plx<B: Bus>(sys: &mut SystemState<B>, byte: u8) {
+    sys.cpu_state.x = byte;
+    set_n_z(sys, sys.cpu_state.x);
+}
+
+fn ply<B: Bus>(sys: &mut SystemState<B>, byte: u8) {
+    sys.cpu_state.y = byte;
+    set_n_z(sys, sys.cpu_state.y);
+}
+
+fn rmb<B: Bus>(sys: &mut SystemState<B>, operand: u8) -> u8 {
+    // the bit cleared is given by bits 4-6 of the opcode
+    operand & !(1 << ((sys.cpu_state.opcode >> 4) & 0x07))
+}
+
+fn smb<B: Bus>(sys: &mut SystemState<B>, operand: u8) -> u8 {
+    // the bit set is given by bits 4-6 of the opcode
+    operand | (1 << ((sys.cpu_state.opcode >> 4) & 0x07))
+}
+
+fn stp<B: Bus>(sys: &mut SystemState<B>, cycle: u8) -> bool {
+    dummy_read(sys, sys.cpu_state.pc);
+    if cycle < 2 {
+        return false;
+    }
+
+    sys.cpu_state.run_state = RunState::Stopped;
+
+    true
+}
+
+fn stz<B: Bus>(_sys: &mut SystemState<B>) -> u8 {
+    0
+}
+
+fn nop_5c<B: Bus>(sys: &mut SystemState<B>, cycle: u8) -> bool {
+    match cycle {
+        1 => sys.cpu_state.data = fetch_byte(sys),
+        2 => {
+            let addr_hi = fetch_byte(sys);
+            sys.cpu_state.addr = cat_bytes(addr_hi, sys.cpu_state.data);
+        }
+        3..=6 => dummy_read(sys, sys.cpu_state.addr),
+        _ => {
+            dummy_read(sys, sys.cpu_state.addr);
+            return true;
+        }
+    }
+
+    false
+}
+
+fn trb<B: Bus>(sys: &mut SystemState<B>, operand: u8) -> u8 {
+    // Z is set as by BIT, then the bits set in A are cleared in memory
+    sys.cpu_state.zero = (operand & sys.cpu_state.a) == 0;
+
+    operand & !sys.cpu_state.a
+}
+
+fn tsb<B: Bus>(sys: &mut SystemState<B>, operand: u8) -> u8 {
+    // Z is set as by BIT, then the bits set in A are set in memory
+    sys.cpu_state.zero = (operand & sys.cpu_state.a) == 0;
+
+    operand | sys.cpu_state.a
+}
+
+fn wai<B: Bus>(sys: &mut SystemState<B>, cycle: u8) -> bool {
+    dummy_read(sys, sys.cpu_state.pc);
+    if cycle < 2 {
+        return false;
+    }
+
+    sys.cpu_state.run_state = RunState::Waiting;
+
+    true
+}
+
+// -- Emulation zone --
+
+fn decode<B: Bus>(
+    opcode: u8,
+    options: &CpuOptions,
+) -> (&'static str, Operation<B>, AddressingMode) {
+    use AddressingMode::*;
+    use Operation::*;
+
+    if options.variant == CpuVariant::Cmos65c02 {
+        if let Some(decoded) = decode_65c02(opcode) {
+            return decoded;
+        }
+    } else if options.illegal_opcodes == IllegalOpcodes::Halt && undocumented(opcode) {
+        return ("JAM", Jam, Imp);
+    }
+
+    let (mnemonic, operation, mode) = match opcode {
+        0x00 => ("BRK", Brk, Imp),
+        0x01 => ("ORA", Read(ora), Zpiix),
+        0x02 => ("JAM", Jam, Imp),
+        0x03 => ("SLO", ReadModifyWrite(slo), Zpiix),
+        0x04 => ("NOP", Read(nop), Zp),
+        0x05 => ("ORA", Read(ora), Zp),
+        0x06 => ("ASL", ReadModifyWrite(asl), Zp),
+        0x07 => ("SLO", ReadModifyWrite(slo), Zp),
+
+        0x08 => ("PHP", Push(php), Imp),
+        0x09 => ("ORA", Read(ora), I),
+        0x0a => ("ASL", ReadModifyWrite(asl), Acc),
+        0x0b => ("ANC", Read(anc), I),
+        0x0c => ("NOP", Read(nop), A),
+        0x0d => ("ORA", Read(ora), A),
+        0x0e => ("ASL", ReadModifyWrite(asl), A),
+        0x0f => ("SLO", ReadModifyWrite(slo), A),
+
+        0x10 => ("BPL", Branch(bpl), Rel),
+        0x11 => ("ORA", Read(ora), Zpiiy),
+        0x12 => ("JAM", Jam, Imp),
+        0x13 => ("SLO", ReadModifyWrite(slo), Zpiiy),
+        0x14 => ("NOP", Read(nop), Zpix),
+        0x15 => ("ORA", Read(ora), Zpix),
+        0x16 => ("ASL", ReadModifyWrite(asl), Zpix),
+        0x17 => ("SLO", ReadModifyWrite(slo), Zpix),
+
+        0x18 => ("CLC", Implied(clc), Imp),
+        0x19 => ("ORA", Read(ora), Aiy),
+        0x1a => ("NOP", Read(nop), Imp),
+        0x1b => ("SLO", ReadModifyWrite(slo), Aiy),
+        0x1c => ("NOP", Read(nop), Aix),
+        0x1d => ("ORA", Read(ora), Aix),
+        0x1e => ("ASL", ReadModifyWrite(asl), Aix),
+        0x1f => ("SLO", ReadModifyWrite(slo), Aix),
+
+        0x20 => ("JSR", Jsr, A),
+        0x21 => ("AND", Read(and), Zpiix),
+        0x22 => ("JAM", Jam, Imp),
+        0x23 => ("RLA", ReadModifyWrite(rla), Zpiix),
+        0x24 => ("BIT", Read(bit), Zp),
+        0x25 => ("AND", Read(and), Zp),
+        0x26 => ("ROL", ReadModifyWrite(rol), Zp),
+        0x27 => ("RLA", ReadModifyWrite(rla), Zp),
+
+        0x28 => ("PLP", Pull(plp), Imp),
+        0x29 => ("AND", Read(and), I),
+        0x2a => ("ROL", ReadModifyWrite(rol), Acc),
+        0x2b => ("ANC", Read(anc), I),
+        0x2c => ("BIT", Read(bit), A),
+        0x2d => ("AND", Read(and), A),
+        0x2e => ("ROL", ReadModifyWrite(rol), A),
+        0x2f => ("RLA", ReadModifyWrite(rla), A),
+
+        0x30 => ("BMI", Branch(bmi), Rel),
+        0x31 => ("AND", Read(and), Zpiiy),
+        0x32 => ("JAM", Jam, Imp),
+        0x33 => ("RLA", ReadModifyWrite(rla), Zpiiy),
+        0x34 => ("NOP", Read(nop), Zpix),
+        0x35 => ("AND", Read(and), Zpix),
+        0x36 => ("ROL", ReadModifyWrite(rol), Zpix),
+        0x37 => ("RLA", ReadModifyWrite(rla), Zpix),
+
+        0x38 => ("SEC", Implied(sec), Imp),
+        0x39 => ("AND", Read(and), Aiy),
+        0x3a => ("NOP", Read(nop), Imp),
+        0x3b => ("RLA", ReadModifyWrite(rla), Aiy),
+        0x3c => ("NOP", Read(nop), Aix),
+        0x3d => ("AND", Read(and), Aix),
+        0x3e => ("ROL", ReadModifyWrite(rol), Aix),
+        0x3f => ("RLA", ReadModifyWrite(rla), Aix),
+
+        0x40 => ("RTI", Rti, Imp),
+        0x41 => ("EOR", Read(eor), Zpiix),
+        0x42 => ("JAM", Jam, Imp),
+        0x43 => ("SRE", ReadModifyWrite(sre), Zpiix),
+        0x44 => ("NOP", Read(nop), Zp),
+        0x45 => ("EOR", Read(eor), Zp),
+        0x46 => ("LSR", ReadModifyWrite(lsr), Zp),
+        0x47 => ("SRE", ReadModifyWrite(sre), Zp),
+
+        0x48 => ("PHA", Push(pha), Imp),
+        0x49 => ("EOR", Read(eor), I),
+        0x4a => ("LSR", ReadModifyWrite(lsr), Acc),
+        0x4b => ("ALR", Read(alr), I),
+        0x4c => ("JMP", Jmp, A),
+        0x4d => ("EOR", Read(eor), A),
+        0x4e => ("LSR", ReadModifyWrite(lsr), A),
+        0x4f => ("SRE", ReadModifyWrite(sre), A),
+
+        0x50 => ("BVC", Branch(bvc), Rel),
+        0x51 => ("EOR", Read(eor), Zpiiy),
+        0x52 => ("JAM", Jam, Imp),
+        0x53 => ("SRE", ReadModifyWrite(sre), Zpiiy),
+        0x54 => ("NOP", Read(nop), Zpix),
+        0x55 => ("EOR", Read(eor), Zpix),
+        0x56 => ("LSR", ReadModifyWrite(lsr), Zpix),
+        0x57 => ("SRE", ReadModifyWrite(sre), Zpix),
+
+        0x58 => ("CLI", Implied(cli), Imp),
+        0x59 => ("EOR", Read(eor), Aiy),
+        0x5a => ("NOP", Read(nop), Imp),
+        0x5b => ("SRE", ReadModifyWrite(sre), Aiy),
+        0x5c => ("NOP", Read(nop), Aix),
+        0x5d => ("EOR", Read(eor), Aix),
+        0x5e => ("LSR", ReadModifyWrite(lsr), Aix),
+        0x5f => ("SRE", ReadModifyWrite(sre), Aix),
+
+        0x60 => ("RTS", Rts, Imp),
+        0x61 => ("ADC", DecimalRead(adc), Zpiix),
+        0x62 => ("JAM", Jam, Imp),
+        0x63 => ("RRA", ReadModifyWrite(rra), Zpiix),
+        0x64 => ("NOP", Read(nop), Zp),
+        0x65 => ("ADC", DecimalRead(adc), Zp),
+        0x66 => ("ROR", ReadModifyWrite(ror), Zp),
+        0x67 => ("RRA", ReadModifyWrite(rra), Zp),
+
+        0x68 => ("PLA", Pull(pla), Imp),
+        0x69 => ("ADC", DecimalRead(adc), I),
+        0x6a => ("ROR", ReadModifyWrite(ror), Acc),
+        0x6b => ("ARR", Read(arr), I),
+        0x6c => ("JMP", Jmp, Ind),
+        0x6d => ("ADC", DecimalRead(adc), A),
+        0x6e => ("ROR", ReadModifyWrite(ror), A),
+        0x6f => ("RRA", ReadModifyWrite(rra), A),
+
+        0x70 => ("BVS", Branch(bvs), Rel),
+        0x71 => ("ADC", DecimalRead(adc), Zpiiy),
+        0x72 => ("JAM", Jam, Imp),
+        0x73 => ("RRA", ReadModifyWrite(rra), Zpiiy),
+        0x74 => ("NOP", Read(nop), Zpix),
+        0x75 => ("ADC", DecimalRead(adc), Zpix),
+        0x76 => ("ROR", ReadModifyWrite(ror), Zpix),
+        0x77 => ("RRA", ReadModifyWrite(rra), Zpix),
+
+        0x78 => ("SEI", Implied(sei), Imp),
+        0x79 => ("ADC", DecimalRead(adc), Aiy),
+        0x7a => ("NOP", Read(nop), Imp),
+        0x7b => ("RRA", ReadModifyWrite(rra), Aiy),
+        0x7c => ("NOP", Read(nop), Aix),
+        0x7d => ("ADC", DecimalRead(adc), Aix),
+        0x7e => ("ROR", ReadModifyWrite(ror), Aix),
+        0x7f => ("RRA", ReadModifyWrite(rra), Aix),
+
+        0x80 => ("NOP", Read(nop), I),
+        0x81 => ("STA", Write(sta), Zpiix),
+        0x82 => ("NOP", Read(nop), I),
+        0x83 => ("SAX", Write(sax), Zpiix),
+        0x84 => ("STY", Write(sty), Zp),
+        0x85 => ("STA", Write(sta), Zp),
+        0x86 => ("STX", Write(stx), Zp),
+        0x87 => ("SAX", Write(sax), Zp),
+
+        0x88 => ("DEY", Implied(dey), Imp),
+        0x89 => ("NOP", Read(nop), I),
+        0x8a => ("TXA", Implied(txa), Imp),
+        0x8b => ("ANE", Read(ane), I),
+        0x8c => ("STY", Write(sty), A),
+        0x8d => ("STA", Write(sta), A),
+        0x8e => ("STX", Write(stx), A),
+        0x8f => ("SAX", Write(sax), A),
+
+        0x90 => ("BCC", Branch(bcc), Rel),
+        0x91 => ("STA", Write(sta), Zpiiy),
+        0x92 => ("JAM", Jam, Imp),
+        0x93 => ("SHA", StoreAndHighByte(sha), Zpiiy),
+        0x94 => ("STY", Write(sty), Zpix),
+        0x95 => ("STA", Write(sta), Zpix),
+        0x96 => ("STX", Write(stx), Zpiy),
+        0x97 => ("SAX", Write(sax), Zpiy),
+
+        0x98 => ("TYA", Implied(tya), Imp),
+        0x99 => ("STA", Write(sta), Aiy),
+        0x9a => ("TXS", Implied(txs), Imp),
+        0x9b => ("TAS", StoreAndHighByte(tas), Aiy),
+        0x9c => ("SHY", StoreAndHighByte(shy), Aix),
+        0x9d => ("STA", Write(sta), Aix),
+        0x9e => ("SHX", StoreAndHighByte(shx), Aiy),
+        0x9f => ("SHA", StoreAndHighByte(sha), Aiy),
+
+        0xa0 => ("LDY", Read(ldy), I),
+        0xa1 => ("LDA", Read(lda), Zpiix),
+        0xa2 => ("LDX", Read(ldx), I),
+        0xa3 => ("LAX", Read(lax), Zpiix),
+        0xa4 => ("LDY", Read(ldy), Zp),
+        0xa5 => ("LDA", Read(lda), Zp),
+        0xa6 => ("LDX", Read(ldx), Zp),
+        0xa7 => ("LAX", Read(lax), Zp),
+
+        0xa8 => ("TAY", Implied(tay), Imp),
+        0xa9 => ("LDA", Read(lda), I),
+        0xaa => ("TAX", Implied(tax), Imp),
+        0xab => ("LXA", Read(lxa), I),
+        0xac => ("LDY", Read(ldy), A),
+        0xad => ("LDA", Read(lda), A),
+        0xae => ("LDX", Read(ldx), A),
+        0xaf => ("LAX", Read(lax), A),
+
+        0xb0 => ("BCS", Branch(bcs), Rel),
+        0xb1 => ("LDA", Read(lda), Zpiiy),
+        0xb2 => ("JAM", Jam, Imp),
+        0xb3 => ("LAX", Read(lax), Zpiiy),
+        0xb4 => ("LDY", Read(ldy), Zpix),
+        0xb5 => ("LDA", Read(lda), Zpix),
+        0xb6 => ("LDX", Read(ldx), Zpiy),
+        0xb7 => ("LAX", Read(lax), Zpiy),
+
+        0xb8 => ("CLV", Implied(clv), Imp),
+        0xb9 => ("LDA", Read(lda), Aiy),
+        0xba => ("TSX", Implied(tsx), Imp),
+        0xbb => ("LAS", Read(las), Aiy),
+        0xbc => ("LDY", Read(ldy), Aix),
+        0xbd => ("LDA", Read(lda), Aix),
+        0xbe => ("LDX", Read(ldx), Aiy),
+        0xbf => ("LAX", Read(lax), Aiy),
+
+        0xc0 => ("CPY", Read(cpy), I),
+        0xc1 => ("CMP", Read(cmp), Zpiix),
+        0xc2 => ("NOP", Read(nop), I),
+        0xc3 => ("DCP", ReadModifyWrite(dcp), Zpiix),
+        0xc4 => ("CPY", Read(cpy), Zp),
+        0xc5 => ("CMP", Read(cmp), Zp),
+        0xc6 => ("DEC", ReadModifyWrite(dec), Zp),
+        0xc7 => ("DCP", ReadModifyWrite(dcp), Zp),
+
+        0xc8 => ("INY", Implied(iny), Imp),
+        0xc9 => ("CMP", Read(cmp), I),
+        0xca => ("DEX", Implied(dex), Imp),
+        0xcb => ("SBX", Read(sbx), I),
+        0xcc => ("CPY", Read(cpy), A),
+        0xcd => ("CMP", Read(cmp), A),
+        0xce => ("DEC", ReadModifyWrite(dec), A),
+        0xcf => ("DCP", ReadModifyWrite(dcp), A),
+
+        0xd0 => ("BNE", Branch(bne), Rel),
+        0xd1 => ("CMP", Read(cmp), Zpiiy),
+        0xd2 => ("JAM", Jam, Imp),
+        0xd3 => ("DCP", ReadModifyWrite(dcp), Zpiiy),
+        0xd4 => ("NOP", Read(nop), Zpix),
+        0xd5 => ("CMP", Read(cmp), Zpix),
+        0xd6 => ("DEC", ReadModifyWrite(dec), Zpix),
+        0xd7 => ("DCP", ReadModifyWrite(dcp), Zpix),
+
+        0xd8 => ("CLD", Implied(cld), Imp),
+        0xd9 => ("CMP", Read(cmp), Aiy),
+        0xda => ("NOP", Read(nop), Imp),
+        0xdb => ("DCP", ReadModifyWrite(dcp), Aiy),
+        0xdc => ("NOP", Read(nop), Aix),
+        0xdd => ("CMP", Read(cmp), Aix),
+        0xde => ("DEC", ReadModifyWrite(dec), Aix),
+        0xdf => ("DCP", ReadModifyWrite(dcp), Aix),
+
+        0xe0 => ("CPX", Read(cpx), I),
+        0xe1 => ("SBC", DecimalRead(sbc), Zpiix),
+        0xe2 => ("NOP", Read(nop), I),
+        0xe3 => ("ISC", ReadModifyWrite(isc), Zpiix),
+        0xe4 => ("CPX", Read(cpx), Zp),
+        0xe5 => ("SBC", DecimalRead(sbc), Zp),
+        0xe6 => ("INC", ReadModifyWrite(inc), Zp),
+        0xe7 => ("ISC", ReadModifyWrite(isc), Zp),
+
+        0xe8 => ("INX", Implied(inx), Imp),
+        0xe9 => ("SBC", DecimalRead(sbc), I),
+        0xea => ("NOP", Read(nop), Imp),
+        0xeb => ("SBC", DecimalRead(sbc), I),
+        0xec => ("CPX", Read(cpx), A),
+        0xed => ("SBC", DecimalRead(sbc), A),
+        0xee => ("INC", ReadModifyWrite(inc), A),
+        0xef => ("ISC", ReadModifyWrite(isc), A),
+
+        0xf0 => ("BEQ", Branch(beq), Rel),
+        0xf1 => ("SBC", DecimalRead(sbc), Zpiiy),
+        0xf2 => ("JAM", Jam, Imp),
+        0xf3 => ("ISC", ReadModifyWrite(isc), Zpiiy),
+        0xf4 => ("NOP", Read(nop), Zpix),
+        0xf5 => ("SBC", DecimalRead(sbc), Zpix),
+        0xf6 => ("INC", ReadModifyWrite(inc), Zpix),
+        0xf7 => ("ISC", ReadModifyWrite(isc), Zpix),
+
+        0xf8 => ("SED", Implied(sed), Imp),
+        0xf9 => ("SBC", DecimalRead(sbc), Aiy),
+        0xfa => ("NOP", Read(nop), Imp),
+        0xfb => ("ISC", ReadModifyWrite(isc), Aiy),
+        0xfc => ("NOP", Read(nop), Aix),
+        0xfd => ("SBC", DecimalRead(sbc), Aix),
+        0xfe => ("INC", ReadModifyWrite(inc), Aix),
+        0xff => ("ISC", ReadModifyWrite(isc), Aix),
+    };
+    (mnemonic, operation, mode)
+}
+
+pub(crate) fn decode_mnemonic(opcode: u8, options: &CpuOptions) -> (&'static str, AddressingMode) {
+    // the disassembler reads the executor's own table, so the two always agree
+    let (mnemonic, _, mode) = decode::<FlatRam>(opcode, options);
+    (mnemonic, mode)
+}
+
+pub(crate) fn undocumented(opcode: u8) -> bool {
+    // opcodes outside the 151 documented for the NMOS 6502
+    matches!(
+        opcode,
+        0x02 | 0x03
+            | 0x04
+            | 0x07
+            | 0x0b
+            | 0x0c
+            | 0x0f
+            | 0x12
+            | 0x13
+            | 0x14
+            | 0x17
+            | 0x1a
+            | 0x1b
+            | 0x1c
+            | 0x1f
+            | 0x22
+            | 0x23
+            | 0x27
+            | 0x2b
+            | 0x2f
+            | 0x32
+            | 0x33
+            | 0x34
+            | 0x37
+            | 0x3a
+            | 0x3b
+            | 0x3c
+            | 0x3f
+            | 0x42
+            | 0x43
+            | 0x44
+            | 0x47
+            | 0x4b
+            | 0x4f
+            | 0x52
+            | 0x53
+            | 0x54
+            | 0x57
+            | 0x5a
+            | 0x5b
+            | 0x5c
+            | 0x5f
+            | 0x62
+            | 0x63
+            | 0x64
+            | 0x67
+            | 0x6b
+            | 0x6f
+            | 0x72
+            | 0x73
+            | 0x74
+            | 0x77
+            | 0x7a
+            | 0x7b
+            | 0x7c
+            | 0x7f
+            | 0x80
+            | 0x82
+            | 0x83
+            | 0x87
+            | 0x89
+            | 0x8b
+            | 0x8f
+            | 0x92
+            | 0x93
+            | 0x97
+            | 0x9b
+            | 0x9c
+            | 0x9e
+            | 0x9f
+            | 0xa3
+            | 0xa7
+            | 0xab
+            | 0xaf
+            | 0xb2
+            | 0xb3
+            | 0xb7
+            | 0xbb
+            | 0xbf
+            | 0xc2
+            | 0xc3
+            | 0xc7
+            | 0xcb
+            | 0xcf
+            | 0xd2
+            | 0xd3
+            | 0xd4
+            | 0xd7
+            | 0xda
+            | 0xdb
+            | 0xdc
+            | 0xdf
+            | 0xe2
+            | 0xe3
+            | 0xe7
+            | 0xeb
+            | 0xef
+            | 0xf2
+            | 0xf3
+            | 0xf4
+            | 0xf7
+            | 0xfa
+            | 0xfb
+            | 0xfc
+            | 0xff
+    )
+}
+
+fn decode_65c02<B: Bus>(opcode: u8) -> Option<(&'static str, Operation<B>, AddressingMode)> {
+    // opcodes the 65C02 added or changed, everything else decodes as on the NMOS 6502
+    use AddressingMode::*;
+    use Operation::*;
+
+    let decoded = match opcode {
+        0x02 | 0x22 | 0x42 | 0x62 | 0x82 | 0xc2 | 0xe2 => ("NOP", Read(nop), I),
+        0x44 => ("NOP", Read(nop), Zp),
+        0x54 | 0xd4 | 0xf4 => ("NOP", Read(nop), Zpix),
+        0xdc | 0xfc => ("NOP", Read(nop), A),
+        0x5c => ("NOP", Nop5c, A),
+        // the Rockwell and WDC bit instructions fill the $x7 and $xF columns
+        _ if opcode & 0x8f == 0x07 => ("RMB", ReadModifyWrite(rmb), Zp),
+        _ if opcode & 0x8f == 0x87 => ("SMB", ReadModifyWrite(smb), Zp),
+        _ if opcode & 0x8f == 0x0f => ("BBR", BranchOnBit(bbr), Zpr),
+        _ if opcode & 0x8f == 0x8f => ("BBS", BranchOnBit(bbs), Zpr),
+        0xcb => ("WAI", Wai, Imp),
+        0xdb => ("STP", Stp, Imp),
+        // the remaining undocumented NMOS opcodes are all one byte, one cycle NOPs
+        _ if opcode & 0x03 == 0x03 => ("NOP", OneCycleNop, Imp),
+
+        0x04 => ("TSB", ReadModifyWrite(tsb), Zp),
+        0x0c => ("TSB", ReadModifyWrite(tsb), A),
+
+        0x12 => ("ORA", Read(ora), Zpi),
+        0x14 => ("TRB", ReadModifyWrite(trb), Zp),
+
+        0x1a => ("INC", ReadModifyWrite(inc), Acc),
+        0x1c => ("TRB", ReadModifyWrite(trb), A),
+
+        0x32 => ("AND", Read(and), Zpi),
+        0x34 => ("BIT", Read(bit), Zpix),
+
+        0x3a => ("DEC", ReadModifyWrite(dec), Acc),
+        0x3c => ("BIT", Read(bit), Aix),
+
+        0x52 => ("EOR", Read(eor), Zpi),
+
+        0x5a => ("PHY", Push(phy), Imp),
+
+        0x64 => ("STZ", Write(stz), Zp),
+
+        0x72 => ("ADC", DecimalRead(adc), Zpi),
+        0x74 => ("STZ", Write(stz), Zpix),
+
+        0x7a => ("PLY", Pull(ply), Imp),
+        0x7c => ("JMP", Jmp, Aiix),
+
+        0x80 => ("BRA", Branch(bra), Rel),
+
+        0x89 => ("BIT", Read(bit_immediate), I),
+
+        0x92 => ("STA", Write(sta), Zpi),
+
+        0x9c => ("STZ", Write(stz), A),
+        0x9e => ("STZ", Write(stz), Aix),
+
+        0xb2 => ("LDA", Read(lda), Zpi),
+
+        0xd2 => ("CMP", Read(cmp), Zpi),
+
+        0xda => ("PHX", Push(phx), Imp),
+
+        0xf2 => ("SBC", DecimalRead(sbc), Zpi),
+
+        0xfa => ("PLX", Pull(plx), Imp),
+        _ => return None,
+    };
+
+    Some(decoded)
+}
+
+fn read_cycle<B: Bus>(
+    sys: &mut SystemState<B>,
+    op: fn(&mut SystemState<B>, u8),
+    mode: AddressingMode,
+    cycle: u8,
+) -> bool {
+    let operand = match mode {
+        // implied NOPs still read the byte after the opcode, without stepping over it
+        AddressingMode::Imp => get_byte_at_addr(sys, sys.cpu_state.pc),
+        AddressingMode::I => {
+            sys.cpu_state.addr = sys.cpu_state.pc;
+            fetch_byte(sys)
+        }
+        _ if cycle <= address_cycles(mode) => {
+            address_cycle(sys, mode, cycle);
+            return false;
+        }
+        // reads only spend a cycle fixing up the high byte when indexing crossed a page
+        _ if cycle == address_cycles(mode) + 1 && sys.cpu_state.page_cross => {
+            fix_page_cross(sys);
+            return false;
+        }
+        _ => get_byte_at_addr(sys, sys.cpu_state.addr),
+    };
+
+    op(sys, operand);
+
+    true
+}
+
+fn operand_cycle<B: Bus>(sys: &SystemState<B>, mode: AddressingMode) -> u8 {
+    match mode {
+        AddressingMode::I | AddressingMode::Imp => 1,
+        _ => address_cycles(mode) + 1 + sys.cpu_state.page_cross as u8,
+    }
+}
+
+fn decimal_read_cycle<B: Bus>(
+    sys: &mut SystemState<B>,
+    op: fn(&mut SystemState<B>, u8),
+    mode: AddressingMode,
+    cycle: u8,
+) -> bool {
+    if sys.options.variant != CpuVariant::Cmos65c02 || !decimal_arithmetic(sys) {
+        return read_cycle(sys, op, mode, cycle);
+    }
+
+    // the operand is latched, then the 65C02 reads it again during the extra cycle
+    if cycle == operand_cycle(sys, mode) + 1 {
+        dummy_read(sys, sys.cpu_state.addr);
+        op(sys, sys.cpu_state.data);
+        return true;
+    }
+    read_cycle(
+        sys,
+        |sys, operand| sys.cpu_state.data = operand,
+        mode,
+        cycle,
+    );
+
+    false
+}
+
+fn write_cycle<B: Bus>(
+    sys: &mut SystemState<B>,
+    op: fn(&mut SystemState<B>) -> u8,
+    mode: AddressingMode,
+    cycle: u8,
+) -> bool {
+    if cycle <= address_cycles(mode) {
+        address_cycle(sys, mode, cycle);
+        return false;
+    }
+    // writes can't be undone, so they always spend a cycle fixing up the high byte
+    if cycle == address_cycles(mode) + 1 && may_cross_page(mode) {
+        fix_page_cross(sys);
+        return false;
+    }
+
+    let byte = op(sys);
+    set_byte_at_addr(sys, sys.cpu_state.addr, byte);
+
+    true
+}
+
+fn read_modify_write_cycle<B: Bus>(
+    sys: &mut SystemState<B>,
+    op: fn(&mut SystemState<B>, u8) -> u8,
+    mode: AddressingMode,
+    cycle: u8,
+) -> bool {
+    if let AddressingMode::Acc = mode {
+        dummy_read(sys, sys.cpu_state.pc);
+        sys.cpu_state.a = op(sys, sys.cpu_state.a);
+        return true;
+    }
+
+    // the 65C02's shifts and rotates only spend a cycle fixing up the high byte when
+    // indexing crossed a page, though INC and DEC always do
+    let cmos = sys.options.variant == CpuVariant::Cmos65c02;
+    let cmos_shift = cmos && matches!(sys.cpu_state.opcode, 0x1e | 0x3e | 0x5e | 0x7e);
+    let address_cycles = address_cycles(mode);
+    let fixed_cycles =
+        address_cycles + (may_cross_page(mode) && (!cmos_shift || sys.cpu_state.page_cross)) as u8;
+
+    if cycle <= address_cycles {
+        address_cycle(sys, mode, cycle);
+    } else if cycle <= fixed_cycles {
+        fix_page_cross(sys);
+    } else if cycle == fixed_cycles + 1 {
+        sys.cpu_state.data = get_byte_at_addr(sys, sys.cpu_state.addr);
+    } else if cycle == fixed_cycles + 2 {
+        // while the operand is modified, the NMOS 6502 writes the unmodified value back,
+        // which memory-mapped registers see as an extra write. The 65C02 reads it again
+        if cmos {
+            dummy_read(sys, sys.cpu_state.addr);
+        } else {
+            set_byte_at_addr(sys, sys.cpu_state.addr, sys.cpu_state.data);
+        }
+        sys.cpu_state.data = op(sys, sys.cpu_state.data);
+    } else {
+        set_byte_at_addr(sys, sys.cpu_state.addr, sys.cpu_state.data);
+        return true;
+    }
+
+    false
+}
+
+fn store_and_high_byte_cycle<B: Bus>(
+    sys: &mut SystemState<B>,
+    op: fn(&mut SystemState<B>) -> u8,
+    mode: AddressingMode,
+    cycle: u8,
+) -> bool {
+    if cycle <= address_cycles(mode) {
+        address_cycle(sys, mode, cycle);
+        return false;
+    }
+    if cycle == address_cycles(mode) + 1 {
+        fix_page_cross(sys);
+        return false;
+    }
+
+    // SHA, SHX, SHY and TAS AND the stored value with the high byte of the base address
+    // plus one. When indexing crosses a page, the result also replaces the high byte of the
+    // address written to
+    let page_cross = sys.cpu_state.page_cross;
+    let base_hi = ((sys.cpu_state.addr >> 8) as u8).wrapping_sub(page_cross as u8);
+    let result = op(sys) & base_hi.wrapping_add(1);
+
+    if page_cross {
+        sys.cpu_state.addr = cat_bytes(result, sys.cpu_state.addr as u8);
+    }
+    set_byte_at_addr(sys, sys.cpu_state.addr, result);
+
+    true
+}
+
+fn branch_cycle<B: Bus>(
+    sys: &mut SystemState<B>,
+    predicate: fn(&SystemState<B>) -> bool,
+    cycle: u8,
+) -> bool {
+    match cycle {
+        1 => {
+            sys.cpu_state.data = fetch_byte(sys);
+            !predicate(sys)
+        }
+        2 => {
+            dummy_read(sys, sys.cpu_state.pc);
+
+            // the displacement is signed and relative to the instruction following the
+            // branch. It is added to the low byte first, and carrying into the high byte
+            // takes another cycle
+            let pc = sys.cpu_state.pc;
+            let target = pc.wrapping_add(sys.cpu_state.data as i8 as u16);
+            sys.cpu_state.page_cross = (target >> 8) != (pc >> 8);
+            sys.cpu_state.addr = target;
+            sys.cpu_state.pc = cat_bytes((pc >> 8) as u8, target as u8);
+
+            !sys.cpu_state.page_cross
+        }
+        _ => {
+            dummy_read(sys, sys.cpu_state.pc);
+            sys.cpu_state.pc = sys.cpu_state.addr;
+
+            true
+        }
+    }
+}
+
+fn branch_on_bit_cycle<B: Bus>(
+    sys: &mut SystemState<B>,
+    predicate: fn(&SystemState<B>, u8) -> bool,
+    cycle: u8,
+) -> bool {
+    match cycle {
+        1 => {
+            sys.cpu_state.addr = fetch_byte(sys) as u16;
+            false
+        }
+        2 => {
+            sys.cpu_state.data = get_byte_at_addr(sys, sys.cpu_state.addr);
+            false
+        }
+        3 => {
+            dummy_read(sys, sys.cpu_state.addr);
+            false
+        }
+        4 => {
+            let taken = predicate(sys, sys.cpu_state.data);
+            sys.cpu_state.data = fetch_byte(sys);
+            !taken
+        }
+        // from here on the sequence is that of any taken branch
+        _ => branch_cycle(sys, bra, cycle - 3),
+    }
+}
+
+fn push_cycle<B: Bus>(
+    sys: &mut SystemState<B>,
+    op: fn(&mut SystemState<B>) -> u8,
+    cycle: u8,
+) -> bool {
+    match cycle {
+        1 => {
+            dummy_read(sys, sys.cpu_state.pc);
+            false
+        }
+        _ => {
+            let byte = op(sys);
+            push_to_stack(sys, byte);
+            true
+        }
+    }
+}
+
+fn pull_cycle<B: Bus>(
+    sys: &mut SystemState<B>,
+    op: fn(&mut SystemState<B>, u8),
+    cycle: u8,
+) -> bool {
+    match cycle {
+        1 => {
+            dummy_read(sys, sys.cpu_state.pc);
+            false
+        }
+        2 => {
+            dummy_read(sys, stack_addr(sys));
+            false
+        }
+        _ => {
+            let byte = pull_from_stack(sys);
+            op(sys, byte);
+            true
+        }
+    }
+}
+
+fn execute_cycle<B: Bus>(sys: &mut SystemState<B>) -> bool {
+    let cycle = sys.cpu_state.cycle;
+    let (_, operation, mode) = decode(sys.cpu_state.opcode, &sys.options);
+
+    match operation {
+        Operation::Read(op) => read_cycle(sys, op, mode, cycle),
+        Operation::DecimalRead(op) => decimal_read_cycle(sys, op, mode, cycle),
+        Operation::Write(op) => write_cycle(sys, op, mode, cycle),
+        Operation::ReadModifyWrite(op) => read_modify_write_cycle(sys, op, mode, cycle),
+        Operation::StoreAndHighByte(op) => store_and_high_byte_cycle(sys, op, mode, cycle),
+        Operation::Implied(op) => {
+            dummy_read(sys, sys.cpu_state.pc);
+            op(sys);
+            true
+        }
+        Operation::Branch(predicate) => branch_cycle(sys, predicate, cycle),
+        Operation::BranchOnBit(predicate) => branch_on_bit_cycle(sys, predicate, cycle),
+        Operation::Push(op) => push_cycle(sys, op, cycle),
+        Operation::Pull(op) => pull_cycle(sys, op, cycle),
+        Operation::Brk => brk(sys, cycle),
+        Operation::Jam => jam(sys, cycle),
+        Operation::Jmp => jmp(sys, mode, cycle),
+        Operation::Jsr => jsr(sys, cycle),
+        Operation::Rti => rti(sys, cycle),
+        Operation::Rts => rts(sys, cycle),
+        Operation::Nop5c => nop_5c(sys, cycle),
+        Operation::Stp => stp(sys, cycle),
+        Operation::Wai => wai(sys, cycle),
+        Operation::OneCycleNop => panic!(
+            "one cycle NOP ${:02x} has no execute cycles",
+            sys.cpu_state.opcode
+        ),
+    }
+}
+
+fn fetch_opcode<B: Bus>(sys: &mut SystemState<B>) -> bool {
+    if let Some(mut tracer) = sys.tracer.take() {
+        tracer(&trace::nestest_line(sys));
+        sys.tracer = Some(tracer);
+    }
+
+    sys.cpu_state.instruction_addr = sys.cpu_state.pc;
+    check_access(
+        sys,
+        sys.cpu_state.pc & sys.options.address_mask,
+        Access::Execute,
+    );
+    sys.cpu_state.sync = true;
+    sys.cpu_state.opcode = fetch_byte(sys);
+    sys.cpu_state.sync = false;
+    sys.cpu_state.page_cross = false;
+
+    // one cycle NOPs are complete as soon as they are fetched
+    matches!(
+        decode::<B>(sys.cpu_state.opcode, &sys.options).1,
+        Operation::OneCycleNop
+    )
+}
+
+fn irq_asserted<B: Bus>(sys: &SystemState<B>) -> bool {
+    let lines_asserted = sys
+        .irq_lines
+        .as_ref()
+        .is_some_and(|lines| lines.borrow().is_asserted());
+
+    sys.irq_line || (lines_asserted && sys.options.interrupt_pins)
+}
+
+fn start_instruction<B: Bus>(sys: &mut SystemState<B>) -> bool {
+    // interrupts are polled between instructions, with NMI taking priority. A reset has
+    // already chosen its sequence
+    if sys.cpu_state.interrupt.is_none() {
+        let irq_interrupt_disable = sys
+            .cpu_state
+            .delayed_irq_interrupt_disable
+            .take()
+            .unwrap_or(sys.cpu_state.irq_interrupt_disable);
+
+        if sys.nmi_pending {
+            sys.nmi_pending = false;
+            sys.cpu_state.interrupt = Some(Interrupt::Nmi);
+        } else if irq_asserted(sys) && !irq_interrupt_disable {
+            sys.cpu_state.interrupt = Some(Interrupt::Irq);
+        }
+    }
+
+    if sys.cpu_state.interrupt.is_some() {
+        // the fetched opcode is discarded and a BRK executed in its place
+        sys.cpu_state.sync = true;
+        dummy_read(sys, sys.cpu_state.pc);
+        sys.cpu_state.sync = false;
+        sys.cpu_state.opcode = 0x00;
+        false
+    } else {
+        fetch_opcode(sys)
+    }
+}
+
+fn write_cycle_next<B: Bus>(sys: &SystemState<B>) -> bool {
+    let cycle = sys.cpu_state.cycle;
+    if cycle == 0 {
+        return false;
+    }
+
+    let (_, operation, mode) = decode::<B>(sys.cpu_state.opcode, &sys.options);
+    let fixed_cycles = || address_cycles(mode) + may_cross_page(mode) as u8;
+
+    match operation {
+        Operation::Write(_) => cycle > fixed_cycles(),
+        Operation::StoreAndHighByte(_) => cycle > address_cycles(mode) + 1,
+        Operation::ReadModifyWrite(_) => {
+            !matches!(mode, AddressingMode::Acc) && cycle > fixed_cycles() + 1
+        }
+        Operation::Push(_) => cycle == 2,
+        Operation::Jsr => cycle == 3 || cycle == 4,
+        Operation::Brk => {
+            (2..=4).contains(&cycle) && sys.cpu_state.interrupt != Some(Interrupt::Reset)
+        }
+        _ => false,
+    }
+}
+
+fn dma_active<B: Bus>(sys: &SystemState<B>) -> bool {
+    sys.dma.as_ref().is_some_and(|dma| dma.borrow().active())
+}
+
+fn stalled<B: Bus>(sys: &SystemState<B>) -> bool {
+    // with RDY low the NMOS parts stall on the next read, but a write goes ahead, so a
+    // stall can take up to three cycles to land. The 65C02 stalls on writes too
+    (!sys.rdy_line || dma_active(sys))
+        && sys.cpu_state.run_state == RunState::Running
+        && (sys.options.variant == CpuVariant::Cmos65c02 || !write_cycle_next(sys))
+}
+
+fn run_scheduled_events<B: Bus>(sys: &mut SystemState<B>) {
+    let scheduler = match sys.scheduler.as_ref() {
+        Some(scheduler) => scheduler,
+        None => return,
+    };
+
+    // the scheduler isn't borrowed while an event runs, so events can schedule more
+    let cycle_count = sys.cpu_state.cycle_count;
+    loop {
+        let event = scheduler.borrow_mut().pop_due(cycle_count);
+        match event {
+            Some(event) => event(cycle_count),
+            None => break,
+        }
+    }
+}
+
+fn sample_overflow_pin<B: Bus>(sys: &mut SystemState<B>) {
+    if sys.so_pending {
+        sys.so_pending = false;
+        sys.cpu_state.signed_overflow = true;
+    }
+}
+
+pub fn tick<B: Bus>(sys: &mut SystemState<B>) -> bool {
+    // runs a single bus cycle, returning true when it completes an instruction or interrupt
+    // sequence
+    run_scheduled_events(sys);
+
+    let cycle = sys.cpu_state.cycle;
+
+    if cycle == 0 {
+        match sys.cpu_state.run_state {
+            // a halted or stopped CPU doesn't fetch any more instructions until it is reset
+            RunState::Halted | RunState::Stopped => return false,
+            // WAI ends when an interrupt is signalled, even an IRQ masked by I, which then
+            // just resumes execution. Until then the CPU idles a cycle at a time
+            RunState::Waiting if !sys.nmi_pending && !irq_asserted(sys) => {
+                sample_overflow_pin(sys);
+                sys.cpu_state.cycle_count += 1;
+                return false;
+            }
+            RunState::Waiting => sys.cpu_state.run_state = RunState::Running,
+            RunState::Running => {}
+        }
+    }
+
+    // a stalled CPU holds the read on the bus, and picks up where it left off once RDY
+    // goes high again
+    if stalled(sys) {
+        if let Some(dma) = sys.dma.clone() {
+            let access = dma
+                .borrow_mut()
+                .cycle(&mut sys.memory, sys.cpu_state.cycle_count);
+            if let Some((addr, value, write)) = access {
+                observe_bus(sys, addr, value, write);
+            }
+        }
+
+        sample_overflow_pin(sys);
+        sys.cpu_state.cycle_count += 1;
+        return false;
+    }
+
+    let finished = if cycle == 0 {
+        start_instruction(sys)
+    } else {
+        execute_cycle(sys)
+    };
+
+    // an edge on SO wins over anything the instruction did to V this cycle
+    sample_overflow_pin(sys);
+
+    sys.cpu_state.cycle = if finished { 0 } else { cycle + 1 };
+    sys.cpu_state.cycle_count += 1;
+
+    finished
+}
+
+fn run_instruction<B: Bus>(sys: &mut SystemState<B>) -> (u16, bool) {
+    // returns the cycles taken, and whether the instruction finished rather than stalling.
+    // Nothing can raise RDY until control returns to the caller, so a stall ends the run,
+    // though DMA releases the CPU by itself
+    let start = sys.cpu_state.cycle_count;
+
+    let finished = loop {
+        let stalled = !sys.rdy_line && stalled(sys);
+        if tick(sys) {
+            break true;
+        }
+        if stalled {
+            break false;
+        }
+    };
+
+    ((sys.cpu_state.cycle_count - start) as u16, finished)
+}
+
+pub fn emulate_op<B: Bus>(sys: &mut SystemState<B>) -> u16 {
+    // runs the next instruction without polling for interrupts first
+    let finished = fetch_opcode(sys);
+    sys.cpu_state.cycle_count += 1;
+    if finished {
+        return 1;
+    }
+    sys.cpu_state.cycle = 1;
+
+    1 + run_instruction(sys).0
+}
+
+pub fn step<B: Bus>(sys: &mut SystemState<B>) -> StepResult {
+    let pc = sys.cpu_state.pc;
+    let result = step_instruction(sys);
+
+    // an instruction that leaves the PC where it was can only be jumping to itself, as an
+    // interrupt taken first would have moved it to the handler
+    let window = match sys.trap_window {
+        Some(window) => window,
+        None => return result,
+    };
+    if !matches!(result, StepResult::Executed(_)) || sys.cpu_state.pc != pc {
+        sys.trap_repeats = 0;
+        return result;
+    }
+    sys.trap_repeats += 1;
+    if sys.trap_repeats >= window {
+        return StepResult::Trapped(pc);
+    }
+    result
+}
+
+fn step_instruction<B: Bus>(sys: &mut SystemState<B>) -> StepResult {
+    match sys.cpu_state.run_state {
+        RunState::Halted => return StepResult::Halted,
+        RunState::Stopped => return StepResult::Stopped,
+        RunState::Waiting => {
+            // a waiting CPU only runs an instruction if this cycle wakes it
+            let start = sys.cpu_state.cycle_count;
+            tick(sys);
+            if sys.cpu_state.run_state == RunState::Waiting {
+                return StepResult::Waiting;
+            }
+
+            let woken = (sys.cpu_state.cycle_count - start) as u16;
+            let (cycles, finished) = run_instruction(sys);
+            let cycles = woken + cycles;
+            if !finished {
+                return StepResult::Stalled(cycles);
+            }
+            return StepResult::Executed(cycles);
+        }
+        RunState::Running => {}
+    }
+
+    // an instruction already partway through from tick() is run to completion
+    let (cycles, finished) = run_instruction(sys);
+    if !finished {
+        return StepResult::Stalled(cycles);
+    }
+
+    match sys.cpu_state.run_state {
+        RunState::Running => StepResult::Executed(cycles),
+        RunState::Halted => StepResult::Halted,
+        RunState::Waiting => StepResult::Waiting,
+        RunState::Stopped => StepResult::Stopped,
+    }
+}
+
+pub fn run<B: Bus>(sys: &mut SystemState<B>) -> RunEvent {
+    // runs instructions until the PC reaches a breakpoint, a condition is met, a watchpoint
+    // is hit, or the CPU can't go on. The first instruction always runs, so a stop at a
+    // breakpoint can be continued from
+    loop {
+        if let Some(event) = run_step(sys) {
+            return event;
+        }
+    }
+}
+
+pub fn run_until<B: Bus>(
+    sys: &mut SystemState<B>,
+    mut predicate: impl FnMut(&SystemState<B>) -> bool,
+    max_cycles: u64,
+) -> RunUntil {
+    // steps until the predicate holds between instructions, checking before the first. The
+    // instruction that uses up the budget is run to its end, so it may go a little over
+    let start = sys.cpu_state.cycle_count;
+    loop {
+        if predicate(sys) {
+            return RunUntil::Satisfied;
+        }
+        if sys.cpu_state.cycle_count - start >= max_cycles {
+            return RunUntil::OutOfCycles;
+        }
+
+        // a waiting or stalled CPU still uses up cycles
+        match step(sys) {
+            StepResult::Halted => return RunUntil::Halted,
+            StepResult::Stopped => return RunUntil::Stopped,
+            StepResult::Trapped(addr) => return RunUntil::Trapped(addr),
+            _ => {}
+        }
+    }
+}
+
+pub(crate) fn run_step<B: Bus>(sys: &mut SystemState<B>) -> Option<RunEvent> {
+    // runs one instruction of run(), returning why it should stop if it should
+    sys.watchpoint_hit = None;
+    match step(sys) {
+        StepResult::Executed(_) => {}
+        StepResult::Halted => return Some(RunEvent::Halted),
+        StepResult::Waiting => return Some(RunEvent::Waiting),
+        StepResult::Stopped => return Some(RunEvent::Stopped),
+        StepResult::Stalled(_) => return Some(RunEvent::Stalled),
+        StepResult::Trapped(addr) => return Some(RunEvent::Trapped(addr)),
+    }
+
+    if let Some(hit) = sys.watchpoint_hit.take() {
+        return Some(RunEvent::WatchpointHit(hit));
+    }
+    let pc = sys.cpu_state.pc;
+    if sys.breakpoints.contains(&pc) {
+        return Some(RunEvent::BreakpointHit(pc));
+    }
+    if let Some(index) = sys.conditions.iter().position(|c| c.is_met(sys)) {
+        return Some(RunEvent::ConditionMet(index));
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::{Cell, RefCell};
+    use std::rc::Rc;
+
+    #[test]
+    fn test_bcd_add() {
+        assert_eq!((0x98, true), bcd_add(0x99, 0x99, false));
+    }
+
+    #[test]
+    fn test_bcd_invalid_digits() {
+        // results match an NMOS 6502, not any sensible decimal interpretation
+        assert_eq!((0x14, false), bcd_add(0x0f, 0x0f, false));
+        assert_eq!((0x10, false), bcd_add(0x0a, 0x00, false));
+        assert_eq!((0x54, true), bcd_add(0xff, 0xff, false));
+        assert_eq!((0x00, true), bcd_add(0x9a, 0x00, false));
+        assert_eq!(0x09, bcd_sub(0x0a, 0x00, true));
+        assert_eq!(0xf0, bcd_sub(0x00, 0xaa, false));
+    }
+
+    #[test]
+    fn test_adc_decimal_flags() {
+        let mut sys = SystemState::default();
+        sys.cpu_state.decimal_mode = true;
+
+        // 99 + 01 gives 00 but Z comes from the binary result $9a
+        sys.cpu_state.a = 0x99;
+        sys.cpu_state.carry = false;
+        add_with_carry(&mut sys, 0x01);
+        assert_eq!(0x00, sys.cpu_state.a);
+        assert!(sys.cpu_state.carry && !sys.cpu_state.zero);
+
+        // 79 + 00 + 1 gives 80, with N and V from the unadjusted high nybble
+        sys.cpu_state.a = 0x79;
+        sys.cpu_state.carry = true;
+        add_with_carry(&mut sys, 0x00);
+        assert_eq!(0x80, sys.cpu_state.a);
+        assert!(sys.cpu_state.negative && sys.cpu_state.signed_overflow);
+    }
+
+    #[test]
+    fn test_adc_reference() {
+        // (decimal, a, operand, carry in, result, carry out)
+        let cases = [
+            (false, 0x00, 0x00, false, 0x00, false),
+            (false, 0x7f, 0x00, true, 0x80, false),
+            (false, 0xff, 0x00, true, 0x00, true),
+            (false, 0xff, 0xff, true, 0xff, true),
+            (false, 0x80, 0x80, false, 0x00, true),
+            (true, 0x12, 0x34, false, 0x46, false),
+            (true, 0x15, 0x26, false, 0x41, false),
+            (true, 0x58, 0x46, true, 0x05, true),
+            (true, 0x81, 0x92, false, 0x73, true),
+            (true, 0x99, 0x00, true, 0x00, true),
+            (true, 0x09, 0x00, true, 0x10, false),
+            (true, 0x99, 0x99, true, 0x99, true),
+        ];
+        let mut sys = SystemState::default();
+
+        for (decimal, a, operand, carry, result, carry_out) in cases {
+            sys.cpu_state.decimal_mode = decimal;
+            sys.cpu_state.a = a;
+            sys.cpu_state.carry = carry;
+            add_with_carry(&mut sys, operand);
+            assert_eq!(
+                (result, carry_out),
+                (sys.cpu_state.a, sys.cpu_state.carry),
+                "{:02x} + {:02x} + {} (decimal: {})",
+                a,
+                operand,
+                carry,
+                decimal
+            );
+        }
+    }
+
+    #[test]
+    fn test_bcd_sub() {
+        assert_eq!(0x01, bcd_sub(0x10, 0x09, false));
+        assert_eq!(0x99, bcd_sub(0x00, 0x01, false));
+        assert_eq!(0x98, bcd_sub(0x00, 0x01, true));
+    }
+
+    #[test]
+    fn test_sbc_decimal() {
+        // examples from Bruce Clark's decimal mode tutorial:
+        // (a, operand, carry in, result, carry out, N, V, Z)
+        let cases = [
+            (0x46, 0x12, true, 0x34, true, false, false, false),
+            (0x40, 0x13, true, 0x27, true, false, false, false),
+            (0x32, 0x02, false, 0x29, true, false, false, false),
+            (0x12, 0x21, true, 0x91, false, true, false, false),
+            (0x21, 0x34, true, 0x87, false, true, false, false),
+            (0x00, 0x00, true, 0x00, true, false, false, true),
+            (0x00, 0x01, true, 0x99, false, true, false, false),
+            (0x80, 0x01, true, 0x79, true, false, true, false),
+            (0x01, 0x01, false, 0x99, false, true, false, false),
+        ];
+        let mut sys = SystemState::default();
+        sys.cpu_state.decimal_mode = true;
+
+        for (a, operand, carry, result, carry_out, negative, overflow, zero) in cases {
+            sys.cpu_state.a = a;
+            sys.cpu_state.carry = carry;
+            subtract_with_carry(&mut sys, operand);
+            assert_eq!(
+                (result, carry_out, negative, overflow, zero),
+                (
+                    sys.cpu_state.a,
+                    sys.cpu_state.carry,
+                    sys.cpu_state.negative,
+                    sys.cpu_state.signed_overflow,
+                    sys.cpu_state.zero
+                ),
+                "{:02x} - {:02x} - {}",
+                a,
+                operand,
+                !carry
+            );
+        }
+    }
+
+    #[test]
+    fn test_sbc() {
+        let mut sys = SystemState::default();
+        sys.memory[0x0000] = 0xe9;
+        sys.memory[0x0001] = 0x01;
+        sys.cpu_state.a = 0x80;
+        sys.cpu_state.carry = true;
+
+        assert_eq!(2, emulate_op(&mut sys));
+        assert_eq!(0x7f, sys.cpu_state.a);
+        assert!(sys.cpu_state.carry);
+        assert!(sys.cpu_state.signed_overflow);
+        assert!(!sys.cpu_state.negative);
+    }
+
+    #[test]
+    fn test_status_byte() {
+        let mut cpu_state = CpuState::default();
+        assert_eq!(0x20, cpu_state.status_byte());
+
+        cpu_state.set_status_byte(0xff);
+        assert_eq!(0xef, cpu_state.status_byte());
+
+        cpu_state.set_status_byte(0x30);
+        assert_eq!(0x20, cpu_state.status_byte());
+    }
+
+    #[test]
+    fn test_stack_wraps_within_page_one() {
+        let mut sys = SystemState::default();
+
+        sys.cpu_state.s = 0x00;
+        push_to_stack(&mut sys, 0x12);
+        push_to_stack(&mut sys, 0x34);
+        assert_eq!(0x12, sys.memory[0x0100]);
+        assert_eq!(0x34, sys.memory[0x01ff]);
+        assert_eq!(0xfe, sys.cpu_state.s);
+
+        assert_eq!(0x34, pull_from_stack(&mut sys));
+        assert_eq!(0x12, pull_from_stack(&mut sys));
+        assert_eq!(0x00, sys.cpu_state.s);
+
+        sys.cpu_state.s = 0xff;
+        sys.memory[0x0100] = 0x56;
+        assert_eq!(0x56, pull_from_stack(&mut sys));
+        assert_eq!(0x00, sys.cpu_state.s);
+        assert_eq!(0x00, sys.memory[0x0200]);
+    }
+
+    #[test]
+    fn test_php_plp() {
+        let mut sys = SystemState::default();
+        sys.memory[0x0000] = 0x08;
+        sys.memory[0x0001] = 0x28;
+        sys.cpu_state.carry = true;
+        sys.cpu_state.negative = true;
+
+        assert_eq!(3, emulate_op(&mut sys));
+        assert_eq!(0xfe, sys.cpu_state.s);
+        assert_eq!(0xb1, sys.memory[0x01ff]);
+
+        sys.cpu_state.carry = false;
+        sys.cpu_state.negative = false;
+
+        assert_eq!(4, emulate_op(&mut sys));
+        assert_eq!(0xff, sys.cpu_state.s);
+        assert!(sys.cpu_state.carry);
+        assert!(sys.cpu_state.negative);
+    }
+
+    #[test]
+    fn test_jsr_rts() {
+        let mut sys = SystemState::default();
+        sys.cpu_state.pc = 0x12fe;
+        sys.memory[0x12fe] = 0x20;
+        sys.memory[0x12ff] = 0x00;
+        sys.memory[0x1300] = 0x40;
+        sys.memory[0x4000] = 0x60;
+
+        assert_eq!(6, emulate_op(&mut sys));
+        assert_eq!(0x4000, sys.cpu_state.pc);
+        assert_eq!(0x13, sys.memory[0x01ff]);
+        assert_eq!(0x00, sys.memory[0x01fe]);
+
+        assert_eq!(6, emulate_op(&mut sys));
+        assert_eq!(0x1301, sys.cpu_state.pc);
+        assert_eq!(0xff, sys.cpu_state.s);
+    }
+
+    #[test]
+    fn test_jmp_indirect_page_boundary() {
+        let mut sys = SystemState::default();
+        sys.memory[0x0000] = 0x6c;
+        sys.memory[0x0001] = 0xff;
+        sys.memory[0x0002] = 0x30;
+        sys.memory[0x30ff] = 0x80;
+        sys.memory[0x3000] = 0x40;
+        sys.memory[0x3100] = 0x50;
+
+        assert_eq!(5, emulate_op(&mut sys));
+        assert_eq!(0x4080, sys.cpu_state.pc);
+
+        sys.options.jmp_indirect_bug = false;
+        sys.cpu_state.pc = 0x0000;
+
+        emulate_op(&mut sys);
+        assert_eq!(0x5080, sys.cpu_state.pc);
+    }
+
+    #[test]
+    fn test_brk_rti() {
+        let mut sys = SystemState::default();
+        sys.cpu_state.pc = 0x0200;
+        sys.cpu_state.carry = true;
+        sys.memory[0x0200] = 0x00;
+        sys.memory[0xfffe] = 0x00;
+        sys.memory[0xffff] = 0x80;
+        sys.memory[0x8000] = 0x40;
+
+        assert_eq!(7, emulate_op(&mut sys));
+        assert_eq!(0x8000, sys.cpu_state.pc);
+        assert_eq!(0x02, sys.memory[0x01ff]);
+        assert_eq!(0x02, sys.memory[0x01fe]);
+        assert_eq!(0x31, sys.memory[0x01fd]);
+        assert!(sys.cpu_state.irq_interrupt_disable);
+
+        assert_eq!(6, emulate_op(&mut sys));
+        assert_eq!(0x0202, sys.cpu_state.pc);
+        assert!(sys.cpu_state.carry);
+        assert!(!sys.cpu_state.irq_interrupt_disable);
+        assert_eq!(0xff, sys.cpu_state.s);
+    }
+
+    #[test]
+    fn test_compare() {
+        let mut sys = SystemState::default();
+
+        compare(&mut sys, 0x40, 0x40);
+        assert!(sys.cpu_state.carry && sys.cpu_state.zero && !sys.cpu_state.negative);
+
+        compare(&mut sys, 0x40, 0x41);
+        assert!(!sys.cpu_state.carry && !sys.cpu_state.zero && sys.cpu_state.negative);
+
+        compare(&mut sys, 0x90, 0x01);
+        assert!(sys.cpu_state.carry && !sys.cpu_state.zero && sys.cpu_state.negative);
+    }
+
+    #[test]
+    fn test_inc_dec() {
+        let mut sys = SystemState::default();
+        sys.memory[0x0000] = 0xe6;
+        sys.memory[0x0001] = 0x10;
+        sys.memory[0x0002] = 0xce;
+        sys.memory[0x0003] = 0x00;
+        sys.memory[0x0004] = 0x20;
+        sys.memory[0x0010] = 0xff;
+
+        assert_eq!(5, emulate_op(&mut sys));
+        assert_eq!(0x00, sys.memory[0x0010]);
+        assert!(sys.cpu_state.zero);
+
+        assert_eq!(6, emulate_op(&mut sys));
+        assert_eq!(0xff, sys.memory[0x2000]);
+        assert!(sys.cpu_state.negative);
+    }
+
+    #[test]
+    fn test_register_inc_dec_wraps() {
+        let mut sys = SystemState::default();
+        sys.memory[0x0000] = 0xe8;
+        sys.memory[0x0001] = 0x88;
+        sys.cpu_state.x = 0xff;
+
+        assert_eq!(2, emulate_op(&mut sys));
+        assert_eq!(0x00, sys.cpu_state.x);
+        assert!(sys.cpu_state.zero);
+
+        assert_eq!(2, emulate_op(&mut sys));
+        assert_eq!(0xff, sys.cpu_state.y);
+        assert!(sys.cpu_state.negative);
+        assert_eq!(0x0002, sys.cpu_state.pc);
+    }
+
+    fn run_shift_in_each_mode(opcodes: [u8; 5], operand: u8, carry: bool) -> Vec<(u8, bool)> {
+        // runs an accumulator, zero page, zero page X, absolute and absolute X shift with the
+        // same operand, returning the result and carry of each
+        let mut results = Vec::new();
+
+        for (i, opcode) in opcodes.into_iter().enumerate() {
+            let mut sys = SystemState::default();
+            sys.memory[0x0000] = opcode;
+            sys.memory[0x0001] = 0x10;
+            sys.memory[0x0002] = 0x20;
+            sys.cpu_state.x = 0x01;
+            sys.cpu_state.a = operand;
+            sys.cpu_state.carry = carry;
+
+            let addr = match i {
+                0 => None,
+                1 => Some(0x0010),
+                2 => Some(0x0011),
+                3 => Some(0x2010),
+                _ => Some(0x2011),
+            };
+            if let Some(addr) = addr {
+                sys.memory[addr] = operand;
+            }
+
+            let cycles = emulate_op(&mut sys);
+            assert_eq!([2, 5, 6, 6, 7][i], cycles);
+
+            let result = match addr {
+                None => sys.cpu_state.a,
+                Some(addr) => sys.memory[addr],
+            };
+            assert_eq!(result == 0, sys.cpu_state.zero);
+            assert_eq!(negative_u8(result), sys.cpu_state.negative);
+
+            results.push((result, sys.cpu_state.carry));
+        }
+
+        results
+    }
+
+    #[test]
+    fn test_asl_all_modes() {
+        let opcodes = [0x0a, 0x06, 0x16, 0x0e, 0x1e];
+
+        for (result, carry) in run_shift_in_each_mode(opcodes, 0xc1, false) {
+            assert_eq!((0x82, true), (result, carry));
+        }
+        for (result, carry) in run_shift_in_each_mode(opcodes, 0x80, true) {
+            assert_eq!((0x00, true), (result, carry));
+        }
+        for (result, carry) in run_shift_in_each_mode(opcodes, 0x21, true) {
+            assert_eq!((0x42, false), (result, carry));
+        }
+    }
+
+    #[test]
+    fn test_lsr_all_modes() {
+        let opcodes = [0x4a, 0x46, 0x56, 0x4e, 0x5e];
+
+        for (result, carry) in run_shift_in_each_mode(opcodes, 0x81, true) {
+            assert_eq!((0x40, true), (result, carry));
+        }
+        for (result, carry) in run_shift_in_each_mode(opcodes, 0x01, false) {
+            assert_eq!((0x00, true), (result, carry));
+        }
+    }
+
+    #[test]
+    fn test_rol_all_modes() {
+        let opcodes = [0x2a, 0x26, 0x36, 0x2e, 0x3e];
+
+        for (result, carry) in run_shift_in_each_mode(opcodes, 0x40, true) {
+            assert_eq!((0x81, false), (result, carry));
+        }
+        for (result, carry) in run_shift_in_each_mode(opcodes, 0x80, false) {
+            assert_eq!((0x00, true), (result, carry));
+        }
+    }
+
+    #[test]
+    fn test_ror_all_modes() {
+        let opcodes = [0x6a, 0x66, 0x76, 0x6e, 0x7e];
+
+        for (result, carry) in run_shift_in_each_mode(opcodes, 0x02, true) {
+            assert_eq!((0x81, false), (result, carry));
+        }
+        for (result, carry) in run_shift_in_each_mode(opcodes, 0x01, false) {
+            assert_eq!((0x00, true), (result, carry));
+        }
+    }
+
+    #[test]
+    fn test_lsr() {
+        let mut sys = SystemState::default();
+        sys.memory[0x0000] = 0x4a;
+        sys.memory[0x0001] = 0x46;
+        sys.memory[0x0002] = 0x10;
+        sys.memory[0x0010] = 0x80;
+        sys.cpu_state.a = 0x01;
+        sys.cpu_state.negative = true;
+
+        assert_eq!(2, emulate_op(&mut sys));
+        assert_eq!(0x00, sys.cpu_state.a);
+        assert!(sys.cpu_state.carry && sys.cpu_state.zero && !sys.cpu_state.negative);
+
+        assert_eq!(5, emulate_op(&mut sys));
+        assert_eq!(0x40, sys.memory[0x0010]);
+        assert!(!sys.cpu_state.carry && !sys.cpu_state.zero);
+    }
+
+    #[test]
+    fn test_rol_ror() {
+        let mut sys = SystemState::default();
+        sys.memory[0x0000] = 0x2a;
+        sys.memory[0x0001] = 0x6a;
+        sys.cpu_state.a = 0x81;
+        sys.cpu_state.carry = false;
+
+        assert_eq!(2, emulate_op(&mut sys));
+        assert_eq!(0x02, sys.cpu_state.a);
+        assert!(sys.cpu_state.carry && !sys.cpu_state.negative);
+
+        assert_eq!(2, emulate_op(&mut sys));
+        assert_eq!(0x81, sys.cpu_state.a);
+        assert!(!sys.cpu_state.carry && sys.cpu_state.negative);
+    }
+
+    #[test]
+    fn test_ora() {
+        let mut sys = SystemState::default();
+        sys.memory[0x0000] = 0x09;
+        sys.memory[0x0001] = 0x81;
+        sys.cpu_state.a = 0x10;
+
+        assert_eq!(2, emulate_op(&mut sys));
+        assert_eq!(0x91, sys.cpu_state.a);
+        assert!(sys.cpu_state.negative && !sys.cpu_state.zero);
+    }
+
+    #[test]
+    fn test_eor() {
+        let mut sys = SystemState::default();
+        sys.memory[0x0000] = 0x49;
+        sys.memory[0x0001] = 0xff;
+        sys.cpu_state.a = 0xff;
+
+        assert_eq!(2, emulate_op(&mut sys));
+        assert_eq!(0x00, sys.cpu_state.a);
+        assert!(sys.cpu_state.zero && !sys.cpu_state.negative);
+    }
+
+    #[test]
+    fn test_transfers() {
+        let mut sys = SystemState::default();
+        sys.memory[0x0000] = 0xaa;
+        sys.memory[0x0001] = 0x9a;
+        sys.cpu_state.a = 0x00;
+        sys.cpu_state.x = 0x80;
+
+        assert_eq!(2, emulate_op(&mut sys));
+        assert_eq!(0x00, sys.cpu_state.x);
+        assert!(sys.cpu_state.zero);
+
+        sys.cpu_state.zero = false;
+        sys.cpu_state.x = 0x80;
+
+        assert_eq!(2, emulate_op(&mut sys));
+        assert_eq!(0x80, sys.cpu_state.s);
+        assert!(!sys.cpu_state.zero && !sys.cpu_state.negative);
+    }
+
+    #[test]
+    fn test_flag_instructions() {
+        let mut sys = SystemState::default();
+        sys.memory[0x0000] = 0x38;
+        sys.memory[0x0001] = 0xf8;
+        sys.memory[0x0002] = 0x78;
+        sys.memory[0x0003] = 0x18;
+        sys.memory[0x0004] = 0xd8;
+        sys.memory[0x0005] = 0x58;
+        sys.memory[0x0006] = 0xb8;
+        sys.cpu_state.signed_overflow = true;
+
+        for _ in 0..3 {
+            assert_eq!(2, emulate_op(&mut sys));
+        }
+        assert!(sys.cpu_state.carry);
+        assert!(sys.cpu_state.decimal_mode);
+        assert!(sys.cpu_state.irq_interrupt_disable);
+
+        for _ in 0..4 {
+            assert_eq!(2, emulate_op(&mut sys));
+        }
+        assert!(!sys.cpu_state.carry);
+        assert!(!sys.cpu_state.decimal_mode);
+        assert!(!sys.cpu_state.irq_interrupt_disable);
+        assert!(!sys.cpu_state.signed_overflow);
+    }
+
+    #[test]
+    fn test_undocumented_nops() {
+        let mut sys = SystemState::default();
+        sys.memory[0x0000] = 0xea;
+        sys.memory[0x0001] = 0x04;
+        sys.memory[0x0003] = 0x1c;
+        sys.memory[0x0004] = 0xff;
+        sys.memory[0x0005] = 0x20;
+        sys.cpu_state.x = 0x01;
+
+        assert_eq!(2, emulate_op(&mut sys));
+        assert_eq!(3, emulate_op(&mut sys));
+        assert_eq!(5, emulate_op(&mut sys));
+        assert_eq!(0x0006, sys.cpu_state.pc);
+    }
+
+    #[test]
+    fn test_dcp() {
+        let mut sys = SystemState::default();
+        sys.memory[0x0000] = 0xc7;
+        sys.memory[0x0001] = 0x10;
+        sys.memory[0x0010] = 0x41;
+        sys.cpu_state.a = 0x40;
+
+        assert_eq!(5, emulate_op(&mut sys));
+        assert_eq!(0x40, sys.memory[0x0010]);
+        assert!(sys.cpu_state.zero && sys.cpu_state.carry);
+    }
+
+    #[test]
+    fn test_isc() {
+        let mut sys = SystemState::default();
+        sys.memory[0x0000] = 0xe3;
+        sys.memory[0x0001] = 0x10;
+        sys.memory[0x0012] = 0x00;
+        sys.memory[0x0013] = 0x20;
+        sys.memory[0x2000] = 0x0f;
+        sys.cpu_state.a = 0x20;
+        sys.cpu_state.x = 0x02;
+        sys.cpu_state.carry = true;
+
+        assert_eq!(8, emulate_op(&mut sys));
+        assert_eq!(0x10, sys.memory[0x2000]);
+        assert_eq!(0x10, sys.cpu_state.a);
+        assert!(sys.cpu_state.carry);
+    }
+
+    #[test]
+    fn test_lax_sax() {
+        let mut sys = SystemState::default();
+        sys.memory[0x0000] = 0xa7;
+        sys.memory[0x0001] = 0x10;
+        sys.memory[0x0002] = 0x87;
+        sys.memory[0x0003] = 0x11;
+        sys.memory[0x0010] = 0xf0;
+
+        assert_eq!(3, emulate_op(&mut sys));
+        assert_eq!((0xf0, 0xf0), (sys.cpu_state.a, sys.cpu_state.x));
+        assert!(sys.cpu_state.negative);
+
+        sys.cpu_state.x = 0x3c;
+
+        assert_eq!(3, emulate_op(&mut sys));
+        assert_eq!(0x30, sys.memory[0x0011]);
+    }
+
+    #[test]
+    fn test_slo_sre() {
+        let mut sys = SystemState::default();
+        sys.memory[0x0000] = 0x07;
+        sys.memory[0x0001] = 0x10;
+        sys.memory[0x0002] = 0x47;
+        sys.memory[0x0003] = 0x11;
+        sys.memory[0x0010] = 0x81;
+        sys.memory[0x0011] = 0x03;
+        sys.cpu_state.a = 0x01;
+
+        assert_eq!(5, emulate_op(&mut sys));
+        assert_eq!(0x02, sys.memory[0x0010]);
+        assert_eq!(0x03, sys.cpu_state.a);
+        assert!(sys.cpu_state.carry);
+
+        assert_eq!(5, emulate_op(&mut sys));
+        assert_eq!(0x01, sys.memory[0x0011]);
+        assert_eq!(0x02, sys.cpu_state.a);
+        assert!(sys.cpu_state.carry);
+    }
+
+    #[test]
+    fn test_rla_rra() {
+        let mut sys = SystemState::default();
+        sys.memory[0x0000] = 0x27;
+        sys.memory[0x0001] = 0x10;
+        sys.memory[0x0002] = 0x67;
+        sys.memory[0x0003] = 0x11;
+        sys.memory[0x0010] = 0x80;
+        sys.memory[0x0011] = 0x02;
+        sys.cpu_state.a = 0xff;
+        sys.cpu_state.carry = true;
+
+        assert_eq!(5, emulate_op(&mut sys));
+        assert_eq!(0x01, sys.memory[0x0010]);
+        assert_eq!(0x01, sys.cpu_state.a);
+        assert!(sys.cpu_state.carry);
+
+        assert_eq!(5, emulate_op(&mut sys));
+        assert_eq!(0x81, sys.memory[0x0011]);
+        assert_eq!(0x82, sys.cpu_state.a);
+        assert!(!sys.cpu_state.carry);
+    }
+
+    #[test]
+    fn test_ane_lxa_magic_constant() {
+        let mut sys = SystemState::default();
+        sys.memory[0x0000] = 0x8b;
+        sys.memory[0x0001] = 0xff;
+        sys.memory[0x0002] = 0xab;
+        sys.memory[0x0003] = 0x0f;
+        sys.cpu_state.a = 0x00;
+        sys.cpu_state.x = 0x3c;
+
+        assert_eq!(2, emulate_op(&mut sys));
+        assert_eq!(0x2c, sys.cpu_state.a);
+
+        sys.options.ane_magic_constant = 0xff;
+        sys.options.lxa_magic_constant = 0xff;
+
+        assert_eq!(2, emulate_op(&mut sys));
+        assert_eq!((0x0f, 0x0f), (sys.cpu_state.a, sys.cpu_state.x));
+    }
+
+    #[test]
+    fn test_shx_page_cross() {
+        let mut sys = SystemState::default();
+        sys.memory[0x0000] = 0x9e;
+        sys.memory[0x0001] = 0xff;
+        sys.memory[0x0002] = 0x10;
+        sys.cpu_state.x = 0xff;
+        sys.cpu_state.y = 0x01;
+
+        assert_eq!(5, emulate_op(&mut sys));
+        // the value is ANDed with $11, which also becomes the high byte of the address
+        assert_eq!(0x11, sys.memory[0x1100]);
+    }
+
+    #[test]
+    fn test_anc_alr() {
+        let mut sys = SystemState::default();
+        sys.memory[0x0000] = 0x0b;
+        sys.memory[0x0001] = 0xf0;
+        sys.memory[0x0002] = 0x4b;
+        sys.memory[0x0003] = 0x03;
+        sys.cpu_state.a = 0x81;
+
+        assert_eq!(2, emulate_op(&mut sys));
+        assert_eq!(0x80, sys.cpu_state.a);
+        assert!(sys.cpu_state.carry && sys.cpu_state.negative);
+
+        sys.cpu_state.a = 0xff;
+
+        assert_eq!(2, emulate_op(&mut sys));
+        assert_eq!(0x01, sys.cpu_state.a);
+        assert!(sys.cpu_state.carry && !sys.cpu_state.negative);
+    }
+
+    #[test]
+    fn test_arr() {
+        let mut sys = SystemState::default();
+        sys.memory[0x0000] = 0x6b;
+        sys.memory[0x0001] = 0xff;
+        sys.memory[0x0002] = 0x6b;
+        sys.memory[0x0003] = 0xff;
+        sys.cpu_state.a = 0xc0;
+        sys.cpu_state.carry = true;
+
+        assert_eq!(2, emulate_op(&mut sys));
+        assert_eq!(0xe0, sys.cpu_state.a);
+        assert!(sys.cpu_state.carry && !sys.cpu_state.signed_overflow);
+
+        sys.cpu_state.decimal_mode = true;
+        sys.cpu_state.a = 0x99;
+        sys.cpu_state.carry = false;
+
+        assert_eq!(2, emulate_op(&mut sys));
+        assert_eq!(0xa2, sys.cpu_state.a);
+        assert!(sys.cpu_state.carry && !sys.cpu_state.negative);
+    }
+
+    #[test]
+    fn test_sbx() {
+        let mut sys = SystemState::default();
+        sys.memory[0x0000] = 0xcb;
+        sys.memory[0x0001] = 0x05;
+        sys.cpu_state.a = 0x0f;
+        sys.cpu_state.x = 0xfc;
+
+        assert_eq!(2, emulate_op(&mut sys));
+        assert_eq!(0x07, sys.cpu_state.x);
+        assert!(sys.cpu_state.carry);
+    }
+
+    #[test]
+    fn test_jam_halts() {
+        let mut sys = SystemState::default();
+        sys.memory[0x0000] = 0xea;
+        sys.memory[0x0001] = 0x02;
+
+        assert_eq!(StepResult::Executed(2), step(&mut sys));
+        assert_eq!(StepResult::Halted, step(&mut sys));
+        assert_eq!(StepResult::Halted, step(&mut sys));
+        assert_eq!(0x0001, sys.cpu_state.pc);
+    }
+
+    #[test]
+    fn test_adc_sbc_overflow_exhaustive() {
+        let mut sys = SystemState::default();
+
+        for a in 0..=0xff {
+            for operand in 0..=0xff {
+                for carry in [false, true] {
+                    let signed_sum = a as i8 as i16 + operand as i8 as i16 + carry as i16;
+                    sys.cpu_state.a = a;
+                    sys.cpu_state.carry = carry;
+                    add_with_carry(&mut sys, operand);
+                    assert_eq!(
+                        !(-128..=127).contains(&signed_sum),
+                        sys.cpu_state.signed_overflow,
+                        "ADC {:02x} + {:02x} + {}",
+                        a,
+                        operand,
+                        carry
+                    );
+
+                    let signed_diff = a as i8 as i16 - operand as i8 as i16 - !carry as i16;
+                    sys.cpu_state.a = a;
+                    sys.cpu_state.carry = carry;
+                    subtract_with_carry(&mut sys, operand);
+                    assert_eq!(
+                        !(-128..=127).contains(&signed_diff),
+                        sys.cpu_state.signed_overflow,
+                        "SBC {:02x} - {:02x} - {}",
+                        a,
+                        operand,
+                        !carry
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_branch() {
+        let mut sys = SystemState::default();
+        sys.cpu_state.pc = 0x1210;
+        sys.memory[0x1210] = 0xd0;
+        sys.memory[0x1211] = 0x10;
+
+        // not taken
+        sys.cpu_state.zero = true;
+        assert_eq!(2, emulate_op(&mut sys));
+        assert_eq!(0x1212, sys.cpu_state.pc);
+
+        // taken forwards, relative to the next instruction
+        sys.cpu_state.pc = 0x1210;
+        sys.cpu_state.zero = false;
+        assert_eq!(3, emulate_op(&mut sys));
+        assert_eq!(0x1222, sys.cpu_state.pc);
+    }
+
+    #[test]
+    fn test_branch_backwards_across_page() {
+        let mut sys = SystemState::default();
+        sys.cpu_state.pc = 0x1300;
+        sys.memory[0x1300] = 0x90;
+        sys.memory[0x1301] = 0xf0;
+
+        assert_eq!(4, emulate_op(&mut sys));
+        assert_eq!(0x12f2, sys.cpu_state.pc);
+
+        // branch to self, without crossing a page
+        sys.memory[0x12f2] = 0x90;
+        sys.memory[0x12f3] = 0xfe;
+        assert_eq!(3, emulate_op(&mut sys));
+        assert_eq!(0x12f2, sys.cpu_state.pc);
+    }
+
+    #[test]
+    fn test_bvc_bvs() {
+        let mut sys = SystemState::default();
+        sys.memory[0x1210] = 0x50; // BVC +$10
+        sys.memory[0x1211] = 0x10;
+        sys.memory[0x12f0] = 0x70; // BVS +$10, crossing into $13xx
+        sys.memory[0x12f1] = 0x10;
+
+        for (overflow, bvc_cycles, bvc_pc, bvs_cycles, bvs_pc) in
+            [(false, 3, 0x1222, 2, 0x12f2), (true, 2, 0x1212, 4, 0x1302)]
+        {
+            sys.cpu_state.signed_overflow = overflow;
+            sys.cpu_state.pc = 0x1210;
+            assert_eq!(bvc_cycles, emulate_op(&mut sys));
+            assert_eq!(bvc_pc, sys.cpu_state.pc);
+
+            sys.cpu_state.pc = 0x12f0;
+            assert_eq!(bvs_cycles, emulate_op(&mut sys));
+            assert_eq!(bvs_pc, sys.cpu_state.pc);
+        }
+
+        // BVC across a page too
+        sys.memory[0x12f0] = 0x50;
+        sys.cpu_state.signed_overflow = false;
+        sys.cpu_state.pc = 0x12f0;
+        assert_eq!(4, emulate_op(&mut sys));
+        assert_eq!(0x1302, sys.cpu_state.pc);
+    }
+
+    #[test]
+    fn test_irq() {
+        let mut sys = SystemState::default();
+        sys.cpu_state.pc = 0x0234;
+        sys.cpu_state.irq_interrupt_disable = true;
+        sys.memory[0x0234] = 0xea;
+        sys.memory[0xfffe] = 0x00;
+        sys.memory[0xffff] = 0x90;
+
+        // masked while I is set
+        sys.set_irq_line(true);
+        assert_eq!(StepResult::Executed(2), step(&mut sys));
+
+        sys.cpu_state.irq_interrupt_disable = false;
+
+        assert_eq!(StepResult::Executed(7), step(&mut sys));
+        assert_eq!(0x9000, sys.cpu_state.pc);
+        assert_eq!(0x02, sys.memory[0x01ff]);
+        assert_eq!(0x35, sys.memory[0x01fe]);
+        assert_eq!(0x20, sys.memory[0x01fd]);
+        assert!(sys.cpu_state.irq_interrupt_disable);
+    }
+
+    #[test]
+    fn test_nmi_edge_triggered() {
+        let mut sys = SystemState::default();
+        sys.cpu_state.irq_interrupt_disable = true;
+        sys.memory[0xfffa] = 0x00;
+        sys.memory[0xfffb] = 0xa0;
+        sys.memory[0xa000] = 0xea;
+
+        sys.set_nmi_line(true);
+        assert_eq!(StepResult::Executed(7), step(&mut sys));
+        assert_eq!(0xa000, sys.cpu_state.pc);
+
+        // holding the line doesn't retrigger
+        assert_eq!(StepResult::Executed(2), step(&mut sys));
+        assert_eq!(0xa001, sys.cpu_state.pc);
+
+        sys.set_nmi_line(false);
+        sys.set_nmi_line(true);
+        assert_eq!(StepResult::Executed(7), step(&mut sys));
+        assert_eq!(0xf9, sys.cpu_state.s);
+    }
+
+    #[test]
+    fn test_reset() {
+        let mut sys = SystemState::default();
+        sys.memory[0x0000] = 0x02;
+        sys.memory[0xfffc] = 0x00;
+        sys.memory[0xfffd] = 0xc0;
+
+        assert_eq!(StepResult::Halted, step(&mut sys));
+
+        assert_eq!(7, sys.reset());
+        assert_eq!(0xc000, sys.cpu_state.pc);
+        assert_eq!(0xfc, sys.cpu_state.s);
+        assert!(sys.cpu_state.irq_interrupt_disable);
+        assert_eq!(RunState::Running, sys.cpu_state.run_state);
+        assert!(sys.memory[0x0100..0x0200].iter().all(|&byte| byte == 0));
+    }
+
+    #[test]
+    fn test_brk_hijacked_by_nmi() {
+        let mut sys = SystemState::default();
+        sys.memory[0xfffa] = 0x00;
+        sys.memory[0xfffb] = 0xa0;
+        sys.memory[0xfffe] = 0x00;
+        sys.memory[0xffff] = 0x90;
+
+        // the NMI arrives partway through the BRK sequence, before the status is pushed
+        for _ in 0..4 {
+            assert!(!tick(&mut sys));
+        }
+        sys.set_nmi_line(true);
+        assert!(!tick(&mut sys));
+        assert!(!tick(&mut sys));
+        assert!(tick(&mut sys));
+
+        assert_eq!(0xa000, sys.cpu_state.pc);
+        assert_eq!(0x30, sys.memory[0x01fd]);
+        assert!(!sys.nmi_pending);
+    }
+
+    #[test]
+    fn test_tick() {
+        let mut sys = SystemState::default();
+        sys.memory[0x0000] = 0xad; // LDA $1234
+        sys.memory[0x0001] = 0x34;
+        sys.memory[0x0002] = 0x12;
+        sys.memory[0x1234] = 0x42;
+
+        // the operand is only read on the final cycle, so a peripheral changing it between
+        // cycles is seen
+        assert!(!tick(&mut sys));
+        assert!(!tick(&mut sys));
+        assert!(!tick(&mut sys));
+        sys.memory[0x1234] = 0x43;
+        assert!(tick(&mut sys));
+
+        assert_eq!(0x43, sys.cpu_state.a);
+        assert_eq!(0x0003, sys.cpu_state.pc);
+        assert_eq!(4, sys.cpu_state().cycle_count());
+    }
 
     #[test]
-    fn test_bcd_add() {
-        assert_eq!((0x98, true), bcd_add(0x99, 0x99));
+    fn test_step_finishes_ticked_instruction() {
+        let mut sys = SystemState::default();
+        sys.memory[0x0000] = 0xe6; // INC $10
+        sys.memory[0x0001] = 0x10;
+        sys.memory[0x0010] = 0x7f;
+
+        tick(&mut sys);
+        tick(&mut sys);
+        assert_eq!(StepResult::Executed(3), step(&mut sys));
+
+        assert_eq!(0x80, sys.memory[0x0010]);
+        assert_eq!(5, sys.cpu_state().cycle_count());
+    }
+
+    #[test]
+    fn test_cli_sei_delay_irq() {
+        let mut sys = SystemState::default();
+        sys.cpu_state.irq_interrupt_disable = true;
+        sys.memory[0x0000] = 0x58;
+        sys.memory[0x0001] = 0xea;
+        sys.memory[0xfffe] = 0x00;
+        sys.memory[0xffff] = 0x90;
+        sys.memory[0x9000] = 0x58;
+        sys.memory[0x9001] = 0x78;
+        sys.set_irq_line(true);
+
+        // the instruction after CLI still runs before the IRQ is taken
+        assert_eq!(StepResult::Executed(2), step(&mut sys));
+        assert_eq!(StepResult::Executed(2), step(&mut sys));
+        assert_eq!(StepResult::Executed(7), step(&mut sys));
+        assert_eq!(0x02, sys.memory[0x01fe]);
+
+        // and an IRQ is still taken straight after SEI, with I set in the pushed status
+        assert_eq!(StepResult::Executed(2), step(&mut sys));
+        assert_eq!(StepResult::Executed(2), step(&mut sys));
+        assert_eq!(StepResult::Executed(7), step(&mut sys));
+        assert_eq!(0x02, sys.memory[0x01fb]);
+        assert_eq!(0x24, sys.memory[0x01fa]);
+    }
+
+    #[test]
+    fn test_set_pc() {
+        let mut sys = SystemState::default();
+        sys.cpu_state_mut().set_pc(0x1234);
+        sys.memory[0x1234] = 0xea;
+
+        assert_eq!(StepResult::Executed(2), step(&mut sys));
+        assert_eq!(0x1235, sys.cpu_state().pc());
+    }
+
+    #[test]
+    fn test_pc_wraps() {
+        let mut sys = SystemState::default();
+        sys.cpu_state.pc = 0xfffe;
+        sys.memory[0xfffe] = 0xa9;
+        sys.memory[0xffff] = 0x42;
+
+        assert_eq!(2, emulate_op(&mut sys));
+        assert_eq!(0x0000, sys.cpu_state.pc);
+        assert_eq!(0x42, sys.cpu_state.a);
+
+        // an instruction straddling the top of memory fetches its operand from the bottom
+        sys.cpu_state.pc = 0xffff;
+        sys.memory[0xffff] = 0xa9;
+        sys.memory[0x0000] = 0x17;
+
+        assert_eq!(2, emulate_op(&mut sys));
+        assert_eq!(0x0001, sys.cpu_state.pc);
+        assert_eq!(0x17, sys.cpu_state.a);
+    }
+
+    #[test]
+    fn test_indexed_addressing_wraps() {
+        let mut sys = SystemState::default();
+        sys.memory[0x0000] = 0xbd;
+        sys.memory[0x0001] = 0xff;
+        sys.memory[0x0002] = 0xff;
+        sys.memory[0x0003] = 0xb1;
+        sys.memory[0x0004] = 0x10;
+        sys.memory[0x0010] = 0xf0;
+        sys.memory[0x0011] = 0xff;
+        sys.memory[0x0020] = 0x42;
+        sys.cpu_state.x = 0x21;
+        sys.cpu_state.y = 0x30;
+
+        assert_eq!(5, emulate_op(&mut sys));
+        assert_eq!(0x42, sys.cpu_state.a);
+
+        sys.cpu_state.a = 0x00;
+
+        assert_eq!(6, emulate_op(&mut sys));
+        assert_eq!(0x42, sys.cpu_state.a);
+    }
+
+    #[test]
+    fn test_rmw_dummy_write() {
+        let mut sys = SystemState::default();
+        sys.memory[0x0000] = 0x0e; // ASL $4000
+        sys.memory[0x0001] = 0x00;
+        sys.memory[0x0002] = 0x40;
+        sys.memory[0x4000] = 0x21;
+
+        for _ in 0..4 {
+            tick(&mut sys);
+        }
+
+        // a register changing after the read is overwritten with the unmodified value first
+        sys.memory[0x4000] = 0xff;
+        tick(&mut sys);
+        assert_eq!(0x21, sys.memory[0x4000]);
+        assert!(tick(&mut sys));
+        assert_eq!(0x42, sys.memory[0x4000]);
+    }
+
+    #[test]
+    fn test_page_cross_bus_cycles() {
+        // each instruction's accesses as (address, write)
+        let accesses = |program: &[u8], x: u8| {
+            let mut sys = SystemState::default();
+            sys.memory[0x0200..0x0200 + program.len()].copy_from_slice(program);
+            sys.cpu_state.pc = 0x0200;
+            sys.cpu_state.x = x;
+
+            let accesses = Rc::new(RefCell::new(Vec::new()));
+            let observer_accesses = accesses.clone();
+            sys.set_bus_observer(move |access| {
+                observer_accesses
+                    .borrow_mut()
+                    .push((access.addr, access.write))
+            });
+            step(&mut sys);
+            let accesses = accesses.borrow().clone();
+            accesses
+        };
+
+        // the NMOS 6502 reads the un-carried address while it fixes up the high byte
+        assert_eq!(
+            vec![
+                (0x0200, false),
+                (0x0201, false),
+                (0x0202, false),
+                (0x4000, false),
+                (0x4100, false),
+            ],
+            accesses(&[0xbd, 0xff, 0x40], 0x01) // LDA $40FF,X
+        );
+
+        // stores always take the extra cycle, reading the right address when there's no
+        // page cross
+        assert_eq!(
+            vec![
+                (0x0200, false),
+                (0x0201, false),
+                (0x0202, false),
+                (0x4001, false),
+                (0x4001, true),
+            ],
+            accesses(&[0x9d, 0x00, 0x40], 0x01) // STA $4000,X
+        );
+    }
+
+    #[test]
+    fn test_65c02_bus_cycles() {
+        // each instruction's accesses as (address, write)
+        let accesses = |program: &[u8], x: u8| {
+            let mut sys = SystemState {
+                options: CpuOptions::for_variant(CpuVariant::Cmos65c02),
+                ..Default::default()
+            };
+            sys.memory[0x0200..0x0200 + program.len()].copy_from_slice(program);
+            sys.cpu_state.pc = 0x0200;
+            sys.cpu_state.x = x;
+
+            let accesses = Rc::new(RefCell::new(Vec::new()));
+            let observer_accesses = accesses.clone();
+            sys.set_bus_observer(move |access| {
+                observer_accesses
+                    .borrow_mut()
+                    .push((access.addr, access.write))
+            });
+            step(&mut sys);
+            let accesses = accesses.borrow().clone();
+            accesses
+        };
+
+        // INC reads its operand twice rather than writing it back unmodified
+        assert_eq!(
+            vec![
+                (0x0200, false),
+                (0x0201, false),
+                (0x0202, false),
+                (0x4000, false),
+                (0x4000, false),
+                (0x4000, true),
+            ],
+            accesses(&[0xee, 0x00, 0x40], 0) // INC $4000
+        );
+
+        // shifts only fix up the high byte on a page cross, but INC and DEC always do
+        assert_eq!(6, accesses(&[0x1e, 0x00, 0x40], 0x10).len()); // ASL $4000,X
+        assert_eq!(7, accesses(&[0x1e, 0xff, 0x40], 0x10).len());
+        assert_eq!(7, accesses(&[0x3e, 0xff, 0x40], 0x10).len()); // ROL $40FF,X
+        assert_eq!(7, accesses(&[0xfe, 0x00, 0x40], 0x10).len()); // INC $4000,X
+
+        // on a page cross the last operand byte is read again rather than the un-carried
+        // address
+        assert_eq!(
+            vec![
+                (0x0200, false),
+                (0x0201, false),
+                (0x0202, false),
+                (0x0202, false),
+                (0x4100, false),
+            ],
+            accesses(&[0xbd, 0xff, 0x40], 0x01) // LDA $40FF,X
+        );
+    }
+
+    #[test]
+    fn test_65c02_instructions() {
+        let mut sys = SystemState {
+            options: CpuOptions::for_variant(CpuVariant::Cmos65c02),
+            ..Default::default()
+        };
+        sys.cpu_state.a = 0x0f;
+        sys.cpu_state.x = 0x02;
+        sys.memory[0x0000] = 0x80; // BRA +$02
+        sys.memory[0x0001] = 0x02;
+        sys.memory[0x0004] = 0x04; // TSB $20
+        sys.memory[0x0005] = 0x20;
+        sys.memory[0x0006] = 0x64; // STZ $21
+        sys.memory[0x0007] = 0x21;
+        sys.memory[0x0008] = 0xb2; // LDA ($22)
+        sys.memory[0x0009] = 0x22;
+        sys.memory[0x000a] = 0xda; // PHX
+        sys.memory[0x000b] = 0x7a; // PLY
+        sys.memory[0x000c] = 0x1a; // INC A
+        sys.memory[0x000d] = 0x7c; // JMP ($3000,X)
+        sys.memory[0x000e] = 0x00;
+        sys.memory[0x000f] = 0x30;
+        sys.memory[0x0020] = 0xf0;
+        sys.memory[0x0021] = 0x55;
+        sys.memory[0x0022] = 0x00;
+        sys.memory[0x0023] = 0x40;
+        sys.memory[0x3002] = 0x34;
+        sys.memory[0x3003] = 0x12;
+        sys.memory[0x4000] = 0x7f;
+
+        assert_eq!(3, emulate_op(&mut sys));
+        assert_eq!(0x0004, sys.cpu_state.pc);
+        assert_eq!(5, emulate_op(&mut sys));
+        assert_eq!(0xff, sys.memory[0x0020]);
+        assert!(sys.cpu_state.zero);
+        assert_eq!(3, emulate_op(&mut sys));
+        assert_eq!(0x00, sys.memory[0x0021]);
+        assert_eq!(5, emulate_op(&mut sys));
+        assert_eq!(0x7f, sys.cpu_state.a);
+        assert_eq!(3, emulate_op(&mut sys));
+        assert_eq!(4, emulate_op(&mut sys));
+        assert_eq!(0x02, sys.cpu_state.y);
+        assert_eq!(2, emulate_op(&mut sys));
+        assert_eq!(0x80, sys.cpu_state.a);
+        assert!(sys.cpu_state.negative);
+        assert_eq!(6, emulate_op(&mut sys));
+        assert_eq!(0x1234, sys.cpu_state.pc);
+    }
+
+    #[test]
+    fn test_65c02_behaviour() {
+        let mut sys = SystemState {
+            options: CpuOptions::for_variant(CpuVariant::Cmos65c02),
+            ..Default::default()
+        };
+        sys.cpu_state.a = 0x99;
+        sys.cpu_state.decimal_mode = true;
+        sys.memory[0x0000] = 0x69; // ADC #$01
+        sys.memory[0x0001] = 0x01;
+        sys.memory[0x0002] = 0x03; // one cycle NOP
+        sys.memory[0x0003] = 0x02; // NOP #$00
+        sys.memory[0x0005] = 0x5c; // NOP $0000
+        sys.memory[0x0008] = 0x6c; // JMP ($10ff)
+        sys.memory[0x0009] = 0xff;
+        sys.memory[0x000a] = 0x10;
+        sys.memory[0x10ff] = 0x00;
+        sys.memory[0x1000] = 0x30;
+        sys.memory[0x1100] = 0x40;
+        sys.memory[0x4000] = 0x00; // BRK
+        sys.memory[0xfffe] = 0x00;
+        sys.memory[0xffff] = 0x90;
+
+        // unlike the NMOS 6502, Z reflects the decimal result
+        assert_eq!(3, emulate_op(&mut sys));
+        assert_eq!(0x00, sys.cpu_state.a);
+        assert!(sys.cpu_state.zero);
+
+        assert_eq!(1, emulate_op(&mut sys));
+        assert_eq!(2, emulate_op(&mut sys));
+        assert_eq!(8, emulate_op(&mut sys));
+        assert_eq!(0x0008, sys.cpu_state.pc);
+
+        assert_eq!(6, emulate_op(&mut sys));
+        assert_eq!(0x4000, sys.cpu_state.pc);
+
+        assert_eq!(7, emulate_op(&mut sys));
+        assert_eq!(0x9000, sys.cpu_state.pc);
+        assert!(!sys.cpu_state.decimal_mode);
+    }
+
+    #[test]
+    fn test_65c02_bit_instructions() {
+        let mut sys = SystemState {
+            options: CpuOptions::for_variant(CpuVariant::Cmos65c02),
+            ..Default::default()
+        };
+        sys.memory[0x0000] = 0xb7; // SMB3 $10
+        sys.memory[0x0001] = 0x10;
+        sys.memory[0x0002] = 0x07; // RMB0 $10
+        sys.memory[0x0003] = 0x10;
+        sys.memory[0x0004] = 0xbf; // BBS3 $10,+$10
+        sys.memory[0x0005] = 0x10;
+        sys.memory[0x0006] = 0x10;
+        sys.memory[0x0017] = 0x3f; // BBR3 $10,+$10
+        sys.memory[0x0018] = 0x10;
+        sys.memory[0x0019] = 0x10;
+        sys.memory[0x0010] = 0x01;
+
+        assert_eq!(5, emulate_op(&mut sys));
+        assert_eq!(0x09, sys.memory[0x0010]);
+        assert_eq!(5, emulate_op(&mut sys));
+        assert_eq!(0x08, sys.memory[0x0010]);
+
+        assert_eq!(6, emulate_op(&mut sys));
+        assert_eq!(0x0017, sys.cpu_state.pc);
+        assert_eq!(5, emulate_op(&mut sys));
+        assert_eq!(0x001a, sys.cpu_state.pc);
+    }
+
+    #[test]
+    fn test_wai_stp() {
+        let mut sys = SystemState {
+            options: CpuOptions::for_variant(CpuVariant::Cmos65c02),
+            ..Default::default()
+        };
+        sys.cpu_state.irq_interrupt_disable = true;
+        sys.memory[0x0000] = 0xcb; // WAI
+        sys.memory[0x0001] = 0xe8; // INX
+        sys.memory[0x0002] = 0xdb; // STP
+        sys.memory[0xfffc] = 0x00;
+        sys.memory[0xfffd] = 0xc0;
+
+        assert_eq!(StepResult::Waiting, step(&mut sys));
+        assert_eq!(StepResult::Waiting, step(&mut sys));
+        assert_eq!(0x0001, sys.cpu_state.pc);
+
+        // with I set, an IRQ just wakes the CPU to carry on from the next instruction
+        sys.set_irq_line(true);
+        assert_eq!(StepResult::Executed(2), step(&mut sys));
+        assert_eq!(0x01, sys.cpu_state.x);
+
+        assert_eq!(StepResult::Stopped, step(&mut sys));
+        assert_eq!(StepResult::Stopped, step(&mut sys));
+        assert!(!tick(&mut sys));
+
+        sys.reset();
+        assert_eq!(0xc000, sys.cpu_state.pc);
+        assert_eq!(RunState::Running, sys.cpu_state.run_state);
+    }
+
+    #[test]
+    fn test_2a03_ignores_decimal_mode() {
+        let mut sys = SystemState {
+            options: CpuOptions::for_variant(CpuVariant::Ricoh2a03),
+            ..Default::default()
+        };
+        sys.cpu_state.a = 0x09;
+        sys.memory[0x0000] = 0xf8; // SED
+        sys.memory[0x0001] = 0x69; // ADC #$01
+        sys.memory[0x0002] = 0x01;
+
+        assert_eq!(2, emulate_op(&mut sys));
+        assert_eq!(2, emulate_op(&mut sys));
+        assert!(sys.cpu_state.decimal_mode);
+        assert_eq!(0x0a, sys.cpu_state.a);
+    }
+
+    #[test]
+    fn test_6510_io_port() {
+        let mut sys = SystemState {
+            options: CpuOptions::for_variant(CpuVariant::Mos6510),
+            ..Default::default()
+        };
+
+        let pins = Rc::new(Cell::new(0x00));
+        let output_pins = pins.clone();
+        sys.set_io_port_output(move |byte| output_pins.set(byte));
+        sys.set_io_port_input(|| 0xc0);
+
+        set_byte_at_addr(&mut sys, 0x0000, 0x2f);
+        set_byte_at_addr(&mut sys, 0x0001, 0x35);
+        assert_eq!(0xf5, pins.get());
+        assert_eq!(0xf5, sys.io_port_pins());
+
+        // output bits read back from the latch, and input bits from the external lines
+        assert_eq!(0x2f, get_byte_at_addr(&mut sys, 0x0000));
+        assert_eq!(0xe5, get_byte_at_addr(&mut sys, 0x0001));
+        assert_eq!(0x00, sys.memory[0x0001]);
+
+        sys.reset();
+        assert_eq!(0xff, pins.get());
+    }
+
+    #[test]
+    fn test_6507() {
+        let mut sys = SystemState {
+            options: CpuOptions::for_variant(CpuVariant::Mos6507),
+            ..Default::default()
+        };
+        sys.memory[0x1ffc] = 0x00;
+        sys.memory[0x1ffd] = 0xf0;
+        sys.memory[0x1000] = 0xad; // LDA $3080
+        sys.memory[0x1001] = 0x80;
+        sys.memory[0x1002] = 0x30;
+        sys.memory[0x1080] = 0x42;
+
+        // addresses are mirrored every 8K, including the vectors
+        sys.reset();
+        assert_eq!(0xf000, sys.cpu_state.pc);
+        assert_eq!(StepResult::Executed(4), step(&mut sys));
+        assert_eq!(0x42, sys.cpu_state.a);
+
+        sys.set_irq_line(true);
+        sys.set_nmi_line(true);
+        assert!(!sys.irq_line);
+        assert!(!sys.nmi_pending);
+    }
+
+    #[test]
+    fn test_options() {
+        let mut sys = SystemState::default();
+        assert!(sys.options().jmp_indirect_bug);
+        assert!(!CpuOptions::for_variant(CpuVariant::Cmos65c02).jmp_indirect_bug);
+        assert!(!CpuOptions::for_variant(CpuVariant::Ricoh2a03).decimal_mode);
+
+        sys.options_mut().illegal_opcodes = IllegalOpcodes::Halt;
+        sys.memory[0x0000] = 0xea;
+        sys.memory[0x0001] = 0xa7; // LAX $00
+        assert_eq!(StepResult::Executed(2), step(&mut sys));
+        assert_eq!(StepResult::Halted, step(&mut sys));
+        assert_eq!(0x0001, sys.cpu_state.pc);
+
+        assert_eq!(0x0b, bcd_sub(0x10, 0x0f, false));
+        assert_eq!(0xfb, bcd_sub_cmos(0x10, 0x0f, false));
+        assert_eq!(0x19, bcd_sub_cmos(0x42, 0x23, false));
+    }
+
+    #[test]
+    fn test_overflow_pin() {
+        let mut sys = SystemState::default();
+        sys.memory[0x0000] = 0xb8; // CLV
+        sys.memory[0x0001] = 0x50; // BVC -$03
+        sys.memory[0x0002] = 0xfd;
+
+        // a byte-ready loop spins on BVC until SO sets V
+        assert_eq!(StepResult::Executed(2), step(&mut sys));
+        assert_eq!(StepResult::Executed(3), step(&mut sys));
+        assert_eq!(0x0000, sys.cpu_state.pc);
+
+        assert_eq!(StepResult::Executed(2), step(&mut sys));
+        sys.set_overflow_pin(true);
+        assert_eq!(StepResult::Executed(2), step(&mut sys));
+        assert!(sys.cpu_state.signed_overflow);
+        assert_eq!(0x0003, sys.cpu_state.pc);
+
+        // only the edge counts, so holding the pin doesn't set V again
+        sys.cpu_state.signed_overflow = false;
+        sys.set_overflow_pin(true);
+        tick(&mut sys);
+        assert!(!sys.cpu_state.signed_overflow);
+    }
+
+    #[test]
+    fn test_bus() {
+        // a bus with 256 bytes of RAM mirrored through the whole address space, which
+        // records every write
+        struct MirroredRam {
+            bytes: [u8; 0x100],
+            writes: Vec<(u16, u8)>,
+        }
+
+        impl Bus for MirroredRam {
+            fn read(&mut self, addr: u16) -> u8 {
+                self.bytes[addr as usize & 0xff]
+            }
+
+            fn write(&mut self, addr: u16, val: u8) {
+                self.bytes[addr as usize & 0xff] = val;
+                self.writes.push((addr, val));
+            }
+
+            fn peek(&self, addr: u16) -> u8 {
+                self.bytes[addr as usize & 0xff]
+            }
+
+            fn poke(&mut self, addr: u16, val: u8) {
+                self.bytes[addr as usize & 0xff] = val;
+            }
+        }
+
+        let mut sys = SystemState::new(MirroredRam {
+            bytes: [0; 0x100],
+            writes: Vec::new(),
+        });
+        sys.memory.bytes[0x00] = 0xa9; // LDA #$42
+        sys.memory.bytes[0x01] = 0x42;
+        sys.memory.bytes[0x02] = 0x8d; // STA $1234
+        sys.memory.bytes[0x03] = 0x34;
+        sys.memory.bytes[0x04] = 0x12;
+
+        step(&mut sys);
+        step(&mut sys);
+        assert_eq!(vec![(0x1234, 0x42)], sys.memory().writes);
+        assert_eq!(0x42, sys.memory.bytes[0x34]);
+    }
+
+    #[test]
+    fn test_scheduler() {
+        let mut sys = SystemState::default();
+        let scheduler = Rc::new(RefCell::new(Scheduler::new()));
+        sys.attach_scheduler(scheduler.clone());
+
+        // a timer that reschedules itself every 10 cycles
+        fn timer(scheduler: Rc<RefCell<Scheduler>>, fired: Rc<RefCell<Vec<u64>>>, cycle: u64) {
+            let next_scheduler = scheduler.clone();
+            let next_fired = fired.clone();
+            scheduler.borrow_mut().schedule(cycle + 10, move |cycle| {
+                next_fired.borrow_mut().push(cycle);
+                timer(next_scheduler, next_fired, cycle);
+            });
+        }
+        let fired = Rc::new(RefCell::new(Vec::new()));
+        timer(scheduler.clone(), fired.clone(), 0);
+
+        let cancelled = scheduler.borrow_mut().schedule(5, |_| panic!("cancelled"));
+        assert!(scheduler.borrow_mut().cancel(cancelled));
+
+        for _ in 0..35 {
+            tick(&mut sys);
+        }
+        assert_eq!(vec![10, 20, 30], *fired.borrow());
+        assert_eq!(Some(40), scheduler.borrow().next_event());
+    }
+
+    #[test]
+    fn test_irq_lines() {
+        let mut sys = SystemState::default();
+        sys.memory[0xfffe] = 0x00; // IRQ vector $0400
+        sys.memory[0xffff] = 0x04;
+        sys.memory[0x0400] = 0xea; // NOP
+
+        let lines = Rc::new(RefCell::new(InterruptLines::new()));
+        sys.attach_irq_lines(lines.clone());
+
+        // the line stays asserted until every device lets go of it
+        lines.borrow_mut().assert(0);
+        lines.borrow_mut().assert(3);
+        lines.borrow_mut().deassert(0);
+        assert!(lines.borrow().asserted_by(3));
+
+        step(&mut sys);
+        assert_eq!(0x0400, sys.cpu_state.pc);
+
+        lines.borrow_mut().deassert(3);
+        sys.cpu_state.irq_interrupt_disable = false;
+        step(&mut sys);
+        assert_eq!(0x0401, sys.cpu_state.pc);
+    }
+
+    #[test]
+    fn test_uninitialized_reads() {
+        let mut sys = SystemState::default();
+        let reads = Rc::new(RefCell::new(Vec::new()));
+        let handler_reads = reads.clone();
+        sys.set_uninitialized_read_handler(move |read| handler_reads.borrow_mut().push(read));
+
+        // LDA $10, STA $11, LDA $11, PHA, PLA
+        sys.load_bytes(0x0000, &[0xa5, 0x10, 0x85, 0x11, 0xa5, 0x11, 0x48, 0x68]);
+        for _ in 0..5 {
+            step(&mut sys);
+        }
+
+        assert_eq!(
+            vec![UninitializedRead {
+                addr: 0x0010,
+                instruction_addr: 0x0000,
+            }],
+            *reads.borrow()
+        );
+
+        // on the 6507, ROM marked where the cartridge appears is read through its mirror
+        let mut sys = SystemState {
+            options: CpuOptions::for_variant(CpuVariant::Mos6507),
+            ..Default::default()
+        };
+        let reads = Rc::new(RefCell::new(Vec::new()));
+        let handler_reads = reads.clone();
+        sys.set_uninitialized_read_handler(move |read| handler_reads.borrow_mut().push(read));
+        sys.memory[0x1000] = 0xea; // NOP
+        sys.mark_initialized(0xf000, 0x1000);
+        sys.cpu_state.pc = 0xf000;
+        step(&mut sys);
+        assert!(reads.borrow().is_empty());
+    }
+
+    #[test]
+    fn test_dma() {
+        use crate::bus::{DmaTransfer, MappedBus};
+
+        let dma = Rc::new(RefCell::new(Dma::new()));
+        let oam = Rc::new(RefCell::new(Vec::new()));
+
+        let mut bus = MappedBus::new();
+        let register_dma = dma.clone();
+        bus.map_write(0x4014..=0x4014, move |_, page| {
+            register_dma.borrow_mut().start(DmaTransfer::nes_oam(page))
+        });
+        let oam_data = oam.clone();
+        bus.map_write(0x2004..=0x2004, move |_, val| oam_data.borrow_mut().push(val));
+
+        let mut sys = SystemState::new(bus);
+        sys.attach_dma(dma);
+        sys.load_bytes(0x0000, &[0xa9, 0x02, 0x8d, 0x14, 0x40, 0xea]); // LDA #$02, STA $4014, NOP
+        let page: Vec<u8> = (0..=0xff).collect();
+        sys.load_bytes(0x0200, &page);
+
+        // the copy starts after the STA, so it stalls the NOP's fetch by a halt cycle, an
+        // alignment cycle and 512 cycles of copying
+        assert_eq!(StepResult::Executed(2), step(&mut sys));
+        assert_eq!(StepResult::Executed(4), step(&mut sys));
+        assert_eq!(StepResult::Executed(516), step(&mut sys));
+        assert_eq!(page, *oam.borrow());
+        assert_eq!(522, sys.cpu_state.cycle_count);
+    }
+
+    #[test]
+    fn test_dma_empty() {
+        use crate::bus::DmaTransfer;
+
+        let dma = Rc::new(RefCell::new(Dma::new()));
+        dma.borrow_mut().start(DmaTransfer {
+            len: 0,
+            ..DmaTransfer::nes_oam(0x02)
+        });
+        assert!(!dma.borrow().active());
+
+        let mut sys = SystemState::default();
+        sys.attach_dma(dma);
+        sys.memory[0x0000] = 0xea; // NOP
+        assert_eq!(StepResult::Executed(2), step(&mut sys));
+    }
+
+    #[test]
+    fn test_snapshot_diff() {
+        let mut sys = SystemState::default();
+        sys.memory[0x0000] = 0xa9; // LDA #$80
+        sys.memory[0x0001] = 0x80;
+        sys.memory[0x0002] = 0x85; // STA $10
+        sys.memory[0x0003] = 0x10;
+
+        let before = sys.snapshot();
+        assert!(before.diff(&sys.snapshot()).is_empty());
+
+        step(&mut sys);
+        step(&mut sys);
+        let diff = before.diff(&sys.snapshot());
+
+        assert_eq!(
+            vec![
+                RegisterChange {
+                    register: Register::A,
+                    old: 0x00,
+                    new: 0x80,
+                },
+                RegisterChange {
+                    register: Register::Pc,
+                    old: 0x0000,
+                    new: 0x0004,
+                },
+                RegisterChange {
+                    register: Register::Status,
+                    old: 0x20,
+                    new: 0xa0,
+                },
+            ],
+            diff.registers
+        );
+        assert_eq!(
+            vec![MemoryChange {
+                addr: 0x0010,
+                old: 0x00,
+                new: 0x80,
+            }],
+            diff.memory
+        );
+    }
+
+    #[test]
+    fn test_snapshot_banked() {
+        // a banked window shows the selected bank rather than the RAM under it
+        use crate::bus::{BankSize, BankedMemory, MappedBus};
+        let prg: Vec<u8> = (0..2).flat_map(|bank| vec![bank + 1; 0x4000]).collect();
+        let banks = Rc::new(RefCell::new(BankedMemory::rom(prg, BankSize::Kb16)));
+        let mut bus = MappedBus::new();
+        bus.map_banked(0x8000, banks.clone());
+        bus.map_bank_select(0x8000..=0xbfff, banks);
+
+        let mut sys = SystemState::new(bus);
+        let before = sys.snapshot();
+        assert_eq!(0x01, before.memory()[0x8000]);
+        sys.memory.write(0x8000, 1);
+        assert_eq!(
+            vec![MemoryChange {
+                addr: 0x8000,
+                old: 0x01,
+                new: 0x02,
+            }],
+            before.diff(&sys.snapshot()).memory[..1]
+        );
+    }
+
+    #[test]
+    fn test_bus_observer() {
+        let mut sys = SystemState::default();
+        sys.memory[0x0000] = 0xe6; // INC $10
+        sys.memory[0x0001] = 0x10;
+        sys.memory[0x0010] = 0x41;
+
+        let accesses = Rc::new(RefCell::new(Vec::new()));
+        let observer_accesses = accesses.clone();
+        sys.set_bus_observer(move |access| observer_accesses.borrow_mut().push(access));
+        step(&mut sys);
+
+        let access = |cycle, addr, value, write, sync| BusAccess {
+            cycle,
+            addr,
+            value,
+            write,
+            sync,
+            irq: false,
+            nmi: false,
+        };
+        assert_eq!(
+            vec![
+                access(0, 0x0000, 0xe6, false, true),
+                access(1, 0x0001, 0x10, false, false),
+                access(2, 0x0010, 0x41, false, false),
+                access(3, 0x0010, 0x41, true, false),
+                access(4, 0x0010, 0x42, true, false),
+            ],
+            *accesses.borrow()
+        );
+    }
+
+    #[test]
+    fn test_read_u16() {
+        let mut sys = SystemState::default();
+        sys.write_u16(0x00ff, 0x1234);
+        sys.write_u16(0xffff, 0xabcd);
+
+        assert_eq!(0x34, sys.memory[0x00ff]);
+        assert_eq!(0x1234, sys.read_u16(0x00ff));
+        assert_eq!(0xab34, sys.read_u16_zp_wrapped(0x00ff));
+        assert_eq!(0xabcd, sys.read_u16(0xffff));
+
+        // without going through devices' read handlers
+        let reads = Rc::new(RefCell::new(0));
+        let handler_reads = reads.clone();
+        let mut bus = crate::bus::MappedBus::new();
+        bus.map_read(0xd000..=0xd0ff, move |_| {
+            *handler_reads.borrow_mut() += 1;
+            0xff
+        });
+        let sys = SystemState::new(bus);
+        sys.read_u16(0xd000);
+        sys.read_u16_zp_wrapped(0xd0ff);
+        sys.read_bytes(0xd000, 4);
+        assert_eq!(0, *reads.borrow());
+    }
+
+    #[test]
+    fn test_load_bytes() {
+        let mut sys = SystemState::default();
+        sys.load_bytes(0xfffe, &[0x12, 0x34, 0x56]);
+
+        assert_eq!(0x34, sys.memory[0xffff]);
+        assert_eq!(0x56, sys.memory[0x0000]);
+        assert_eq!(vec![0x12, 0x34, 0x56, 0x00], sys.read_bytes(0xfffe, 4));
+    }
+
+    #[test]
+    fn test_memory_on_heap() {
+        // systems are small enough to keep plenty of on the stack or in a Vec
+        assert!(std::mem::size_of::<SystemState>() < 0x400);
+
+        let systems: Vec<SystemState> = (0..64).map(|_| SystemState::default()).collect();
+        assert_eq!(0x00, systems[63].memory[0xffff]);
+    }
+
+    #[test]
+    fn test_access_violations() {
+        let mut sys = SystemState::default();
+        sys.memory[0x0000] = 0x8d; // STA $8000
+        sys.memory[0x0002] = 0x80;
+        sys.memory[0x0003] = 0x4c; // JMP $0200
+        sys.memory[0x0005] = 0x02;
+        sys.memory[0x0200] = 0xea; // NOP
+
+        let violations = Rc::new(RefCell::new(Vec::new()));
+        let handler_violations = violations.clone();
+        sys.set_access_violation_handler(move |violation| {
+            handler_violations.borrow_mut().push(violation)
+        });
+        sys.set_permission(0x8000..=0xffff, Permission::ReadOnly);
+        sys.set_permission(0x0200..=0x02ff, Permission::NoExecute);
+
+        // violations are reported, but the program carries on regardless
+        step(&mut sys);
+        step(&mut sys);
+        step(&mut sys);
+        assert_eq!(0x0201, sys.cpu_state.pc);
+        assert_eq!(
+            vec![
+                AccessViolation {
+                    addr: 0x8000,
+                    access: Access::Write,
+                    permission: Permission::ReadOnly,
+                    instruction_addr: 0x0000,
+                },
+                AccessViolation {
+                    addr: 0x0200,
+                    access: Access::Execute,
+                    permission: Permission::NoExecute,
+                    instruction_addr: 0x0200,
+                },
+            ],
+            *violations.borrow()
+        );
+    }
+
+    #[test]
+    fn test_breakpoints() {
+        let mut sys = SystemState::default();
+        // $0200: INX; JMP $0200
+        sys.load_bytes(0x0200, &[0xe8, 0x4c, 0x00, 0x02]);
+        sys.cpu_state.pc = 0x0200;
+
+        sys.add_breakpoint(0x0201);
+        assert_eq!(RunEvent::BreakpointHit(0x0201), run(&mut sys));
+        assert_eq!(0x01, sys.cpu_state.x);
+
+        // continuing runs the instruction at the breakpoint before stopping there again
+        assert_eq!(RunEvent::BreakpointHit(0x0201), run(&mut sys));
+        assert_eq!(0x02, sys.cpu_state.x);
+
+        assert!(sys.remove_breakpoint(0x0201));
+        assert!(!sys.remove_breakpoint(0x0201));
+        sys.add_breakpoint(0x0200);
+        assert_eq!(RunEvent::BreakpointHit(0x0200), run(&mut sys));
+        assert_eq!(vec![0x0200], sys.breakpoints().collect::<Vec<u16>>());
+
+        // and the CPU stopping ends the run too
+        sys.clear_breakpoints();
+        sys.load_bytes(0x0200, &[0x02]);
+        assert_eq!(RunEvent::Halted, run(&mut sys));
+    }
+
+    #[test]
+    fn test_conditions() {
+        let mut sys = SystemState::default();
+        // $0200: INX; STX $10; JMP $0200
+        sys.load_bytes(0x0200, &[0xe8, 0x86, 0x10, 0x4c, 0x00, 0x02]);
+        sys.cpu_state.pc = 0x0200;
+
+        sys.add_condition(Condition::parse("mem[$10] == 3").unwrap());
+        let index = sys.add_condition(Condition::parse("X == 2 && PC == $0201").unwrap());
+        assert_eq!(RunEvent::ConditionMet(index), run(&mut sys));
+        assert_eq!(0x0201, sys.cpu_state.pc);
+
+        assert_eq!(RunEvent::ConditionMet(0), run(&mut sys));
+        assert_eq!(0x0203, sys.cpu_state.pc);
+
+        sys.remove_condition(0);
+        assert_eq!(1, sys.conditions().len());
+        sys.add_breakpoint(0x0203);
+        assert_eq!(RunEvent::BreakpointHit(0x0203), run(&mut sys));
+        assert_eq!(0x04, sys.cpu_state.x);
+    }
+
+    #[test]
+    fn test_watchpoints() {
+        let mut sys = SystemState::default();
+        // $0200: LDA $10; INC $11; JMP $0200
+        sys.load_bytes(0x0200, &[0xa5, 0x10, 0xe6, 0x11, 0x4c, 0x00, 0x02]);
+        sys.load_bytes(0x0010, &[0x42, 0x07]);
+        sys.cpu_state.pc = 0x0200;
+
+        sys.add_watchpoint(0x0010..=0x0011, Watch::Write);
+        let hit = WatchpointHit {
+            addr: 0x0011,
+            write: true,
+            old: 0x07,
+            new: 0x07, // INC writes the unmodified value back first
+            instruction_addr: 0x0202,
+        };
+        assert_eq!(RunEvent::WatchpointHit(hit), run(&mut sys));
+        assert_eq!(0x0204, sys.cpu_state.pc);
+
+        sys.clear_watchpoints();
+        sys.add_watchpoint(0x0010..=0x0010, Watch::Read);
+        let hit = WatchpointHit {
+            addr: 0x0010,
+            write: false,
+            old: 0x42,
+            new: 0x42,
+            instruction_addr: 0x0200,
+        };
+        assert_eq!(RunEvent::WatchpointHit(hit), run(&mut sys));
+        assert_eq!(0x42, sys.cpu_state.a);
+    }
+
+    #[test]
+    fn test_heatmap() {
+        let mut sys = SystemState::default();
+        // $0200: INC $10
+        sys.load_bytes(0x0200, &[0xe6, 0x10]);
+        sys.cpu_state.pc = 0x0200;
+
+        sys.start_heatmap();
+        step(&mut sys);
+
+        let heatmap = sys.stop_heatmap().unwrap();
+        assert_eq!(1, heatmap.get(0x0200, Access::Execute));
+        assert_eq!(1, heatmap.get(0x0200, Access::Read));
+        assert_eq!(0, heatmap.get(0x0201, Access::Execute));
+        // the modify cycle writes the old value back before the new one
+        assert_eq!(1, heatmap.get(0x0010, Access::Read));
+        assert_eq!(2, heatmap.get(0x0010, Access::Write));
+        assert!(sys.heatmap().is_none());
+    }
+
+    #[test]
+    fn test_fill_and_copy() {
+        let mut sys = SystemState::default();
+        sys.fill(0x0200, 5, &[0x01, 0x02]);
+        assert_eq!(
+            vec![0x01, 0x02, 0x01, 0x02, 0x01, 0x00],
+            sys.read_bytes(0x0200, 6)
+        );
+
+        // overlapping forwards, and wrapping at $FFFF
+        sys.copy(0x0200, 4, 0x0201);
+        assert_eq!(
+            vec![0x01, 0x01, 0x02, 0x01, 0x02],
+            sys.read_bytes(0x0200, 5)
+        );
+        sys.copy(0x0200, 2, 0xffff);
+        assert_eq!(0x01, sys.memory[0xffff]);
+        assert_eq!(0x01, sys.memory[0x0000]);
+    }
+
+    #[test]
+    fn test_run_until() {
+        let mut sys = SystemState::default();
+        // $0200: INX; JMP $0200
+        sys.load_bytes(0x0200, &[0xe8, 0x4c, 0x00, 0x02]);
+        sys.cpu_state.pc = 0x0200;
+
+        assert_eq!(
+            RunUntil::Satisfied,
+            run_until(&mut sys, |sys| sys.cpu_state().x() == 3, 1000)
+        );
+        assert_eq!(0x0201, sys.cpu_state.pc);
+        assert_eq!(
+            RunUntil::Satisfied,
+            run_until(&mut sys, |sys| sys.cpu_state().x() == 3, 1000)
+        );
+        assert_eq!(0x0201, sys.cpu_state.pc);
+
+        // 5 cycles a time round the loop
+        let start = sys.cpu_state.cycle_count;
+        assert_eq!(
+            RunUntil::OutOfCycles,
+            run_until(&mut sys, |sys| sys.cpu_state().x() == 0xff, 24)
+        );
+        assert_eq!(25, sys.cpu_state.cycle_count - start);
+
+        sys.load_bytes(0x0200, &[0x02]);
+        assert_eq!(RunUntil::Halted, run_until(&mut sys, |_| false, 1000));
+    }
+
+    #[test]
+    fn test_trap_detection() {
+        let mut sys = SystemState::default();
+        // $0200: LDX #$00; DEX; BNE $0202; JMP $0205
+        sys.load_bytes(0x0200, &[0xa2, 0x00, 0xca, 0xd0, 0xfd, 0x4c, 0x05, 0x02]);
+        sys.cpu_state.pc = 0x0200;
+        sys.set_trap_window(Some(3));
+
+        // the BNE loop goes round 256 times without ever branching to itself
+        assert_eq!(RunEvent::Trapped(0x0205), run(&mut sys));
+        assert_eq!(0x0205, sys.cpu_state.pc);
+        assert_eq!(StepResult::Trapped(0x0205), step(&mut sys));
+
+        // only after the window has filled again
+        sys.set_trap_window(Some(2));
+        assert_eq!(StepResult::Executed(3), step(&mut sys));
+        assert_eq!(StepResult::Trapped(0x0205), step(&mut sys));
+
+        sys.set_trap_window(None);
+        assert_eq!(RunUntil::OutOfCycles, run_until(&mut sys, |_| false, 100));
+
+        // $0300: BEQ $0300
+        sys.load_bytes(0x0300, &[0xf0, 0xfe]);
+        sys.cpu_state.pc = 0x0300;
+        sys.set_trap_window(Some(1));
+        assert_eq!(
+            RunUntil::Trapped(0x0300),
+            run_until(&mut sys, |_| false, 100)
+        );
+    }
+
+    #[test]
+    fn test_stack_warnings() {
+        let mut sys = SystemState::default();
+        let warnings = Rc::new(RefCell::new(Vec::new()));
+        let log = warnings.clone();
+        sys.set_stack_warning_handler(move |warning| log.borrow_mut().push(warning));
+
+        // $0200: PHA; PLA; PLA; PHA, with the first two wrapping
+        sys.load_bytes(0x0200, &[0x48, 0x68, 0x68, 0x48]);
+        sys.cpu_state.pc = 0x0200;
+        sys.cpu_state.s = 0x00;
+        for _ in 0..4 {
+            step(&mut sys);
+        }
+
+        assert_eq!(
+            vec![
+                StackWarning {
+                    problem: StackProblem::Overflow,
+                    instruction_addr: 0x0200,
+                },
+                StackWarning {
+                    problem: StackProblem::Underflow,
+                    instruction_addr: 0x0201,
+                },
+            ],
+            *warnings.borrow()
+        );
+    }
+
+    #[test]
+    fn test_power_on() {
+        let mut sys = SystemState::default();
+        sys.power_on(&PowerOnState {
+            a: 0xaa,
+            s: 0xfd,
+            status: 0x04,
+            memory_fill: MemoryFill::Stripes(0x40),
+            ..Default::default()
+        });
+
+        assert_eq!(0xaa, sys.cpu_state.a);
+        assert_eq!(0xfd, sys.cpu_state.s);
+        assert!(sys.cpu_state.irq_interrupt_disable);
+        assert_eq!(0x00, sys.memory[0x003f]);
+        assert_eq!(0xff, sys.memory[0x0040]);
+        assert_eq!(0x00, sys.memory[0x0080]);
+
+        sys.power_on(&PowerOnState {
+            memory_fill: MemoryFill::Pattern(vec![0x12, 0x34, 0x56]),
+            ..Default::default()
+        });
+        assert_eq!(0x00, sys.cpu_state.a);
+        assert_eq!(0x12, sys.memory[0x0000]);
+        assert_eq!(0x56, sys.memory[0x0005]);
+    }
+
+    #[test]
+    fn test_power_on_mapped_bus() {
+        // filling memory doesn't write to devices, or to ROM
+        let writes = Rc::new(Cell::new(0));
+        let faults = Rc::new(Cell::new(0));
+        let mut bus = crate::bus::MappedBus::new();
+        bus.map_rom(0xf000, &[0xea; 0x1000]);
+        let device_writes = writes.clone();
+        bus.map_write(0xd000..=0xd0ff, move |_, _| {
+            device_writes.set(device_writes.get() + 1)
+        });
+        let bus_faults = faults.clone();
+        bus.set_bus_fault_handler(move |_, _| bus_faults.set(bus_faults.get() + 1));
+
+        let mut sys = SystemState::new(bus);
+        sys.power_on(&PowerOnState {
+            memory_fill: MemoryFill::Pattern(vec![0x55]),
+            ..Default::default()
+        });
+        assert_eq!(0, writes.get());
+        assert_eq!(0, faults.get());
+        assert_eq!(0x55, sys.memory.peek(0x0200));
+        assert_eq!(0xea, sys.memory.peek(0xf000));
+    }
+
+    #[test]
+    fn test_rdy_line() {
+        let mut sys = SystemState::default();
+        sys.cpu_state.a = 0x42;
+        sys.memory[0x0000] = 0x8d; // STA $0200
+        sys.memory[0x0001] = 0x00;
+        sys.memory[0x0002] = 0x02;
+        sys.memory[0x0003] = 0xad; // LDA $0300
+        sys.memory[0x0004] = 0x00;
+        sys.memory[0x0005] = 0x03;
+        sys.memory[0x0300] = 0x99;
+
+        // pulled low before the write cycle, RDY lets the write complete
+        tick(&mut sys);
+        tick(&mut sys);
+        tick(&mut sys);
+        sys.set_rdy_line(false);
+        assert!(tick(&mut sys));
+        assert_eq!(0x42, sys.memory[0x0200]);
+
+        // but the next read stalls for as long as RDY stays low
+        for _ in 0..3 {
+            assert!(!tick(&mut sys));
+        }
+        assert_eq!(0x0003, sys.cpu_state.pc);
+        assert_eq!(StepResult::Stalled(1), step(&mut sys));
+        assert_eq!(0x0003, sys.cpu_state.pc);
+        assert_eq!(8, sys.cpu_state.cycle_count);
+
+        sys.set_rdy_line(true);
+        assert_eq!(StepResult::Executed(4), step(&mut sys));
+        assert_eq!(0x99, sys.cpu_state.a);
+
+        // the 65C02 stalls on writes too
+        let mut sys = SystemState {
+            options: CpuOptions::for_variant(CpuVariant::Cmos65c02),
+            ..Default::default()
+        };
+        sys.memory[0x0000] = 0x8d; // STA $0200
+        sys.memory[0x0002] = 0x02;
+        sys.cpu_state.a = 0x42;
+        tick(&mut sys);
+        tick(&mut sys);
+        tick(&mut sys);
+        sys.set_rdy_line(false);
+        assert!(!tick(&mut sys));
+        assert_eq!(0x00, sys.memory[0x0200]);
+    }
+
+    #[test]
+    fn test_lda() {
+        let mut sys = SystemState::default();
+        sys.memory[0x0000] = 0xa9;
+        sys.memory[0x0001] = 0x80;
+
+        assert_eq!(2, emulate_op(&mut sys));
+        assert_eq!(0x80, sys.cpu_state.a);
+        assert!(sys.cpu_state.negative);
+        assert!(!sys.cpu_state.zero);
+        assert_eq!(0x0002, sys.cpu_state.pc);
+    }
+
+    #[test]
+    fn test_sta_absolute_indexed() {
+        let mut sys = SystemState::default();
+        sys.memory[0x0000] = 0x9d;
+        sys.memory[0x0001] = 0x00;
+        sys.memory[0x0002] = 0x20;
+        sys.cpu_state.a = 0x42;
+        sys.cpu_state.x = 0x01;
+
+        // no page cross, but STA abs,X always takes 5 cycles
+        assert_eq!(5, emulate_op(&mut sys));
+        assert_eq!(0x42, sys.memory[0x2001]);
+        assert_eq!(0x0003, sys.cpu_state.pc);
     }
 }
@@ -0,0 +1,572 @@
+use crate::bus::{Bus, FlatRam};
+use crate::cpu::{self, run_step, CpuState, RunEvent, StackProblem, StepResult, SystemState};
+use crate::disasm::disassemble;
+use crate::profile::Profile;
+use crate::timeline::{SpanKind, Timeline};
+use std::collections::VecDeque;
+use std::ops::{Deref, DerefMut};
+
+// a system with debugging on top, for stepping by subroutine rather than by instruction.
+// Breakpoints, conditions and watchpoints set on the system still stop these early
+pub struct Debugger<B: Bus = FlatRam> {
+    sys: SystemState<B>,
+    history: VecDeque<UndoRecord>, // most recent last
+    history_limit: usize,
+    call_stack: Vec<Frame>, // innermost last
+    profile: Option<Profile>,
+    timeline: Option<Timeline>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FrameKind {
+    Subroutine,
+    Interrupt, // including BRK
+}
+
+// a JSR or interrupt that hasn't returned yet
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Frame {
+    pub kind: FrameKind,
+    pub call_addr: u16, // the JSR or BRK, or the instruction an interrupt came before
+    pub entry: u16,     // where it went
+    pub return_addr: u16, // where it will return to
+    pub s: u8,          // the stack pointer once the return address was pushed
+}
+
+// what an instruction changed, for running it backwards. Only the CPU and the memory it
+// wrote are restored, so anything devices did in the meantime stays done
+struct UndoRecord {
+    cpu_state: CpuState,
+    writes: Vec<(u16, u8)>, // in the order written, with the values they replaced
+    call_stack: Vec<Frame>,
+}
+
+impl<B: Bus> Debugger<B> {
+    pub fn new(sys: SystemState<B>) -> Self {
+        Debugger {
+            sys,
+            history: VecDeque::new(),
+            history_limit: 0,
+            call_stack: Vec::new(),
+            profile: None,
+            timeline: None,
+        }
+    }
+
+    pub fn start_profiling(&mut self) {
+        // counts cycles of instructions run through the debugger from now on, adding to any
+        // already counted
+        self.profile.get_or_insert_with(Profile::new);
+    }
+
+    pub fn stop_profiling(&mut self) -> Option<Profile> {
+        self.profile.take()
+    }
+
+    pub fn profile(&self) -> Option<&Profile> {
+        self.profile.as_ref()
+    }
+
+    pub fn start_timeline(&mut self, instructions: bool) {
+        // records the calls made and interrupts taken by instructions run through the
+        // debugger from now on, and each instruction as well if asked. Stepping back
+        // doesn't take anything out
+        self.timeline = Some(Timeline::new(instructions));
+    }
+
+    pub fn stop_timeline(&mut self) -> Option<Timeline> {
+        self.timeline.take()
+    }
+
+    pub fn timeline(&self) -> Option<&Timeline> {
+        self.timeline.as_ref()
+    }
+
+    pub fn reset(&mut self) -> u16 {
+        // the reset sequence, forgetting the calls and history from before it
+        if let Some(timeline) = self.timeline.as_mut() {
+            let cycle_count = self.sys.cpu_state().cycle_count();
+            for _ in &self.call_stack {
+                timeline.leave(cycle_count);
+            }
+        }
+        self.call_stack.clear();
+        self.history.clear();
+        self.sys.reset()
+    }
+
+    pub fn call_stack(&self) -> &[Frame] {
+        &self.call_stack
+    }
+
+    pub fn set_history_limit(&mut self, instructions: usize) {
+        // how many instructions can be stepped back over. Instructions are only recorded
+        // when run through the debugger, and none are by default
+        self.history_limit = instructions;
+        while self.history.len() > instructions {
+            self.history.pop_front();
+        }
+    }
+
+    pub fn clear_history(&mut self) {
+        self.history.clear();
+    }
+
+    pub fn history_len(&self) -> usize {
+        self.history.len()
+    }
+
+    fn record<T>(&mut self, run: impl FnOnce(&mut SystemState<B>) -> T) -> T {
+        if self.history_limit == 0 {
+            return self.track(run);
+        }
+
+        let cpu_state = self.sys.cpu_state().clone();
+        let call_stack = self.call_stack.clone();
+        self.sys.start_write_log();
+        let result = self.track(run);
+        let writes = self.sys.take_write_log();
+
+        if self.history.len() == self.history_limit {
+            self.history.pop_front();
+        }
+        self.history.push_back(UndoRecord {
+            cpu_state,
+            writes,
+            call_stack,
+        });
+
+        result
+    }
+
+    fn track<T>(&mut self, run: impl FnOnce(&mut SystemState<B>) -> T) -> T {
+        let pc = self.sys.cpu_state().pc();
+        let s = self.sys.cpu_state().s();
+        let cycle_count = self.sys.cpu_state().cycle_count();
+        // a JSR's own cycles are its caller's
+        let subroutine = self.call_stack.last().map(|frame| frame.entry);
+        let frames = self.timeline.as_ref().map(|_| self.call_stack.clone());
+        let text = match self.timeline.as_ref() {
+            Some(timeline) if timeline.records_instructions() => {
+                disassemble(self.sys.memory(), pc, self.sys.options()).to_string()
+            }
+            _ => String::new(),
+        };
+
+        let result = run(&mut self.sys);
+
+        if let Some(profile) = self.profile.as_mut() {
+            let cycles = self.sys.cpu_state().cycle_count() - cycle_count;
+            profile.add(pc, subroutine, cycles);
+        }
+        self.track_calls(pc, s);
+
+        if let (Some(timeline), Some(frames)) = (self.timeline.as_mut(), frames) {
+            let end = self.sys.cpu_state().cycle_count();
+            if timeline.records_instructions() && end > cycle_count {
+                timeline.instruction(pc, text, cycle_count, end);
+            }
+
+            // calls that returned end, and new ones start, once the instruction is done
+            let kept = frames
+                .iter()
+                .zip(&self.call_stack)
+                .take_while(|(old, new)| old == new)
+                .count();
+            for _ in kept..frames.len() {
+                timeline.leave(end);
+            }
+            for frame in &self.call_stack[kept..] {
+                let kind = match frame.kind {
+                    FrameKind::Subroutine => SpanKind::Subroutine,
+                    FrameKind::Interrupt => SpanKind::Interrupt,
+                };
+                timeline.enter(kind, frame.entry, end);
+            }
+        }
+
+        result
+    }
+
+    fn track_calls(&mut self, pc: u16, s: u8) {
+        // frames are matched against the stack pointer rather than RTS against JSR, so
+        // returning by pulling the return address, or calling by pushing one and RTS, or
+        // resetting the stack with TXS, all leave the frames that are really there
+        let new_s = self.sys.cpu_state().s();
+        let opcode = self.sys.memory().peek(pc);
+        if opcode == 0x60 && stack_depth(s, new_s) == -2 && self.call_stack.is_empty() {
+            self.sys.warn_stack(StackProblem::UnmatchedReturn, pc);
+        }
+
+        while let Some(frame) = self.call_stack.last() {
+            if stack_depth(frame.s, new_s) >= 0 {
+                break;
+            }
+            self.call_stack.pop();
+        }
+
+        let entry = self.sys.cpu_state().pc();
+        let peek_word = |addr: u16| {
+            let bus = self.sys.memory();
+            u16::from_le_bytes([bus.peek(addr), bus.peek(addr.wrapping_add(1))])
+        };
+
+        let frame = match stack_depth(s, new_s) {
+            2 if opcode == 0x20 => Frame {
+                kind: FrameKind::Subroutine,
+                call_addr: pc,
+                entry,
+                return_addr: pc.wrapping_add(3),
+                s: new_s,
+            },
+            // an interrupt taken instead of the instruction at pc pushes pc itself, while
+            // BRK skips its signature byte
+            3 if entry == peek_word(0xfffe) || entry == peek_word(0xfffa) => Frame {
+                kind: FrameKind::Interrupt,
+                call_addr: pc,
+                entry,
+                return_addr: u16::from_le_bytes([
+                    self.sys.memory().peek(cat_stack(new_s.wrapping_add(2))),
+                    self.sys.memory().peek(cat_stack(new_s.wrapping_add(3))),
+                ]),
+                s: new_s,
+            },
+            _ => return,
+        };
+        self.call_stack.push(frame);
+    }
+
+    pub fn step(&mut self) -> StepResult {
+        self.record(cpu::step)
+    }
+
+    pub fn run(&mut self) -> RunEvent {
+        loop {
+            if let Some(event) = self.record(run_step) {
+                return event;
+            }
+        }
+    }
+
+    pub fn run_for(&mut self, instructions: usize) -> Option<RunEvent> {
+        // like run, but giving up after the given number of instructions with None, so a
+        // frontend can run a bit at a time and stay responsive
+        for _ in 0..instructions {
+            if let Some(event) = self.record(run_step) {
+                return Some(event);
+            }
+        }
+        None
+    }
+
+    pub fn step_back(&mut self, instructions: usize) -> usize {
+        // undoes up to the given number of recorded instructions, returning how many were
+        let mut undone = 0;
+        while undone < instructions {
+            let record = match self.history.pop_back() {
+                Some(record) => record,
+                None => break,
+            };
+
+            for &(addr, byte) in record.writes.iter().rev() {
+                self.sys.memory_mut().write(addr, byte);
+            }
+            *self.sys.cpu_state_mut() = record.cpu_state;
+            self.call_stack = record.call_stack;
+            undone += 1;
+        }
+
+        undone
+    }
+
+    pub fn into_system(self) -> SystemState<B> {
+        self.sys
+    }
+
+    pub fn step_over(&mut self) -> Option<RunEvent> {
+        // runs the next instruction, and a JSR through to its return. Returns None when it
+        // finished, or whatever stopped it first
+        let s = self.sys.cpu_state().s();
+        let jsr = self.sys.memory().peek(self.sys.cpu_state().pc()) == 0x20;
+
+        if let Some(event) = self.record(run_step) {
+            return Some(event);
+        }
+        if !jsr {
+            return None;
+        }
+
+        // the subroutine has returned when the stack is back where it was, which also
+        // catches returns made by pulling the return address and jumping
+        while stack_depth(s, self.sys.cpu_state().s()) > 0 {
+            if let Some(event) = self.record(run_step) {
+                return Some(event);
+            }
+        }
+
+        None
+    }
+
+    pub fn step_out(&mut self) -> Option<RunEvent> {
+        // runs until an RTS or RTI returns from the current subroutine or interrupt handler
+        let s = self.sys.cpu_state().s();
+
+        loop {
+            let opcode = self.sys.memory().peek(self.sys.cpu_state().pc());
+            if let Some(event) = self.record(run_step) {
+                return Some(event);
+            }
+
+            let returned = opcode == 0x60 || opcode == 0x40;
+            if returned && stack_depth(s, self.sys.cpu_state().s()) < 0 {
+                return None;
+            }
+        }
+    }
+}
+
+fn cat_stack(s: u8) -> u16 {
+    0x0100 | s as u16
+}
+
+fn stack_depth(start: u8, s: u8) -> i8 {
+    // bytes pushed since the stack pointer was at start, negative when more were pulled
+    start.wrapping_sub(s) as i8
+}
+
+impl<B: Bus> Deref for Debugger<B> {
+    type Target = SystemState<B>;
+
+    fn deref(&self) -> &SystemState<B> {
+        &self.sys
+    }
+}
+
+impl<B: Bus> DerefMut for Debugger<B> {
+    fn deref_mut(&mut self) -> &mut SystemState<B> {
+        &mut self.sys
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::{step, StackWarning};
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    fn debugger() -> Debugger {
+        let mut sys = SystemState::default();
+        // $0200: JSR $0300; INX; JMP $0200
+        // $0300: INY; JSR $0310; INY; RTS
+        // $0310: INY; RTS
+        sys.load_bytes(0x0200, &[0x20, 0x00, 0x03, 0xe8, 0x4c, 0x00, 0x02]);
+        sys.load_bytes(0x0300, &[0xc8, 0x20, 0x10, 0x03, 0xc8, 0x60]);
+        sys.load_bytes(0x0310, &[0xc8, 0x60]);
+        sys.cpu_state_mut().set_pc(0x0200);
+
+        Debugger::new(sys)
+    }
+
+    #[test]
+    fn test_step_over() {
+        let mut debugger = debugger();
+
+        assert_eq!(None, debugger.step_over());
+        assert_eq!(0x0203, debugger.cpu_state().pc());
+        assert_eq!(0x03, debugger.cpu_state().y());
+
+        assert_eq!(None, debugger.step_over());
+        assert_eq!(0x0204, debugger.cpu_state().pc());
+
+        // a breakpoint inside the subroutine still stops it
+        debugger.step_over();
+        debugger.add_breakpoint(0x0310);
+        assert_eq!(Some(RunEvent::BreakpointHit(0x0310)), debugger.step_over());
+    }
+
+    #[test]
+    fn test_unmatched_return() {
+        let mut debugger = debugger();
+        let warnings = Rc::new(RefCell::new(Vec::new()));
+        let log = warnings.clone();
+        debugger.set_stack_warning_handler(move |warning| log.borrow_mut().push(warning));
+
+        // returns that match their JSR are fine
+        for _ in 0..7 {
+            debugger.step();
+        }
+        assert!(warnings.borrow().is_empty());
+
+        // $0210: RTS
+        debugger.load_bytes(0x0210, &[0x60]);
+        debugger.cpu_state_mut().set_pc(0x0210);
+        debugger.cpu_state_mut().set_s(0xfd);
+        debugger.step();
+        assert_eq!(
+            vec![StackWarning {
+                problem: StackProblem::UnmatchedReturn,
+                instruction_addr: 0x0210,
+            }],
+            *warnings.borrow()
+        );
+    }
+
+    #[test]
+    fn test_run_for() {
+        let mut debugger = debugger();
+
+        assert_eq!(None, debugger.run_for(3));
+        assert_eq!(0x0310, debugger.cpu_state().pc());
+        debugger.add_breakpoint(0x0200);
+        assert_eq!(Some(RunEvent::BreakpointHit(0x0200)), debugger.run_for(100));
+    }
+
+    #[test]
+    fn test_step_back() {
+        let mut debugger = debugger();
+        debugger.set_history_limit(3);
+        let start = debugger.cpu_state().clone();
+
+        // JSR pushes the return address, which stepping back takes off again
+        debugger.step();
+        debugger.step();
+        assert_eq!(0x02, debugger.memory()[0x01ff]);
+        assert_eq!(2, debugger.history_len());
+        assert_eq!(2, debugger.step_back(5));
+        assert_eq!(&start, debugger.cpu_state());
+        assert_eq!(0x00, debugger.memory()[0x01ff]);
+
+        // only the most recent instructions are kept
+        debugger.step_over();
+        debugger.step();
+        assert_eq!(3, debugger.history_len());
+        assert_eq!(3, debugger.step_back(3));
+        assert_eq!(0x0304, debugger.cpu_state().pc());
+        assert_eq!(0x02, debugger.cpu_state().y());
+    }
+
+    #[test]
+    fn test_call_stack() {
+        let mut debugger = debugger();
+        for _ in 0..3 {
+            debugger.step();
+        }
+        let frames = [
+            Frame {
+                kind: FrameKind::Subroutine,
+                call_addr: 0x0200,
+                entry: 0x0300,
+                return_addr: 0x0203,
+                s: 0xfd,
+            },
+            Frame {
+                kind: FrameKind::Subroutine,
+                call_addr: 0x0301,
+                entry: 0x0310,
+                return_addr: 0x0304,
+                s: 0xfb,
+            },
+        ];
+        assert_eq!(&frames, debugger.call_stack());
+
+        // a BRK in the nested subroutine
+        debugger.load_bytes(0xfffe, &[0x00, 0x04]);
+        debugger.load_bytes(0x0310, &[0x00, 0xea]);
+        debugger.cpu_state_mut().set_pc(0x0310);
+        debugger.step();
+        assert_eq!(
+            Frame {
+                kind: FrameKind::Interrupt,
+                call_addr: 0x0310,
+                entry: 0x0400,
+                return_addr: 0x0312,
+                s: 0xf8,
+            },
+            debugger.call_stack()[2]
+        );
+
+        // resetting the stack pointer drops the frames above it
+        debugger.load_bytes(0x0400, &[0xa2, 0xfc, 0x9a]); // LDX #$FC; TXS
+        debugger.step();
+        debugger.step();
+        assert_eq!(&frames[..1], debugger.call_stack());
+
+        // and stepping back brings them back
+        debugger.set_history_limit(2);
+        debugger.load_bytes(0x0403, &[0x68, 0x68]); // PLA; PLA
+        debugger.step();
+        debugger.step();
+        assert!(debugger.call_stack().is_empty());
+        debugger.step_back(2);
+        assert_eq!(&frames[..1], debugger.call_stack());
+    }
+
+    #[test]
+    fn test_profiling() {
+        let mut debugger = debugger();
+        debugger.start_profiling();
+        for _ in 0..9 {
+            debugger.step();
+        }
+
+        // JSR, INX and JMP at the top level, and each INY and RTS in its subroutine
+        let profile = debugger.stop_profiling().unwrap();
+        assert_eq!(6 + 2 + 3, profile.subroutine_cycles(None));
+        assert_eq!(2 + 6 + 2 + 6, profile.subroutine_cycles(Some(0x0300)));
+        assert_eq!(2 + 6, profile.subroutine_cycles(Some(0x0310)));
+        assert_eq!(6, profile.addr_cycles(0x0200));
+        assert!(debugger.profile().is_none());
+    }
+
+    #[test]
+    fn test_step_out() {
+        let mut debugger = debugger();
+        // into the nested subroutine, just after its INY
+        for _ in 0..4 {
+            step(&mut debugger);
+        }
+        assert_eq!(0x0311, debugger.cpu_state().pc());
+
+        assert_eq!(None, debugger.step_out());
+        assert_eq!(0x0304, debugger.cpu_state().pc());
+        assert_eq!(None, debugger.step_out());
+        assert_eq!(0x0203, debugger.cpu_state().pc());
+        assert_eq!(0xff, debugger.cpu_state().s());
+    }
+
+    #[test]
+    fn test_timeline() {
+        let mut debugger = debugger();
+        debugger.start_timeline(false);
+        for _ in 0..9 {
+            debugger.step();
+        }
+
+        // the nested subroutine, then the one it was called from, both returned
+        let timeline = debugger.stop_timeline().unwrap();
+        let spans = timeline.spans();
+        assert_eq!(2, spans.len());
+        assert_eq!(
+            (0x0310, 14, 22),
+            (spans[0].addr, spans[0].start, spans[0].end)
+        );
+        assert_eq!(
+            (0x0300, 6, 30),
+            (spans[1].addr, spans[1].start, spans[1].end)
+        );
+        assert!(debugger.timeline().is_none());
+
+        // with instructions, and a call still going
+        debugger.start_timeline(true);
+        debugger.step();
+        debugger.step();
+        let spans = debugger.timeline().unwrap().spans();
+        assert_eq!(SpanKind::Instruction, spans[0].kind);
+        assert_eq!("JSR $0300", spans[0].text);
+        assert_eq!(
+            (SpanKind::Subroutine, 0x0300),
+            (spans[2].kind, spans[2].addr)
+        );
+    }
+}
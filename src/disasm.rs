@@ -0,0 +1,327 @@
+use crate::bus::Bus;
+use crate::cpu::{decode_mnemonic, AddressingMode, CpuOptions};
+use crate::symbols::Symbols;
+use std::fmt;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Instruction {
+    pub addr: u16,
+    pub bytes: Vec<u8>, // the opcode and its operand
+    pub mnemonic: String,
+    pub mode: AddressingMode,
+    pub operand: String, // formatted in the usual assembler syntax
+}
+
+impl Instruction {
+    pub fn len(&self) -> u16 {
+        self.bytes.len() as u16
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.operand.is_empty() {
+            write!(f, "{}", self.mnemonic)
+        } else {
+            write!(f, "{} {}", self.mnemonic, self.operand)
+        }
+    }
+}
+
+pub fn operand_len(mode: AddressingMode) -> u16 {
+    match mode {
+        AddressingMode::Acc | AddressingMode::Imp => 0,
+        AddressingMode::I
+        | AddressingMode::Zp
+        | AddressingMode::Zpix
+        | AddressingMode::Zpiy
+        | AddressingMode::Zpiix
+        | AddressingMode::Zpiiy
+        | AddressingMode::Zpi
+        | AddressingMode::Rel => 1,
+        AddressingMode::A
+        | AddressingMode::Aix
+        | AddressingMode::Aiy
+        | AddressingMode::Ind
+        | AddressingMode::Aiix
+        | AddressingMode::Zpr => 2,
+    }
+}
+
+pub(crate) fn full_mnemonic(opcode: u8, mnemonic: &str) -> String {
+    // the 65C02's bit instructions take the bit number from the opcode
+    match mnemonic {
+        "RMB" | "SMB" | "BBR" | "BBS" => format!("{}{}", mnemonic, (opcode >> 4) & 0x07),
+        _ => mnemonic.to_string(),
+    }
+}
+
+// decodes the instruction at addr without any side effects on the bus, wrapping at $FFFF
+pub fn disassemble(bus: &impl Bus, addr: u16, options: &CpuOptions) -> Instruction {
+    decode_instruction(bus, addr, options, None)
+}
+
+// the same, but with addresses that have names shown by name
+pub fn disassemble_with_symbols(
+    bus: &impl Bus,
+    addr: u16,
+    options: &CpuOptions,
+    symbols: &Symbols,
+) -> Instruction {
+    decode_instruction(bus, addr, options, Some(symbols))
+}
+
+fn decode_instruction(
+    bus: &impl Bus,
+    addr: u16,
+    options: &CpuOptions,
+    symbols: Option<&Symbols>,
+) -> Instruction {
+    let opcode = bus.peek(addr);
+    let (mnemonic, mode) = decode_mnemonic(opcode, options);
+
+    let bytes: Vec<u8> = (0..=operand_len(mode))
+        .map(|i| bus.peek(addr.wrapping_add(i)))
+        .collect();
+    let byte = bytes.get(1).copied().unwrap_or(0);
+    let word = u16::from_le_bytes([byte, bytes.get(2).copied().unwrap_or(0)]);
+
+    // branch displacements are relative to the following instruction
+    let next = addr.wrapping_add(bytes.len() as u16);
+    let branch_target = |displacement: u8| next.wrapping_add(displacement as i8 as u16);
+
+    let name = |addr: u16| symbols.and_then(|symbols| symbols.name(addr));
+    let zp = name(byte as u16).map_or(format!("${:02X}", byte), str::to_string);
+    let abs = |addr: u16| name(addr).map_or(format!("${:04X}", addr), str::to_string);
+
+    let operand = match mode {
+        AddressingMode::I => format!("#${:02X}", byte),
+        AddressingMode::A => abs(word),
+        AddressingMode::Zp => zp,
+        AddressingMode::Aix => format!("{},X", abs(word)),
+        AddressingMode::Aiy => format!("{},Y", abs(word)),
+        AddressingMode::Zpix => format!("{},X", zp),
+        AddressingMode::Zpiy => format!("{},Y", zp),
+        AddressingMode::Zpiix => format!("({},X)", zp),
+        AddressingMode::Zpiiy => format!("({}),Y", zp),
+        AddressingMode::Zpi => format!("({})", zp),
+        AddressingMode::Zpr => format!("{},{}", zp, abs(branch_target(bytes[2]))),
+        AddressingMode::Acc => "A".to_string(),
+        AddressingMode::Ind => format!("({})", abs(word)),
+        AddressingMode::Aiix => format!("({},X)", abs(word)),
+        AddressingMode::Rel => abs(branch_target(byte)),
+        AddressingMode::Imp => String::new(),
+    };
+
+    Instruction {
+        addr,
+        bytes,
+        mnemonic: full_mnemonic(opcode, mnemonic),
+        mode,
+        operand,
+    }
+}
+
+// disassembles count instructions in a row from addr
+pub fn disassemble_range(
+    bus: &impl Bus,
+    addr: u16,
+    count: usize,
+    options: &CpuOptions,
+) -> Vec<Instruction> {
+    let mut addr = addr;
+
+    (0..count)
+        .map(|_| {
+            let instruction = disassemble(bus, addr, options);
+            addr = addr.wrapping_add(instruction.len());
+            instruction
+        })
+        .collect()
+}
+
+// a listing of len bytes from start, as an assembler would write one:
+//
+// 0200  A9 42     LDA #$42
+//       loop:
+// 0202  4C 02 02  JMP loop
+// 0205            .BYTE $01,$02,$03
+//
+// Given execution counts, like a heatmap's, only bytes that were run as opcodes start
+// instructions, and the rest are listed as data. Without them everything is taken as code
+pub fn listing(
+    bus: &impl Bus,
+    start: u16,
+    len: usize,
+    options: &CpuOptions,
+    symbols: Option<&Symbols>,
+    executed: Option<&[u32]>,
+) -> String {
+    let is_code = |addr: u16| executed.is_none_or(|counts| counts[addr as usize] > 0);
+    let label = |addr: u16| symbols.and_then(|symbols| symbols.name(addr));
+
+    let mut lines = Vec::new();
+    let mut offset = 0;
+    while offset < len {
+        let addr = start.wrapping_add(offset as u16);
+        if let Some(name) = label(addr) {
+            lines.push(format!("      {}:", name));
+        }
+
+        if is_code(addr) {
+            let instruction = decode_instruction(bus, addr, options, symbols);
+            let bytes: Vec<String> = instruction
+                .bytes
+                .iter()
+                .map(|byte| format!("{:02X}", byte))
+                .collect();
+            lines.push(format!(
+                "{:04X}  {:<8}  {}",
+                addr,
+                bytes.join(" "),
+                instruction
+            ));
+            offset += instruction.bytes.len();
+            continue;
+        }
+
+        // data runs up to 8 bytes a line, ending early at code or a label
+        let mut bytes = vec![format!("${:02X}", bus.peek(addr))];
+        offset += 1;
+        while offset < len && bytes.len() < 8 {
+            let addr = start.wrapping_add(offset as u16);
+            if is_code(addr) || label(addr).is_some() {
+                break;
+            }
+            bytes.push(format!("${:02X}", bus.peek(addr)));
+            offset += 1;
+        }
+        lines.push(format!(
+            "{:04X}  {:<8}  .BYTE {}",
+            addr,
+            "",
+            bytes.join(",")
+        ));
+    }
+
+    lines.join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::FlatRam;
+    use crate::cpu::CpuVariant;
+
+    #[test]
+    fn test_disassemble() {
+        let mut ram = FlatRam::default();
+        let program = [
+            0xa9, 0x42, // LDA #$42
+            0x9d, 0x00, 0x02, // STA $0200,X
+            0xb1, 0x10, // LDA ($10),Y
+            0x0a, // ASL A
+            0xd0, 0xf6, // BNE $0000
+            0x6c, 0xfc, 0xff, // JMP ($FFFC)
+        ];
+        ram[0x0000..program.len()].copy_from_slice(&program);
+
+        let options = CpuOptions::default();
+        let listing: Vec<String> = disassemble_range(&ram, 0x0000, 6, &options)
+            .iter()
+            .map(|instruction| instruction.to_string())
+            .collect();
+        assert_eq!(
+            vec![
+                "LDA #$42",
+                "STA $0200,X",
+                "LDA ($10),Y",
+                "ASL A",
+                "BNE $0000",
+                "JMP ($FFFC)",
+            ],
+            listing
+        );
+
+        let instruction = disassemble(&ram, 0x0002, &options);
+        assert_eq!(vec![0x9d, 0x00, 0x02], instruction.bytes);
+        assert_eq!(AddressingMode::Aix, instruction.mode);
+
+        // the variant's own table is used
+        ram[0x0100..0x0103].copy_from_slice(&[0x9f, 0x20, 0x10]);
+        assert_eq!(
+            "SHA $1020,Y",
+            disassemble(&ram, 0x0100, &options).to_string()
+        );
+        let options = CpuOptions::for_variant(CpuVariant::Cmos65c02);
+        assert_eq!(
+            "BBS1 $20,$0113",
+            disassemble(&ram, 0x0100, &options).to_string()
+        );
+    }
+
+    #[test]
+    fn test_disassemble_with_symbols() {
+        let mut ram = FlatRam::default();
+        let program = [
+            0x20, 0xa0, 0x80, // JSR init_screen
+            0xb1, 0x10, // LDA (ptr),Y
+            0xd0, 0xf9, // BNE start
+            0xad, 0x00, 0x02, // LDA $0200
+        ];
+        ram[0x0000..program.len()].copy_from_slice(&program);
+
+        let mut symbols = Symbols::new();
+        symbols.insert("start", 0x0000);
+        symbols.insert("ptr", 0x0010);
+        symbols.insert("init_screen", 0x80a0);
+
+        let options = CpuOptions::default();
+        let listing: Vec<String> = [0x0000, 0x0003, 0x0005, 0x0007]
+            .iter()
+            .map(|&addr| disassemble_with_symbols(&ram, addr, &options, &symbols).to_string())
+            .collect();
+        assert_eq!(
+            vec!["JSR init_screen", "LDA (ptr),Y", "BNE start", "LDA $0200"],
+            listing
+        );
+    }
+
+    #[test]
+    fn test_listing() {
+        let mut ram = FlatRam::default();
+        let program = [
+            0xa9, 0x42, // LDA #$42
+            0x4c, 0x02, 0x02, // JMP loop
+            0x01, 0x02, 0x03, // data
+        ];
+        ram[0x0200..0x0200 + program.len()].copy_from_slice(&program);
+        let options = CpuOptions::default();
+        let mut symbols = Symbols::new();
+        symbols.insert("loop", 0x0202);
+
+        let mut executed = vec![0; 0x10000];
+        executed[0x0200] = 1;
+        executed[0x0202] = 5;
+        assert_eq!(
+            "0200  A9 42     LDA #$42\n      \
+             loop:\n\
+             0202  4C 02 02  JMP loop\n\
+             0205            .BYTE $01,$02,$03",
+            listing(&ram, 0x0200, 8, &options, Some(&symbols), Some(&executed))
+        );
+
+        // without coverage the data is decoded too
+        assert_eq!(
+            "0200  A9 42     LDA #$42\n\
+             0202  4C 02 02  JMP $0202\n\
+             0205  01 02     ORA ($02,X)\n\
+             0207  03 00     SLO ($00,X)",
+            listing(&ram, 0x0200, 8, &options, None, None)
+        );
+    }
+}
@@ -0,0 +1,153 @@
+use crate::bus::Bus;
+use crate::cpu::SnapshotDiff;
+
+// memory written out for EPROM programmers and other emulators, the other way from the
+// loader. Ranges are a start and a length, wrapping at $FFFF, and are read with peek so
+// writing them has no side effects on the bus
+
+// data bytes in each record, which most programmers expect
+const RECORD_LEN: usize = 16;
+
+// the ranges of memory that changed, with neighbouring changes joined into one range
+pub fn changed_ranges(diff: &SnapshotDiff) -> Vec<(u16, usize)> {
+    let mut ranges: Vec<(u16, usize)> = Vec::new();
+    for change in &diff.memory {
+        match ranges.last_mut() {
+            Some((start, len)) if *start as usize + *len == change.addr as usize => *len += 1,
+            _ => ranges.push((change.addr, 1)),
+        }
+    }
+    ranges
+}
+
+// the ranges split into records, none of which cross $FFFF
+fn records(bus: &impl Bus, ranges: &[(u16, usize)]) -> Vec<(u16, Vec<u8>)> {
+    let mut records = Vec::new();
+    for &(start, len) in ranges {
+        let mut addr = start;
+        let mut left = len;
+        while left > 0 {
+            let len = left.min(RECORD_LEN).min(0x10000 - addr as usize);
+            let data = (0..len)
+                .map(|i| bus.peek(addr.wrapping_add(i as u16)))
+                .collect();
+            records.push((addr, data));
+            addr = addr.wrapping_add(len as u16);
+            left -= len;
+        }
+    }
+    records
+}
+
+fn hex_record(start: char, bytes: &[u8], checksum: u8) -> String {
+    let hex: String = bytes.iter().map(|byte| format!("{:02X}", byte)).collect();
+    format!("{}{}{:02X}\n", start, hex, checksum)
+}
+
+// Intel HEX, with a start linear address record for the entry point if there is one
+//
+// :10020000A9018D0002...E4
+pub fn to_intel_hex(bus: &impl Bus, ranges: &[(u16, usize)], entry: Option<u16>) -> String {
+    // the checksum makes all the bytes of the record sum to 0
+    let record = |kind: u8, addr: u16, data: &[u8]| {
+        let mut bytes = vec![data.len() as u8, (addr >> 8) as u8, addr as u8, kind];
+        bytes.extend(data);
+        let sum = bytes.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte));
+        hex_record(':', &bytes, sum.wrapping_neg())
+    };
+
+    let mut text: String = records(bus, ranges)
+        .iter()
+        .map(|(addr, data)| record(0x00, *addr, data))
+        .collect();
+    if let Some(entry) = entry {
+        text += &record(0x05, 0, &(entry as u32).to_be_bytes());
+    }
+    text += &record(0x01, 0, &[]);
+    text
+}
+
+// Motorola S-records with 16 bit addresses: a header, S1 data records, a count of them and
+// an S9 record giving the entry point, or 0 without one
+pub fn to_srec(bus: &impl Bus, ranges: &[(u16, usize)], entry: Option<u16>) -> String {
+    // the checksum makes all the bytes of the record sum to $FF
+    let record = |kind: char, addr: u16, data: &[u8]| {
+        let mut bytes = vec![data.len() as u8 + 3, (addr >> 8) as u8, addr as u8];
+        bytes.extend(data);
+        let sum = bytes.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte));
+        format!("S{}", hex_record(kind, &bytes, !sum))
+    };
+
+    let records = records(bus, ranges);
+    let mut text = record('0', 0, b"m6502e");
+    for (addr, data) in &records {
+        text += &record('1', *addr, data);
+    }
+    // the count only has 16 bits
+    if records.len() <= 0xffff {
+        text += &record('5', records.len() as u16, &[]);
+    }
+    text += &record('9', entry.unwrap_or(0), &[]);
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::FlatRam;
+    use crate::cpu::SystemState;
+    use crate::loader::load_srec;
+
+    fn ram() -> FlatRam {
+        let mut ram = FlatRam::default();
+        for i in 0..20 {
+            ram[0x0200 + i] = i as u8;
+        }
+        ram[0xffff] = 0xee;
+        ram
+    }
+
+    #[test]
+    fn test_to_intel_hex() {
+        let text = to_intel_hex(&ram(), &[(0x0200, 20), (0xffff, 2)], Some(0x0200));
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(
+            vec![
+                ":10020000000102030405060708090A0B0C0D0E0F76",
+                ":0402100010111213A4",
+                // split where the range wraps
+                ":01FFFF00EE13",
+                ":0100000000FF",
+                ":0400000500000200F5",
+                ":00000001FF",
+            ],
+            lines
+        );
+    }
+
+    #[test]
+    fn test_to_srec() {
+        let text = to_srec(&ram(), &[(0x0200, 20)], Some(0x0200));
+        assert!(text.starts_with("S0"));
+        assert!(text.contains("S107021010111213A0\n"));
+        assert!(text.contains("S5030002FA\n"));
+        assert!(text.ends_with("S9030200FA\n"));
+
+        // and back again
+        let mut sys = SystemState::default();
+        assert_eq!(Ok(Some(0x0200)), load_srec(&mut sys, &text));
+        assert_eq!(19, sys.memory()[0x0213]);
+    }
+
+    #[test]
+    fn test_changed_ranges() {
+        let mut sys = SystemState::default();
+        let before = sys.snapshot();
+        sys.load_bytes(0x0200, &[1, 2, 3]);
+        sys.load_bytes(0x0210, &[4]);
+        assert_eq!(
+            vec![(0x0200, 3), (0x0210, 1)],
+            changed_ranges(&before.diff(&sys.snapshot()))
+        );
+    }
+}
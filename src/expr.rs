@@ -0,0 +1,357 @@
+use crate::bus::Bus;
+use crate::cpu::{Register, SystemState};
+use std::fmt;
+
+// conditions for breakpoints, such as "A == $3F && mem[$10] != 0". Operands are registers
+// (A, X, Y, S or SP, PC, P), flags (C, Z, I, D, V, N), numbers ($hex, %binary or decimal),
+// memory bytes as mem[addr], and parenthesised expressions. Comparisons and the logical
+// operators give 1 or 0, and anything nonzero is true
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ExprError {
+    pub offset: usize, // into the source text
+    pub message: String,
+}
+
+impl fmt::Display for ExprError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at offset {}", self.message, self.offset)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum BinaryOp {
+    Or,
+    And,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Add,
+    Sub,
+    BitAnd,
+    BitOr,
+    BitXor,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Expr {
+    Number(u32),
+    Register(Register),
+    Flag(u8), // mask into the status byte
+    Memory(Box<Expr>),
+    Not(Box<Expr>),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Number(u32),
+    Name(String),
+    Op(&'static str),
+}
+
+// longest first, so "&&" isn't read as two "&"s
+const OPERATORS: [&str; 18] = [
+    "||", "&&", "==", "!=", "<=", ">=", "<", ">", "+", "-", "&", "|", "^", "!", "(", ")", "[", "]",
+];
+
+fn tokenize(text: &str) -> Result<Vec<(usize, Token)>, ExprError> {
+    let mut tokens = Vec::new();
+    let mut rest = text;
+
+    loop {
+        rest = rest.trim_start();
+        let offset = text.len() - rest.len();
+        let c = match rest.chars().next() {
+            Some(c) => c,
+            None => return Ok(tokens),
+        };
+
+        let error = |message: String| ExprError { offset, message };
+
+        if let Some(op) = OPERATORS.iter().find(|op| rest.starts_with(**op)) {
+            tokens.push((offset, Token::Op(op)));
+            rest = &rest[op.len()..];
+        } else if c == '$' || c == '%' || c.is_ascii_digit() {
+            let (radix, digits) = match c {
+                '$' => (16, &rest[1..]),
+                '%' => (2, &rest[1..]),
+                _ => (10, rest),
+            };
+            let len = digits
+                .find(|c: char| !c.is_ascii_alphanumeric())
+                .unwrap_or(digits.len());
+            let value = u32::from_str_radix(&digits[..len], radix).map_err(|_| {
+                error(format!(
+                    "bad number {}",
+                    &rest[..rest.len() - digits.len() + len]
+                ))
+            })?;
+            tokens.push((offset, Token::Number(value)));
+            rest = &digits[len..];
+        } else if c.is_ascii_alphabetic() {
+            let len = rest
+                .find(|c: char| !c.is_ascii_alphanumeric())
+                .unwrap_or(rest.len());
+            tokens.push((offset, Token::Name(rest[..len].to_uppercase())));
+            rest = &rest[len..];
+        } else {
+            return Err(error(format!("unexpected {}", c)));
+        }
+    }
+}
+
+struct Parser {
+    tokens: Vec<(usize, Token)>,
+    pos: usize,
+    end: usize, // offset of the end of the text, for errors there
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos).map(|(_, token)| token)
+    }
+
+    fn error(&self, message: &str) -> ExprError {
+        let offset = self
+            .tokens
+            .get(self.pos)
+            .map_or(self.end, |&(offset, _)| offset);
+        ExprError {
+            offset,
+            message: message.to_string(),
+        }
+    }
+
+    fn eat(&mut self, op: &str) -> bool {
+        if matches!(self.peek(), Some(Token::Op(o)) if *o == op) {
+            self.pos += 1;
+            return true;
+        }
+        false
+    }
+
+    fn expect(&mut self, op: &str) -> Result<(), ExprError> {
+        if !self.eat(op) {
+            return Err(self.error(&format!("expected {}", op)));
+        }
+        Ok(())
+    }
+
+    fn binary(
+        &mut self,
+        ops: &[(&str, BinaryOp)],
+        operand: fn(&mut Parser) -> Result<Expr, ExprError>,
+    ) -> Result<Expr, ExprError> {
+        let mut left = operand(self)?;
+        'outer: loop {
+            for &(text, op) in ops {
+                if self.eat(text) {
+                    let right = operand(self)?;
+                    left = Expr::Binary(op, Box::new(left), Box::new(right));
+                    continue 'outer;
+                }
+            }
+            return Ok(left);
+        }
+    }
+
+    fn or(&mut self) -> Result<Expr, ExprError> {
+        self.binary(&[("||", BinaryOp::Or)], Parser::and)
+    }
+
+    fn and(&mut self) -> Result<Expr, ExprError> {
+        self.binary(&[("&&", BinaryOp::And)], Parser::comparison)
+    }
+
+    fn comparison(&mut self) -> Result<Expr, ExprError> {
+        self.binary(
+            &[
+                ("==", BinaryOp::Eq),
+                ("!=", BinaryOp::Ne),
+                ("<=", BinaryOp::Le),
+                (">=", BinaryOp::Ge),
+                ("<", BinaryOp::Lt),
+                (">", BinaryOp::Gt),
+            ],
+            Parser::arithmetic,
+        )
+    }
+
+    fn arithmetic(&mut self) -> Result<Expr, ExprError> {
+        self.binary(
+            &[
+                ("+", BinaryOp::Add),
+                ("-", BinaryOp::Sub),
+                ("&", BinaryOp::BitAnd),
+                ("|", BinaryOp::BitOr),
+                ("^", BinaryOp::BitXor),
+            ],
+            Parser::unary,
+        )
+    }
+
+    fn unary(&mut self) -> Result<Expr, ExprError> {
+        if self.eat("!") {
+            return Ok(Expr::Not(Box::new(self.unary()?)));
+        }
+        if self.eat("(") {
+            let expr = self.or()?;
+            self.expect(")")?;
+            return Ok(expr);
+        }
+
+        let token = match self.peek() {
+            Some(token) => token.clone(),
+            None => return Err(self.error("expected an operand")),
+        };
+        let expr = match token {
+            Token::Number(value) => Expr::Number(value),
+            Token::Name(name) => match name.as_str() {
+                "A" => Expr::Register(Register::A),
+                "X" => Expr::Register(Register::X),
+                "Y" => Expr::Register(Register::Y),
+                "S" | "SP" => Expr::Register(Register::S),
+                "PC" => Expr::Register(Register::Pc),
+                "P" => Expr::Register(Register::Status),
+                "C" => Expr::Flag(0x01),
+                "Z" => Expr::Flag(0x02),
+                "I" => Expr::Flag(0x04),
+                "D" => Expr::Flag(0x08),
+                "V" => Expr::Flag(0x40),
+                "N" => Expr::Flag(0x80),
+                "MEM" => {
+                    self.pos += 1;
+                    self.expect("[")?;
+                    let addr = self.or()?;
+                    self.expect("]")?;
+                    return Ok(Expr::Memory(Box::new(addr)));
+                }
+                _ => return Err(self.error(&format!("unknown name {}", name))),
+            },
+            Token::Op(op) => return Err(self.error(&format!("unexpected {}", op))),
+        };
+        self.pos += 1;
+
+        Ok(expr)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Condition {
+    source: String,
+    expr: Expr,
+}
+
+impl Condition {
+    pub fn parse(text: &str) -> Result<Self, ExprError> {
+        let mut parser = Parser {
+            tokens: tokenize(text)?,
+            pos: 0,
+            end: text.len(),
+        };
+        let expr = parser.or()?;
+        if parser.peek().is_some() {
+            return Err(parser.error("unexpected trailing input"));
+        }
+
+        Ok(Condition {
+            source: text.to_string(),
+            expr,
+        })
+    }
+
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    pub fn evaluate<B: Bus>(&self, sys: &SystemState<B>) -> u32 {
+        evaluate(&self.expr, sys)
+    }
+
+    pub fn is_met<B: Bus>(&self, sys: &SystemState<B>) -> bool {
+        self.evaluate(sys) != 0
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
+
+fn evaluate<B: Bus>(expr: &Expr, sys: &SystemState<B>) -> u32 {
+    match expr {
+        Expr::Number(value) => *value,
+        Expr::Register(register) => sys.cpu_state().register(*register) as u32,
+        Expr::Flag(mask) => (sys.cpu_state().status_byte() & mask != 0) as u32,
+        Expr::Memory(addr) => sys.memory().peek(evaluate(addr, sys) as u16) as u32,
+        Expr::Not(operand) => (evaluate(operand, sys) == 0) as u32,
+        Expr::Binary(op, left, right) => {
+            let left = evaluate(left, sys);
+            // the logical operators short-circuit
+            match op {
+                BinaryOp::Or if left != 0 => return 1,
+                BinaryOp::And if left == 0 => return 0,
+                _ => {}
+            }
+            let right = evaluate(right, sys);
+
+            match op {
+                BinaryOp::Or | BinaryOp::And => (right != 0) as u32,
+                BinaryOp::Eq => (left == right) as u32,
+                BinaryOp::Ne => (left != right) as u32,
+                BinaryOp::Lt => (left < right) as u32,
+                BinaryOp::Le => (left <= right) as u32,
+                BinaryOp::Gt => (left > right) as u32,
+                BinaryOp::Ge => (left >= right) as u32,
+                BinaryOp::Add => left.wrapping_add(right),
+                BinaryOp::Sub => left.wrapping_sub(right),
+                BinaryOp::BitAnd => left & right,
+                BinaryOp::BitOr => left | right,
+                BinaryOp::BitXor => left ^ right,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_condition() {
+        let mut sys = SystemState::default();
+        sys.load_bytes(0x0010, &[0x00, 0x80]);
+        sys.cpu_state_mut().set_a(0x3f);
+        sys.cpu_state_mut().set_pc(0x1234);
+        sys.cpu_state_mut().set_status_byte(0x01);
+
+        let is_met = |text: &str| Condition::parse(text).unwrap().is_met(&sys);
+
+        assert!(!is_met("A == $3F && mem[$10] != 0"));
+        assert!(is_met("A == $3F && mem[$11] != 0"));
+        assert!(is_met("a == 63 || x"));
+        assert!(is_met("pc >= $1200 && pc < $1300"));
+        assert!(is_met("C && !Z"));
+        assert!(is_met("mem[$10 + 1] & %10000000"));
+        assert!(is_met("(x + 1) == 1 && sp == $FF"));
+        assert!(!is_met("mem[$0F + 1]"));
+        assert_eq!(0x41, Condition::parse("A + 2").unwrap().evaluate(&sys));
+    }
+
+    #[test]
+    fn test_condition_errors() {
+        let error = |text: &str| Condition::parse(text).unwrap_err();
+
+        assert_eq!(5, error("A == Q").offset);
+        assert_eq!(4, error("A ==").offset);
+        assert_eq!(11, error("mem[$10 + 1").offset);
+        assert_eq!(2, error("A @ 1").offset);
+        assert_eq!(2, error("A 1").offset);
+        assert_eq!(0, error("$G1").offset);
+    }
+}
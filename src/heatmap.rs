@@ -0,0 +1,105 @@
+use crate::cpu::Access;
+
+// counts of every bus access by address, including the reads the CPU makes only because of
+// its timing. Opcode fetches count as executions as well as reads
+#[derive(Clone, Debug, PartialEq)]
+pub struct Heatmap {
+    reads: Vec<u32>,
+    writes: Vec<u32>,
+    executes: Vec<u32>,
+}
+
+impl Default for Heatmap {
+    fn default() -> Self {
+        Heatmap {
+            reads: vec![0; 0x10000],
+            writes: vec![0; 0x10000],
+            executes: vec![0; 0x10000],
+        }
+    }
+}
+
+// from cold to hot, with anything touched at all showing
+const SHADES: &[u8] = b" .:-=+*#%@";
+
+impl Heatmap {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub(crate) fn count(&mut self, addr: u16, access: Access) {
+        let counts = match access {
+            Access::Read => &mut self.reads,
+            Access::Write => &mut self.writes,
+            Access::Execute => &mut self.executes,
+        };
+        counts[addr as usize] = counts[addr as usize].saturating_add(1);
+    }
+
+    pub fn counts(&self, access: Access) -> &[u32] {
+        match access {
+            Access::Read => &self.reads,
+            Access::Write => &self.writes,
+            Access::Execute => &self.executes,
+        }
+    }
+
+    pub fn get(&self, addr: u16, access: Access) -> u32 {
+        self.counts(access)[addr as usize]
+    }
+
+    pub fn page_totals(&self, access: Access) -> Vec<u64> {
+        self.counts(access)
+            .chunks(0x100)
+            .map(|page| page.iter().map(|&count| count as u64).sum())
+            .collect()
+    }
+
+    pub fn render(&self, access: Access) -> String {
+        // a 16 by 16 grid with a character per page, shaded on a log scale relative to the
+        // busiest page
+        let totals = self.page_totals(access);
+        let max = totals.iter().copied().max().unwrap_or(0);
+        let shade = |total: u64| {
+            if total == 0 {
+                return SHADES[0] as char;
+            }
+            let level = ((total as f64).ln_1p() / (max as f64).ln_1p() * (SHADES.len() - 2) as f64)
+                .round() as usize;
+            SHADES[1 + level.min(SHADES.len() - 2)] as char
+        };
+
+        let mut lines = vec!["   0123456789ABCDEF".to_string()];
+        for (row, pages) in totals.chunks(16).enumerate() {
+            let cells: String = pages.iter().map(|&total| shade(total)).collect();
+            lines.push(format!("{:X}0 {}", row, cells));
+        }
+
+        lines.join("\n")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_heatmap() {
+        let mut heatmap = Heatmap::new();
+        for _ in 0..1000 {
+            heatmap.count(0x0210, Access::Read);
+        }
+        heatmap.count(0xfffc, Access::Read);
+        heatmap.count(0x0300, Access::Write);
+
+        assert_eq!(1000, heatmap.get(0x0210, Access::Read));
+        assert_eq!(0, heatmap.get(0x0210, Access::Write));
+        assert_eq!(1000, heatmap.page_totals(Access::Read)[0x02]);
+
+        let render = heatmap.render(Access::Read);
+        let lines: Vec<&str> = render.lines().collect();
+        assert_eq!(17, lines.len());
+        assert_eq!("00   @             ", lines[1]);
+        assert_eq!("F0                :", lines[16]);
+    }
+}
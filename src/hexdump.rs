@@ -0,0 +1,92 @@
+use crate::bus::Bus;
+
+// looking at memory without any side effects on the bus. Ranges are a start and a length,
+// wrapping at $FFFF
+
+// 16 bytes a line, as hex and as text:
+//
+// >0200 48 45 4C 4C 4F 00 00 00 00 00 00 00 00 00 00 00  HELLO...........
+pub fn hexdump(bus: &impl Bus, start: u16, len: usize) -> String {
+    let bytes: Vec<u8> = (0..len)
+        .map(|i| bus.peek(start.wrapping_add(i as u16)))
+        .collect();
+
+    let lines: Vec<String> = bytes
+        .chunks(16)
+        .enumerate()
+        .map(|(i, line)| {
+            let hex: Vec<String> = line.iter().map(|byte| format!("{:02X}", byte)).collect();
+            let text: String = line
+                .iter()
+                .map(|&byte| match byte {
+                    0x20..=0x7e => byte as char,
+                    _ => '.',
+                })
+                .collect();
+            let addr = start.wrapping_add(i as u16 * 16);
+            format!(">{:04X} {:<47}  {}", addr, hex.join(" "), text)
+        })
+        .collect();
+
+    lines.join("\n")
+}
+
+// the addresses in the range where the pattern starts, with None in the pattern matching
+// any byte. Matches may run past the end of the range
+pub fn search(bus: &impl Bus, start: u16, len: usize, pattern: &[Option<u8>]) -> Vec<u16> {
+    (0..len)
+        .map(|i| start.wrapping_add(i as u16))
+        .filter(|&addr| {
+            pattern.iter().enumerate().all(|(i, byte)| match byte {
+                Some(byte) => bus.peek(addr.wrapping_add(i as u16)) == *byte,
+                None => true,
+            })
+        })
+        .collect()
+}
+
+pub fn search_text(bus: &impl Bus, start: u16, len: usize, text: &str) -> Vec<u16> {
+    let pattern: Vec<Option<u8>> = text.bytes().map(Some).collect();
+    search(bus, start, len, &pattern)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::FlatRam;
+
+    #[test]
+    fn test_hexdump() {
+        let mut ram = FlatRam::default();
+        ram[0x0200..0x0205].copy_from_slice(b"HELLO");
+        ram[0x0210] = 0xff;
+
+        assert_eq!(
+            ">0200 48 45 4C 4C 4F 00 00 00 00 00 00 00 00 00 00 00  HELLO...........\n\
+             >0210 FF 00 00                                         ...",
+            hexdump(&ram, 0x0200, 0x13)
+        );
+        assert_eq!(
+            ">FFFF 00 00                                            ..",
+            hexdump(&ram, 0xffff, 2)
+        );
+    }
+
+    #[test]
+    fn test_search() {
+        let mut ram = FlatRam::default();
+        ram[0x0200..0x0205].copy_from_slice(b"HELLO");
+        ram[0x0300..0x0305].copy_from_slice(b"HELP!");
+
+        assert_eq!(
+            vec![0x0200, 0x0300],
+            search_text(&ram, 0x0000, 0x10000, "HEL")
+        );
+        assert_eq!(vec![0x0300], search_text(&ram, 0x0201, 0x1000, "HEL"));
+        assert_eq!(
+            vec![0x0202],
+            search(&ram, 0x0000, 0x10000, &[Some(b'L'), None, Some(b'O')])
+        );
+        assert!(search_text(&ram, 0x0000, 0x10000, "GOODBYE").is_empty());
+    }
+}
@@ -0,0 +1,21 @@
+pub mod asm;
+pub mod bus;
+pub mod cpu;
+pub mod debugger;
+pub mod disasm;
+pub mod export;
+pub mod expr;
+pub mod heatmap;
+pub mod hexdump;
+pub mod loader;
+pub mod profile;
+#[cfg(feature = "serde")]
+pub mod savestate;
+#[cfg(feature = "scripting")]
+pub mod script;
+#[cfg(feature = "serde")]
+pub mod single_step;
+pub mod symbols;
+pub mod timeline;
+pub mod trace;
+pub mod vcd;
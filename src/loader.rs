@@ -0,0 +1,1078 @@
+use crate::bus::Bus;
+use crate::cpu::{run_until, RunUntil, SystemState};
+use crate::symbols::Symbols;
+use std::fmt;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum LoadError {
+    OutOfRange { addr: u16, len: usize }, // would run past $FFFF
+    BadChecksum { line: usize },          // counting from 1
+    Invalid { line: usize, message: String },
+    BadHeader(String),
+    UnsupportedMapper(u8),
+    Malformed(String),
+    InitFailed(u16),    // an init routine at the address didn't return
+    Unresolved(String), // a reference to a symbol from another object
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LoadError::OutOfRange { addr, len } => {
+                write!(f, "{} bytes at ${:04X} would run past $FFFF", len, addr)
+            }
+            LoadError::BadChecksum { line } => write!(f, "line {}: bad checksum", line),
+            LoadError::Invalid { line, message } => write!(f, "line {}: {}", line, message),
+            LoadError::BadHeader(message) => write!(f, "bad header: {}", message),
+            LoadError::UnsupportedMapper(mapper) => write!(f, "mapper {} isn't supported", mapper),
+            LoadError::Malformed(message) => write!(f, "{}", message),
+            LoadError::InitFailed(addr) => {
+                write!(f, "the init routine at ${:04X} didn't return", addr)
+            }
+            LoadError::Unresolved(name) => write!(f, "{} is undefined", name),
+        }
+    }
+}
+
+const RESET_VECTOR: u16 = 0xfffc;
+
+// the biggest image a patch can make, which is as far as IPS offsets reach and more than
+// any cartridge for a 6502
+const MAX_PATCHED_LEN: usize = 1 << 24;
+
+fn check_range(addr: u16, len: usize) -> Result<(), LoadError> {
+    if addr as usize + len > 0x10000 {
+        return Err(LoadError::OutOfRange { addr, len });
+    }
+    Ok(())
+}
+
+fn set_entry<B: Bus>(sys: &mut SystemState<B>, entry: u16, loaded: &[(u16, usize)]) {
+    // the PC is set to start there now, and the reset vector to start there after a reset,
+    // unless what was loaded has its own vectors
+    let covers_vector = loaded.iter().any(|&(addr, len)| {
+        let end = addr as usize + len;
+        (addr as usize) < RESET_VECTOR as usize + 2 && end > RESET_VECTOR as usize
+    });
+    if !covers_vector {
+        sys.load_bytes(RESET_VECTOR, &entry.to_le_bytes());
+    }
+    sys.cpu_state_mut().set_pc(entry);
+}
+
+// a flat binary copied to memory as it is, like a ROM image or a program assembled with
+// no header
+pub fn load_raw<B: Bus>(
+    sys: &mut SystemState<B>,
+    bytes: &[u8],
+    load_addr: u16,
+    entry: Option<u16>,
+) -> Result<(), LoadError> {
+    check_range(load_addr, bytes.len())?;
+    sys.load_bytes(load_addr, bytes);
+
+    if let Some(entry) = entry {
+        set_entry(sys, entry, &[(load_addr, bytes.len())]);
+    }
+    Ok(())
+}
+
+// Motorola S-records, with lines like
+//
+// S1130800A9018D0002A2FF9A60000000000000000046
+//
+// giving the type, byte count, address, data and checksum in hex. Data records may have
+// 16, 24 or 32 bit addresses as long as they fit in 16. Returns the start address, which
+// the PC and reset vector are set to, if there's a termination record with one. A start
+// of $0000 is taken as none, as tools write that when they're not given one
+pub fn load_srec<B: Bus>(sys: &mut SystemState<B>, text: &str) -> Result<Option<u16>, LoadError> {
+    let mut loaded = Vec::new();
+    let mut start = None;
+
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let invalid = |message: &str| LoadError::Invalid {
+            line: i + 1,
+            message: message.to_string(),
+        };
+
+        let kind = match line.strip_prefix('S').and_then(|rest| rest.chars().next()) {
+            Some(kind) => kind,
+            None => return Err(invalid("expected a record starting with S")),
+        };
+        let bytes = line
+            .get(1 + kind.len_utf8()..)
+            .and_then(parse_hex_bytes)
+            .ok_or_else(|| invalid("bad hex"))?;
+        if bytes.is_empty() || bytes[0] as usize != bytes.len() - 1 {
+            return Err(invalid("byte count doesn't match the record"));
+        }
+        let sum = bytes.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte));
+        if sum != 0xff {
+            return Err(LoadError::BadChecksum { line: i + 1 });
+        }
+
+        // the count, address and checksum around the data
+        let addr_len = match kind {
+            '0' | '1' | '5' | '9' => 2,
+            '2' | '6' | '8' => 3,
+            '3' | '7' => 4,
+            _ => return Err(invalid(&format!("unknown record type S{}", kind))),
+        };
+        if bytes.len() < addr_len + 2 {
+            return Err(invalid("record too short"));
+        }
+        let addr = bytes[1..1 + addr_len]
+            .iter()
+            .fold(0u32, |addr, &byte| addr << 8 | byte as u32);
+        let data = &bytes[1 + addr_len..bytes.len() - 1];
+        let addr =
+            u16::try_from(addr).map_err(|_| invalid(&format!("address ${:X} past $FFFF", addr)))?;
+
+        match kind {
+            '1' | '2' | '3' => {
+                check_range(addr, data.len())?;
+                sys.load_bytes(addr, data);
+                loaded.push((addr, data.len()));
+            }
+            '7' | '8' | '9' if addr != 0 => start = Some(addr),
+            // headers, record counts and termination without a start
+            _ => {}
+        }
+    }
+
+    if let Some(start) = start {
+        set_entry(sys, start, &loaded);
+    }
+    Ok(start)
+}
+
+// the MOS paper tape format of the KIM-1 and its relatives, with lines like
+//
+// ;180200A9018D0002...0B7A
+//
+// giving the byte count, address, data and a 16 bit sum of the bytes before it in hex. The
+// last record has a count of 0, and the number of records in place of the address
+pub fn load_ptp<B: Bus>(sys: &mut SystemState<B>, text: &str) -> Result<(), LoadError> {
+    let mut records = 0;
+
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let invalid = |message: &str| LoadError::Invalid {
+            line: i + 1,
+            message: message.to_string(),
+        };
+
+        let record = line
+            .strip_prefix(';')
+            .ok_or_else(|| invalid("expected a record starting with ;"))?;
+        let bytes = parse_hex_bytes(record).ok_or_else(|| invalid("bad hex"))?;
+        if bytes.len() < 5 || bytes[0] as usize != bytes.len() - 5 {
+            return Err(invalid("byte count doesn't match the record"));
+        }
+
+        let (body, checksum) = bytes.split_at(bytes.len() - 2);
+        let sum = body
+            .iter()
+            .fold(0u16, |sum, &byte| sum.wrapping_add(byte as u16));
+        if sum != u16::from_be_bytes([checksum[0], checksum[1]]) {
+            return Err(LoadError::BadChecksum { line: i + 1 });
+        }
+
+        let addr = u16::from_be_bytes([body[1], body[2]]);
+        let data = &body[3..];
+        if data.is_empty() {
+            if addr != records {
+                return Err(invalid(&format!(
+                    "the tape says {} records, but there were {}",
+                    addr, records
+                )));
+            }
+            return Ok(());
+        }
+
+        check_range(addr, data.len())?;
+        sys.load_bytes(addr, data);
+        records += 1;
+    }
+
+    Err(LoadError::Malformed("no end of tape record".to_string()))
+}
+
+fn parse_hex_bytes(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) || !text.is_ascii() {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&text[i..i + 2], 16).ok())
+        .collect()
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mirroring {
+    Horizontal,
+    Vertical,
+    FourScreen,
+}
+
+// a NES cartridge image. Only PRG-ROM is used by the CPU, the rest is kept for whatever
+// emulates the PPU and cartridge hardware
+#[derive(Clone, Debug, PartialEq)]
+pub struct Ines {
+    pub prg_rom: Vec<u8>,
+    pub chr_rom: Vec<u8>, // empty when the cartridge has CHR-RAM instead
+    pub trainer: Option<Vec<u8>>,
+    pub mapper: u8,
+    pub mirroring: Mirroring,
+    pub battery: bool, // battery backed PRG-RAM at $6000-$7FFF
+}
+
+impl Ines {
+    pub fn parse(bytes: &[u8]) -> Result<Self, LoadError> {
+        let header = bytes
+            .get(..16)
+            .ok_or_else(|| LoadError::BadHeader("shorter than the header".to_string()))?;
+        if &header[..4] != b"NES\x1a" {
+            return Err(LoadError::BadHeader("no NES signature".to_string()));
+        }
+
+        // PRG-ROM comes in 16K banks and CHR-ROM in 8K ones, with a 512 byte trainer
+        // between the header and PRG-ROM if flag 6 says so
+        let prg_len = header[4] as usize * 0x4000;
+        let chr_len = header[5] as usize * 0x2000;
+        let flags6 = header[6];
+        let flags7 = header[7];
+        let trainer_len = if flags6 & 0x04 != 0 { 512 } else { 0 };
+
+        let body = &bytes[16..];
+        if body.len() < trainer_len + prg_len + chr_len {
+            return Err(LoadError::BadHeader(format!(
+                "{} bytes of ROM are missing",
+                trainer_len + prg_len + chr_len - body.len()
+            )));
+        }
+        let (trainer, body) = body.split_at(trainer_len);
+        let (prg_rom, body) = body.split_at(prg_len);
+
+        Ok(Ines {
+            prg_rom: prg_rom.to_vec(),
+            chr_rom: body[..chr_len].to_vec(),
+            trainer: (!trainer.is_empty()).then(|| trainer.to_vec()),
+            mapper: (flags7 & 0xf0) | (flags6 >> 4),
+            mirroring: if flags6 & 0x08 != 0 {
+                Mirroring::FourScreen
+            } else if flags6 & 0x01 != 0 {
+                Mirroring::Vertical
+            } else {
+                Mirroring::Horizontal
+            },
+            battery: flags6 & 0x02 != 0,
+        })
+    }
+}
+
+// maps the PRG-ROM of a mapper 0 (NROM) cartridge into $8000-$FFFF, with a 16K image
+// mirrored into both halves. A reset then starts it through its vector. The system should be
+// a 2A03 for NES code to run as it does on the console
+pub fn load_ines<B: Bus>(sys: &mut SystemState<B>, bytes: &[u8]) -> Result<Ines, LoadError> {
+    let ines = Ines::parse(bytes)?;
+    if ines.mapper != 0 {
+        return Err(LoadError::UnsupportedMapper(ines.mapper));
+    }
+
+    match ines.prg_rom.len() {
+        0x4000 => {
+            sys.load_bytes(0x8000, &ines.prg_rom);
+            sys.load_bytes(0xc000, &ines.prg_rom);
+        }
+        0x8000 => sys.load_bytes(0x8000, &ines.prg_rom),
+        len => {
+            return Err(LoadError::BadHeader(format!(
+                "NROM has 16K or 32K of PRG-ROM, not {}K",
+                len / 0x400
+            )))
+        }
+    }
+    if let Some(trainer) = &ines.trainer {
+        sys.load_bytes(0x7000, trainer);
+    }
+
+    Ok(ines)
+}
+
+// where Atari DOS looks for the addresses to run once a file is loaded, and to call
+// as each segment is
+const RUNAD: u16 = 0x02e0;
+const INITAD: u16 = 0x02e2;
+
+// the most an init routine can run for before it's given up on
+const INIT_CYCLES: u64 = 50_000_000;
+
+fn call<B: Bus>(sys: &mut SystemState<B>, addr: u16) -> Result<(), LoadError> {
+    // runs a subroutine as if called by a JSR that would return to $FFFE, until it does
+    let s = sys.cpu_state().s();
+    sys.load_bytes(0x0100 | s as u16, &[0xff]);
+    sys.load_bytes(0x0100 | s.wrapping_sub(1) as u16, &[0xfd]);
+    sys.cpu_state_mut().set_s(s.wrapping_sub(2));
+    sys.cpu_state_mut().set_pc(addr);
+
+    let returned =
+        |sys: &SystemState<B>| sys.cpu_state().pc() == 0xfffe && sys.cpu_state().s() == s;
+    match run_until(sys, returned, INIT_CYCLES) {
+        RunUntil::Satisfied => Ok(()),
+        _ => Err(LoadError::InitFailed(addr)),
+    }
+}
+
+// an Atari DOS executable, made of segments each giving the first and last address it
+// loads, and starting with $FFFF, which may also come before any later segment. A segment
+// that writes INITAD has the routine there called as soon as it's loaded, as DOS does.
+// Returns where the program starts, from RUNAD or else the first segment, and sets the PC
+// and reset vector to it
+pub fn load_xex<B: Bus>(sys: &mut SystemState<B>, bytes: &[u8]) -> Result<Option<u16>, LoadError> {
+    let word = |i: usize| {
+        bytes
+            .get(i..i + 2)
+            .map(|word| u16::from_le_bytes([word[0], word[1]]))
+            .ok_or_else(|| {
+                LoadError::Malformed(format!("file ends partway through a header at {}", i))
+            })
+    };
+    if word(0)? != 0xffff {
+        return Err(LoadError::BadHeader("no $FFFF at the start".to_string()));
+    }
+
+    sys.load_bytes(RUNAD, &[0x00, 0x00]);
+    let mut loaded = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        if word(i)? == 0xffff {
+            i += 2;
+        }
+        let start = word(i)?;
+        let end = word(i + 2)?;
+        if end < start {
+            return Err(LoadError::Malformed(format!(
+                "segment ends at ${:04X} before it starts at ${:04X}",
+                end, start
+            )));
+        }
+        let len = (end - start) as usize + 1;
+        let data = bytes.get(i + 4..i + 4 + len).ok_or_else(|| {
+            LoadError::Malformed(format!("segment at ${:04X} is cut short", start))
+        })?;
+        i += 4 + len;
+
+        sys.load_bytes(INITAD, &[0x00, 0x00]);
+        sys.load_bytes(start, data);
+        loaded.push((start, len));
+
+        let init = u16::from_le_bytes([sys.memory().peek(INITAD), sys.memory().peek(INITAD + 1)]);
+        if init != 0 {
+            call(sys, init)?;
+        }
+    }
+
+    let run = match u16::from_le_bytes([sys.memory().peek(RUNAD), sys.memory().peek(RUNAD + 1)]) {
+        0 => loaded.first().map(|&(start, _)| start),
+        run => Some(run),
+    };
+    if let Some(run) = run {
+        set_entry(sys, run, &loaded);
+    }
+    Ok(run)
+}
+
+// where an o65 file was put, and the symbols it exports at their new addresses
+#[derive(Clone, Debug, PartialEq)]
+pub struct O65 {
+    pub text: u16,
+    pub data: u16,
+    pub bss: u16,
+    pub zero: u16, // zero page isn't relocated
+    pub globals: Symbols,
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], LoadError> {
+        let bytes = self
+            .bytes
+            .get(self.pos..self.pos + len)
+            .ok_or_else(|| LoadError::Malformed(format!("file ends early at {}", self.pos)))?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn byte(&mut self) -> Result<u8, LoadError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn word(&mut self) -> Result<u16, LoadError> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    // BPS's variable length numbers, 7 bits a byte with the top bit ending them
+    fn number(&mut self) -> Result<usize, LoadError> {
+        let mut number = 0usize;
+        let mut shift = 1usize;
+        loop {
+            let byte = self.byte()? as usize;
+            number = number
+                .checked_add((byte & 0x7f) * shift)
+                .ok_or_else(|| LoadError::Malformed("number too large".to_string()))?;
+            if byte & 0x80 != 0 {
+                return Ok(number);
+            }
+            shift = shift
+                .checked_mul(0x80)
+                .ok_or_else(|| LoadError::Malformed("number too large".to_string()))?;
+            number = number
+                .checked_add(shift)
+                .ok_or_else(|| LoadError::Malformed("number too large".to_string()))?;
+        }
+    }
+
+    fn name(&mut self) -> Result<String, LoadError> {
+        let len = self.bytes[self.pos.min(self.bytes.len())..]
+            .iter()
+            .position(|&byte| byte == 0)
+            .ok_or_else(|| LoadError::Malformed("name without a terminating 0".to_string()))?;
+        let name = String::from_utf8_lossy(self.take(len)?).into_owned();
+        self.pos += 1;
+        Ok(name)
+    }
+}
+
+fn relocate(
+    reader: &mut Reader,
+    segment: &mut [u8],
+    diffs: &[u16; 6],
+    undefined: &[String],
+    page_wise: bool,
+) -> Result<(), LoadError> {
+    // entries give the distance from the last, starting from just before the segment, with
+    // 255 skipping 254 bytes ahead, and 0 ending the table
+    let mut pos = -1isize;
+    loop {
+        let offset = reader.byte()?;
+        match offset {
+            0 => return Ok(()),
+            255 => {
+                pos += 254;
+                continue;
+            }
+            _ => pos += offset as isize,
+        }
+
+        let kind = reader.byte()?;
+        let diff = match kind & 0x0f {
+            0 => {
+                let index = reader.word()? as usize;
+                let name = undefined.get(index).cloned().unwrap_or_default();
+                return Err(LoadError::Unresolved(name));
+            }
+            id @ 1..=5 => diffs[id as usize],
+            id => return Err(LoadError::Malformed(format!("unknown segment {}", id))),
+        };
+
+        let at = pos as usize;
+        let len = if kind & 0xe0 == 0x80 { 2 } else { 1 };
+        if at + len > segment.len() {
+            return Err(LoadError::Malformed(format!(
+                "relocation past the end of the segment at {}",
+                at
+            )));
+        }
+        match kind & 0xe0 {
+            0x80 => {
+                let word = u16::from_le_bytes([segment[at], segment[at + 1]]).wrapping_add(diff);
+                segment[at..at + 2].copy_from_slice(&word.to_le_bytes());
+            }
+            0x40 => {
+                // the low byte the high byte goes with is kept in the table, for the carry
+                let low = if page_wise { 0 } else { reader.byte()? };
+                let addr = u16::from_le_bytes([low, segment[at]]).wrapping_add(diff);
+                segment[at] = (addr >> 8) as u8;
+            }
+            0x20 => segment[at] = segment[at].wrapping_add(diff as u8),
+            kind => {
+                return Err(LoadError::Malformed(format!(
+                    "relocation type ${:02X} is only for the 65816",
+                    kind
+                )))
+            }
+        }
+    }
+}
+
+// an o65 relocatable object, as xa and ld65 write, with its text segment put at the given
+// address and its data and bss following it, zeroing the bss. Only the 16 bit 6502 form is
+// read, and references to other objects can't be resolved
+pub fn load_o65<B: Bus>(
+    sys: &mut SystemState<B>,
+    bytes: &[u8],
+    text_addr: u16,
+) -> Result<O65, LoadError> {
+    let mut reader = Reader { bytes, pos: 0 };
+    if reader.take(6).ok() != Some(&[0x01, 0x00, b'o', b'6', b'5', 0x00][..]) {
+        return Err(LoadError::BadHeader("no o65 marker".to_string()));
+    }
+    let mode = reader.word()?;
+    if mode & 0x8000 != 0 {
+        return Err(LoadError::BadHeader("65816 code".to_string()));
+    }
+    if mode & 0x2000 != 0 {
+        return Err(LoadError::BadHeader("32 bit addresses".to_string()));
+    }
+    let page_wise = mode & 0x4000 != 0;
+
+    // the zero page length and stack size that follow are only hints
+    let tbase = reader.word()?;
+    let tlen = reader.word()?;
+    let dbase = reader.word()?;
+    let dlen = reader.word()?;
+    let bbase = reader.word()?;
+    let blen = reader.word()?;
+    let zbase = reader.word()?;
+    reader.take(4)?;
+
+    // options, like the assembler and author, which the loader doesn't need
+    loop {
+        let len = reader.byte()? as usize;
+        if len == 0 {
+            break;
+        }
+        reader.take(len.saturating_sub(1))?;
+    }
+
+    let data_addr = text_addr.wrapping_add(tlen);
+    let bss_addr = data_addr.wrapping_add(dlen);
+    check_range(text_addr, tlen as usize + dlen as usize + blen as usize)?;
+    let diffs = [
+        0,
+        0,
+        text_addr.wrapping_sub(tbase),
+        data_addr.wrapping_sub(dbase),
+        bss_addr.wrapping_sub(bbase),
+        0,
+    ];
+    if page_wise && diffs.iter().any(|&diff| diff & 0xff != 0) {
+        return Err(LoadError::Malformed(
+            "relocation is by page, so segments must move by whole pages".to_string(),
+        ));
+    }
+
+    let mut text = reader.take(tlen as usize)?.to_vec();
+    let mut data = reader.take(dlen as usize)?.to_vec();
+    let undefined: Vec<String> = (0..reader.word()?)
+        .map(|_| reader.name())
+        .collect::<Result<_, _>>()?;
+    relocate(&mut reader, &mut text, &diffs, &undefined, page_wise)?;
+    relocate(&mut reader, &mut data, &diffs, &undefined, page_wise)?;
+
+    let mut globals = Symbols::new();
+    for _ in 0..reader.word()? {
+        let name = reader.name()?;
+        let segment = reader.byte()? as usize;
+        let value = reader.word()?;
+        let diff = diffs.get(segment).copied().unwrap_or(0);
+        globals.insert(&name, value.wrapping_add(diff));
+    }
+
+    sys.load_bytes(text_addr, &text);
+    sys.load_bytes(data_addr, &data);
+    sys.load_bytes(bss_addr, &vec![0; blen as usize]);
+
+    Ok(O65 {
+        text: text_addr,
+        data: data_addr,
+        bss: bss_addr,
+        zero: zbase,
+        globals,
+    })
+}
+
+// patches are applied to an image before it's loaded, picking the format by its magic
+pub fn apply_patch(image: &[u8], patch: &[u8]) -> Result<Vec<u8>, LoadError> {
+    if patch.starts_with(b"PATCH") {
+        apply_ips(image, patch)
+    } else if patch.starts_with(b"BPS1") {
+        apply_bps(image, patch)
+    } else {
+        Err(LoadError::BadHeader("not an IPS or BPS patch".to_string()))
+    }
+}
+
+// IPS: "PATCH", then records of a 24 bit offset and 16 bit length, all big endian, with the
+// bytes to write there, or with a length of 0, a count and a byte to repeat. "EOF" ends
+// them, and may be followed by a length to truncate the image to
+pub fn apply_ips(image: &[u8], patch: &[u8]) -> Result<Vec<u8>, LoadError> {
+    let mut reader = Reader {
+        bytes: patch,
+        pos: 5,
+    };
+    if !patch.starts_with(b"PATCH") {
+        return Err(LoadError::BadHeader("no PATCH magic".to_string()));
+    }
+
+    let mut image = image.to_vec();
+    let be = |bytes: &[u8]| bytes.iter().fold(0usize, |n, &byte| n << 8 | byte as usize);
+    loop {
+        let offset = reader.take(3)?;
+        if offset == b"EOF" {
+            break;
+        }
+        let offset = be(offset);
+        let len = be(reader.take(2)?);
+        let bytes = match len {
+            0 => {
+                let count = be(reader.take(2)?);
+                vec![reader.byte()?; count]
+            }
+            _ => reader.take(len)?.to_vec(),
+        };
+
+        if image.len() < offset + bytes.len() {
+            image.resize(offset + bytes.len(), 0);
+        }
+        image[offset..offset + bytes.len()].copy_from_slice(&bytes);
+    }
+    if let Ok(len) = reader.take(3) {
+        image.truncate(be(len));
+    }
+
+    Ok(image)
+}
+
+fn crc32(bytes: &[u8]) -> u32 {
+    !bytes.iter().fold(!0, |crc, &byte| {
+        (0..8).fold(crc ^ byte as u32, |crc, _| {
+            (crc >> 1) ^ (0xedb88320 & (crc & 1).wrapping_neg())
+        })
+    })
+}
+
+// BPS: "BPS1", the source, target and metadata sizes, the metadata, and then actions
+// building the target from the source, the patch and what's been built so far. It ends
+// with the CRC32s of the source, the target and the patch, which are all checked
+pub fn apply_bps(image: &[u8], patch: &[u8]) -> Result<Vec<u8>, LoadError> {
+    if !patch.starts_with(b"BPS1") || patch.len() < 16 {
+        return Err(LoadError::BadHeader("no BPS1 magic".to_string()));
+    }
+    let footer = |i: usize| {
+        let start = patch.len() - 12 + i * 4;
+        u32::from_le_bytes(patch[start..start + 4].try_into().unwrap())
+    };
+    if crc32(&patch[..patch.len() - 4]) != footer(2) {
+        return Err(LoadError::Malformed("the patch is corrupt".to_string()));
+    }
+    if crc32(image) != footer(0) {
+        return Err(LoadError::Malformed(
+            "the patch is for a different image".to_string(),
+        ));
+    }
+
+    let mut reader = Reader {
+        bytes: &patch[..patch.len() - 12],
+        pos: 4,
+    };
+    let source_len = reader.number()?;
+    let target_len = reader.number()?;
+    let metadata_len = reader.number()?;
+    reader.take(metadata_len)?;
+    if source_len != image.len() {
+        return Err(LoadError::Malformed(
+            "the patch is for a different size of image".to_string(),
+        ));
+    }
+    if target_len > MAX_PATCHED_LEN {
+        return Err(LoadError::Malformed(
+            "the patched image is too big".to_string(),
+        ));
+    }
+
+    let out_of_range = || LoadError::Malformed("an action runs outside the image".to_string());
+    let mut target = Vec::new();
+    let mut source_offset = 0isize;
+    let mut target_offset = 0isize;
+    while reader.pos < reader.bytes.len() {
+        let action = reader.number()?;
+        let len = (action >> 2) + 1;
+        if len > target_len - target.len() {
+            return Err(out_of_range());
+        }
+        // the copies move their offsets by a signed amount with the sign in the low bit
+        let mut relative = |offset: &mut isize| -> Result<usize, LoadError> {
+            let n = reader.number()?;
+            let delta = (n >> 1) as isize;
+            *offset += if n & 1 != 0 { -delta } else { delta };
+            usize::try_from(*offset).map_err(|_| out_of_range())
+        };
+
+        match action & 3 {
+            0 => {
+                let start = target.len();
+                let bytes = image.get(start..start + len).ok_or_else(out_of_range)?;
+                target.extend_from_slice(bytes);
+            }
+            1 => target.extend_from_slice(reader.take(len)?),
+            2 => {
+                let start = relative(&mut source_offset)?;
+                let bytes = image.get(start..start + len).ok_or_else(out_of_range)?;
+                target.extend_from_slice(bytes);
+                source_offset += len as isize;
+            }
+            _ => {
+                // a byte at a time, since the copy can overlap what it's making
+                let start = relative(&mut target_offset)?;
+                for i in start..start + len {
+                    let byte = *target.get(i).ok_or_else(out_of_range)?;
+                    target.push(byte);
+                }
+                target_offset += len as isize;
+            }
+        }
+    }
+
+    if target.len() != target_len || crc32(&target) != footer(1) {
+        return Err(LoadError::Malformed(
+            "the patched image doesn't match".to_string(),
+        ));
+    }
+    Ok(target)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_load_raw() {
+        let mut sys = SystemState::default();
+        load_raw(&mut sys, &[0xa9, 0x01, 0x60], 0x0800, Some(0x0800)).unwrap();
+        assert_eq!(&[0xa9, 0x01, 0x60], &sys.memory()[0x0800..0x0803]);
+        assert_eq!(0x0800, sys.cpu_state().pc());
+        assert_eq!(&[0x00, 0x08], &sys.memory()[0xfffc..0xfffe]);
+
+        // a ROM with its own vectors keeps them
+        let mut rom = vec![0xea; 0x1000];
+        rom[0xffc..0xffe].copy_from_slice(&[0x00, 0xf0]);
+        load_raw(&mut sys, &rom, 0xf000, Some(0xf100)).unwrap();
+        assert_eq!(&[0x00, 0xf0], &sys.memory()[0xfffc..0xfffe]);
+        assert_eq!(0xf100, sys.cpu_state().pc());
+
+        assert_eq!(
+            Err(LoadError::OutOfRange {
+                addr: 0xf001,
+                len: 0x1000
+            }),
+            load_raw(&mut sys, &rom, 0xf001, None)
+        );
+        assert!(load_raw(&mut sys, &rom, 0xf000, None).is_ok());
+    }
+
+    #[test]
+    fn test_load_srec() {
+        let mut sys = SystemState::default();
+        let srec = "S00600004844521B\n\
+                    S1070800A9018D00B9\n\
+                    S105080402608C\n\
+                    S5030002FA\n\
+                    S9030800F4\n";
+        assert_eq!(Ok(Some(0x0800)), load_srec(&mut sys, srec));
+        assert_eq!(
+            &[0xa9, 0x01, 0x8d, 0x00, 0x02, 0x60],
+            &sys.memory()[0x0800..0x0806]
+        );
+        assert_eq!(0x0800, sys.cpu_state().pc());
+        assert_eq!(&[0x00, 0x08], &sys.memory()[0xfffc..0xfffe]);
+
+        // 24 bit addresses, and a start of zero meaning none
+        assert_eq!(
+            Ok(None),
+            load_srec(&mut sys, "S206001000424166\nS804000000FB\n")
+        );
+        assert_eq!(&[0x42, 0x41], &sys.memory()[0x1000..0x1002]);
+
+        assert_eq!(
+            Err(LoadError::BadChecksum { line: 2 }),
+            load_srec(&mut sys, "S105080402608C\nS105080402608B\n")
+        );
+        assert!(matches!(
+            load_srec(&mut sys, "S206010000424175\n"),
+            Err(LoadError::Invalid { line: 1, .. })
+        ));
+        assert!(load_srec(&mut sys, "S1050803026\n").is_err());
+        assert!(matches!(
+            load_srec(&mut sys, "S\u{e9}0508\n"),
+            Err(LoadError::Invalid { line: 1, .. })
+        ));
+    }
+
+    fn nes_image(prg_banks: u8, flags6: u8) -> Vec<u8> {
+        let mut image = vec![b'N', b'E', b'S', 0x1a, prg_banks, 1, flags6, 0];
+        image.resize(16, 0);
+        let mut prg = vec![0xea; prg_banks as usize * 0x4000];
+        let len = prg.len();
+        prg[len - 4..len - 2].copy_from_slice(&[0x00, 0xc0]); // reset to $C000
+        prg[0] = 0x4c;
+        image.extend(prg);
+        image.extend(vec![0x55; 0x2000]);
+        image
+    }
+
+    #[test]
+    fn test_load_ines() {
+        let mut sys = SystemState::default();
+        let ines = load_ines(&mut sys, &nes_image(1, 0x01)).unwrap();
+        assert_eq!(0x4000, ines.prg_rom.len());
+        assert_eq!(vec![0x55; 0x2000], ines.chr_rom);
+        assert_eq!(Mirroring::Vertical, ines.mirroring);
+        assert!(!ines.battery);
+
+        // 16K is mirrored, so the vector at the end of the bank is also at $FFFC
+        assert_eq!(0x4c, sys.memory()[0x8000]);
+        assert_eq!(0x4c, sys.memory()[0xc000]);
+        sys.reset();
+        assert_eq!(0xc000, sys.cpu_state().pc());
+
+        let ines = load_ines(&mut sys, &nes_image(2, 0x02)).unwrap();
+        assert_eq!(Mirroring::Horizontal, ines.mirroring);
+        assert!(ines.battery);
+        assert_eq!(0xea, sys.memory()[0xc000]);
+
+        assert_eq!(
+            Err(LoadError::UnsupportedMapper(1)),
+            load_ines(&mut sys, &nes_image(1, 0x10))
+        );
+        assert!(matches!(
+            load_ines(&mut sys, &nes_image(1, 0x00)[..0x1000]),
+            Err(LoadError::BadHeader(_))
+        ));
+        assert!(matches!(Ines::parse(b"NES"), Err(LoadError::BadHeader(_))));
+    }
+
+    #[test]
+    fn test_load_xex() {
+        let mut sys = SystemState::default();
+        let xex = [
+            // $2000: LDA #$01; STA $0600; RTS, called through INITAD
+            &[0xff, 0xff, 0x00, 0x20, 0x05, 0x20][..],
+            &[0xa9, 0x01, 0x8d, 0x00, 0x06, 0x60],
+            &[0xe2, 0x02, 0xe3, 0x02, 0x00, 0x20],
+            // $3000 is run through RUNAD, after a $FFFF before the segment
+            &[0xff, 0xff, 0x00, 0x30, 0x00, 0x30, 0xea],
+            &[0xe0, 0x02, 0xe1, 0x02, 0x00, 0x30],
+        ]
+        .concat();
+
+        assert_eq!(Ok(Some(0x3000)), load_xex(&mut sys, &xex));
+        assert_eq!(0x01, sys.memory()[0x0600]);
+        assert_eq!(0x3000, sys.cpu_state().pc());
+        assert_eq!(0xff, sys.cpu_state().s());
+
+        // without RUNAD, the first segment is run
+        assert_eq!(
+            Ok(Some(0x4000)),
+            load_xex(&mut sys, &[0xff, 0xff, 0x00, 0x40, 0x00, 0x40, 0x60])
+        );
+
+        assert!(matches!(
+            load_xex(&mut sys, &[0x00, 0x40, 0x00, 0x40, 0x60]),
+            Err(LoadError::BadHeader(_))
+        ));
+        assert!(matches!(
+            load_xex(&mut sys, &[0xff, 0xff, 0x00, 0x40, 0x10, 0x40, 0x60]),
+            Err(LoadError::Malformed(_))
+        ));
+
+        // an init routine that halts the CPU rather than returning
+        assert_eq!(
+            Err(LoadError::InitFailed(0x5000)),
+            load_xex(
+                &mut sys,
+                &[0xff, 0xff, 0x00, 0x50, 0x00, 0x50, 0x02, 0xe2, 0x02, 0xe3, 0x02, 0x00, 0x50]
+            )
+        );
+    }
+
+    fn o65_file(mode: u16, undefined: &[u8], text_relocations: &[u8]) -> Vec<u8> {
+        [
+            &[0x01, 0x00, b'o', b'6', b'5', 0x00][..],
+            &mode.to_le_bytes(),
+            // text at $1000 for 6 bytes, data at $1006 for 1, bss at $1007 for 2
+            &[
+                0x00, 0x10, 0x06, 0x00, 0x06, 0x10, 0x01, 0x00, 0x07, 0x10, 0x02, 0x00,
+            ],
+            &[0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
+            &[0x04, 0x02, b'x', 0x00, 0x00],
+            // LDA data; LDA #>$10FF; RTS
+            &[0xad, 0x06, 0x10, 0xa9, 0x10, 0x60],
+            &[0x42],
+            undefined,
+            text_relocations,
+            &[0x00],
+            &[
+                0x01, 0x00, b's', b't', b'a', b'r', b't', 0x00, 0x02, 0x00, 0x10,
+            ],
+        ]
+        .concat()
+    }
+
+    #[test]
+    fn test_load_o65() {
+        let mut sys = SystemState::default();
+        // a word in data at 1, and a high byte in text at 4 with its low byte
+        let file = o65_file(0x0000, &[0x00, 0x00], &[0x02, 0x83, 0x03, 0x42, 0xff, 0x00]);
+
+        let o65 = load_o65(&mut sys, &file, 0x2080).unwrap();
+        assert_eq!((0x2080, 0x2086, 0x2087), (o65.text, o65.data, o65.bss));
+        assert_eq!(Some(0x2080), o65.globals.addr("start"));
+        assert_eq!(
+            &[0xad, 0x86, 0x20, 0xa9, 0x21, 0x60, 0x42],
+            &sys.memory()[0x2080..0x2087]
+        );
+
+        let file = o65_file(
+            0x0000,
+            &[0x01, 0x00, b'f', b'o', b'o', 0x00],
+            &[0x02, 0x80, 0x00, 0x00, 0x00],
+        );
+        assert_eq!(
+            Err(LoadError::Unresolved("foo".to_string())),
+            load_o65(&mut sys, &file, 0x2000)
+        );
+
+        // relocating by page needs segments to move by whole pages
+        let file = o65_file(0x4000, &[0x00, 0x00], &[0x05, 0x42, 0x00]);
+        assert!(matches!(
+            load_o65(&mut sys, &file, 0x2080),
+            Err(LoadError::Malformed(_))
+        ));
+        assert!(load_o65(&mut sys, &file, 0x3000).is_ok());
+        assert_eq!(0x30, sys.memory()[0x3004]);
+
+        assert!(matches!(
+            load_o65(&mut sys, &file[..20], 0x3000),
+            Err(LoadError::Malformed(_))
+        ));
+    }
+
+    #[test]
+    fn test_load_ptp() {
+        let mut sys = SystemState::default();
+        let ptp = ";050200A9018D00020140\n\
+                   ;010205600068\n\
+                   ;0000020002\n";
+        assert_eq!(Ok(()), load_ptp(&mut sys, ptp));
+        assert_eq!(
+            &[0xa9, 0x01, 0x8d, 0x00, 0x02, 0x60],
+            &sys.memory()[0x0200..0x0206]
+        );
+
+        assert_eq!(
+            Err(LoadError::BadChecksum { line: 2 }),
+            load_ptp(&mut sys, ";050200A9018D00020140\n;010205600069\n")
+        );
+        assert!(matches!(
+            load_ptp(&mut sys, ";010205600068\n;0000020002\n"),
+            Err(LoadError::Invalid { line: 2, .. })
+        ));
+        assert!(matches!(
+            load_ptp(&mut sys, ";010205600068\n"),
+            Err(LoadError::Malformed(_))
+        ));
+    }
+
+    #[test]
+    fn test_apply_ips() {
+        let image = [0u8; 8];
+        let mut patch = b"PATCH".to_vec();
+        patch.extend([0x00, 0x00, 0x02, 0x00, 0x02, 0xa9, 0x01]); // 2 bytes at 2
+        patch.extend([0x00, 0x00, 0x06, 0x00, 0x00, 0x00, 0x04, 0xea]); // 4 $EA at 6
+        patch.extend(b"EOF");
+        assert_eq!(
+            vec![0, 0, 0xa9, 0x01, 0, 0, 0xea, 0xea, 0xea, 0xea],
+            apply_patch(&image, &patch).unwrap()
+        );
+
+        // truncated
+        patch.extend([0x00, 0x00, 0x04]);
+        assert_eq!(vec![0, 0, 0xa9, 0x01], apply_ips(&image, &patch).unwrap());
+
+        assert!(apply_ips(&image, b"PATCH\x00\x00").is_err());
+        assert!(apply_patch(&image, b"nonsense").is_err());
+    }
+
+    #[test]
+    fn test_apply_bps() {
+        assert_eq!(0xcbf43926, crc32(b"123456789"));
+
+        let image = [1u8, 2, 3, 4];
+        let target = [1u8, 2, 9, 9, 9, 3, 4];
+        let mut patch = b"BPS1".to_vec();
+        patch.extend([0x84, 0x87, 0x80]); // sizes 4 and 7, no metadata
+        patch.push(0x84); // source read of 2
+        patch.push(0x81); // target read of 1
+        patch.push(9);
+        patch.extend([0x87, 0x84]); // target copy of 2 from 2, overlapping
+        patch.extend([0x86, 0x84]); // source copy of 2 from 2
+        patch.extend(crc32(&image).to_le_bytes());
+        patch.extend(crc32(&target).to_le_bytes());
+        patch.extend(crc32(&patch).to_le_bytes());
+        assert_eq!(target.to_vec(), apply_patch(&image, &patch).unwrap());
+
+        // the wrong image, and a corrupt patch
+        assert!(apply_bps(&[1, 2, 3, 5], &patch).is_err());
+        patch[8] ^= 1;
+        assert!(apply_bps(&image, &patch).is_err());
+    }
+
+    fn bps(header: &[u8], image: &[u8]) -> Vec<u8> {
+        let mut patch = b"BPS1".to_vec();
+        patch.extend(header);
+        patch.extend(crc32(image).to_le_bytes());
+        patch.extend(0u32.to_le_bytes());
+        patch.extend(crc32(&patch).to_le_bytes());
+        patch
+    }
+
+    #[test]
+    fn test_apply_bps_malformed() {
+        let image = [1u8, 2, 3, 4];
+        // a target far too big to allocate
+        let patch = bps(
+            &[0x84, 0x7f, 0x7f, 0x7f, 0x7f, 0x7f, 0x7f, 0x7f, 0x80, 0x80],
+            &image,
+        );
+        assert_eq!(
+            Err(LoadError::Malformed(
+                "the patched image is too big".to_string()
+            )),
+            apply_bps(&image, &patch)
+        );
+        // a target read past the target's size
+        let patch = bps(&[0x84, 0x81, 0x80, 0x85, 9, 9], &image);
+        assert!(apply_bps(&image, &patch).is_err());
+        // a source size with more digits than a usize has room for
+        let mut header = vec![0x7f; 9];
+        header.extend([0x80, 0x84, 0x80]);
+        assert_eq!(
+            Err(LoadError::Malformed("number too large".to_string())),
+            apply_bps(&image, &bps(&header, &image))
+        );
+    }
+}
@@ -1,7 +1,927 @@
-mod cpu;
+use m6502e_rs::asm::assemble_line;
+use m6502e_rs::cpu::{
+    Access, CpuOptions, CpuVariant, RunEvent, Snapshot, StackProblem, StackWarning, StepResult,
+    SystemState, Watch,
+};
+use m6502e_rs::debugger::{Debugger, FrameKind};
+use m6502e_rs::disasm::{disassemble, disassemble_with_symbols, listing, Instruction};
+use m6502e_rs::export::{changed_ranges, to_intel_hex, to_srec};
+use m6502e_rs::expr::Condition;
+use m6502e_rs::hexdump::{hexdump, search};
+use m6502e_rs::loader::{
+    apply_patch, load_ines, load_o65, load_ptp, load_raw, load_srec, load_xex,
+};
+use m6502e_rs::trace::compare_trace;
+use m6502e_rs::vcd::Vcd;
+use std::cell::RefCell;
+use std::env;
+use std::fs;
+use std::io::{self, BufRead, BufWriter, Write};
+use std::rc::Rc;
+
+// a machine language monitor in the style of VICE and Wozmon. Numbers are hex, with or
+// without a $, and addresses can be given by label once labels are loaded
+const HELP: &str = "\
+m [start] [end]     examine memory
+> addr bytes...     modify memory
+f start end bytes   fill memory with a repeated pattern
+t start end dest    copy memory
+h start end pattern search memory for bytes, with ?? matching any, or for \"text\"
+r [reg value]       show or set registers (a, x, y, s, pc, p)
+d [addr] [count]    disassemble
+a addr instruction  assemble a line
+z                   step one instruction
+n                   step over a subroutine call
+ret                 step out of the current subroutine
+back [count]        step back over instructions run since the last reset
+bt                  show the subroutine calls that haven't returned
+prof [on|off|count] count cycles by address and subroutine, or show the hottest
+heat [on|off|r|w|x] count accesses by address, or show reads, writes or executions by page
+timeline [on|ins|off|file]
+                    record calls and interrupts, with each instruction for ins, or
+                    write them for the Chrome trace viewer or Perfetto
+g [addr]            continue until BRK, a breakpoint, or the CPU stops
+break [addr]        set a breakpoint, or list them
+ll file             load labels from a VICE label file or an ld65 .dbg file
+cmp file            run against a nestest or CSV trace until they differ
+list file start end write a disassembly listing, with anything the heatmap hasn't seen
+                    run listed as data
+vcd [file|off]      record the pins each cycle to a VCD file for GTKWave, or stop
+save file [start end]
+                    write memory as Intel HEX, or as S-records for a .s19 or .srec
+                    file, or without a range all memory changed since loading
+del addr            delete a breakpoint
+cond [expr]         break when an expression like \"A == $3F && mem[$10] != 0\" is true,
+                    or list them
+cdel n              delete a condition
+watch [r|w|rw] start [end]
+                    break on reads or writes of memory
+unwatch             delete all watchpoints
+reset               reset the CPU
+x                   exit";
+
+#[cfg(feature = "tui")]
+mod tui;
+
+// instructions that can be stepped back over
+const HISTORY: usize = 100_000;
+
+// the clock VCD times are given at
+const VCD_CLOCK: u64 = 1_000_000;
+
+struct Monitor {
+    sys: Debugger,
+    next_examine: u16,                                  // where m continues from
+    next_disassemble: u16,                              // where d continues from
+    loaded: Snapshot, // memory as the program was loaded, for save to compare against
+    vcd: Option<Rc<RefCell<Vcd<BufWriter<fs::File>>>>>, // shared with the bus observer
+}
+
+fn parse_hex(text: &str) -> Result<u16, String> {
+    let digits = text.strip_prefix('$').unwrap_or(text);
+    u16::from_str_radix(digits, 16).map_err(|_| format!("bad number {}", text))
+}
+
+fn parse_byte(text: &str) -> Result<u8, String> {
+    match parse_hex(text)? {
+        byte @ 0..=0xff => Ok(byte as u8),
+        _ => Err(format!("{} doesn't fit in a byte", text)),
+    }
+}
+
+impl Monitor {
+    fn registers(&self) -> String {
+        let state = self.sys.cpu_state();
+        format!(
+            "  PC  SR AC XR YR SP\n.;{:04X} {:02X} {:02X} {:02X} {:02X} {:02X}",
+            state.pc(),
+            state.status_byte(),
+            state.a(),
+            state.x(),
+            state.y(),
+            state.s()
+        )
+    }
+
+    fn addr(&self, text: &str) -> Result<u16, String> {
+        // labels can be given with a . in front, or without where they can't be hex
+        let symbol = |name: &str| self.sys.symbols().and_then(|symbols| symbols.addr(name));
+        match text.strip_prefix('.') {
+            Some(name) => symbol(name).ok_or_else(|| format!("no label {}", name)),
+            None => parse_hex(text).or_else(|e| symbol(text).ok_or(e)),
+        }
+    }
+
+    fn disassemble_at(&self, addr: u16) -> Instruction {
+        match self.sys.symbols() {
+            Some(symbols) => {
+                disassemble_with_symbols(self.sys.memory(), addr, self.sys.options(), symbols)
+            }
+            None => disassemble(self.sys.memory(), addr, self.sys.options()),
+        }
+    }
+
+    fn load_labels(&mut self, args: &[&str]) -> Result<String, String> {
+        let path = match args.first() {
+            Some(path) => path,
+            None => return Err("usage: ll file".to_string()),
+        };
+        let text = fs::read_to_string(path).map_err(|e| e.to_string())?;
+
+        // labels are added to any already loaded
+        let mut symbols = self.sys.symbols().cloned().unwrap_or_default();
+        let result = if path.ends_with(".dbg") {
+            symbols.parse_ld65_dbg(&text)
+        } else {
+            symbols.parse_vice(&text)
+        };
+        result.map_err(|e| e.to_string())?;
+
+        let count = symbols.len();
+        self.sys.set_symbols(symbols);
+        Ok(format!("{} labels", count))
+    }
+
+    fn compare(&mut self, args: &[&str]) -> Result<String, String> {
+        let path = match args.first() {
+            Some(path) => path,
+            None => return Err("usage: cmp file".to_string()),
+        };
+        let text = fs::read_to_string(path).map_err(|e| e.to_string())?;
+
+        // the instructions run aren't recorded, so they can't be stepped back over
+        let result = compare_trace(&mut self.sys, &text).map_err(|e| e.to_string());
+        self.sys.clear_history();
+        self.next_disassemble = self.sys.cpu_state().pc();
+
+        match result? {
+            Some(divergence) => Ok(divergence.to_string()),
+            None => Ok("matched to the end".to_string()),
+        }
+    }
+
+    fn list(&mut self, args: &[&str]) -> Result<String, String> {
+        let (path, start, end) = match args {
+            [path, start, end] => (path, self.addr(start)?, self.addr(end)?),
+            _ => return Err("usage: list file start end".to_string()),
+        };
+
+        let executed = self
+            .sys
+            .heatmap()
+            .map(|heatmap| heatmap.counts(Access::Execute));
+        let text = listing(
+            self.sys.memory(),
+            start,
+            end.wrapping_sub(start) as usize + 1,
+            self.sys.options(),
+            self.sys.symbols(),
+            executed,
+        );
+        fs::write(path, text + "\n").map_err(|e| e.to_string())?;
+
+        Ok(match executed {
+            Some(_) => "listed using the heatmap's coverage".to_string(),
+            None => "listed as all code, turn on the heatmap with heat on to find data".to_string(),
+        })
+    }
+
+    fn record_vcd(&mut self, args: &[&str]) -> Result<String, String> {
+        // a recording already going is finished first
+        if let Some(vcd) = self.vcd.take() {
+            self.sys.clear_bus_observer();
+            let vcd = Rc::try_unwrap(vcd).ok().unwrap().into_inner();
+            vcd.finish().map_err(|e| e.to_string())?;
+        }
+
+        match args {
+            ["off"] => Ok(String::new()),
+            [path] => {
+                let file = fs::File::create(path).map_err(|e| e.to_string())?;
+                let vcd = Rc::new(RefCell::new(Vcd::new(BufWriter::new(file), VCD_CLOCK)));
+                let observer_vcd = vcd.clone();
+                self.sys
+                    .set_bus_observer(move |access| observer_vcd.borrow_mut().record(access));
+                self.vcd = Some(vcd);
+                Ok(format!("recording to {}", path))
+            }
+            _ => Err("usage: vcd file|off".to_string()),
+        }
+    }
+
+    fn save(&mut self, args: &[&str]) -> Result<String, String> {
+        let (path, ranges) = match args {
+            [path] => (
+                path,
+                changed_ranges(&self.loaded.diff(&self.sys.snapshot())),
+            ),
+            [path, start, end] => {
+                let start = self.addr(start)?;
+                let end = self.addr(end)?;
+                (path, vec![(start, end.wrapping_sub(start) as usize + 1)])
+            }
+            _ => return Err("usage: save file [start end]".to_string()),
+        };
+
+        let text = if path.ends_with(".s19") || path.ends_with(".srec") {
+            to_srec(self.sys.memory(), &ranges, None)
+        } else {
+            to_intel_hex(self.sys.memory(), &ranges, None)
+        };
+        fs::write(path, text).map_err(|e| e.to_string())?;
+
+        let count: usize = ranges.iter().map(|(_, len)| len).sum();
+        Ok(format!("{} bytes", count))
+    }
+
+    fn examine(&mut self, args: &[&str]) -> Result<String, String> {
+        let start = match args.first() {
+            Some(arg) => self.addr(arg)?,
+            None => self.next_examine,
+        };
+        let end = match args.get(1) {
+            Some(arg) => self.addr(arg)?,
+            None => start.wrapping_add(0x7f),
+        };
+
+        let len = end.wrapping_sub(start) as usize + 1;
+        self.next_examine = end.wrapping_add(1);
+        Ok(hexdump(self.sys.memory(), start, len))
+    }
+
+    fn range(&self, start: &str, end: &str) -> Result<(u16, usize), String> {
+        // the start and length of an inclusive range
+        let start = self.addr(start)?;
+        let end = self.addr(end)?;
+        Ok((start, end.wrapping_sub(start) as usize + 1))
+    }
+
+    fn fill(&mut self, args: &[&str]) -> Result<String, String> {
+        let (start, len, bytes) = match args {
+            [start, end, bytes @ ..] if !bytes.is_empty() => {
+                let (start, len) = self.range(start, end)?;
+                (start, len, bytes)
+            }
+            _ => return Err("usage: f start end bytes...".to_string()),
+        };
+        let bytes = bytes
+            .iter()
+            .map(|byte| parse_byte(byte))
+            .collect::<Result<Vec<u8>, String>>()?;
+
+        self.sys.fill(start, len, &bytes);
+        Ok(String::new())
+    }
+
+    fn transfer(&mut self, args: &[&str]) -> Result<String, String> {
+        let (start, len, dest) = match args {
+            [start, end, dest] => {
+                let (start, len) = self.range(start, end)?;
+                (start, len, self.addr(dest)?)
+            }
+            _ => return Err("usage: t start end dest".to_string()),
+        };
+
+        self.sys.copy(start, len, dest);
+        Ok(String::new())
+    }
+
+    fn hunt(&mut self, rest: &str) -> Result<String, String> {
+        // for bytes, with ?? matching any, or for "text"
+        let usage = || "usage: h start end bytes... or h start end \"text\"".to_string();
+        let args: Vec<&str> = rest.splitn(3, char::is_whitespace).collect();
+        let (start, len, pattern) = match args.as_slice() {
+            [start, end, pattern] => {
+                let (start, len) = self.range(start, end)?;
+                (start, len, pattern.trim())
+            }
+            _ => return Err(usage()),
+        };
+
+        let pattern: Vec<Option<u8>> = match pattern.strip_prefix('"') {
+            Some(text) => text.trim_end_matches('"').bytes().map(Some).collect(),
+            None => pattern
+                .split_whitespace()
+                .map(|byte| match byte {
+                    "??" => Ok(None),
+                    byte => parse_byte(byte).map(Some),
+                })
+                .collect::<Result<Vec<Option<u8>>, String>>()?,
+        };
+        if pattern.is_empty() {
+            return Err(usage());
+        }
+
+        let found: Vec<String> = search(self.sys.memory(), start, len, &pattern)
+            .iter()
+            .map(|addr| format!("${:04X}", addr))
+            .collect();
+        Ok(found.join("\n"))
+    }
+
+    fn modify(&mut self, args: &[&str]) -> Result<String, String> {
+        let (addr, bytes) = match args.split_first() {
+            Some((addr, bytes)) if !bytes.is_empty() => (self.addr(addr)?, bytes),
+            _ => return Err("usage: > addr bytes...".to_string()),
+        };
+        let bytes = bytes
+            .iter()
+            .map(|byte| parse_byte(byte))
+            .collect::<Result<Vec<u8>, String>>()?;
+
+        self.sys.load_bytes(addr, &bytes);
+        Ok(String::new())
+    }
+
+    fn set_register(&mut self, args: &[&str]) -> Result<String, String> {
+        let (register, value) = match args {
+            [] => return Ok(self.registers()),
+            [register, value] => (register.to_lowercase(), *value),
+            _ => return Err("usage: r [reg value]".to_string()),
+        };
+
+        if register == "pc" {
+            let addr = self.addr(value)?;
+            self.sys.cpu_state_mut().set_pc(addr);
+            return Ok(self.registers());
+        }
+
+        let state = self.sys.cpu_state_mut();
+        match register.as_str() {
+            "a" => state.set_a(parse_byte(value)?),
+            "x" => state.set_x(parse_byte(value)?),
+            "y" => state.set_y(parse_byte(value)?),
+            "s" | "sp" => state.set_s(parse_byte(value)?),
+            "p" | "sr" => state.set_status_byte(parse_byte(value)?),
+            _ => return Err(format!("no register {}", register)),
+        }
+
+        Ok(self.registers())
+    }
+
+    fn disassemble(&mut self, args: &[&str]) -> Result<String, String> {
+        let addr = match args.first() {
+            Some(arg) => self.addr(arg)?,
+            None => self.next_disassemble,
+        };
+        let count = match args.get(1) {
+            Some(arg) => parse_hex(arg)? as usize,
+            None => 16,
+        };
+
+        let mut lines = Vec::new();
+        let mut next = addr;
+        for _ in 0..count {
+            let instruction = self.disassemble_at(next);
+            next = next.wrapping_add(instruction.len());
+            lines.push(format_instruction(&instruction));
+        }
+
+        self.next_disassemble = next;
+        Ok(lines.join("\n"))
+    }
+
+    fn assemble(&mut self, args: &[&str]) -> Result<String, String> {
+        let (addr, instruction) = match args.split_first() {
+            Some((addr, instruction)) if !instruction.is_empty() => {
+                (self.addr(addr)?, instruction.join(" "))
+            }
+            _ => return Err("usage: a addr instruction".to_string()),
+        };
+
+        let instruction = self.replace_labels(&instruction);
+        let bytes =
+            assemble_line(&instruction, addr, self.sys.options()).map_err(|e| e.to_string())?;
+        self.sys.load_bytes(addr, &bytes);
+
+        let instruction = self.disassemble_at(addr);
+        Ok(format_instruction(&instruction))
+    }
+
+    fn replace_labels(&self, instruction: &str) -> String {
+        // labels in the operand become addresses, in the zero page form where they fit
+        let (mnemonic, operand) = instruction.split_once(' ').unwrap_or((instruction, ""));
+        let symbols = match self.sys.symbols() {
+            Some(symbols) => symbols,
+            None => return instruction.to_string(),
+        };
+
+        let mut replaced = String::new();
+        let mut rest = operand;
+        while let Some(c) = rest.chars().next() {
+            let len = rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '.'))
+                .unwrap_or(rest.len());
+            if len == 0 {
+                replaced.push(c);
+                rest = &rest[c.len_utf8()..];
+                continue;
+            }
+
+            let word = &rest[..len];
+            match symbols.addr(word.trim_start_matches('.')) {
+                Some(addr) if addr <= 0xff => replaced.push_str(&format!("${:02X}", addr)),
+                Some(addr) => replaced.push_str(&format!("${:04X}", addr)),
+                None => replaced.push_str(word),
+            }
+            rest = &rest[len..];
+        }
+
+        format!("{} {}", mnemonic, replaced)
+    }
+
+    fn profile(&mut self, args: &[&str]) -> Result<String, String> {
+        match args.first().copied() {
+            Some("on") => self.sys.start_profiling(),
+            Some("off") => {
+                self.sys.stop_profiling();
+            }
+            Some(arg) => {
+                let limit = parse_hex(arg)? as usize;
+                return self.profile_report(limit);
+            }
+            None => return self.profile_report(16),
+        }
+        Ok(String::new())
+    }
+
+    fn profile_report(&self, limit: usize) -> Result<String, String> {
+        match self.sys.profile() {
+            Some(profile) => Ok(profile.report(self.sys.symbols(), limit)),
+            None => Err("profiling is off, turn it on with prof on".to_string()),
+        }
+    }
+
+    fn timeline(&mut self, args: &[&str]) -> Result<String, String> {
+        match args.first().copied() {
+            Some("on") => self.sys.start_timeline(false),
+            Some("ins") => self.sys.start_timeline(true),
+            Some("off") => {
+                self.sys.stop_timeline();
+            }
+            Some(path) => {
+                let timeline = self
+                    .sys
+                    .timeline()
+                    .ok_or("the timeline is off, turn it on with timeline on or timeline ins")?;
+                let json = timeline.to_chrome_json(self.sys.symbols());
+                fs::write(path, json).map_err(|e| e.to_string())?;
+                return Ok(format!("{} spans", timeline.spans().len()));
+            }
+            None => return Err("usage: timeline on|ins|off|file".to_string()),
+        }
+        Ok(String::new())
+    }
+
+    fn heatmap(&mut self, args: &[&str]) -> Result<String, String> {
+        let access = match args.first().copied() {
+            Some("on") => {
+                self.sys.start_heatmap();
+                return Ok(String::new());
+            }
+            Some("off") => {
+                self.sys.stop_heatmap();
+                return Ok(String::new());
+            }
+            Some("r") | None => Access::Read,
+            Some("w") => Access::Write,
+            Some("x") => Access::Execute,
+            Some(_) => return Err("usage: heat [on|off|r|w|x]".to_string()),
+        };
+
+        match self.sys.heatmap() {
+            Some(heatmap) => Ok(heatmap.render(access)),
+            None => Err("the heatmap is off, turn it on with heat on".to_string()),
+        }
+    }
+
+    fn backtrace(&self) -> String {
+        let name = |addr: u16| match self.sys.symbols().and_then(|symbols| symbols.name(addr)) {
+            Some(name) => format!("${:04X} {}", addr, name),
+            None => format!("${:04X}", addr),
+        };
+
+        let mut lines = vec![format!("#0 {}", name(self.sys.cpu_state().pc()))];
+        for (i, frame) in self.sys.call_stack().iter().rev().enumerate() {
+            let kind = match frame.kind {
+                FrameKind::Subroutine => "",
+                FrameKind::Interrupt => " (interrupt)",
+            };
+            lines.push(format!(
+                "#{} {} called from {}{}",
+                i + 1,
+                name(frame.entry),
+                name(frame.call_addr),
+                kind
+            ));
+        }
+
+        lines.join("\n")
+    }
+
+    fn step(&mut self) -> Result<String, String> {
+        let result = self.sys.step();
+        let pc = self.sys.cpu_state().pc();
+        let next = self.disassemble_at(pc);
+
+        Ok(format!(
+            "{}\n{}",
+            describe(&result),
+            format_instruction(&next)
+        ))
+    }
+
+    fn step_back(&mut self, args: &[&str]) -> Result<String, String> {
+        let count = match args.first() {
+            Some(arg) => parse_hex(arg)? as usize,
+            None => 1,
+        };
+        let stepped = self.sys.step_back(count);
+
+        let pc = self.sys.cpu_state().pc();
+        let next = format_instruction(&self.disassemble_at(pc));
+        if stepped < count {
+            return Ok(format!("no more history\n{}", next));
+        }
+        Ok(next)
+    }
+
+    fn go(&mut self, args: &[&str]) -> Result<String, String> {
+        if let Some(arg) = args.first() {
+            let addr = self.addr(arg)?;
+            self.sys.cpu_state_mut().set_pc(addr);
+        }
+
+        // stopping at a BRK is one more condition, after the user's so their indices hold.
+        // The first instruction always runs, so g can continue from a BRK
+        let at_brk = self
+            .sys
+            .add_condition(Condition::parse("mem[PC] == 0").unwrap());
+        let event = self.sys.run();
+        self.sys.remove_condition(at_brk);
+
+        let reason = match event {
+            RunEvent::ConditionMet(index) if index == at_brk => "BRK".to_string(),
+            event => describe_event(&event),
+        };
+        Ok(format!("{}\n{}", reason, self.registers()))
+    }
+
+    fn step_over(&mut self, out: bool) -> Result<String, String> {
+        let event = if out {
+            self.sys.step_out()
+        } else {
+            self.sys.step_over()
+        };
+
+        let pc = self.sys.cpu_state().pc();
+        let next = self.disassemble_at(pc);
+        match event {
+            Some(event) => Ok(format!(
+                "{}\n{}",
+                describe_event(&event),
+                format_instruction(&next)
+            )),
+            None => Ok(format_instruction(&next)),
+        }
+    }
+
+    fn add_watchpoint(&mut self, args: &[&str]) -> Result<String, String> {
+        let (watch, args) = match args.split_first() {
+            Some((&"r", args)) => (Watch::Read, args),
+            Some((&"w", args)) => (Watch::Write, args),
+            Some((&"rw", args)) => (Watch::ReadWrite, args),
+            _ => (Watch::ReadWrite, args),
+        };
+        let start = match args.first() {
+            Some(arg) => self.addr(arg)?,
+            None => return Err("usage: watch [r|w|rw] start [end]".to_string()),
+        };
+        let end = match args.get(1) {
+            Some(arg) => self.addr(arg)?,
+            None => start,
+        };
+
+        self.sys.add_watchpoint(start..=end, watch);
+        Ok(String::new())
+    }
+
+    fn set_breakpoint(&mut self, args: &[&str]) -> Result<String, String> {
+        match args.first() {
+            Some(arg) => {
+                let addr = self.addr(arg)?;
+                self.sys.add_breakpoint(addr);
+                Ok(String::new())
+            }
+            None => {
+                let addrs: Vec<String> = self
+                    .sys
+                    .breakpoints()
+                    .map(|addr| format!("${:04X}", addr))
+                    .collect();
+                Ok(addrs.join("\n"))
+            }
+        }
+    }
+
+    fn delete_breakpoint(&mut self, args: &[&str]) -> Result<String, String> {
+        let addr = match args.first() {
+            Some(arg) => self.addr(arg)?,
+            None => return Err("usage: del addr".to_string()),
+        };
+        if !self.sys.remove_breakpoint(addr) {
+            return Err(format!("no breakpoint at ${:04X}", addr));
+        }
+        Ok(String::new())
+    }
+
+    fn add_condition(&mut self, rest: &str) -> Result<String, String> {
+        if rest.trim().is_empty() {
+            let conditions: Vec<String> = self
+                .sys
+                .conditions()
+                .iter()
+                .enumerate()
+                .map(|(i, condition)| format!("{}: {}", i, condition))
+                .collect();
+            return Ok(conditions.join("\n"));
+        }
+
+        let condition = Condition::parse(rest.trim()).map_err(|e| e.to_string())?;
+        let index = self.sys.add_condition(condition);
+        Ok(format!("{}", index))
+    }
+
+    fn delete_condition(&mut self, args: &[&str]) -> Result<String, String> {
+        let index = match args.first().map(|arg| arg.parse::<usize>()) {
+            Some(Ok(index)) if index < self.sys.conditions().len() => index,
+            _ => return Err("usage: cdel n".to_string()),
+        };
+        self.sys.remove_condition(index);
+        Ok(String::new())
+    }
+
+    fn execute(&mut self, line: &str) -> Option<Result<String, String>> {
+        // returns None when the monitor should exit
+        let (command, rest) = match line.strip_prefix('>') {
+            Some(rest) => (">", rest),
+            None => line.split_at(line.find(char::is_whitespace).unwrap_or(line.len())),
+        };
+        let args: Vec<&str> = rest.split_whitespace().collect();
+
+        let result = match command.to_lowercase().as_str() {
+            "" => Ok(String::new()),
+            "m" => self.examine(&args),
+            ">" => self.modify(&args),
+            "f" => self.fill(&args),
+            "t" => self.transfer(&args),
+            "h" => self.hunt(rest.trim()),
+            "r" => self.set_register(&args),
+            "d" => self.disassemble(&args),
+            "a" => self.assemble(&args),
+            "z" => self.step(),
+            "n" => self.step_over(false),
+            "ret" => self.step_over(true),
+            "back" => self.step_back(&args),
+            "bt" => Ok(self.backtrace()),
+            "prof" => self.profile(&args),
+            "heat" => self.heatmap(&args),
+            "timeline" => self.timeline(&args),
+            "g" => self.go(&args),
+            "ll" => self.load_labels(&args),
+            "cmp" => self.compare(&args),
+            "list" => self.list(&args),
+            "vcd" => self.record_vcd(&args),
+            "save" => self.save(&args),
+            "break" => self.set_breakpoint(&args),
+            "del" => self.delete_breakpoint(&args),
+            "cond" => self.add_condition(rest),
+            "cdel" => self.delete_condition(&args),
+            "watch" => self.add_watchpoint(&args),
+            "unwatch" => {
+                self.sys.clear_watchpoints();
+                Ok(String::new())
+            }
+            "reset" => {
+                self.sys.reset();
+                Ok(self.registers())
+            }
+            "x" | "q" => {
+                self.record_vcd(&["off"]).ok();
+                return None;
+            }
+            "?" | "help" => Ok(HELP.to_string()),
+            _ => Err(format!("unknown command {} (? for help)", command)),
+        };
+
+        Some(result)
+    }
+}
+
+fn format_instruction(instruction: &Instruction) -> String {
+    let bytes: Vec<String> = instruction
+        .bytes
+        .iter()
+        .map(|byte| format!("{:02X}", byte))
+        .collect();
+    format!(
+        ".{:04X}  {:<8} {}",
+        instruction.addr,
+        bytes.join(" "),
+        instruction
+    )
+}
+
+fn describe(result: &StepResult) -> String {
+    match result {
+        StepResult::Executed(cycles) => format!("{} cycles", cycles),
+        StepResult::Halted => "halted".to_string(),
+        StepResult::Waiting => "waiting for an interrupt".to_string(),
+        StepResult::Stopped => "stopped".to_string(),
+        StepResult::Stalled(cycles) => format!("stalled after {} cycles", cycles),
+        StepResult::Trapped(addr) => format!("trapped at ${:04X}", addr),
+    }
+}
+
+fn describe_warning(warning: &StackWarning) -> String {
+    let problem = match warning.problem {
+        StackProblem::Overflow => "stack overflow",
+        StackProblem::Underflow => "stack underflow",
+        StackProblem::UnmatchedReturn => "RTS without a JSR",
+    };
+    format!("warning: {} at ${:04X}", problem, warning.instruction_addr)
+}
+
+fn describe_event(event: &RunEvent) -> String {
+    match event {
+        RunEvent::BreakpointHit(addr) => format!("breakpoint at ${:04X}", addr),
+        RunEvent::ConditionMet(index) => format!("condition {} met", index),
+        RunEvent::WatchpointHit(hit) => format!(
+            "{} ${:04X} by the instruction at ${:04X}: ${:02X} -> ${:02X}",
+            if hit.write { "write to" } else { "read from" },
+            hit.addr,
+            hit.instruction_addr,
+            hit.old,
+            hit.new
+        ),
+        RunEvent::Halted => "halted".to_string(),
+        RunEvent::Waiting => "waiting for an interrupt".to_string(),
+        RunEvent::Stopped => "stopped".to_string(),
+        RunEvent::Stalled => "stalled".to_string(),
+        RunEvent::Trapped(addr) => format!("trapped at ${:04X}", addr),
+    }
+}
+
+// a binary file with any patches for it applied
+fn read_image(path: &str, patches: &[String]) -> Result<Vec<u8>, String> {
+    let mut bytes = fs::read(path).map_err(|e| format!("{}: {}", path, e))?;
+    for patch in patches {
+        let patch_bytes = fs::read(patch).map_err(|e| format!("{}: {}", patch, e))?;
+        bytes = apply_patch(&bytes, &patch_bytes).map_err(|e| format!("{}: {}", patch, e))?;
+    }
+    Ok(bytes)
+}
+
+fn load_program(monitor: &mut Monitor, args: &[String]) -> Result<(), String> {
+    // --raw file@addr loads a flat binary, --srec file Motorola S-records, --nes file a
+    // NES cartridge, which makes the CPU a 2A03, --xex file an Atari executable, and
+    // --o65 file@addr an o65 object relocated to there, with its globals as labels, and
+    // --ptp file a KIM-1 paper tape. --patch file applies an IPS or BPS patch to the binary
+    // file before it. The program starts at --entry addr if given, or at the start address
+    // in the files, or else from a reset through the vectors
+    let mut files: Vec<(&str, String, Vec<String>)> = Vec::new();
+    let mut entry = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(format!("{} needs a value", arg));
+        match arg.as_str() {
+            "--raw" | "--srec" | "--nes" | "--xex" | "--o65" | "--ptp" => {
+                files.push((arg.as_str(), value()?.clone(), Vec::new()))
+            }
+            "--patch" => match files.last_mut() {
+                Some((option, _, patches)) if !matches!(*option, "--srec" | "--ptp") => {
+                    patches.push(value()?.clone())
+                }
+                _ => return Err("--patch needs a binary file before it".to_string()),
+            },
+            "--entry" => entry = Some(parse_hex(value()?)?),
+            "--tui" => {}
+            _ => return Err(format!("unknown option {}", arg)),
+        }
+    }
+
+    let mut start = None;
+    for (option, file, patches) in &files {
+        match *option {
+            "--raw" => {
+                let (path, addr) = file
+                    .rsplit_once('@')
+                    .ok_or("usage: --raw file@addr".to_string())?;
+                let bytes = read_image(path, patches)?;
+                load_raw(&mut monitor.sys, &bytes, parse_hex(addr)?, entry)
+                    .map_err(|e| format!("{}: {}", path, e))?;
+            }
+            "--nes" => {
+                let bytes = read_image(file, patches)?;
+                *monitor.sys.options_mut() = CpuOptions::for_variant(CpuVariant::Ricoh2a03);
+                load_ines(&mut monitor.sys, &bytes).map_err(|e| format!("{}: {}", file, e))?;
+            }
+            "--o65" => {
+                let (path, addr) = file
+                    .rsplit_once('@')
+                    .ok_or("usage: --o65 file@addr".to_string())?;
+                let bytes = read_image(path, patches)?;
+                let o65 = load_o65(&mut monitor.sys, &bytes, parse_hex(addr)?)
+                    .map_err(|e| format!("{}: {}", path, e))?;
+
+                let mut symbols = monitor.sys.symbols().cloned().unwrap_or_default();
+                for (addr, name) in o65.globals.iter() {
+                    symbols.insert(name, addr);
+                }
+                monitor.sys.set_symbols(symbols);
+                start = Some(o65.text);
+            }
+            "--xex" => {
+                let bytes = read_image(file, patches)?;
+                start = load_xex(&mut monitor.sys, &bytes)
+                    .map_err(|e| format!("{}: {}", file, e))?
+                    .or(start);
+            }
+            "--ptp" => {
+                let text = fs::read_to_string(file).map_err(|e| format!("{}: {}", file, e))?;
+                load_ptp(&mut monitor.sys, &text).map_err(|e| format!("{}: {}", file, e))?;
+            }
+            _ => {
+                let text = fs::read_to_string(file).map_err(|e| format!("{}: {}", file, e))?;
+                start = load_srec(&mut monitor.sys, &text)
+                    .map_err(|e| format!("{}: {}", file, e))?
+                    .or(start);
+            }
+        }
+    }
+
+    match entry.or(start) {
+        Some(entry) => monitor.sys.cpu_state_mut().set_pc(entry),
+        None if !files.is_empty() => {
+            monitor.sys.reset();
+        }
+        None => {}
+    }
+    monitor.next_disassemble = monitor.sys.cpu_state().pc();
+    Ok(())
+}
 
 fn main() {
-    let mut sys = cpu::SystemState::default();
+    let mut monitor = Monitor {
+        sys: Debugger::new(SystemState::default()),
+        next_examine: 0x0000,
+        next_disassemble: 0x0000,
+        loaded: SystemState::default().snapshot(),
+        vcd: None,
+    };
+    monitor.sys.reset();
+    monitor.sys.set_history_limit(HISTORY);
+    monitor
+        .sys
+        .set_stack_warning_handler(|warning| println!("{}", describe_warning(&warning)));
+
+    let args: Vec<String> = env::args().skip(1).collect();
+    if let Err(error) = load_program(&mut monitor, &args) {
+        eprintln!("error: {}", error);
+        std::process::exit(1);
+    }
+    monitor.loaded = monitor.sys.snapshot();
+
+    // a full screen frontend when built with the tui feature
+    if args.iter().any(|arg| arg == "--tui") {
+        #[cfg(feature = "tui")]
+        {
+            tui::run(monitor).unwrap();
+            return;
+        }
+        #[cfg(not(feature = "tui"))]
+        {
+            eprintln!("built without the tui feature");
+            std::process::exit(1);
+        }
+    }
+
+    let stdin = io::stdin();
+    let mut stdout = io::stdout();
+    loop {
+        print!("(C:${:04X}) ", monitor.sys.cpu_state().pc());
+        stdout.flush().unwrap();
+
+        let mut line = String::new();
+        if stdin.lock().read_line(&mut line).unwrap() == 0 {
+            break;
+        }
 
-    cpu::emulate_op(&mut sys);
+        match monitor.execute(line.trim()) {
+            None => break,
+            Some(Ok(output)) if output.is_empty() => {}
+            Some(Ok(output)) => println!("{}", output),
+            Some(Err(error)) => println!("error: {}", error),
+        }
+    }
 }
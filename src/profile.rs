@@ -0,0 +1,120 @@
+use crate::symbols::Symbols;
+use std::collections::HashMap;
+
+// cycles spent per instruction address, and per subroutine. A subroutine is counted by its
+// entry address, or None for code not called from anywhere, and only has the cycles of its
+// own instructions, not those of the subroutines it calls
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Profile {
+    addrs: HashMap<u16, u64>,
+    subroutines: HashMap<Option<u16>, u64>,
+    total: u64,
+}
+
+impl Profile {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn add(&mut self, addr: u16, subroutine: Option<u16>, cycles: u64) {
+        *self.addrs.entry(addr).or_default() += cycles;
+        *self.subroutines.entry(subroutine).or_default() += cycles;
+        self.total += cycles;
+    }
+
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+
+    pub fn addr_cycles(&self, addr: u16) -> u64 {
+        self.addrs.get(&addr).copied().unwrap_or(0)
+    }
+
+    pub fn subroutine_cycles(&self, subroutine: Option<u16>) -> u64 {
+        self.subroutines.get(&subroutine).copied().unwrap_or(0)
+    }
+
+    pub fn hottest_addrs(&self) -> Vec<(u16, u64)> {
+        // most cycles first, then by address
+        let mut addrs: Vec<(u16, u64)> = self.addrs.iter().map(|(&a, &c)| (a, c)).collect();
+        addrs.sort_by_key(|&(addr, cycles)| (std::cmp::Reverse(cycles), addr));
+        addrs
+    }
+
+    pub fn hottest_subroutines(&self) -> Vec<(Option<u16>, u64)> {
+        let mut subroutines: Vec<(Option<u16>, u64)> =
+            self.subroutines.iter().map(|(&s, &c)| (s, c)).collect();
+        subroutines.sort_by_key(|&(subroutine, cycles)| (std::cmp::Reverse(cycles), subroutine));
+        subroutines
+    }
+
+    pub fn report(&self, symbols: Option<&Symbols>, limit: usize) -> String {
+        // the hottest subroutines and addresses, with their share of all cycles
+        let name = |addr: u16| match symbols.and_then(|symbols| symbols.name(addr)) {
+            Some(name) => format!("${:04X} {}", addr, name),
+            None => format!("${:04X}", addr),
+        };
+        let percent = |cycles: u64| cycles as f64 * 100.0 / self.total.max(1) as f64;
+
+        let mut lines = vec![format!("{} cycles", self.total), "subroutines:".to_string()];
+        for (subroutine, cycles) in self.hottest_subroutines().into_iter().take(limit) {
+            let subroutine = subroutine.map_or("(top level)".to_string(), name);
+            lines.push(format!(
+                "{:>12} {:>6.2}%  {}",
+                cycles,
+                percent(cycles),
+                subroutine
+            ));
+        }
+        lines.push("addresses:".to_string());
+        for (addr, cycles) in self.hottest_addrs().into_iter().take(limit) {
+            lines.push(format!(
+                "{:>12} {:>6.2}%  {}",
+                cycles,
+                percent(cycles),
+                name(addr)
+            ));
+        }
+
+        lines.join("\n")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_profile() {
+        let mut profile = Profile::new();
+        profile.add(0x0200, None, 6);
+        profile.add(0x0300, Some(0x0300), 2);
+        profile.add(0x0301, Some(0x0300), 2);
+        profile.add(0x0300, Some(0x0300), 2);
+
+        assert_eq!(12, profile.total());
+        assert_eq!(4, profile.addr_cycles(0x0300));
+        assert_eq!(6, profile.subroutine_cycles(Some(0x0300)));
+        assert_eq!(
+            vec![(0x0200, 6), (0x0300, 4), (0x0301, 2)],
+            profile.hottest_addrs()
+        );
+        assert_eq!(
+            vec![(None, 6), (Some(0x0300), 6)],
+            profile.hottest_subroutines()
+        );
+
+        let mut symbols = Symbols::new();
+        symbols.insert("loop", 0x0300);
+        assert_eq!(
+            "12 cycles\n\
+             subroutines:\n\
+             \x20          6  50.00%  (top level)\n\
+             \x20          6  50.00%  $0300 loop\n\
+             addresses:\n\
+             \x20          6  50.00%  $0200\n\
+             \x20          4  33.33%  $0300 loop",
+            profile.report(Some(&symbols), 2)
+        );
+    }
+}
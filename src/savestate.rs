@@ -0,0 +1,233 @@
+use crate::bus::Bus;
+use crate::cpu::{CpuVariant, SystemState};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::fmt;
+
+// whole systems saved and loaded, as JSON to read and diff, or as bincode to be small and
+// quick. Handlers, attached devices and debugging state aren't saved, so a loaded system
+// has none until they're set up again.
+//
+// save and load put a header in front, so that states can be recognised, and states saved
+// by older versions of the crate can still be loaded after the layout changes:
+//
+// "M65S"   magic
+// u16 LE   format version
+// u8       CPU variant
+// u8       flags, FLAG_JSON when the body is JSON rather than bincode
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct SaveStateError {
+    pub message: String,
+}
+
+impl fmt::Display for SaveStateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+fn error(e: impl fmt::Display) -> SaveStateError {
+    SaveStateError {
+        message: e.to_string(),
+    }
+}
+
+const MAGIC: &[u8; 4] = b"M65S";
+const HEADER_LEN: usize = 8;
+
+// the version written. Version 0 was the bare body, before there was a header
+pub const VERSION: u16 = 1;
+
+pub const FLAG_JSON: u8 = 0x01;
+const KNOWN_FLAGS: u8 = FLAG_JSON;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Json,
+    Bincode,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Header {
+    pub version: u16,
+    pub variant: CpuVariant,
+    pub flags: u8,
+}
+
+const VARIANTS: [CpuVariant; 5] = [
+    CpuVariant::Nmos6502,
+    CpuVariant::Cmos65c02,
+    CpuVariant::Ricoh2a03,
+    CpuVariant::Mos6510,
+    CpuVariant::Mos6507,
+];
+
+pub fn read_header(bytes: &[u8]) -> Result<Header, SaveStateError> {
+    if bytes.len() < HEADER_LEN || &bytes[..4] != MAGIC {
+        return Err(error("not a savestate"));
+    }
+
+    let version = u16::from_le_bytes([bytes[4], bytes[5]]);
+    let variant = *VARIANTS
+        .get(bytes[6] as usize)
+        .ok_or_else(|| error(format!("unknown CPU variant {}", bytes[6])))?;
+    let flags = bytes[7];
+    if version > VERSION || flags & !KNOWN_FLAGS != 0 {
+        return Err(error(format!(
+            "saved by a newer version, format {} with flags ${:02X}",
+            version, flags
+        )));
+    }
+
+    Ok(Header {
+        version,
+        variant,
+        flags,
+    })
+}
+
+pub fn save<B: Bus + Serialize>(
+    sys: &SystemState<B>,
+    format: Format,
+) -> Result<Vec<u8>, SaveStateError> {
+    let (flags, body) = match format {
+        Format::Json => (FLAG_JSON, to_json(sys)?.into_bytes()),
+        Format::Bincode => (0, to_bincode(sys)?),
+    };
+    let variant = VARIANTS
+        .iter()
+        .position(|&variant| variant == sys.options().variant)
+        .unwrap() as u8;
+
+    let mut bytes = MAGIC.to_vec();
+    bytes.extend(VERSION.to_le_bytes());
+    bytes.extend([variant, flags]);
+    bytes.extend(body);
+    Ok(bytes)
+}
+
+pub fn load<B: Bus + DeserializeOwned>(bytes: &[u8]) -> Result<SystemState<B>, SaveStateError> {
+    // states without a header are from version 0, as JSON or bincode
+    if !bytes.starts_with(MAGIC) {
+        return match bytes.first() {
+            Some(b'{') => from_json(std::str::from_utf8(bytes).map_err(error)?),
+            _ => from_bincode(bytes),
+        };
+    }
+
+    let header = read_header(bytes)?;
+    let body = &bytes[HEADER_LEN..];
+    // older layouts are converted here as versions are added. Version 1's body is the
+    // same as version 0's
+    if header.flags & FLAG_JSON != 0 {
+        from_json(std::str::from_utf8(body).map_err(error)?)
+    } else {
+        from_bincode(body)
+    }
+}
+
+pub fn to_json<B: Bus + Serialize>(sys: &SystemState<B>) -> Result<String, SaveStateError> {
+    serde_json::to_string(sys).map_err(error)
+}
+
+pub fn from_json<B: Bus + DeserializeOwned>(text: &str) -> Result<SystemState<B>, SaveStateError> {
+    serde_json::from_str(text).map_err(error)
+}
+
+pub fn to_bincode<B: Bus + Serialize>(sys: &SystemState<B>) -> Result<Vec<u8>, SaveStateError> {
+    bincode::serde::encode_to_vec(sys, bincode::config::standard()).map_err(error)
+}
+
+pub fn from_bincode<B: Bus + DeserializeOwned>(
+    bytes: &[u8],
+) -> Result<SystemState<B>, SaveStateError> {
+    let (sys, _) =
+        bincode::serde::decode_from_slice(bytes, bincode::config::standard()).map_err(error)?;
+    Ok(sys)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::{FlatRam, MappedBus};
+    use crate::cpu::step;
+
+    fn system() -> SystemState {
+        let mut sys = SystemState::default();
+        // $0200: LDA #$42; STA $10; JMP $0200
+        sys.load_bytes(0x0200, &[0xa9, 0x42, 0x85, 0x10, 0x4c, 0x00, 0x02]);
+        sys.cpu_state_mut().set_pc(0x0200);
+        sys.add_breakpoint(0x0204);
+        step(&mut sys);
+        sys
+    }
+
+    #[test]
+    fn test_json() {
+        let sys = system();
+        let mut loaded: SystemState<FlatRam> = from_json(&to_json(&sys).unwrap()).unwrap();
+        assert_eq!(sys.snapshot(), loaded.snapshot());
+        assert!(!loaded.is_breakpoint(0x0204));
+
+        // carries on just the same
+        step(&mut loaded);
+        assert_eq!(0x42, loaded.memory()[0x10]);
+
+        assert!(from_json::<FlatRam>("{}").is_err());
+    }
+
+    #[test]
+    fn test_bincode() {
+        let sys = system();
+        let bytes = to_bincode(&sys).unwrap();
+        let loaded: SystemState<FlatRam> = from_bincode(&bytes).unwrap();
+        assert_eq!(sys.snapshot(), loaded.snapshot());
+        assert_eq!(sys.cpu_state(), loaded.cpu_state());
+
+        assert!(from_bincode::<FlatRam>(&bytes[..100]).is_err());
+
+        let mut sys = SystemState::new(MappedBus::new());
+        sys.load_bytes(0x1234, &[0x56]);
+        let loaded: SystemState<MappedBus> = from_bincode(&to_bincode(&sys).unwrap()).unwrap();
+        assert_eq!(0x56, loaded.memory().peek(0x1234));
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let mut sys = system();
+        *sys.options_mut() = crate::cpu::CpuOptions::for_variant(CpuVariant::Mos6510);
+
+        for format in [Format::Json, Format::Bincode] {
+            let bytes = save(&sys, format).unwrap();
+            let header = read_header(&bytes).unwrap();
+            assert_eq!(VERSION, header.version);
+            assert_eq!(CpuVariant::Mos6510, header.variant);
+            assert_eq!(format == Format::Json, header.flags & FLAG_JSON != 0);
+
+            let loaded: SystemState<FlatRam> = load(&bytes).unwrap();
+            assert_eq!(sys.snapshot(), loaded.snapshot());
+            assert_eq!(CpuVariant::Mos6510, loaded.options().variant);
+        }
+    }
+
+    #[test]
+    fn test_old_and_new_versions() {
+        let sys = system();
+
+        // from before the header
+        let loaded: SystemState<FlatRam> = load(&to_bincode(&sys).unwrap()).unwrap();
+        assert_eq!(sys.snapshot(), loaded.snapshot());
+        let loaded: SystemState<FlatRam> = load(to_json(&sys).unwrap().as_bytes()).unwrap();
+        assert_eq!(sys.snapshot(), loaded.snapshot());
+
+        let mut bytes = save(&sys, Format::Bincode).unwrap();
+        bytes[4..6].copy_from_slice(&(VERSION + 1).to_le_bytes());
+        assert!(load::<FlatRam>(&bytes).is_err());
+
+        let mut bytes = save(&sys, Format::Bincode).unwrap();
+        bytes[7] = 0x80;
+        assert!(read_header(&bytes).is_err());
+        assert!(read_header(b"M65").is_err());
+    }
+}
@@ -0,0 +1,222 @@
+use crate::bus::Bus;
+use crate::cpu::{run_step, BusAccess, RunEvent, SystemState};
+use rhai::{Engine, Scope, AST};
+use std::cell::{Cell, RefCell};
+use std::fmt;
+use std::ops::RangeInclusive;
+use std::rc::Rc;
+
+// Rhai scripts run when the PC reaches an address, when memory is accessed, or at frame
+// boundaries the host marks, so behaviour can be added without recompiling. Scripts see the
+// registers as a, x, y, s, pc and p, and the cycle count as cycles, and can call peek(addr),
+// poke(addr, value) and stop(). Bus access scripts also see addr, value and write, and run
+// after the instruction that made the access
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ScriptError {
+    pub message: String,
+}
+
+impl fmt::Display for ScriptError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Hook {
+    Pc(u16),                        // before the instruction at the address runs
+    BusAccess(RangeInclusive<u16>), // reads and writes in the range
+    Frame,                          // whenever the host calls frame()
+}
+
+// the system is shared with the functions scripts call, and only borrowed by them while a
+// script runs, never while an instruction does
+pub struct ScriptHost<B: Bus + 'static> {
+    sys: Rc<RefCell<SystemState<B>>>,
+    engine: Engine,
+    hooks: Vec<(Hook, AST)>,
+    accesses: Rc<RefCell<Vec<BusAccess>>>, // made by the instruction just run
+    stopped: Rc<Cell<bool>>,
+}
+
+impl<B: Bus + 'static> ScriptHost<B> {
+    pub fn new(sys: SystemState<B>) -> Self {
+        let sys = Rc::new(RefCell::new(sys));
+        let stopped = Rc::new(Cell::new(false));
+        let mut engine = Engine::new();
+
+        let peek_sys = sys.clone();
+        engine.register_fn("peek", move |addr: i64| -> i64 {
+            peek_sys.borrow().memory().peek(addr as u16) as i64
+        });
+        let poke_sys = sys.clone();
+        engine.register_fn("poke", move |addr: i64, value: i64| {
+            poke_sys
+                .borrow_mut()
+                .load_bytes(addr as u16, &[value as u8]);
+        });
+        let stop = stopped.clone();
+        engine.register_fn("stop", move || stop.set(true));
+
+        ScriptHost {
+            sys,
+            engine,
+            hooks: Vec::new(),
+            accesses: Rc::new(RefCell::new(Vec::new())),
+            stopped,
+        }
+    }
+
+    pub fn system(&self) -> Rc<RefCell<SystemState<B>>> {
+        self.sys.clone()
+    }
+
+    pub fn add_hook(&mut self, hook: Hook, script: &str) -> Result<(), ScriptError> {
+        let ast = self.engine.compile(script).map_err(|e| ScriptError {
+            message: e.to_string(),
+        })?;
+
+        // bus accesses are collected with the system's bus observer, replacing any other
+        if matches!(hook, Hook::BusAccess(_)) {
+            let accesses = self.accesses.clone();
+            self.sys
+                .borrow_mut()
+                .set_bus_observer(move |access| accesses.borrow_mut().push(access));
+        }
+        self.hooks.push((hook, ast));
+
+        Ok(())
+    }
+
+    fn scope(&self) -> Scope<'static> {
+        let sys = self.sys.borrow();
+        let cpu_state = sys.cpu_state();
+
+        let mut scope = Scope::new();
+        scope.push("a", cpu_state.a() as i64);
+        scope.push("x", cpu_state.x() as i64);
+        scope.push("y", cpu_state.y() as i64);
+        scope.push("s", cpu_state.s() as i64);
+        scope.push("pc", cpu_state.pc() as i64);
+        scope.push("p", cpu_state.status_byte() as i64);
+        scope.push("cycles", cpu_state.cycle_count() as i64);
+        scope
+    }
+
+    fn run_script(&self, ast: &AST, mut scope: Scope) -> Result<(), ScriptError> {
+        self.engine
+            .run_ast_with_scope(&mut scope, ast)
+            .map_err(|e| ScriptError {
+                message: e.to_string(),
+            })
+    }
+
+    fn run_pc_hooks(&self) -> Result<(), ScriptError> {
+        let pc = self.sys.borrow().cpu_state().pc();
+        for (hook, ast) in &self.hooks {
+            if *hook == Hook::Pc(pc) {
+                self.run_script(ast, self.scope())?;
+            }
+        }
+        Ok(())
+    }
+
+    fn run_bus_hooks(&self) -> Result<(), ScriptError> {
+        let accesses = std::mem::take(&mut *self.accesses.borrow_mut());
+        for access in accesses {
+            for (hook, ast) in &self.hooks {
+                if let Hook::BusAccess(range) = hook {
+                    if range.contains(&access.addr) {
+                        let mut scope = self.scope();
+                        scope.push("addr", access.addr as i64);
+                        scope.push("value", access.value as i64);
+                        scope.push("write", access.write);
+                        self.run_script(ast, scope)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn frame(&mut self) -> Result<(), ScriptError> {
+        // for the host to call at the end of each video frame, or whatever suits it
+        for (hook, ast) in &self.hooks {
+            if *hook == Hook::Frame {
+                self.run_script(ast, self.scope())?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn run(&mut self) -> Result<Option<RunEvent>, ScriptError> {
+        // runs like cpu::run, with scripts run along the way. Returns None when a script
+        // called stop()
+        self.stopped.set(false);
+        loop {
+            self.run_pc_hooks()?;
+            if self.stopped.get() {
+                return Ok(None);
+            }
+
+            let event = run_step(&mut self.sys.borrow_mut());
+            self.run_bus_hooks()?;
+            if event.is_some() || self.stopped.get() {
+                return Ok(event);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn host() -> ScriptHost<crate::bus::FlatRam> {
+        let mut sys = SystemState::default();
+        // $0200: INX; STX $10; JMP $0200
+        sys.load_bytes(0x0200, &[0xe8, 0x86, 0x10, 0x4c, 0x00, 0x02]);
+        sys.cpu_state_mut().set_pc(0x0200);
+        ScriptHost::new(sys)
+    }
+
+    #[test]
+    fn test_pc_hook() {
+        let mut host = host();
+        // keeps a count at $20 of each time through the loop, until the fourth
+        host.add_hook(
+            Hook::Pc(0x0200),
+            "poke(0x20, peek(0x20) + 1); if x == 3 { stop() }",
+        )
+        .unwrap();
+
+        assert_eq!(Ok(None), host.run());
+        let sys = host.system();
+        assert_eq!(0x04, sys.borrow().memory()[0x20]);
+        assert_eq!(0x0200, sys.borrow().cpu_state().pc());
+    }
+
+    #[test]
+    fn test_bus_hook() {
+        let mut host = host();
+        host.add_hook(
+            Hook::BusAccess(0x0010..=0x0010),
+            "if write && value == 2 { poke(0x30, pc); stop() }",
+        )
+        .unwrap();
+        host.add_hook(Hook::Frame, "poke(0x31, cycles)").unwrap();
+
+        assert_eq!(Ok(None), host.run());
+        host.frame().unwrap();
+        let sys = host.system();
+        assert_eq!(0x03, sys.borrow().memory()[0x30]);
+        assert_eq!(2, sys.borrow().cpu_state().x());
+        assert_eq!(
+            sys.borrow().cpu_state().cycle_count() as u8,
+            sys.borrow().memory()[0x31]
+        );
+
+        assert!(host.add_hook(Hook::Frame, "poke(").is_err());
+    }
+}
@@ -0,0 +1,243 @@
+use crate::bus::FlatRam;
+use crate::cpu::{step, CpuOptions, SystemState};
+use serde::Deserialize;
+use std::cell::RefCell;
+use std::fmt;
+use std::rc::Rc;
+
+// a harness for the SingleStepTests suite (github.com/SingleStepTests/65x02), which has a
+// JSON file per opcode of 10,000 cases, each giving the registers and memory before and
+// after one instruction and every bus cycle in between. The suite is too big to include, so
+// the test at the bottom runs it from a copy named by SINGLE_STEP_TESTS and is ignored by
+// default:
+//
+// SINGLE_STEP_TESTS=65x02/6502/v1 cargo test --features serde -- --ignored single_step
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct SingleStepError {
+    pub message: String,
+}
+
+impl fmt::Display for SingleStepError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+#[derive(Deserialize)]
+struct Case {
+    name: String,
+    initial: State,
+    #[serde(rename = "final")]
+    expected: State,
+    cycles: Vec<(u16, u8, String)>, // "read" or "write"
+}
+
+#[derive(Deserialize)]
+struct State {
+    pc: u16,
+    s: u8,
+    a: u8,
+    x: u8,
+    y: u8,
+    p: u8,
+    ram: Vec<(u16, u8)>,
+}
+
+// the first difference found in a case
+#[derive(Clone, Debug, PartialEq)]
+pub struct Failure {
+    pub name: String, // the case's, which is its instruction bytes
+    pub message: String,
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.name, self.message)
+    }
+}
+
+// runs every case in a file, returning those that failed
+pub fn run_cases(json: &str, options: &CpuOptions) -> Result<Vec<Failure>, SingleStepError> {
+    let cases: Vec<Case> = serde_json::from_str(json).map_err(|e| SingleStepError {
+        message: e.to_string(),
+    })?;
+
+    Ok(cases
+        .iter()
+        .filter_map(|case| {
+            run_case(case, options).err().map(|message| Failure {
+                name: case.name.clone(),
+                message,
+            })
+        })
+        .collect())
+}
+
+fn run_case(case: &Case, options: &CpuOptions) -> Result<(), String> {
+    let mut sys = SystemState::new(FlatRam::default());
+    *sys.options_mut() = options.clone();
+    let state = sys.cpu_state_mut();
+    state.set_pc(case.initial.pc);
+    state.set_s(case.initial.s);
+    state.set_a(case.initial.a);
+    state.set_x(case.initial.x);
+    state.set_y(case.initial.y);
+    state.set_status_byte(case.initial.p);
+    for &(addr, value) in &case.initial.ram {
+        sys.memory_mut()[addr as usize] = value;
+    }
+
+    let cycles = Rc::new(RefCell::new(Vec::new()));
+    let observer_cycles = cycles.clone();
+    sys.set_bus_observer(move |access| {
+        let kind = if access.write { "write" } else { "read" };
+        observer_cycles
+            .borrow_mut()
+            .push((access.addr, access.value, kind.to_string()));
+    });
+    step(&mut sys);
+
+    // bits 4 and 5 aren't in the register, so whatever the suite has for them is ignored
+    let state = sys.cpu_state();
+    let expected = &case.expected;
+    for (register, actual, expected) in [
+        ("PC", state.pc(), expected.pc),
+        ("S", state.s() as u16, expected.s as u16),
+        ("A", state.a() as u16, expected.a as u16),
+        ("X", state.x() as u16, expected.x as u16),
+        ("Y", state.y() as u16, expected.y as u16),
+        (
+            "P",
+            (state.status_byte() | 0x30) as u16,
+            (expected.p | 0x30) as u16,
+        ),
+    ] {
+        if actual != expected {
+            return Err(format!(
+                "{} is ${:X} rather than ${:X}",
+                register, actual, expected
+            ));
+        }
+    }
+    for &(addr, value) in &expected.ram {
+        let actual = sys.memory()[addr as usize];
+        if actual != value {
+            return Err(format!(
+                "${:04X} is ${:02X} rather than ${:02X}",
+                addr, actual, value
+            ));
+        }
+    }
+
+    let cycles = cycles.borrow();
+    let describe = |(addr, value, kind): &(u16, u8, String)| {
+        format!("{} ${:02X} at ${:04X}", kind, value, addr)
+    };
+    for (i, (actual, expected)) in cycles.iter().zip(&case.cycles).enumerate() {
+        if actual != expected {
+            return Err(format!(
+                "cycle {} was a {} rather than a {}",
+                i + 1,
+                describe(actual),
+                describe(expected)
+            ));
+        }
+    }
+    if cycles.len() != case.cycles.len() {
+        return Err(format!(
+            "took {} cycles rather than {}",
+            cycles.len(),
+            case.cycles.len()
+        ));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::CpuVariant;
+    use std::env;
+    use std::fs;
+
+    // LDA #$42, and the same expecting the wrong flags and an extra cycle
+    const CASES: &str = r#"[
+        {
+            "name": "a9 42 00",
+            "initial": {"pc": 512, "s": 253, "a": 0, "x": 0, "y": 0, "p": 38,
+                        "ram": [[512, 169], [513, 66]]},
+            "final": {"pc": 514, "s": 253, "a": 66, "x": 0, "y": 0, "p": 36,
+                      "ram": [[512, 169], [513, 66]]},
+            "cycles": [[512, 169, "read"], [513, 66, "read"]]
+        },
+        {
+            "name": "a9 42 01",
+            "initial": {"pc": 512, "s": 253, "a": 0, "x": 0, "y": 0, "p": 38,
+                        "ram": [[512, 169], [513, 66]]},
+            "final": {"pc": 514, "s": 253, "a": 66, "x": 0, "y": 0, "p": 38,
+                      "ram": [[512, 169], [513, 66]]},
+            "cycles": [[512, 169, "read"], [513, 66, "read"]]
+        },
+        {
+            "name": "a9 42 02",
+            "initial": {"pc": 512, "s": 253, "a": 0, "x": 0, "y": 0, "p": 38,
+                        "ram": [[512, 169], [513, 66]]},
+            "final": {"pc": 514, "s": 253, "a": 66, "x": 0, "y": 0, "p": 36,
+                      "ram": [[512, 169], [513, 66]]},
+            "cycles": [[512, 169, "read"], [513, 66, "read"], [514, 0, "read"]]
+        }
+    ]"#;
+
+    #[test]
+    fn test_run_cases() {
+        assert_eq!(
+            vec![
+                Failure {
+                    name: "a9 42 01".to_string(),
+                    message: "P is $34 rather than $36".to_string(),
+                },
+                Failure {
+                    name: "a9 42 02".to_string(),
+                    message: "took 2 cycles rather than 3".to_string(),
+                },
+            ],
+            run_cases(CASES, &CpuOptions::default()).unwrap()
+        );
+        assert!(run_cases("[{}]", &CpuOptions::default()).is_err());
+    }
+
+    #[test]
+    #[ignore]
+    fn test_single_step_suite() {
+        // a directory of the suite's files for one CPU, like 65x02/6502/v1 or
+        // 65x02/wdc65c02/v1
+        let dir = env::var("SINGLE_STEP_TESTS")
+            .expect("set SINGLE_STEP_TESTS to a directory of SingleStepTests JSON files");
+        let variant = if dir.contains("65c02") {
+            CpuVariant::Cmos65c02
+        } else {
+            CpuVariant::Nmos6502
+        };
+        let options = CpuOptions::for_variant(variant);
+
+        let mut paths: Vec<_> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+            .collect();
+        paths.sort();
+
+        let mut failed_files = 0;
+        for path in &paths {
+            let json = fs::read_to_string(path).unwrap();
+            let failures = run_cases(&json, &options).unwrap();
+            if let Some(failure) = failures.first() {
+                println!("{}: {} failed, {}", path.display(), failures.len(), failure);
+                failed_files += 1;
+            }
+        }
+        assert_eq!(0, failed_files, "of {} files", paths.len());
+    }
+}
@@ -0,0 +1,198 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct SymbolError {
+    pub line: usize, // counting from 1
+    pub message: String,
+}
+
+impl fmt::Display for SymbolError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+// names for addresses, for showing `JSR init_screen` rather than `JSR $80A0`. Where an
+// address has several names, the first one added is shown
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Symbols {
+    names: BTreeMap<u16, String>,
+    addrs: HashMap<String, u16>,
+}
+
+impl Symbols {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn insert(&mut self, name: &str, addr: u16) {
+        // a name given a new address, as after reassembling, no longer names the old one
+        if let Some(old) = self.addrs.insert(name.to_string(), addr) {
+            if old != addr
+                && self
+                    .names
+                    .get(&old)
+                    .is_some_and(|old_name| old_name == name)
+            {
+                self.names.remove(&old);
+            }
+        }
+        self.names.entry(addr).or_insert_with(|| name.to_string());
+    }
+
+    pub fn name(&self, addr: u16) -> Option<&str> {
+        self.names.get(&addr).map(|name| name.as_str())
+    }
+
+    pub fn addr(&self, name: &str) -> Option<u16> {
+        self.addrs.get(name).copied()
+    }
+
+    pub fn len(&self) -> usize {
+        self.addrs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.addrs.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (u16, &str)> {
+        // in address order, with only the name shown for each
+        self.names.iter().map(|(&addr, name)| (addr, name.as_str()))
+    }
+
+    // the label files VICE writes and reads with -moncommands, with lines like
+    //
+    // al C:80a0 .init_screen
+    //
+    // other monitor commands in the file are skipped
+    pub fn parse_vice(&mut self, text: &str) -> Result<(), SymbolError> {
+        for (i, line) in text.lines().enumerate() {
+            let error = |message: &str| SymbolError {
+                line: i + 1,
+                message: message.to_string(),
+            };
+
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            match fields.as_slice() {
+                ["al", addr, name] => {
+                    // the address may be prefixed with its memory space
+                    let addr = addr.rsplit(':').next().unwrap_or(addr);
+                    let addr = u16::from_str_radix(addr, 16)
+                        .map_err(|_| error(&format!("bad address {}", addr)))?;
+                    self.insert(name.trim_start_matches('.'), addr);
+                }
+                ["al", ..] => return Err(error("expected al address .name")),
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+
+    // the debug info ld65 writes with --dbgfile, taking the labels from lines like
+    //
+    // sym id=0,name="init_screen",addrsize=absolute,scope=0,def=5,val=0x80A0,seg=1,type=lab
+    pub fn parse_ld65_dbg(&mut self, text: &str) -> Result<(), SymbolError> {
+        for (i, line) in text.lines().enumerate() {
+            let error = |message: &str| SymbolError {
+                line: i + 1,
+                message: message.to_string(),
+            };
+
+            let attributes = match line.strip_prefix("sym\t") {
+                Some(attributes) => attributes,
+                None => continue,
+            };
+            let attribute = |key: &str| {
+                attributes
+                    .split(',')
+                    .find_map(|attribute| attribute.strip_prefix(key)?.strip_prefix('='))
+            };
+
+            // constants made with = aren't addresses
+            if attribute("type") != Some("lab") {
+                continue;
+            }
+            let name = match attribute("name") {
+                Some(name) => name.trim_matches('"'),
+                None => return Err(error("symbol without a name")),
+            };
+            let val = match attribute("val") {
+                Some(val) => val,
+                None => return Err(error("symbol without a value")),
+            };
+            let addr = match val.strip_prefix("0x") {
+                Some(hex) => u16::from_str_radix(hex, 16),
+                None => val.parse(),
+            }
+            .map_err(|_| error(&format!("bad value {}", val)))?;
+
+            self.insert(name, addr);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_insert_moved() {
+        let mut symbols = Symbols::new();
+        symbols.insert("loop", 0x0200);
+        symbols.insert("start", 0x0300);
+        symbols.insert("loop", 0x0210);
+        // a name moving away from an address another name has leaves that one alone
+        symbols.insert("entry", 0x0300);
+        symbols.insert("entry", 0x0400);
+
+        assert_eq!(None, symbols.name(0x0200));
+        assert_eq!(Some("loop"), symbols.name(0x0210));
+        assert_eq!(Some(0x0210), symbols.addr("loop"));
+        assert_eq!(Some("start"), symbols.name(0x0300));
+        assert_eq!(
+            vec![(0x0210, "loop"), (0x0300, "start"), (0x0400, "entry")],
+            symbols.iter().collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_parse_vice() {
+        let mut symbols = Symbols::new();
+        symbols
+            .parse_vice("al C:80a0 .init_screen\nbreak 80a0\nal 0010 .ptr\nal 0010 .ptr_lo\n")
+            .unwrap();
+
+        assert_eq!(Some(0x80a0), symbols.addr("init_screen"));
+        assert_eq!(Some("init_screen"), symbols.name(0x80a0));
+        assert_eq!(Some("ptr"), symbols.name(0x0010));
+        assert_eq!(Some(0x0010), symbols.addr("ptr_lo"));
+        assert_eq!(3, symbols.len());
+
+        let error = symbols.parse_vice("al C:80a0 .a\nal C:xyz .b").unwrap_err();
+        assert_eq!(2, error.line);
+    }
+
+    #[test]
+    fn test_parse_ld65_dbg() {
+        let dbg = "version\tmajor=2,minor=0\n\
+                   sym\tid=0,name=\"init_screen\",addrsize=absolute,scope=0,def=5,val=0x80A0,seg=1,type=lab\n\
+                   sym\tid=1,name=\"SCREEN_WIDTH\",addrsize=zeropage,scope=0,def=2,val=0x28,type=equ\n\
+                   sym\tid=2,name=\"ptr\",addrsize=zeropage,scope=0,def=3,val=16,seg=0,type=lab\n";
+
+        let mut symbols = Symbols::new();
+        symbols.parse_ld65_dbg(dbg).unwrap();
+
+        assert_eq!(Some(0x80a0), symbols.addr("init_screen"));
+        assert_eq!(Some("ptr"), symbols.name(0x0010));
+        assert_eq!(None, symbols.addr("SCREEN_WIDTH"));
+        assert_eq!(
+            vec![(0x0010, "ptr"), (0x80a0, "init_screen")],
+            symbols.iter().collect::<Vec<(u16, &str)>>()
+        );
+    }
+}
@@ -0,0 +1,146 @@
+use crate::symbols::Symbols;
+
+// spans of time spent in subroutines and interrupt handlers, and optionally in each
+// instruction, for exploring a run in the Chrome trace viewer or Perfetto. Times are in
+// cycles, shown as microseconds as if the clock were 1 MHz
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SpanKind {
+    Subroutine,
+    Interrupt,
+    Instruction,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Span {
+    pub kind: SpanKind,
+    pub addr: u16,    // the entry, or the instruction's own address
+    pub text: String, // the instruction disassembled, and empty for calls
+    pub start: u64,
+    pub end: u64,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Timeline {
+    instructions: bool,
+    spans: Vec<Span>,                // in the order they ended
+    open: Vec<(SpanKind, u16, u64)>, // calls that haven't returned, innermost last
+    last_cycle: u64,
+}
+
+impl Timeline {
+    pub fn new(instructions: bool) -> Self {
+        Timeline {
+            instructions,
+            ..Default::default()
+        }
+    }
+
+    pub fn records_instructions(&self) -> bool {
+        self.instructions
+    }
+
+    pub(crate) fn enter(&mut self, kind: SpanKind, addr: u16, cycle: u64) {
+        self.open.push((kind, addr, cycle));
+        self.last_cycle = cycle;
+    }
+
+    pub(crate) fn leave(&mut self, cycle: u64) {
+        if let Some((kind, addr, start)) = self.open.pop() {
+            self.spans.push(Span {
+                kind,
+                addr,
+                text: String::new(),
+                start,
+                end: cycle,
+            });
+        }
+        self.last_cycle = cycle;
+    }
+
+    pub(crate) fn instruction(&mut self, addr: u16, text: String, start: u64, end: u64) {
+        self.spans.push(Span {
+            kind: SpanKind::Instruction,
+            addr,
+            text,
+            start,
+            end,
+        });
+        self.last_cycle = end;
+    }
+
+    // the finished spans, and those still going as if they'd ended at the last cycle seen
+    pub fn spans(&self) -> Vec<Span> {
+        let mut spans = self.spans.clone();
+        spans.extend(self.open.iter().rev().map(|&(kind, addr, start)| Span {
+            kind,
+            addr,
+            text: String::new(),
+            start,
+            end: self.last_cycle,
+        }));
+        spans
+    }
+
+    // the Trace Event Format, as complete events on a single thread, which the viewers nest
+    // by time
+    pub fn to_chrome_json(&self, symbols: Option<&Symbols>) -> String {
+        let name = |addr: u16| match symbols.and_then(|symbols| symbols.name(addr)) {
+            Some(name) => name.to_string(),
+            None => format!("${:04X}", addr),
+        };
+
+        let events: Vec<String> = self
+            .spans()
+            .iter()
+            .map(|span| {
+                let (name, category) = match span.kind {
+                    SpanKind::Subroutine => (name(span.addr), "subroutine"),
+                    SpanKind::Interrupt => (format!("interrupt {}", name(span.addr)), "interrupt"),
+                    SpanKind::Instruction => (span.text.clone(), "instruction"),
+                };
+                format!(
+                    "{{\"name\":\"{}\",\"cat\":\"{}\",\"ph\":\"X\",\"ts\":{},\"dur\":{},\
+                     \"pid\":1,\"tid\":1,\"args\":{{\"addr\":\"${:04X}\"}}}}",
+                    escape(&name),
+                    category,
+                    span.start,
+                    span.end - span.start,
+                    span.addr
+                )
+            })
+            .collect();
+
+        format!("{{\"traceEvents\":[\n{}\n]}}\n", events.join(",\n"))
+    }
+}
+
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chrome_json() {
+        let mut timeline = Timeline::new(true);
+        timeline.enter(SpanKind::Subroutine, 0x0300, 6);
+        timeline.instruction(0x0300, "INY".to_string(), 6, 8);
+        timeline.enter(SpanKind::Interrupt, 0x0400, 15);
+
+        let mut symbols = Symbols::new();
+        symbols.insert("print", 0x0300);
+        assert_eq!(
+            "{\"traceEvents\":[\n\
+             {\"name\":\"INY\",\"cat\":\"instruction\",\"ph\":\"X\",\"ts\":6,\"dur\":2,\
+             \"pid\":1,\"tid\":1,\"args\":{\"addr\":\"$0300\"}},\n\
+             {\"name\":\"interrupt $0400\",\"cat\":\"interrupt\",\"ph\":\"X\",\"ts\":15,\"dur\":0,\
+             \"pid\":1,\"tid\":1,\"args\":{\"addr\":\"$0400\"}},\n\
+             {\"name\":\"print\",\"cat\":\"subroutine\",\"ph\":\"X\",\"ts\":6,\"dur\":9,\
+             \"pid\":1,\"tid\":1,\"args\":{\"addr\":\"$0300\"}}\n\
+             ]}\n",
+            timeline.to_chrome_json(Some(&symbols))
+        );
+    }
+}
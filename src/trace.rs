@@ -0,0 +1,426 @@
+use crate::bus::Bus;
+use crate::cpu::{step, undocumented, AddressingMode, CpuVariant, SystemState};
+use crate::disasm::{disassemble, disassemble_with_symbols, Instruction};
+use std::fmt;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct TraceError {
+    pub line: usize, // counting from 1
+    pub message: String,
+}
+
+impl fmt::Display for TraceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+// the registers before an instruction runs, as a reference trace gives them
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TraceState {
+    pub pc: u16,
+    pub a: u8,
+    pub x: u8,
+    pub y: u8,
+    pub p: u8,
+    pub s: u8,
+    pub cycles: Option<u64>,
+}
+
+impl TraceState {
+    pub fn of<B: Bus>(sys: &SystemState<B>) -> Self {
+        let cpu_state = sys.cpu_state();
+        TraceState {
+            pc: cpu_state.pc(),
+            a: cpu_state.a(),
+            x: cpu_state.x(),
+            y: cpu_state.y(),
+            p: cpu_state.status_byte(),
+            s: cpu_state.s(),
+            cycles: Some(cpu_state.cycle_count()),
+        }
+    }
+
+    // a line of a nestest-style log, taking the PC from the start of the line and the
+    // registers from their A:, X:, Y:, P:, SP: and CYC: fields, in any order
+    pub fn parse_nestest(line: &str) -> Result<Self, String> {
+        let pc = line.get(..4).ok_or("line too short")?;
+        let pc = u16::from_str_radix(pc, 16).map_err(|_| format!("bad PC {}", pc))?;
+
+        let field = |name: &str| {
+            line.split_whitespace()
+                .find_map(|token| token.strip_prefix(name)?.strip_prefix(':'))
+        };
+        let register = |name: &str| {
+            let value = field(name).ok_or(format!("no {} field", name))?;
+            u8::from_str_radix(value, 16).map_err(|_| format!("bad {} {}", name, value))
+        };
+        let cycles = match field("CYC") {
+            Some(cycles) => Some(cycles.parse().map_err(|_| format!("bad CYC {}", cycles))?),
+            None => None,
+        };
+
+        Ok(TraceState {
+            pc,
+            a: register("A")?,
+            x: register("X")?,
+            y: register("Y")?,
+            p: register("P")?,
+            s: register("SP")?,
+            cycles,
+        })
+    }
+
+    // a line of comma separated pc,a,x,y,p,sp in hex, with the cycle count in decimal as an
+    // optional last field
+    pub fn parse_csv(line: &str) -> Result<Self, String> {
+        let fields: Vec<&str> = line.split(',').map(|field| field.trim()).collect();
+        if fields.len() != 6 && fields.len() != 7 {
+            return Err("expected pc,a,x,y,p,sp[,cycles]".to_string());
+        }
+
+        let hex = |i: usize| {
+            u16::from_str_radix(fields[i], 16).map_err(|_| format!("bad number {}", fields[i]))
+        };
+        let byte = |i: usize| match hex(i)? {
+            byte @ 0..=0xff => Ok(byte as u8),
+            _ => Err(format!("{} doesn't fit in a byte", fields[i])),
+        };
+        let cycles = match fields.get(6) {
+            Some(cycles) => Some(
+                cycles
+                    .parse()
+                    .map_err(|_| format!("bad cycles {}", cycles))?,
+            ),
+            None => None,
+        };
+
+        Ok(TraceState {
+            pc: hex(0)?,
+            a: byte(1)?,
+            x: byte(2)?,
+            y: byte(3)?,
+            p: byte(4)?,
+            s: byte(5)?,
+            cycles,
+        })
+    }
+}
+
+impl fmt::Display for TraceState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "PC:{:04X} A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X}",
+            self.pc, self.a, self.x, self.y, self.p, self.s
+        )?;
+        if let Some(cycles) = self.cycles {
+            write!(f, " CYC:{}", cycles)?;
+        }
+        Ok(())
+    }
+}
+
+// where a run first parted from its reference trace, with the instruction about to run
+#[derive(Clone, Debug, PartialEq)]
+pub struct Divergence {
+    pub line: usize, // of the reference, counting from 1
+    pub expected: TraceState,
+    pub actual: TraceState,
+    pub disassembly: String, // our nestest line for the instruction
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "diverged at line {}\nexpected {}\nactual   {}\n{}",
+            self.line, self.expected, self.actual, self.disassembly
+        )
+    }
+}
+
+// runs the system an instruction per line of the reference, stopping at the first line
+// whose registers don't match. Lines with an A: field are read as nestest, and others as
+// CSV, with blank lines, # comments and a pc,... header skipped. Cycle counts are compared
+// from the first line on, so the trace can start at any count. Returns None when the whole
+// reference matched
+pub fn compare_trace<B: Bus>(
+    sys: &mut SystemState<B>,
+    reference: &str,
+) -> Result<Option<Divergence>, TraceError> {
+    let mut cycle_offset = None;
+
+    for (i, line) in reference.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') || line.to_lowercase().starts_with("pc,") {
+            continue;
+        }
+
+        let expected = if line.contains(" A:") {
+            TraceState::parse_nestest(line)
+        } else {
+            TraceState::parse_csv(line)
+        }
+        .map_err(|message| TraceError {
+            line: i + 1,
+            message,
+        })?;
+
+        let mut actual = TraceState::of(sys);
+        if let (Some(expected), Some(cycles)) = (expected.cycles, actual.cycles) {
+            let offset = *cycle_offset.get_or_insert(expected.wrapping_sub(cycles));
+            actual.cycles = Some(cycles.wrapping_add(offset));
+        } else {
+            actual.cycles = None;
+        }
+
+        if actual != expected {
+            return Ok(Some(Divergence {
+                line: i + 1,
+                expected,
+                actual,
+                disassembly: nestest_line(sys),
+            }));
+        }
+        step(sys);
+    }
+
+    Ok(None)
+}
+
+// the instruction about to run at the PC, in the format of the widely used nestest.log:
+//
+// C000  4C F5 C5  JMP $C5F5                       A:00 X:00 Y:00 P:24 SP:FD CYC:7
+//
+// undocumented opcodes are marked with a *, and operands annotated with the addresses
+// and values they will use, so logs from different emulators can be diffed directly.
+// Operands are shown by name when the system has symbols
+pub fn nestest_line<B: Bus>(sys: &SystemState<B>) -> String {
+    let cpu_state = sys.cpu_state();
+    let instruction = match sys.symbols() {
+        Some(symbols) => {
+            disassemble_with_symbols(sys.memory(), cpu_state.pc(), sys.options(), symbols)
+        }
+        None => disassemble(sys.memory(), cpu_state.pc(), sys.options()),
+    };
+
+    let bytes: Vec<String> = instruction
+        .bytes
+        .iter()
+        .map(|byte| format!("{:02X}", byte))
+        .collect();
+    let marker =
+        if sys.options().variant != CpuVariant::Cmos65c02 && undocumented(instruction.bytes[0]) {
+            '*'
+        } else {
+            ' '
+        };
+
+    // nestest calls ISC by its other name
+    let mnemonic = match instruction.mnemonic.as_str() {
+        "ISC" => "ISB",
+        mnemonic => mnemonic,
+    };
+    let disassembly = format!(
+        "{} {}{}",
+        mnemonic,
+        instruction.operand,
+        annotation(sys, &instruction)
+    );
+
+    format!(
+        "{:04X}  {:<8} {}{:<32}A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} CYC:{}",
+        instruction.addr,
+        bytes.join(" "),
+        marker,
+        disassembly.trim_end(),
+        cpu_state.a(),
+        cpu_state.x(),
+        cpu_state.y(),
+        cpu_state.status_byte(),
+        cpu_state.s(),
+        cpu_state.cycle_count()
+    )
+}
+
+fn annotation<B: Bus>(sys: &SystemState<B>, instruction: &Instruction) -> String {
+    let bus = sys.memory();
+    let cpu_state = sys.cpu_state();
+    let peek_word = |lo: u16, hi: u16| u16::from_le_bytes([bus.peek(lo), bus.peek(hi)]);
+    // zero page pointers wrap within the zero page
+    let peek_zp_word = |ptr: u8| peek_word(ptr as u16, ptr.wrapping_add(1) as u16);
+
+    let byte = instruction.bytes.get(1).copied().unwrap_or(0);
+    let word = u16::from_le_bytes([byte, instruction.bytes.get(2).copied().unwrap_or(0)]);
+    let jump = matches!(instruction.mnemonic.as_str(), "JMP" | "JSR");
+
+    match instruction.mode {
+        AddressingMode::Zp => format!(" = {:02X}", bus.peek(byte as u16)),
+        AddressingMode::A if !jump => format!(" = {:02X}", bus.peek(word)),
+        AddressingMode::Zpix | AddressingMode::Zpiy => {
+            let index = match instruction.mode {
+                AddressingMode::Zpix => cpu_state.x(),
+                _ => cpu_state.y(),
+            };
+            let addr = byte.wrapping_add(index);
+            format!(" @ {:02X} = {:02X}", addr, bus.peek(addr as u16))
+        }
+        AddressingMode::Aix | AddressingMode::Aiy => {
+            let index = match instruction.mode {
+                AddressingMode::Aix => cpu_state.x(),
+                _ => cpu_state.y(),
+            };
+            let addr = word.wrapping_add(index as u16);
+            format!(" @ {:04X} = {:02X}", addr, bus.peek(addr))
+        }
+        AddressingMode::Zpiix => {
+            let ptr = byte.wrapping_add(cpu_state.x());
+            let addr = peek_zp_word(ptr);
+            format!(" @ {:02X} = {:04X} = {:02X}", ptr, addr, bus.peek(addr))
+        }
+        AddressingMode::Zpiiy => {
+            let base = peek_zp_word(byte);
+            let addr = base.wrapping_add(cpu_state.y() as u16);
+            format!(" = {:04X} @ {:04X} = {:02X}", base, addr, bus.peek(addr))
+        }
+        AddressingMode::Zpi => {
+            let addr = peek_zp_word(byte);
+            format!(" = {:04X} = {:02X}", addr, bus.peek(addr))
+        }
+        AddressingMode::Ind => {
+            // the NMOS bug fetches the high byte without carrying into the pointer's page
+            let hi = if sys.options().jmp_indirect_bug {
+                (word & 0xff00) | (word as u8).wrapping_add(1) as u16
+            } else {
+                word.wrapping_add(1)
+            };
+            format!(" = {:04X}", peek_word(word, hi))
+        }
+        _ => String::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::PowerOnState;
+    use crate::symbols::Symbols;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[test]
+    fn test_nestest_line() {
+        let mut sys = SystemState::default();
+        sys.power_on(&PowerOnState {
+            s: 0xfd,
+            status: 0x24,
+            ..Default::default()
+        });
+        sys.cpu_state_mut().set_pc(0xc000);
+        sys.load_bytes(0xc000, &[0x4c, 0xf5, 0xc5]); // JMP $C5F5
+        sys.load_bytes(0xc5f5, &[0xa2, 0x00]); // LDX #$00
+        sys.load_bytes(0xc5f7, &[0x86, 0x00]); // STX $00
+        sys.load_bytes(0xc5f9, &[0x04, 0xa9]); // NOP $A9
+        sys.load_bytes(0xc5fb, &[0xb1, 0x89]); // LDA ($89),Y
+        sys.load_bytes(0x0089, &[0x00, 0x03]);
+        sys.load_bytes(0x0300, &[0x89]);
+
+        let lines = Rc::new(RefCell::new(Vec::new()));
+        let tracer_lines = lines.clone();
+        sys.set_tracer(move |line| tracer_lines.borrow_mut().push(line.to_string()));
+        for _ in 0..5 {
+            step(&mut sys);
+        }
+
+        assert_eq!(
+            vec![
+                "C000  4C F5 C5  JMP $C5F5                       A:00 X:00 Y:00 P:24 SP:FD CYC:0",
+                "C5F5  A2 00     LDX #$00                        A:00 X:00 Y:00 P:24 SP:FD CYC:3",
+                "C5F7  86 00     STX $00 = 00                    A:00 X:00 Y:00 P:26 SP:FD CYC:5",
+                "C5F9  04 A9    *NOP $A9 = 00                    A:00 X:00 Y:00 P:26 SP:FD CYC:8",
+                "C5FB  B1 89     LDA ($89),Y = 0300 @ 0300 = 89  A:00 X:00 Y:00 P:26 SP:FD CYC:11",
+            ],
+            *lines.borrow()
+        );
+    }
+
+    #[test]
+    fn test_nestest_line_with_symbols() {
+        let mut sys = SystemState::default();
+        sys.cpu_state_mut().set_pc(0xc000);
+        sys.load_bytes(0xc000, &[0x20, 0xa0, 0x80]); // JSR init_screen
+
+        let mut symbols = Symbols::new();
+        symbols.insert("init_screen", 0x80a0);
+        sys.set_symbols(symbols);
+
+        assert!(nestest_line(&sys).starts_with("C000  20 A0 80  JSR init_screen   "));
+    }
+
+    #[test]
+    fn test_parse_trace_lines() {
+        assert_eq!(
+            Ok(TraceState {
+                pc: 0xc000,
+                a: 0x00,
+                x: 0x01,
+                y: 0x02,
+                p: 0x24,
+                s: 0xfd,
+                cycles: Some(7),
+            }),
+            TraceState::parse_nestest(
+                "C000  4C F5 C5  JMP $C5F5                       A:00 X:01 Y:02 P:24 SP:FD PPU:  0, 21 CYC:7"
+            )
+        );
+        assert_eq!(
+            Ok(TraceState {
+                pc: 0x0200,
+                a: 0xff,
+                x: 0x00,
+                y: 0x00,
+                p: 0x34,
+                s: 0xfd,
+                cycles: None,
+            }),
+            TraceState::parse_csv("0200, FF, 00, 00, 34, FD")
+        );
+        assert!(TraceState::parse_nestest("C000  EA  NOP  A:00 X:00").is_err());
+        assert!(TraceState::parse_csv("0200,100,00,00,34,FD").is_err());
+    }
+
+    #[test]
+    fn test_compare_trace() {
+        let mut sys = SystemState::default();
+        sys.cpu_state_mut().set_pc(0x0200);
+        sys.load_bytes(0x0200, &[0xa9, 0x01, 0xaa, 0xe8]); // LDA #$01; TAX; INX
+
+        // cycle counts are only compared relative to the first line
+        let reference = "pc,a,x,y,p,sp,cycles\n\
+                         0200,00,00,00,20,FF,100\n\
+                         0202,01,00,00,20,FF,102\n\
+                         0203,01,01,00,20,FF,104\n";
+        assert_eq!(Ok(None), compare_trace(&mut sys, reference));
+
+        // the registers carry on from the last run
+        sys.cpu_state_mut().set_pc(0x0200);
+        let reference = "0200  A9 01     LDA #$01      A:01 X:02 Y:00 P:20 SP:FF\n\
+                         0202  AA        TAX           A:01 X:02 Y:00 P:20 SP:FF\n\
+                         0203  E8        INX           A:01 X:02 Y:00 P:20 SP:FF\n";
+        let divergence = compare_trace(&mut sys, reference).unwrap().unwrap();
+        assert_eq!(3, divergence.line);
+        assert_eq!(0x01, divergence.actual.x);
+        assert!(divergence.disassembly.starts_with("0203  E8        INX"));
+        assert_eq!(
+            "diverged at line 3\n\
+             expected PC:0203 A:01 X:02 Y:00 P:20 SP:FF\n\
+             actual   PC:0203 A:01 X:01 Y:00 P:20 SP:FF\n\
+             0203  E8        INX                             A:01 X:01 Y:00 P:20 SP:FF CYC:10",
+            divergence.to_string()
+        );
+
+        let error = compare_trace(&mut sys, "0200,00\n").unwrap_err();
+        assert_eq!(1, error.line);
+    }
+}
@@ -0,0 +1,300 @@
+use crate::{describe_event, describe_warning, format_instruction, Monitor};
+use m6502e_rs::bus::Bus;
+use m6502e_rs::disasm::Instruction;
+use m6502e_rs::hexdump::hexdump;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
+use ratatui::layout::{Constraint, Layout, Rect};
+use ratatui::style::{Modifier, Style};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Block, Paragraph};
+use ratatui::{DefaultTerminal, Frame};
+use std::cell::RefCell;
+use std::io;
+use std::rc::Rc;
+use std::time::Duration;
+
+// the monitor as a full screen debugger, with the registers, the code around the PC, the
+// stack and memory always showing. Commands are the monitor's, typed at the bottom
+const KEYS: &str = "F5 run  F7 step  F8 step over  Esc stop  PgUp/PgDn memory  Ctrl-C quit";
+
+// instructions run between redraws while running
+const RUN_SLICE: usize = 20_000;
+
+// lines of command output kept
+const OUTPUT_LINES: usize = 1000;
+
+struct Tui {
+    monitor: Monitor,
+    input: String,
+    output: Rc<RefCell<Vec<String>>>, // shared with the stack warning handler
+    disassembly_top: u16,
+    memory_top: u16,
+    running: bool,
+}
+
+pub fn run(monitor: Monitor) -> io::Result<()> {
+    let mut tui = Tui {
+        disassembly_top: monitor.sys.cpu_state().pc(),
+        monitor,
+        input: String::new(),
+        output: Rc::new(RefCell::new(Vec::new())),
+        memory_top: 0x0000,
+        running: false,
+    };
+
+    // warnings go to the output pane rather than over the screen
+    let output = tui.output.clone();
+    tui.monitor.sys.set_stack_warning_handler(move |warning| {
+        output.borrow_mut().push(describe_warning(&warning));
+    });
+
+    let mut terminal = ratatui::init();
+    let result = tui.event_loop(&mut terminal);
+    ratatui::restore();
+    result
+}
+
+impl Tui {
+    fn event_loop(&mut self, terminal: &mut DefaultTerminal) -> io::Result<()> {
+        loop {
+            terminal.draw(|frame| self.draw(frame))?;
+
+            // while running, keys are only looked for between slices
+            let timeout = if self.running {
+                Duration::ZERO
+            } else {
+                Duration::from_millis(250)
+            };
+            if event::poll(timeout)? {
+                if let Event::Key(key) = event::read()? {
+                    if key.kind != KeyEventKind::Press {
+                        continue;
+                    }
+                    if key.modifiers.contains(KeyModifiers::CONTROL)
+                        && key.code == KeyCode::Char('c')
+                    {
+                        return Ok(());
+                    }
+                    if !self.key(key.code) {
+                        return Ok(());
+                    }
+                }
+            }
+
+            if self.running {
+                if let Some(event) = self.monitor.sys.run_for(RUN_SLICE) {
+                    self.running = false;
+                    self.print(&describe_event(&event));
+                }
+            }
+        }
+    }
+
+    fn key(&mut self, code: KeyCode) -> bool {
+        // returns false when the debugger should exit
+        match code {
+            KeyCode::Esc if self.running => {
+                self.running = false;
+                self.print("stopped");
+            }
+            KeyCode::F(5) => self.running = true,
+            KeyCode::F(7) => return self.command("z"),
+            KeyCode::F(8) => return self.command("n"),
+            KeyCode::PageUp => self.memory_top = self.memory_top.wrapping_sub(0x80),
+            KeyCode::PageDown => self.memory_top = self.memory_top.wrapping_add(0x80),
+            KeyCode::Char(c) => self.input.push(c),
+            KeyCode::Backspace => {
+                self.input.pop();
+            }
+            KeyCode::Enter => {
+                let line = std::mem::take(&mut self.input);
+                return self.command(line.trim());
+            }
+            _ => {}
+        }
+        true
+    }
+
+    fn command(&mut self, line: &str) -> bool {
+        self.print(&format!(
+            "(C:${:04X}) {}",
+            self.monitor.sys.cpu_state().pc(),
+            line
+        ));
+        match self.monitor.execute(line) {
+            None => return false,
+            Some(Ok(output)) => self.print(&output),
+            Some(Err(error)) => self.print(&format!("error: {}", error)),
+        }
+
+        // m leaves off where it stopped, so the memory pane follows it
+        if line.starts_with('m') {
+            self.memory_top = self.monitor.next_examine.wrapping_sub(0x80);
+        }
+        true
+    }
+
+    fn print(&self, text: &str) {
+        let mut output = self.output.borrow_mut();
+        output.extend(text.lines().map(|line| line.to_string()));
+        let excess = output.len().saturating_sub(OUTPUT_LINES);
+        output.drain(..excess);
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let [main, output, input] = Layout::vertical([
+            Constraint::Min(8),
+            Constraint::Length(10),
+            Constraint::Length(3),
+        ])
+        .areas(frame.area());
+        let [left, disassembly, memory] = Layout::horizontal([
+            Constraint::Length(22),
+            Constraint::Min(40),
+            Constraint::Length(73),
+        ])
+        .areas(main);
+        let [registers, stack] =
+            Layout::vertical([Constraint::Length(5), Constraint::Min(3)]).areas(left);
+
+        self.draw_registers(frame, registers);
+        self.draw_stack(frame, stack);
+        self.draw_disassembly(frame, disassembly);
+        self.draw_memory(frame, memory);
+
+        let lines = self.output.borrow();
+        let shown = lines
+            .len()
+            .saturating_sub(output.height.saturating_sub(2) as usize);
+        let text: Vec<Line> = lines[shown..]
+            .iter()
+            .map(|line| Line::raw(line.as_str()))
+            .collect();
+        frame.render_widget(
+            Paragraph::new(text).block(Block::bordered().title("Output")),
+            output,
+        );
+
+        let title = if self.running {
+            "Running (Esc to stop)"
+        } else {
+            KEYS
+        };
+        frame.render_widget(
+            Paragraph::new(format!("> {}", self.input)).block(Block::bordered().title(title)),
+            input,
+        );
+        frame.set_cursor_position((input.x + 3 + self.input.len() as u16, input.y + 1));
+    }
+
+    fn draw_registers(&self, frame: &mut Frame, area: Rect) {
+        let state = self.monitor.sys.cpu_state();
+        let status = state.status_byte();
+
+        // set flags are shown bold, and clear ones dimmed
+        let flags: Vec<Span> = "NV-BDIZC"
+            .chars()
+            .enumerate()
+            .map(|(i, flag)| {
+                let style = if status & (0x80 >> i) != 0 {
+                    Style::new().add_modifier(Modifier::BOLD)
+                } else {
+                    Style::new().add_modifier(Modifier::DIM)
+                };
+                Span::styled(flag.to_string(), style)
+            })
+            .collect();
+
+        let text = vec![
+            Line::raw(format!(
+                "PC {:04X}  A {:02X}  X {:02X}",
+                state.pc(),
+                state.a(),
+                state.x()
+            )),
+            Line::raw(format!("SP {:02X}    Y {:02X}", state.s(), state.y())),
+            Line::from(flags),
+        ];
+        frame.render_widget(
+            Paragraph::new(text).block(Block::bordered().title("Registers")),
+            area,
+        );
+    }
+
+    fn draw_stack(&self, frame: &mut Frame, area: Rect) {
+        // the most recently pushed byte first
+        let s = self.monitor.sys.cpu_state().s();
+        let bus = self.monitor.sys.memory();
+        let text: Vec<Line> = (s as u16 + 1..=0xff)
+            .take(area.height.saturating_sub(2) as usize)
+            .map(|offset| {
+                let addr = 0x0100 | offset;
+                Line::raw(format!("{:04X}  {:02X}", addr, bus.peek(addr)))
+            })
+            .collect();
+        frame.render_widget(
+            Paragraph::new(text).block(Block::bordered().title("Stack")),
+            area,
+        );
+    }
+
+    fn draw_disassembly(&mut self, frame: &mut Frame, area: Rect) {
+        // instructions can't be reliably decoded backwards, so the view only moves when the
+        // PC leaves it, putting the PC at the top
+        let rows = area.height.saturating_sub(2) as usize;
+        let pc = self.monitor.sys.cpu_state().pc();
+        let mut instructions = self.instructions_from(self.disassembly_top, rows);
+        if !instructions[..rows.saturating_sub(2).min(instructions.len())]
+            .iter()
+            .any(|instruction| instruction.addr == pc)
+        {
+            self.disassembly_top = pc;
+            instructions = self.instructions_from(pc, rows);
+        }
+
+        let text: Vec<Line> = instructions
+            .iter()
+            .map(|instruction| {
+                let marker = match (
+                    instruction.addr == pc,
+                    self.monitor.sys.is_breakpoint(instruction.addr),
+                ) {
+                    (true, _) => '>',
+                    (false, true) => '*',
+                    (false, false) => ' ',
+                };
+                let line = format!("{}{}", marker, format_instruction(instruction));
+                if instruction.addr == pc {
+                    Line::styled(line, Style::new().add_modifier(Modifier::REVERSED))
+                } else {
+                    Line::raw(line)
+                }
+            })
+            .collect();
+        frame.render_widget(
+            Paragraph::new(text).block(Block::bordered().title("Disassembly")),
+            area,
+        );
+    }
+
+    fn instructions_from(&self, addr: u16, count: usize) -> Vec<Instruction> {
+        let mut addr = addr;
+        (0..count)
+            .map(|_| {
+                let instruction = self.monitor.disassemble_at(addr);
+                addr = addr.wrapping_add(instruction.len());
+                instruction
+            })
+            .collect()
+    }
+
+    fn draw_memory(&self, frame: &mut Frame, area: Rect) {
+        let rows = area.height.saturating_sub(2) as usize;
+        let dump = hexdump(self.monitor.sys.memory(), self.memory_top, rows * 16);
+        let text: Vec<Line> = dump.lines().map(Line::raw).collect();
+        frame.render_widget(
+            Paragraph::new(text).block(Block::bordered().title("Memory")),
+            area,
+        );
+    }
+}
@@ -0,0 +1,154 @@
+use crate::cpu::BusAccess;
+use std::io::{self, Write};
+
+// bus activity as a Value Change Dump, for viewing in GTKWave next to a logic analyser
+// capture of real hardware. Fed from a bus observer, it has the pins as they're named on the
+// 6502, with the active low ones ending in _n and PHI2 high for the second half of each
+// cycle. Times are in picoseconds from the first access, at the given clock rate
+
+// name, width and identifier
+const SIGNALS: [(&str, u32, char); 7] = [
+    ("phi2", 1, '!'),
+    ("addr", 16, '"'),
+    ("data", 8, '#'),
+    ("rw", 1, '$'),
+    ("sync", 1, '%'),
+    ("irq_n", 1, '&'),
+    ("nmi_n", 1, '\''),
+];
+
+pub struct Vcd<W: Write> {
+    out: W,
+    period: u64, // picoseconds a cycle
+    cycle: u64,  // cycles since the first access
+    last: Option<BusAccess>,
+    error: Option<io::Error>, // the first, after which nothing more is written
+}
+
+impl<W: Write> Vcd<W> {
+    pub fn new(out: W, clock_hz: u64) -> Self {
+        let mut vcd = Vcd {
+            out,
+            period: 1_000_000_000_000 / clock_hz,
+            cycle: 0,
+            last: None,
+            error: None,
+        };
+        if let Err(e) = vcd.write_header() {
+            vcd.error = Some(e);
+        }
+        vcd
+    }
+
+    fn write_header(&mut self) -> io::Result<()> {
+        writeln!(self.out, "$version m6502e-rs $end")?;
+        writeln!(self.out, "$timescale 1ps $end")?;
+        writeln!(self.out, "$scope module cpu $end")?;
+        for (name, width, id) in SIGNALS {
+            match width {
+                1 => writeln!(self.out, "$var wire 1 {} {} $end", id, name)?,
+                _ => writeln!(
+                    self.out,
+                    "$var wire {} {} {} [{}:0] $end",
+                    width,
+                    id,
+                    name,
+                    width - 1
+                )?,
+            }
+        }
+        writeln!(self.out, "$upscope $end")?;
+        writeln!(self.out, "$enddefinitions $end")
+    }
+
+    pub fn record(&mut self, access: BusAccess) {
+        if self.error.is_none() {
+            if let Err(e) = self.write_access(access) {
+                self.error = Some(e);
+            }
+        }
+    }
+
+    fn write_access(&mut self, access: BusAccess) -> io::Result<()> {
+        // cycles without accesses are skipped over, and time carries on forward past a reset
+        // of the cycle count
+        let last = self.last.replace(access);
+        self.cycle += match last {
+            Some(last) if access.cycle > last.cycle => access.cycle - last.cycle,
+            Some(_) => 1,
+            None => 0,
+        };
+        let start = self.cycle * self.period;
+
+        writeln!(self.out, "#{}", start)?;
+        writeln!(self.out, "0!")?;
+        let changed =
+            |pin: fn(&BusAccess) -> u16| last.is_none_or(|last| pin(&last) != pin(&access));
+        if changed(|access| access.addr) {
+            writeln!(self.out, "b{:b} \"", access.addr)?;
+        }
+        if changed(|access| access.value as u16) {
+            writeln!(self.out, "b{:b} #", access.value)?;
+        }
+        if changed(|access| access.write as u16) {
+            writeln!(self.out, "{}$", !access.write as u8)?;
+        }
+        if changed(|access| access.sync as u16) {
+            writeln!(self.out, "{}%", access.sync as u8)?;
+        }
+        if changed(|access| access.irq as u16) {
+            writeln!(self.out, "{}&", !access.irq as u8)?;
+        }
+        if changed(|access| access.nmi as u16) {
+            writeln!(self.out, "{}'", !access.nmi as u8)?;
+        }
+
+        writeln!(self.out, "#{}", start + self.period / 2)?;
+        writeln!(self.out, "1!")
+    }
+
+    // ends the last cycle and hands back the output, or the first error writing it
+    pub fn finish(mut self) -> io::Result<W> {
+        if let Some(e) = self.error {
+            return Err(e);
+        }
+        if self.last.is_some() {
+            writeln!(self.out, "#{}", (self.cycle + 1) * self.period)?;
+        }
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::{step, SystemState};
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[test]
+    fn test_vcd() {
+        let mut sys = SystemState::default();
+        sys.load_bytes(0x0000, &[0x85, 0x10]); // STA $10
+
+        let vcd = Rc::new(RefCell::new(Vcd::new(Vec::new(), 1_000_000)));
+        let observer_vcd = vcd.clone();
+        sys.set_bus_observer(move |access| observer_vcd.borrow_mut().record(access));
+        // with interrupts disabled, so the IRQ is only seen on the pin
+        sys.cpu_state_mut().set_status_byte(0x24);
+        sys.set_irq_line(true);
+        step(&mut sys);
+        sys.clear_bus_observer();
+
+        let vcd = Rc::try_unwrap(vcd).ok().unwrap().into_inner();
+        let text = String::from_utf8(vcd.finish().unwrap()).unwrap();
+        assert!(text.contains("$var wire 16 \" addr [15:0] $end\n"));
+        assert!(text.ends_with(
+            "#0\n0!\nb0 \"\nb10000101 #\n1$\n1%\n0&\n1'\n#500000\n1!\n\
+             #1000000\n0!\nb1 \"\nb10000 #\n0%\n#1500000\n1!\n\
+             #2000000\n0!\nb10000 \"\nb0 #\n0$\n#2500000\n1!\n\
+             #3000000\n"
+        ));
+    }
+}